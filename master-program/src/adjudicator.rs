@@ -0,0 +1,46 @@
+use shakmaty::Color;
+
+// ends a game once one side has been clearly winning for long enough, so
+// unattended tables don't play out dead-lost endgames
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Adjudicator {
+    // how far ahead, in centipawns, a side has to be to count as winning
+    threshold_cp: i32,
+    // how many evaluations in a row the same side has to be winning
+    plies: u32,
+    leader: Option<Color>,
+    streak: u32,
+}
+
+impl Adjudicator {
+    pub const fn new(threshold_cp: i32, plies: u32) -> Self {
+        Self {
+            threshold_cp,
+            plies,
+            leader: None,
+            streak: 0,
+        }
+    }
+
+    // takes the evaluation after a ply, from white's point of view, and
+    // returns the side that should resign if the game is decided
+    pub fn observe(&mut self, score_cp: i32) -> Option<Color> {
+        let leader = if score_cp >= self.threshold_cp {
+            Some(Color::White)
+        } else if score_cp <= -self.threshold_cp {
+            Some(Color::Black)
+        } else {
+            None
+        };
+        if leader.is_some() && leader == self.leader {
+            self.streak += 1;
+        } else {
+            self.streak = u32::from(leader.is_some());
+        }
+        self.leader = leader;
+        match leader {
+            Some(winner) if self.streak >= self.plies => Some(winner.other()),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,360 @@
+use log::{error, info, warn};
+use shakmaty::{Chess, Move, Outcome, Position, Square};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt};
+use tokio::sync::mpsc;
+
+use crate::clock::{Clocks, TimeLeft};
+use crate::config::GameConfig;
+use crate::{
+    finish_steps, game_over_reason, is_insufficient_material, move_to_steps, open_gantry,
+    parse_instruction, spawn_opponent, update_state, Feedback, GameSummary, Graveyard, State,
+    Step, HEARTBEAT_LINE,
+};
+
+// how often the clock task wakes the loop to look for a flag fall
+const CLOCK_TICK: Duration = Duration::from_millis(100);
+
+// room in each channel before its sender waits
+const CHANNEL_DEPTH: usize = 64;
+
+// what the loop asks of the opponent task
+#[derive(Debug, Clone)]
+pub enum Request {
+    // the human played `mv` from the position given
+    HumanMoved(Chess, Move),
+    // reply with a move for the position given
+    Think(Chess),
+    // the clocks as they stand, sent ahead of the other two in a game on the clock
+    Clocks(TimeLeft),
+}
+
+// the loop's ends of the channels to its tasks. a reed line of None is the
+// end of reed input, and every Think gets one reply, the move or why not
+pub struct Channels {
+    pub reeds: mpsc::Receiver<Option<String>>,
+    pub opponent: mpsc::Sender<Request>,
+    pub replies: mpsc::Receiver<Result<Move, String>>,
+    pub ticks: mpsc::Receiver<Instant>,
+    pub steps: mpsc::Sender<Vec<Step>>,
+}
+
+// plays one game with the reeds, the opponent, the clocks and the gantry each
+// in a task of its own, so a slow opponent never holds up reading the reeds or
+// noticing a flag fall
+pub fn play(config: &GameConfig) -> Result<GameSummary, String> {
+    let runtime = tokio::runtime::Runtime::new()
+        .map_err(|e| format!("Failed to start the async runtime: {e}"))?;
+    runtime.block_on(async {
+        let reeds = tokio::io::BufReader::new(tokio::io::stdin());
+        let (opponent, replies) = spawn_opponent_task(config.clone());
+        let channels = Channels {
+            reeds: spawn_reed_task(reeds),
+            opponent,
+            replies,
+            ticks: spawn_clock_task(),
+            steps: spawn_gantry_task(config.clone()),
+        };
+        run(config, channels).await
+    })
+}
+
+// the same game as session::GameSession in keyboard or reed index mode, with
+// update_state and move_to_steps doing the work between awaits. snapshot
+// frames, LEDs and the draw and pause gestures are left to the blocking loop
+pub async fn run(config: &GameConfig, mut channels: Channels) -> Result<GameSummary, String> {
+    let mut pos = config.start.clone();
+    let mut state = State::Idle;
+    let mut graveyard = Graveyard::default();
+    let mut emitted = Vec::new();
+    let mut clocks = config.time_control.map(Clocks::new);
+    if let Some(clocks) = clocks.as_mut() {
+        clocks.start(pos.turn(), Instant::now());
+    }
+    let mut thinking = false;
+    let result = loop {
+        if pos.is_game_over() || is_insufficient_material(&pos) {
+            let outcome = pos.outcome().unwrap_or(Outcome::Draw);
+            break Some((outcome, game_over_reason(&pos)));
+        }
+        if !config.human_plays(pos.turn()) && !thinking {
+            tell_clocks(&channels.opponent, clocks.as_ref()).await?;
+            let request = Request::Think(pos.clone());
+            channels.opponent.send(request).await.map_err(|_| "opponent task ended")?;
+            thinking = true;
+        }
+        tokio::select! {
+            line = channels.reeds.recv() => {
+                let Some(Some(line)) = line else {
+                    info!("received EOF from reed input, exiting");
+                    break None;
+                };
+                if line == HEARTBEAT_LINE {
+                    continue;
+                }
+                let Some(instruction) = read_instruction(config, &line) else {
+                    continue;
+                };
+                // the opponent is thinking, update_state won't read the board
+                let update = update_state(&pos, instruction, state, config.state);
+                if update.feedback == Feedback::OutOfTurn {
+                    continue;
+                }
+                info!("{state:?} -> {:?}", update.next);
+                state = update.next;
+                if state == State::Resigned {
+                    let winner = pos.turn().other();
+                    break Some((Outcome::Decisive { winner }, "resignation"));
+                }
+                if let Some(mv) = update.committed {
+                    let request = Request::HumanMoved(pos.clone(), mv.clone());
+                    pos = played(&pos, &mv, &mut graveyard, clocks.as_mut());
+                    tell_clocks(&channels.opponent, clocks.as_ref()).await?;
+                    channels.opponent.send(request).await.map_err(|_| "opponent task ended")?;
+                }
+            }
+            reply = channels.replies.recv(), if thinking => {
+                thinking = false;
+                let mv = reply.ok_or("opponent task ended")??;
+                if !pos.is_legal(&mv) {
+                    return Err(format!("opponent replied with the illegal move {mv}"));
+                }
+                info!("got move {mv} from opponent");
+                let steps: Vec<Step> = move_to_steps(
+                    mv.clone(),
+                    pos.turn(),
+                    f64::from(graveyard.captured_whites),
+                    f64::from(graveyard.captured_blacks),
+                    pos.board().occupied(),
+                    &config.geometry,
+                    config.capture_strategy,
+                )
+                .into_iter()
+                .map(|step| config.orientation.step(step, &config.geometry))
+                .collect();
+                let steps = finish_steps(steps, config, config.speed);
+                emitted.extend_from_slice(&steps);
+                if channels.steps.send(steps).await.is_err() {
+                    warn!("gantry task ended, {mv} has to be made by hand");
+                }
+                pos = played(&pos, &mv, &mut graveyard, clocks.as_mut());
+            }
+            Some(now) = channels.ticks.recv() => {
+                if let Some(loser) = clocks.and_then(|clocks| clocks.flagged(now)) {
+                    break Some((Outcome::Decisive { winner: loser.other() }, "time"));
+                }
+            }
+        }
+    };
+    Ok(GameSummary {
+        position: pos,
+        result,
+        steps: emitted,
+    })
+}
+
+// a reed index, or a typed square in keyboard mode
+fn read_instruction(config: &GameConfig, line: &str) -> Option<u32> {
+    if config.keyboard {
+        let Ok(square) = line.parse::<Square>() else {
+            warn!("not a square: {line}");
+            return None;
+        };
+        return Some(u32::from(square));
+    }
+    match parse_instruction(line) {
+        Ok(instruction) => Some(config.reed_square(instruction)),
+        Err(e) => {
+            error!("ignoring reed input: {e}");
+            None
+        }
+    }
+}
+
+async fn tell_clocks(
+    opponent: &mpsc::Sender<Request>,
+    clocks: Option<&Clocks>,
+) -> Result<(), String> {
+    let Some(clocks) = clocks else {
+        return Ok(());
+    };
+    let request = Request::Clocks(clocks.time_left(Instant::now()));
+    opponent.send(request).await.map_err(|_| "opponent task ended".to_string())
+}
+
+// the position after `mv`, with its capture and the clock press recorded
+fn played(pos: &Chess, mv: &Move, graveyard: &mut Graveyard, clocks: Option<&mut Clocks>) -> Chess {
+    graveyard.record(mv, pos.turn());
+    if let Some(clocks) = clocks {
+        clocks.press(Instant::now());
+    }
+    pos.clone().play(mv).expect("moves are checked before they're played")
+}
+
+pub fn spawn_reed_task(
+    reeds: impl AsyncBufRead + Unpin + Send + 'static,
+) -> mpsc::Receiver<Option<String>> {
+    let (tx, rx) = mpsc::channel(CHANNEL_DEPTH);
+    tokio::spawn(async move {
+        let mut lines = reeds.lines();
+        while let Ok(Some(line)) = lines.next_line().await {
+            if tx.send(Some(line.trim().to_string())).await.is_err() {
+                return;
+            }
+        }
+        let _ = tx.send(None).await;
+    });
+    rx
+}
+
+// the opponent blocks while it thinks, so it gets a thread of its own. it's
+// started there too, and if that fails every Think is answered with why
+pub fn spawn_opponent_task(
+    config: GameConfig,
+) -> (mpsc::Sender<Request>, mpsc::Receiver<Result<Move, String>>) {
+    let (request_tx, mut requests) = mpsc::channel(CHANNEL_DEPTH);
+    let (reply_tx, replies) = mpsc::channel(CHANNEL_DEPTH);
+    tokio::task::spawn_blocking(move || {
+        let mut opponent = spawn_opponent(&config);
+        while let Some(request) = requests.blocking_recv() {
+            let reply = match (&mut opponent, request) {
+                (Err(e), Request::Think(_)) => Err(e.clone()),
+                (Err(_), Request::HumanMoved(..) | Request::Clocks(_)) => continue,
+                (Ok(opponent), Request::Clocks(time_left)) => {
+                    opponent.clocks(time_left);
+                    continue;
+                }
+                (Ok(opponent), Request::HumanMoved(before, mv)) => {
+                    if let Err(e) = opponent.human_moved(&before, &mv) {
+                        error!("Failed to send human move to opponent: {e}");
+                    }
+                    continue;
+                }
+                (Ok(opponent), Request::Think(pos)) => opponent
+                    .best_move(&pos)
+                    .map_err(|e| format!("Failed to get a move from opponent: {e}")),
+            };
+            if reply_tx.blocking_send(reply).is_err() {
+                break;
+            }
+        }
+        if let Ok(opponent) = opponent.as_mut() {
+            opponent.quit();
+        }
+    });
+    (request_tx, replies)
+}
+
+pub fn spawn_clock_task() -> mpsc::Receiver<Instant> {
+    let (tx, rx) = mpsc::channel(1);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(CLOCK_TICK);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+        loop {
+            let now = interval.tick().await.into_std();
+            if tx.send(now).await.is_err() {
+                return;
+            }
+        }
+    });
+    rx
+}
+
+// steps for the gantry, sent from a thread of its own as they wait on acks.
+// without a gantry they're only logged
+pub fn spawn_gantry_task(config: GameConfig) -> mpsc::Sender<Vec<Step>> {
+    let (tx, mut rx) = mpsc::channel::<Vec<Step>>(CHANNEL_DEPTH);
+    tokio::task::spawn_blocking(move || {
+        let mut gantry = config.gantry.as_ref().and_then(|device| {
+            let journal = config.gantry_journal.clone();
+            open_gantry(
+                device,
+                journal,
+                config.gantry_batch,
+                config.magnet_wiring,
+                config.step_protocol,
+                config.mm_geometry(),
+            )
+            .map_err(|e| error!("{e}"))
+            .ok()
+        });
+        while let Some(steps) = rx.blocking_recv() {
+            info!("produced steps: {steps:?}");
+            if let Err(e) = config.geometry.check(&steps) {
+                error!("Not sending steps to the gantry: {e}");
+            } else if let Some(gantry) = gantry.as_mut() {
+                if let Err(e) = gantry.send(&steps) {
+                    error!("Failed to send steps to gantry: {e}");
+                }
+            }
+        }
+    });
+    tx
+}
+
+#[cfg(all(test, feature = "async-loop"))]
+mod tests {
+    use super::*;
+    use shakmaty::{san::San, Color};
+
+    // the opponent and the gantry both played by one task: for each turn the
+    // human's squares go in, then the opponent's reply once it's asked, and the
+    // next squares wait for its steps, as reeds are ignored while it thinks
+    async fn script(
+        turns: Vec<(&'static [&'static str], &'static str)>,
+        reeds: mpsc::Sender<Option<String>>,
+        mut requests: mpsc::Receiver<Request>,
+        replies: mpsc::Sender<Result<Move, String>>,
+        mut steps: mpsc::Receiver<Vec<Step>>,
+    ) -> Vec<String> {
+        let mut heard = Vec::new();
+        for (squares, reply) in turns {
+            for square in squares {
+                reeds.send(Some((*square).to_string())).await.unwrap();
+            }
+            let pos = loop {
+                match requests.recv().await.unwrap() {
+                    Request::HumanMoved(before, mv) => {
+                        heard.push(San::from_move(&before, &mv).to_string());
+                    }
+                    Request::Think(pos) => break pos,
+                    Request::Clocks(_) => {}
+                }
+            };
+            let mv = reply.parse::<San>().unwrap().to_move(&pos).unwrap();
+            replies.send(Ok(mv)).await.unwrap();
+            assert!(!steps.recv().await.unwrap().is_empty());
+        }
+        // the reeds stay open until the loop is done with its channels
+        while steps.recv().await.is_some() {}
+        heard
+    }
+
+    #[tokio::test]
+    async fn scripted_game_through_the_channels() {
+        let config = GameConfig::builder().human(Color::White).keyboard(true).build();
+        let (reeds_tx, reeds) = mpsc::channel(CHANNEL_DEPTH);
+        let (opponent, requests) = mpsc::channel(CHANNEL_DEPTH);
+        let (replies_tx, replies) = mpsc::channel(CHANNEL_DEPTH);
+        let (_, ticks) = mpsc::channel(1);
+        let (steps, steps_rx) = mpsc::channel(CHANNEL_DEPTH);
+        let turns: Vec<(&'static [&'static str], &'static str)> =
+            vec![(&["f2", "f3"], "e5"), (&["g2", "g4"], "Qh4#")];
+        let opponent_side = tokio::spawn(script(turns, reeds_tx, requests, replies_tx, steps_rx));
+        let channels = Channels {
+            reeds,
+            opponent,
+            replies,
+            ticks,
+            steps,
+        };
+        let summary = run(&config, channels).await.unwrap();
+        assert_eq!(opponent_side.await.unwrap(), ["f3", "g4"]);
+        assert!(summary.position.is_checkmate());
+        let black_wins = Outcome::Decisive {
+            winner: Color::Black,
+        };
+        assert_eq!(summary.result, Some((black_wins, "checkmate")));
+        assert!(summary.steps.len() >= 4);
+    }
+}
@@ -0,0 +1,141 @@
+use rand::Rng;
+use shakmaty::{
+    uci::Uci,
+    zobrist::{Zobrist64, ZobristHash},
+    CastlingMode, Chess, EnPassantMode, Move, Position, Role, Square,
+};
+
+// a Polyglot opening book: 16 byte big-endian entries of position key, move,
+// weight and learning data, sorted by key
+pub struct Book {
+    entries: Vec<Entry>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    key: u64,
+    mv: u16,
+    weight: u16,
+}
+
+impl Book {
+    pub fn open(path: &str) -> std::io::Result<Self> {
+        Ok(Self::from_bytes(&std::fs::read(path)?))
+    }
+
+    // a trailing partial entry is ignored
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        let entries = bytes
+            .chunks_exact(16)
+            .map(|entry| Entry {
+                key: u64::from_be_bytes(entry[0..8].try_into().unwrap()),
+                mv: u16::from_be_bytes([entry[8], entry[9]]),
+                weight: u16::from_be_bytes([entry[10], entry[11]]),
+            })
+            .collect();
+        Self { entries }
+    }
+
+    // every legal move the book has for `pos`, with its weight
+    pub fn moves(&self, pos: &Chess) -> Vec<(Move, u16)> {
+        let key = pos.zobrist_hash::<Zobrist64>(EnPassantMode::Legal).0;
+        let first = self.entries.partition_point(|entry| entry.key < key);
+        self.entries[first..]
+            .iter()
+            .take_while(|entry| entry.key == key)
+            .filter_map(|entry| Some((decode_move(pos, entry.mv)?, entry.weight)))
+            .collect()
+    }
+
+    // one of the book moves for `pos`, picked with `rng` in proportion to
+    // their weights, or None once the game has left the book
+    pub fn pick(&self, pos: &Chess, rng: &mut impl Rng) -> Option<Move> {
+        let moves = self.moves(pos);
+        let total: u64 = moves.iter().map(|&(_, weight)| u64::from(weight)).sum();
+        if total == 0 {
+            return None;
+        }
+        let mut roll = rng.gen_range(0..total);
+        moves.into_iter().find_map(|(mv, weight)| {
+            if roll < u64::from(weight) {
+                Some(mv)
+            } else {
+                roll -= u64::from(weight);
+                None
+            }
+        })
+    }
+}
+
+// polyglot packs a move as to file, to rank, from file, from rank and
+// promotion, three bits each from the bottom. castling is written as the king
+// taking its own rook, the same as chess960 UCI
+fn decode_move(pos: &Chess, mv: u16) -> Option<Move> {
+    let square = |bits: u16| Square::new(u32::from(bits & 0o77));
+    let promotion = match (mv >> 12) & 0b111 {
+        0 => None,
+        1 => Some(Role::Knight),
+        2 => Some(Role::Bishop),
+        3 => Some(Role::Rook),
+        4 => Some(Role::Queen),
+        _ => return None,
+    };
+    let uci = Uci::Normal {
+        from: square(mv >> 6),
+        to: square(mv),
+        promotion,
+    };
+    pos.legal_moves()
+        .into_iter()
+        .find(|legal| Uci::from_move(legal, CastlingMode::Chess960) == uci)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    // e4, d4, c4 and Nf3 from the start, weighted 4, 3, 2 and 1
+    fn opening_book() -> Book {
+        let key = Chess::default().zobrist_hash::<Zobrist64>(EnPassantMode::Legal).0;
+        let moves: [(Square, Square, u16); 4] = [
+            (Square::E2, Square::E4, 4),
+            (Square::D2, Square::D4, 3),
+            (Square::C2, Square::C4, 2),
+            (Square::G1, Square::F3, 1),
+        ];
+        let mut bytes = Vec::new();
+        for (from, to, weight) in moves {
+            let mv = u16::try_from(u32::from(from) << 6 | u32::from(to)).unwrap();
+            bytes.extend_from_slice(&key.to_be_bytes());
+            bytes.extend_from_slice(&mv.to_be_bytes());
+            bytes.extend_from_slice(&weight.to_be_bytes());
+            bytes.extend_from_slice(&[0; 4]);
+        }
+        Book::from_bytes(&bytes)
+    }
+
+    fn picks(book: &Book, seed: u64) -> Vec<Move> {
+        let mut rng = StdRng::seed_from_u64(seed);
+        (0..30).map(|_| book.pick(&Chess::default(), &mut rng).unwrap()).collect()
+    }
+
+    #[test]
+    fn same_seed_picks_the_same_moves() {
+        let book = opening_book();
+        assert_eq!(book.moves(&Chess::default()).len(), 4);
+        let first = picks(&book, 7);
+        assert_eq!(first, picks(&book, 7));
+        assert_ne!(first, picks(&book, 8));
+        // and it isn't just the heaviest move every time
+        assert!(first.iter().any(|mv| mv.to() != Square::E4));
+    }
+
+    #[test]
+    fn out_of_book_picks_nothing() {
+        let book = opening_book();
+        let e4 = book.moves(&Chess::default())[0].0.clone();
+        let after = Chess::default().play(&e4).unwrap();
+        assert_eq!(book.pick(&after, &mut StdRng::seed_from_u64(7)), None);
+    }
+}
@@ -0,0 +1,129 @@
+use log::{error, info, warn};
+use std::io::BufRead;
+
+use crate::config::BoardGeometry;
+use crate::gantry::{Gantry, HOME};
+use crate::{Magnet, Step};
+
+// the corner squares the operator marks, with their centres on 1 and 8 in board units
+const CORNERS: [&str; 4] = ["a1", "h1", "a8", "h8"];
+
+// how far the x and y square sizes may disagree before it's worth a warning
+const SQUARENESS_TOLERANCE: f64 = 0.02;
+
+// interactive jogging to work out the board's real geometry. each line is one of
+//   <dx> <dy>          jog by that many board units
+//   measure <x> <y>    compare the commanded position with one measured in mm
+//   mark <corner>      record the carriage as sitting over a1, h1, a8 or h8
+//   done               compute the geometry from the four marks
+// the magnet stays off the whole time; returns None if the input ends first
+pub fn calibrate(
+    input: impl BufRead,
+    mut gantry: Option<&mut Gantry>,
+    geometry: BoardGeometry,
+) -> Option<BoardGeometry> {
+    let mut position = HOME;
+    let mut marks: [Option<(f64, f64)>; 4] = [None; 4];
+    println!("at {position}, jog with <dx> <dy>, then mark a1, h1, a8 and h8");
+
+    for line in input.lines().map_while(Result::ok) {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice() {
+            ["done"] => {
+                let [Some(a1), Some(h1), Some(a8), Some(h8)] = marks else {
+                    println!("mark all four corners first");
+                    continue;
+                };
+                let fitted = fit_geometry(geometry, [a1, h1, a8, h8]);
+                println!("[geometry]");
+                println!("square_mm = {:?}", fitted.square_mm);
+                println!("origin_mm = [{:?}, {:?}]", fitted.origin_mm.0, fitted.origin_mm.1);
+                return Some(fitted);
+            }
+            ["mark", corner] => {
+                let Some(i) = CORNERS.iter().position(|name| name == corner) else {
+                    println!("{corner} is not a corner, expected one of a1, h1, a8, h8");
+                    continue;
+                };
+                marks[i] = Some((position.x, position.y));
+                println!("marked {corner} at {position}");
+            }
+            ["measure", x, y] => {
+                let (Ok(x), Ok(y)) = (x.parse::<f64>(), y.parse::<f64>()) else {
+                    println!("measure needs two numbers in mm");
+                    continue;
+                };
+                let (commanded_x, commanded_y) = to_mm(geometry, position);
+                println!(
+                    "commanded {commanded_x:.1} {commanded_y:.1} mm, measured {x:.1} {y:.1} mm, off by {:.1} {:.1} mm",
+                    x - commanded_x,
+                    y - commanded_y
+                );
+            }
+            [dx, dy] => {
+                let (Ok(dx), Ok(dy)) = (dx.parse::<f64>(), dy.parse::<f64>()) else {
+                    println!("jog needs two numbers in board units");
+                    continue;
+                };
+                position = Step {
+                    x: position.x + dx,
+                    y: position.y + dy,
+                    magnet: Magnet::Released,
+                    feedrate: None,
+                };
+                println!("{position}");
+                if let Some(gantry) = gantry.as_deref_mut() {
+                    if let Err(e) = gantry.send(&[position]) {
+                        error!("Failed to jog gantry: {e}");
+                    }
+                }
+            }
+            [] => {}
+            _ => println!("unknown command {line:?}"),
+        }
+    }
+    info!("calibration input ended before done");
+    None
+}
+
+const fn to_mm(geometry: BoardGeometry, step: Step) -> (f64, f64) {
+    let mm = geometry.in_mm(step);
+    (mm.x, mm.y)
+}
+
+// `marks` are where the corners were found, in board units of `geometry`,
+// in the order of CORNERS
+fn fit_geometry(geometry: BoardGeometry, marks: [(f64, f64); 4]) -> BoardGeometry {
+    let [a1, h1, a8, h8] = marks;
+    // the corner centres are seven squares apart on both axes
+    let x_scale = f64::midpoint(h1.0 - a1.0, h8.0 - a8.0) / 7.0;
+    let y_scale = f64::midpoint(a8.1 - a1.1, h8.1 - h1.1) / 7.0;
+    if (x_scale - y_scale).abs() > SQUARENESS_TOLERANCE * x_scale.abs() {
+        warn!("squares measure {x_scale:.3} by {y_scale:.3} board units, the axes may be skewed");
+    }
+    let square_mm = geometry.square_mm * (x_scale + y_scale) / 2.0;
+
+    // the board's centre is the middle of the four corners, 4.5 squares from unit 0
+    let centre = marks
+        .into_iter()
+        .map(|(x, y)| {
+            to_mm(
+                geometry,
+                Step {
+                    x,
+                    y,
+                    magnet: Magnet::Released,
+                    feedrate: None,
+                },
+            )
+        })
+        .fold((0.0, 0.0), |acc, (x, y)| (acc.0 + x / 4.0, acc.1 + y / 4.0));
+    BoardGeometry {
+        square_mm,
+        origin_mm: (
+            (-4.5f64).mul_add(square_mm, centre.0),
+            (-4.5f64).mul_add(square_mm, centre.1),
+        ),
+        ..geometry
+    }
+}
@@ -0,0 +1,106 @@
+use shakmaty::Color;
+use std::time::{Duration, Instant};
+
+use crate::config::TimeControl;
+
+// a chess clock: only the side to move's time runs, and finishing a move adds
+// the increment to the mover's time before starting the other side's
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Clocks {
+    white: Duration,
+    black: Duration,
+    increment: Duration,
+    // whose time is running and since when
+    running: Option<(Color, Instant)>,
+}
+
+impl Clocks {
+    pub const fn new(time_control: TimeControl) -> Self {
+        Self {
+            white: time_control.initial,
+            black: time_control.initial,
+            increment: time_control.increment,
+            running: None,
+        }
+    }
+
+    pub fn start(&mut self, color: Color, now: Instant) {
+        self.stop(now);
+        self.running = Some((color, now));
+    }
+
+    // stops whichever clock is running, charging it the time used so far
+    pub fn stop(&mut self, now: Instant) {
+        if let Some((color, since)) = self.running.take() {
+            let used = now.saturating_duration_since(since);
+            let remaining = self.remaining_mut(color);
+            *remaining = remaining.saturating_sub(used);
+        }
+    }
+
+    // the side to move finished its move: add its increment and start the other side
+    pub fn press(&mut self, now: Instant) {
+        let Some((color, _)) = self.running else {
+            return;
+        };
+        self.stop(now);
+        let increment = self.increment;
+        *self.remaining_mut(color) += increment;
+        self.running = Some((color.other(), now));
+    }
+
+    pub fn remaining(&self, color: Color, now: Instant) -> Duration {
+        let stored = match color {
+            Color::White => self.white,
+            Color::Black => self.black,
+        };
+        match self.running {
+            Some((running, since)) if running == color => {
+                stored.saturating_sub(now.saturating_duration_since(since))
+            }
+            _ => stored,
+        }
+    }
+
+    // both sides' time as it stands at `now`, for an engine to pace itself with
+    pub fn time_left(&self, now: Instant) -> TimeLeft {
+        TimeLeft {
+            white: self.remaining(Color::White, now),
+            black: self.remaining(Color::Black, now),
+            increment: self.increment,
+        }
+    }
+
+    // the side whose flag has fallen, if any
+    pub fn flagged(&self, now: Instant) -> Option<Color> {
+        let (color, _) = self.running?;
+        self.remaining(color, now).is_zero().then_some(color)
+    }
+
+    const fn remaining_mut(&mut self, color: Color) -> &mut Duration {
+        match color {
+            Color::White => &mut self.white,
+            Color::Black => &mut self.black,
+        }
+    }
+}
+
+// the clocks as an opponent is told them before it thinks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeLeft {
+    pub white: Duration,
+    pub black: Duration,
+    pub increment: Duration,
+}
+
+impl TimeLeft {
+    // a UCI go that leaves the engine to budget its own time over the game
+    pub fn go_command(&self) -> String {
+        let increment = self.increment.as_millis();
+        format!(
+            "go wtime {} btime {} winc {increment} binc {increment}",
+            self.white.as_millis(),
+            self.black.as_millis()
+        )
+    }
+}
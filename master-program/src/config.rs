@@ -0,0 +1,973 @@
+use log::warn;
+use regex::Regex;
+use serde::Deserialize;
+use shakmaty::{Bitboard, Chess, Color, File, Rank, Role, Square};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::theme::Theme;
+use crate::{
+    Magnet, Orientation, StateConfig, Step, Strictness, DEFAULT_ERROR_TRAIL, DEFAULT_GANTRY_JOURNAL,
+    DEFAULT_LED_FPS, DEFAULT_WRAPPER_PROMPT, OPPONENT_WRAPPER_EXE_PATH,
+};
+
+// what plays against the human
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OpponentBackend {
+    // the opponent-wrapper program at this path, speaking SAN
+    Wrapper(String),
+    // a UCI engine at this path
+    Uci(String),
+    // a UCI engine listening on this host:port
+    Remote(String),
+    // no one: two humans play each other on the board, which only checks
+    // and records their moves
+    Nobody,
+}
+
+// how hard a UCI engine thinks about its replies, None leaves each to the default
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct EngineStrength {
+    pub depth: Option<u32>,
+    pub movetime_ms: Option<u32>,
+    // the engine's Skill Level option, where it has one
+    pub skill: Option<u32>,
+}
+
+// base time plus a per-move increment, e.g. 5+3
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeControl {
+    pub initial: Duration,
+    pub increment: Duration,
+}
+
+impl FromStr for TimeControl {
+    type Err = String;
+
+    // minutes, optionally followed by +increment in seconds
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let bad = || format!("{text} is not a time control, expected minutes+increment like 5+3");
+        let (minutes, increment) = text.split_once('+').unwrap_or((text, "0"));
+        let minutes: f64 = minutes.parse().map_err(|_| bad())?;
+        let increment: f64 = increment.parse().map_err(|_| bad())?;
+        if minutes <= 0.0 || increment < 0.0 {
+            return Err(bad());
+        }
+        Ok(Self {
+            initial: Duration::from_secs_f64(minutes * 60.0),
+            increment: Duration::from_secs_f64(increment),
+        })
+    }
+}
+
+// the physical board as the gantry sees it. steps are planned in board
+// units: squares sit on 1..=8 and the graveyards on the columns either side
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BoardGeometry {
+    // edge length of one square
+    pub square_mm: f64,
+    // where board unit (0, 0) sits relative to the gantry's home switch
+    pub origin_mm: (f64, f64),
+    // furthest the carriage may travel, in board units, starting from 0
+    pub max_x: f64,
+    pub max_y: f64,
+    // where the carriage waits between moves with --home-after-move, in board units
+    pub home: (f64, f64),
+    // a spot off the board where one piece can wait while another gets past
+    // it, in board units as the planner sees them. None on builds without one
+    pub scratch: Option<(f64, f64)>,
+}
+
+impl Default for BoardGeometry {
+    fn default() -> Self {
+        Self {
+            square_mm: 50.0,
+            origin_mm: (0.0, 0.0),
+            max_x: 9.0,
+            max_y: 9.0,
+            home: (0.0, 0.0),
+            scratch: None,
+        }
+    }
+}
+
+impl BoardGeometry {
+    // travel to `home` with the magnet off, so nothing comes along
+    pub const fn home_step(&self) -> Step {
+        Step {
+            x: self.home.0,
+            y: self.home.1,
+            magnet: Magnet::Released,
+            feedrate: None,
+        }
+    }
+
+    // `step` moved from board units to millimetres from the home switch
+    pub const fn in_mm(&self, step: Step) -> Step {
+        Step {
+            x: step.x.mul_add(self.square_mm, self.origin_mm.0),
+            y: step.y.mul_add(self.square_mm, self.origin_mm.1),
+            ..step
+        }
+    }
+
+    // `step` turned half way round, for a board sat the other way in front of
+    // the player. the board sits in the middle of the carriage's travel, so
+    // that's a mirror across both axes of it
+    pub const fn rotated(&self, step: Step) -> Step {
+        Step {
+            x: self.max_x - step.x,
+            y: self.max_y - step.y,
+            ..step
+        }
+    }
+
+    // refuses steps that would drive the carriage past its travel, which it
+    // would otherwise find by hitting the frame
+    pub fn check(&self, steps: &[Step]) -> Result<(), String> {
+        let reachable = |step: &&Step| {
+            (0.0..=self.max_x).contains(&step.x) && (0.0..=self.max_y).contains(&step.y)
+        };
+        let Some(step) = steps.iter().find(|step| !reachable(step)) else {
+            return Ok(());
+        };
+        Err(format!(
+            "step {step} is outside the gantry's 0..={} by 0..={} travel",
+            self.max_x, self.max_y
+        ))
+    }
+}
+
+// the value sent in a step's M field for each magnet state. a carriage wired
+// with inverted polarity swaps the two, and one with two magnets picks which
+// of them engages, e.g. 2 for the second
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MagnetWiring {
+    pub engage: u8,
+    pub release: u8,
+}
+
+impl Default for MagnetWiring {
+    fn default() -> Self {
+        Self {
+            engage: 1,
+            release: 0,
+        }
+    }
+}
+
+impl MagnetWiring {
+    pub const fn wire(self, magnet: Magnet) -> u8 {
+        match magnet {
+            Magnet::Engaged => self.engage,
+            Magnet::Released => self.release,
+        }
+    }
+}
+
+impl FromStr for MagnetWiring {
+    type Err = String;
+
+    // the engage value, then the release value, e.g. 0,1 for inverted polarity
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let bad = || format!("{text} is not a magnet wiring, expected engage,release like 0,1");
+        let (engage, release) = text.split_once(',').ok_or_else(bad)?;
+        let engage = engage.parse().map_err(|_| bad())?;
+        let release = release.parse().map_err(|_| bad())?;
+        if engage == release {
+            return Err(bad());
+        }
+        Ok(Self { engage, release })
+    }
+}
+
+// how steps are framed on the serial link. text sends lines like X3.5 Y2.0 M1,
+// binary sends the compact records described at gantry::encode_frame for
+// slow links. the controller acks either with an "ok" line
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum StepProtocol {
+    #[default]
+    Text,
+    Binary,
+}
+
+impl FromStr for StepProtocol {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        match text {
+            "text" => Ok(Self::Text),
+            "binary" => Ok(Self::Binary),
+            _ => Err(format!(
+                "{text} is not a step protocol, expected text or binary"
+            )),
+        }
+    }
+}
+
+// what a step's X and Y are measured in on the serial link. the planner
+// works in board units either way, and mm converts each step as it goes out
+// to the gantry, for firmware that drives in millimetres from its home switch
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum StepUnits {
+    #[default]
+    Board,
+    Millimetres,
+}
+
+impl FromStr for StepUnits {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        match text {
+            "board" => Ok(Self::Board),
+            "mm" => Ok(Self::Millimetres),
+            _ => Err(format!("{text} is not a step unit, expected board or mm")),
+        }
+    }
+}
+
+// a trapezoidal speed profile for the carriage, so pieces aren't jerked
+// across a high friction board. legs run at `cruise`, but the carriage eases
+// up to it over the first `ramp` of travel after it stops or turns, and eases
+// down over the last `ramp` before it stops or turns, at `cruise * taper`.
+// feedrates are in the controller's units, ramps in squares
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FeedProfile {
+    pub cruise: f64,
+    pub taper: f64,
+    pub ramp: f64,
+}
+
+impl FeedProfile {
+    // `steps` with a feedrate on each. a leg long enough to ramp at an end is
+    // split there, so only its ends go slowly
+    pub fn shape(&self, steps: &[Step]) -> Vec<Step> {
+        let slow = self.cruise * self.taper;
+        let mut shaped = Vec::with_capacity(steps.len());
+        let mut push = |mut step: Step, feedrate: f64| {
+            step.feedrate = Some(feedrate);
+            shaped.push(step);
+        };
+        for (i, &step) in steps.iter().enumerate() {
+            // where the carriage comes from before the first step isn't known
+            let Some(from) = i.checked_sub(1).map(|j| steps[j]) else {
+                push(step, slow);
+                continue;
+            };
+            let ramp_up = if halts(steps, i - 1) { self.ramp } else { 0.0 };
+            let ramp_down = if halts(steps, i) { self.ramp } else { 0.0 };
+            let length = (step.x - from.x).hypot(step.y - from.y);
+            if length <= ramp_up + ramp_down {
+                let eased = ramp_up + ramp_down > 0.0;
+                push(step, if eased { slow } else { self.cruise });
+                continue;
+            }
+            let along = |distance: f64| Step {
+                x: from.x + (step.x - from.x) * distance / length,
+                y: from.y + (step.y - from.y) * distance / length,
+                ..step
+            };
+            if ramp_up > 0.0 {
+                push(along(ramp_up), slow);
+            }
+            if ramp_down > 0.0 {
+                push(along(length - ramp_down), self.cruise);
+                push(step, slow);
+            } else {
+                push(step, self.cruise);
+            }
+        }
+        shaped
+    }
+}
+
+// whether the carriage comes to rest at step `i`: the end of the steps, the
+// magnet picking up or letting go, or a change of direction
+fn halts(steps: &[Step], i: usize) -> bool {
+    let (Some(at), Some(next)) = (steps.get(i), steps.get(i + 1)) else {
+        return true;
+    };
+    if at.magnet != next.magnet {
+        return true;
+    }
+    let Some(before) = i.checked_sub(1).map(|j| steps[j]) else {
+        return false;
+    };
+    let (in_x, in_y) = (at.x - before.x, at.y - before.y);
+    let (out_x, out_y) = (next.x - at.x, next.y - at.y);
+    let cross = in_x.mul_add(out_y, -(in_y * out_x));
+    let dot = in_x.mul_add(out_x, in_y * out_y);
+    cross.abs() > 1e-9 || dot < 0.0
+}
+
+impl FromStr for FeedProfile {
+    type Err = String;
+
+    // cruise,taper,ramp, e.g. 3000,0.3,0.5 eases in and out over half a square
+    // at 900
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let bad = || {
+            format!("{text} is not a feed profile, expected cruise,taper,ramp like 3000,0.3,0.5")
+        };
+        let fields: Vec<f64> = text
+            .split(',')
+            .map(|field| field.trim().parse().map_err(|_| bad()))
+            .collect::<Result<_, _>>()?;
+        let [cruise, taper, ramp] = fields[..] else {
+            return Err(bad());
+        };
+        if cruise <= 0.0 || !(taper > 0.0 && taper <= 1.0) || ramp < 0.0 {
+            return Err(format!(
+                "{text}: the cruise feedrate must be positive, the taper in 0..1 and the ramp \
+                 not negative"
+            ));
+        }
+        Ok(Self {
+            cruise,
+            taper,
+            ramp,
+        })
+    }
+}
+
+// the feedrate --speed scales for steps that --feed-profile doesn't give one,
+// about what the controller runs at on its own
+pub const BASE_FEEDRATE: f64 = 3000.0;
+
+// how fast the carriage carries pieces, as a multiple of its feedrate: slow
+// and deliberate for an exhibit, quick for blitz. held to MIN..=MAX so pieces
+// aren't flung off the magnet, and stepped by STEP with the speed buttons
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Speed(f64);
+
+impl Speed {
+    pub const MIN: f64 = 0.25;
+    pub const MAX: f64 = 2.0;
+    pub const STEP: f64 = 1.25;
+
+    pub const fn new(multiplier: f64) -> Self {
+        Self(multiplier.clamp(Self::MIN, Self::MAX))
+    }
+
+    pub const fn multiplier(self) -> f64 {
+        self.0
+    }
+
+    pub fn faster(self) -> Self {
+        Self::new(self.0 * Self::STEP)
+    }
+
+    pub fn slower(self) -> Self {
+        Self::new(self.0 / Self::STEP)
+    }
+
+    // `steps` with every feedrate scaled, BASE_FEEDRATE standing in where there is none
+    pub fn scale(self, steps: Vec<Step>) -> Vec<Step> {
+        steps
+            .into_iter()
+            .map(|step| Step {
+                feedrate: Some(step.feedrate.unwrap_or(BASE_FEEDRATE) * self.0),
+                ..step
+            })
+            .collect()
+    }
+}
+
+impl Default for Speed {
+    fn default() -> Self {
+        Self(1.0)
+    }
+}
+
+impl TryFrom<f64> for Speed {
+    type Error = String;
+
+    // a multiplier out of bounds is held to them rather than refused
+    fn try_from(multiplier: f64) -> Result<Self, Self::Error> {
+        if !(multiplier.is_finite() && multiplier > 0.0) {
+            return Err(format!("{multiplier} is not a speed, expected a multiplier like 0.5"));
+        }
+        if !(Self::MIN..=Self::MAX).contains(&multiplier) {
+            warn!("speed {multiplier} is out of bounds, using {}", Self::new(multiplier).0);
+        }
+        Ok(Self::new(multiplier))
+    }
+}
+
+impl FromStr for Speed {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let multiplier: f64 = text
+            .parse()
+            .map_err(|_| format!("{text} is not a speed, expected a multiplier like 0.5"))?;
+        multiplier.try_into()
+    }
+}
+
+// the BCM GPIO pins the reed matrix is wired to, reed index row * 8 + col
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MatrixPins {
+    pub rows: [u8; 8],
+    pub cols: [u8; 8],
+}
+
+impl FromStr for MatrixPins {
+    type Err = String;
+
+    // eight row pins, then eight column pins, e.g. 2,3,4,17,27,22,10,9/5,6,13,19,26,21,20,16
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let bad = || format!("{text} is not a reed matrix, expected 8 row pins/8 column pins");
+        let pins = |list: &str| -> Result<[u8; 8], String> {
+            let pins: Vec<u8> = list
+                .split(',')
+                .map(|pin| pin.trim().parse().map_err(|_| bad()))
+                .collect::<Result<_, _>>()?;
+            pins.try_into().map_err(|_| bad())
+        };
+        let (rows, cols) = text.split_once('/').ok_or_else(bad)?;
+        Ok(Self {
+            rows: pins(rows)?,
+            cols: pins(cols)?,
+        })
+    }
+}
+
+// the order a capture's two pieces are carried in. clear-first takes the
+// captured piece to the graveyard and then moves the attacker onto its square.
+// stage-first brings the attacker up to a lane corner of the target first, for
+// builds where it can't get past pieces the capture route would have cleared,
+// then clears the captured piece and finishes the attacker's last leg
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CaptureStrategy {
+    #[default]
+    ClearFirst,
+    StageFirst,
+}
+
+impl FromStr for CaptureStrategy {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        match text {
+            "clear-first" => Ok(Self::ClearFirst),
+            "stage-first" => Ok(Self::StageFirst),
+            _ => Err(format!(
+                "{text} is not a capture strategy, expected clear-first or stage-first"
+            )),
+        }
+    }
+}
+
+// how the reed firmware numbers the squares, named for the square it calls 0
+// and whether it counts along ranks (rows) or files first. a1-row-major is
+// shakmaty's own numbering
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SquareMapping {
+    #[default]
+    A1Rows,
+    A1Files,
+    A8Rows,
+    A8Files,
+}
+
+impl SquareMapping {
+    // `index` must already be below 64
+    pub fn square(self, index: u32) -> Square {
+        let (major, minor) = (index / 8, index % 8);
+        let (file, rank) = match self {
+            Self::A1Rows => (minor, major),
+            Self::A1Files => (major, minor),
+            Self::A8Rows => (minor, 7 - major),
+            Self::A8Files => (major, 7 - minor),
+        };
+        Square::from_coords(File::new(file), Rank::new(rank))
+    }
+
+    // a snapshot frame, whose bit n is the firmware's square n
+    pub fn frame(self, frame: Bitboard) -> Bitboard {
+        frame
+            .into_iter()
+            .map(|square| self.square(u32::from(square)))
+            .collect()
+    }
+}
+
+impl FromStr for SquareMapping {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        match text {
+            "a1-row-major" => Ok(Self::A1Rows),
+            "a1-file-major" => Ok(Self::A1Files),
+            "a8-row-major" => Ok(Self::A8Rows),
+            "a8-file-major" => Ok(Self::A8Files),
+            _ => Err(format!(
+                "{text} is not a square mapping, expected a1-row-major, a1-file-major, \
+                 a8-row-major or a8-file-major"
+            )),
+        }
+    }
+}
+
+// adjudication ends the game once one side has been past `threshold_cp` for `plies` plies in a row
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Adjudication {
+    pub threshold_cp: i32,
+    pub plies: u32,
+}
+
+// everything a game needs to know before it starts
+#[allow(clippy::struct_excessive_bools)]
+#[derive(Debug, Clone)]
+pub struct GameConfig {
+    pub human: Color,
+    // the position the game starts from, normally the standard one
+    pub start: Chess,
+    // None plays without clocks
+    pub time_control: Option<TimeControl>,
+    pub opponent: OpponentBackend,
+    pub engine_strength: EngineStrength,
+    // a Polyglot book the engine's first `book_moves` replies come from
+    pub book: Option<String>,
+    pub book_moves: Option<u32>,
+    // seeds the book's choices, so a seeded run replays the same
+    pub seed: Option<u64>,
+    // which of the opponent wrapper's boot lines are questions to answer
+    pub wrapper_prompt: Regex,
+    // answers to those questions in the order they're asked, any left over
+    // are asked of whoever is at the console
+    pub wrapper_answers: Vec<String>,
+    pub geometry: BoardGeometry,
+    pub theme: Theme,
+    pub orientation: Orientation,
+    pub square_mapping: SquareMapping,
+    pub state: StateConfig,
+    // how long a lifted piece may stay in the air before it's assumed put back
+    pub pickup_timeout: Option<Duration>,
+    pub adjudication: Option<Adjudication>,
+    // plies after which an unattended game is called a draw, in case the opponent loops
+    pub max_plies: Option<u32>,
+    // squares typed at the console instead of reed indices, with states and LEDs printed back
+    pub keyboard: bool,
+    // light up legal move origins and castling squares for learners
+    pub teach: bool,
+    // light what a lifted piece would attack from a destination it's held over
+    pub threat_preview: bool,
+    // flash a piece the human just left en prise
+    pub coach: bool,
+    // keyboard mode draws the LEDs as a coloured grid instead of three bitboards
+    pub led_grid: bool,
+    // least time between two LED frames that differ, so animations go out at a steady rate
+    pub led_frame_interval: Duration,
+    // every reed line is a full occupancy frame rather than one toggled square
+    pub snapshot: bool,
+    pub verbose_board: bool,
+    // serial device of the gantry controller, and where it journals moves in flight
+    pub gantry: Option<String>,
+    pub gantry_journal: PathBuf,
+    // how many steps may share one serial line and ack, see Gantry
+    pub gantry_batch: usize,
+    pub magnet_wiring: MagnetWiring,
+    pub step_protocol: StepProtocol,
+    pub step_units: StepUnits,
+    // eases the carriage in and out of its stops and turns, None runs every step at the
+    // controller's own constant speed
+    pub feed_profile: Option<FeedProfile>,
+    // scales every feedrate, None leaves them as the feed profile has them
+    pub speed: Option<Speed>,
+    // send the carriage home after each of the opponent's moves instead of leaving it there
+    pub home_after_move: bool,
+    // instructions kept to log when the state machine hits Error, 0 keeps none
+    pub error_trail: usize,
+    pub capture_strategy: CaptureStrategy,
+    pub telemetry: Option<String>,
+    pub event_log: Option<String>,
+}
+
+impl Default for GameConfig {
+    fn default() -> Self {
+        Self {
+            human: Color::White,
+            start: Chess::default(),
+            time_control: None,
+            opponent: OpponentBackend::Wrapper(OPPONENT_WRAPPER_EXE_PATH.to_string()),
+            engine_strength: EngineStrength::default(),
+            book: None,
+            book_moves: None,
+            seed: None,
+            wrapper_prompt: Regex::new(DEFAULT_WRAPPER_PROMPT).unwrap(),
+            wrapper_answers: Vec::new(),
+            geometry: BoardGeometry::default(),
+            theme: Theme::default(),
+            orientation: Orientation::Normal,
+            square_mapping: SquareMapping::default(),
+            state: StateConfig {
+                human: Some(Color::White),
+                ..StateConfig::default()
+            },
+            pickup_timeout: None,
+            adjudication: None,
+            max_plies: None,
+            keyboard: false,
+            teach: false,
+            threat_preview: false,
+            coach: false,
+            led_grid: false,
+            led_frame_interval: Duration::from_secs(1) / DEFAULT_LED_FPS,
+            snapshot: false,
+            verbose_board: false,
+            gantry: None,
+            gantry_journal: PathBuf::from(DEFAULT_GANTRY_JOURNAL),
+            gantry_batch: 1,
+            magnet_wiring: MagnetWiring::default(),
+            step_protocol: StepProtocol::default(),
+            step_units: StepUnits::default(),
+            feed_profile: None,
+            speed: None,
+            home_after_move: false,
+            error_trail: DEFAULT_ERROR_TRAIL,
+            capture_strategy: CaptureStrategy::default(),
+            telemetry: None,
+            event_log: None,
+        }
+    }
+}
+
+impl GameConfig {
+    pub fn builder() -> GameConfigBuilder {
+        GameConfigBuilder::default()
+    }
+
+    // the square a raw reed index is on, once the firmware's numbering and
+    // the board's orientation are accounted for. `instruction` must be below 64
+    pub fn reed_square(&self, instruction: u32) -> u32 {
+        let square = self.square_mapping.square(instruction);
+        self.orientation.instruction(u32::from(square))
+    }
+
+    // the same for a whole snapshot frame
+    pub fn reed_frame(&self, frame: Bitboard) -> Bitboard {
+        self.orientation.frame(self.square_mapping.frame(frame))
+    }
+
+    // the geometry steps are turned into millimetres with on their way to the
+    // gantry, None when they go out in board units
+    pub fn mm_geometry(&self) -> Option<BoardGeometry> {
+        (self.step_units == StepUnits::Millimetres).then_some(self.geometry)
+    }
+
+    // whether `color` moves by hand on the board, as both sides do with no opponent
+    pub fn human_plays(&self, color: Color) -> bool {
+        color == self.human || self.opponent == OpponentBackend::Nobody
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct GameConfigBuilder {
+    config: GameConfig,
+}
+
+impl GameConfigBuilder {
+    pub const fn human(mut self, color: Color) -> Self {
+        self.config.human = color;
+        self.config.state.human = Some(color);
+        self
+    }
+
+    pub const fn start(mut self, start: Chess) -> Self {
+        self.config.start = start;
+        self
+    }
+
+    pub const fn time_control(mut self, time_control: Option<TimeControl>) -> Self {
+        self.config.time_control = time_control;
+        self
+    }
+
+    pub fn opponent(mut self, opponent: OpponentBackend) -> Self {
+        self.config.opponent = opponent;
+        self
+    }
+
+    pub const fn engine_strength(mut self, strength: EngineStrength) -> Self {
+        self.config.engine_strength = strength;
+        self
+    }
+
+    pub fn book(mut self, path: Option<String>, moves: Option<u32>) -> Self {
+        self.config.book = path;
+        self.config.book_moves = moves;
+        self
+    }
+
+    pub const fn seed(mut self, seed: Option<u64>) -> Self {
+        self.config.seed = seed;
+        self
+    }
+
+    // None keeps the default prompt
+    pub fn wrapper_prompt(mut self, prompt: Option<Regex>) -> Self {
+        if let Some(prompt) = prompt {
+            self.config.wrapper_prompt = prompt;
+        }
+        self
+    }
+
+    pub fn wrapper_answers(mut self, answers: Vec<String>) -> Self {
+        self.config.wrapper_answers = answers;
+        self
+    }
+
+    pub const fn geometry(mut self, geometry: BoardGeometry) -> Self {
+        self.config.geometry = geometry;
+        self
+    }
+
+    pub const fn theme(mut self, theme: Theme) -> Self {
+        self.config.theme = theme;
+        self
+    }
+
+    pub const fn orientation(mut self, orientation: Orientation) -> Self {
+        self.config.orientation = orientation;
+        self
+    }
+
+    pub const fn square_mapping(mut self, mapping: SquareMapping) -> Self {
+        self.config.square_mapping = mapping;
+        self
+    }
+
+    pub const fn default_promotion(mut self, role: Role) -> Self {
+        self.config.state.default_promotion = role;
+        self
+    }
+
+    pub const fn touch_move(mut self, touch_move: bool) -> Self {
+        self.config.state.touch_move = touch_move;
+        self
+    }
+
+    pub const fn swap_promotion(mut self, swap_promotion: bool) -> Self {
+        self.config.state.swap_promotion = swap_promotion;
+        self
+    }
+
+    pub const fn strictness(mut self, strictness: Strictness) -> Self {
+        self.config.state.strictness = strictness;
+        self
+    }
+
+    pub const fn pickup_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.config.pickup_timeout = timeout;
+        self
+    }
+
+    pub const fn adjudication(mut self, adjudication: Option<Adjudication>) -> Self {
+        self.config.adjudication = adjudication;
+        self
+    }
+
+    pub const fn max_plies(mut self, plies: Option<u32>) -> Self {
+        self.config.max_plies = plies;
+        self
+    }
+
+    pub const fn keyboard(mut self, keyboard: bool) -> Self {
+        self.config.keyboard = keyboard;
+        self
+    }
+
+    pub const fn teach(mut self, teach: bool) -> Self {
+        self.config.teach = teach;
+        self
+    }
+
+    pub const fn threat_preview(mut self, threat_preview: bool) -> Self {
+        self.config.threat_preview = threat_preview;
+        self
+    }
+
+    pub const fn coach(mut self, coach: bool) -> Self {
+        self.config.coach = coach;
+        self
+    }
+
+    pub const fn led_grid(mut self, led_grid: bool) -> Self {
+        self.config.led_grid = led_grid;
+        self
+    }
+
+    pub fn led_fps(mut self, fps: u32) -> Self {
+        self.config.led_frame_interval = Duration::from_secs(1) / fps;
+        self
+    }
+
+    pub const fn snapshot(mut self, snapshot: bool) -> Self {
+        self.config.snapshot = snapshot;
+        self
+    }
+
+    pub const fn verbose_board(mut self, verbose_board: bool) -> Self {
+        self.config.verbose_board = verbose_board;
+        self
+    }
+
+    pub fn gantry(mut self, device: Option<String>, journal: PathBuf) -> Self {
+        self.config.gantry = device;
+        self.config.gantry_journal = journal;
+        self
+    }
+
+    pub const fn gantry_batch(mut self, steps: usize) -> Self {
+        self.config.gantry_batch = steps;
+        self
+    }
+
+    pub const fn magnet_wiring(mut self, wiring: MagnetWiring) -> Self {
+        self.config.magnet_wiring = wiring;
+        self
+    }
+
+    pub const fn step_protocol(mut self, protocol: StepProtocol) -> Self {
+        self.config.step_protocol = protocol;
+        self
+    }
+
+    pub const fn step_units(mut self, units: StepUnits) -> Self {
+        self.config.step_units = units;
+        self
+    }
+
+    pub const fn feed_profile(mut self, profile: Option<FeedProfile>) -> Self {
+        self.config.feed_profile = profile;
+        self
+    }
+
+    pub const fn speed(mut self, speed: Option<Speed>) -> Self {
+        self.config.speed = speed;
+        self
+    }
+
+    pub const fn home_after_move(mut self, home_after_move: bool) -> Self {
+        self.config.home_after_move = home_after_move;
+        self
+    }
+
+    pub const fn error_trail(mut self, length: usize) -> Self {
+        self.config.error_trail = length;
+        self
+    }
+
+    pub const fn capture_strategy(mut self, strategy: CaptureStrategy) -> Self {
+        self.config.capture_strategy = strategy;
+        self
+    }
+
+    pub fn telemetry(mut self, target: Option<String>) -> Self {
+        self.config.telemetry = target;
+        self
+    }
+
+    pub fn event_log(mut self, path: Option<String>) -> Self {
+        self.config.event_log = path;
+        self
+    }
+
+    pub fn build(mut self) -> GameConfig {
+        // with no opponent the state machine takes moves for either side
+        if self.config.opponent == OpponentBackend::Nobody {
+            self.config.state.human = None;
+        }
+        self.config
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn each_mapping_places_the_corners_and_the_second_index() {
+        for (name, first, second, last) in [
+            ("a1-row-major", Square::A1, Square::B1, Square::H8),
+            ("a1-file-major", Square::A1, Square::A2, Square::H8),
+            ("a8-row-major", Square::A8, Square::B8, Square::H1),
+            ("a8-file-major", Square::A8, Square::A7, Square::H1),
+        ] {
+            let mapping = name.parse::<SquareMapping>().unwrap();
+            assert_eq!([0, 1, 63].map(|index| mapping.square(index)), [first, second, last]);
+        }
+        assert!("h8-row-major".parse::<SquareMapping>().is_err());
+    }
+
+    #[test]
+    fn feed_profile_slows_the_ends_of_a_journey() {
+        let profile = FeedProfile {
+            cruise: 1000.0,
+            taper: 0.25,
+            ramp: 0.5,
+        };
+        let step = |y, magnet| Step {
+            x: 1.0,
+            y,
+            magnet,
+            feedrate: None,
+        };
+        // a piece picked up on a1 and carried to a4
+        let journey = [step(1.0, Magnet::Released), step(4.0, Magnet::Engaged)];
+        let shaped: Vec<_> =
+            profile.shape(&journey).iter().map(|step| (step.y, step.feedrate)).collect();
+        assert_eq!(
+            shaped,
+            [(1.0, Some(250.0)), (1.5, Some(250.0)), (3.5, Some(1000.0)), (4.0, Some(250.0))]
+        );
+    }
+
+    #[test]
+    fn board_units_become_millimetres_from_home() {
+        let geometry = BoardGeometry {
+            square_mm: 40.0,
+            origin_mm: (12.0, 20.0),
+            ..BoardGeometry::default()
+        };
+        let a1 = Step {
+            x: 1.0,
+            y: 1.0,
+            magnet: Magnet::Engaged,
+            feedrate: None,
+        };
+        let mm = geometry.in_mm(a1);
+        assert_eq!((mm.x, mm.y, mm.magnet), (52.0, 60.0, Magnet::Engaged));
+        // the half square lanes scale the same way
+        let lane = geometry.in_mm(Step { x: 1.5, ..a1 });
+        assert!((lane.x - 72.0).abs() < f64::EPSILON);
+    }
+
+    #[test]
+    fn speed_scales_feedrates_within_its_bounds() {
+        let step = |feedrate| Step {
+            x: 1.0,
+            y: 1.0,
+            magnet: Magnet::Engaged,
+            feedrate,
+        };
+        let feedrates = |speed: Speed| -> Vec<_> {
+            let steps = speed.scale(vec![step(Some(400.0)), step(None)]);
+            steps.iter().map(|step| step.feedrate).collect()
+        };
+        let half = Speed::try_from(0.5).unwrap();
+        assert_eq!(feedrates(half), [Some(200.0), Some(BASE_FEEDRATE * 0.5)]);
+        // too fast is held to the top speed, and the buttons stop there too
+        let flung = Speed::try_from(10.0).unwrap();
+        assert!((flung.multiplier() - Speed::MAX).abs() < f64::EPSILON);
+        assert_eq!(feedrates(flung), [Some(800.0), Some(BASE_FEEDRATE * Speed::MAX)]);
+        assert_eq!(flung.faster(), flung);
+        assert!((Speed::new(0.01).slower().multiplier() - Speed::MIN).abs() < f64::EPSILON);
+        assert!(Speed::try_from(-1.0).is_err());
+    }
+}
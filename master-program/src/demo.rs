@@ -0,0 +1,160 @@
+use log::{error, info};
+use shakmaty::{san::San, Outcome, Position};
+use std::io::Write;
+
+use crate::config::GameConfig;
+use crate::gantry::Gantry;
+use crate::leds::Leds;
+use crate::opponent::{Opponent, UciEngine};
+use crate::{
+    console_error, finish_steps, game_over_reason, game_over_rgb, get_rgb, is_insufficient_material,
+    move_to_steps, open_gantry, plies_played, print_ply, spawn_opponent, GameSummary, Graveyard,
+    State, Step, Waiting, MOVE_LIMIT,
+};
+
+// an unattended demo with no reed input: the opponent plays its usual side
+// against the UCI engine at `engine`, which stands in for the human. every
+// move goes to the gantry and the LEDs the way the opponent's moves do
+pub fn engine_vs_engine(
+    config: &GameConfig,
+    engine: &str,
+    console: &mut dyn Write,
+) -> Result<GameSummary, String> {
+    let mut opponent = spawn_opponent(config)?;
+    let mut stand_in = UciEngine::spawn(engine, &config.start, config.engine_strength)
+        .map_err(|e| format!("Failed to start engine {engine}: {e}"))?;
+    let mut gantry = match &config.gantry {
+        Some(device) => Some(open_gantry(
+            device,
+            config.gantry_journal.clone(),
+            config.gantry_batch,
+            config.magnet_wiring,
+            config.step_protocol,
+            config.mm_geometry(),
+        )?),
+        None => None,
+    };
+    let summary = play_out(config, opponent.as_mut(), &mut stand_in, gantry.as_mut(), console);
+    opponent.quit();
+    stand_in.quit();
+    summary
+}
+
+// plays from the starting position until the game ends or runs into
+// --max-plies, `stand_in` moving for the human's side. each side hears of the
+// other's move as if a human had made it
+pub fn play_out(
+    config: &GameConfig,
+    opponent: &mut dyn Opponent,
+    stand_in: &mut dyn Opponent,
+    mut gantry: Option<&mut Gantry>,
+    console: &mut dyn Write,
+) -> Result<GameSummary, String> {
+    let mut pos = config.start.clone();
+    let mut graveyard = Graveyard::default();
+    let mut leds = Leds::new(config.led_grid, config.led_frame_interval);
+    let mut emitted = Vec::new();
+    let (outcome, reason) = loop {
+        if pos.is_game_over() || is_insufficient_material(&pos) {
+            break (pos.outcome().unwrap_or(Outcome::Draw), game_over_reason(&pos));
+        }
+        let plies = plies_played(&pos).saturating_sub(plies_played(&config.start));
+        if matches!(config.max_plies, Some(limit) if plies >= limit) {
+            break (Outcome::Draw, MOVE_LIMIT);
+        }
+        let side = pos.turn();
+        let stand_in_moves = side == config.human;
+        let reply = if stand_in_moves {
+            stand_in.best_move(&pos)
+        } else {
+            opponent.best_move(&pos)
+        };
+        let mv = reply.map_err(|e| format!("Failed to get a move for {side}: {e}"))?;
+        if !pos.is_legal(&mv) {
+            return Err(format!("{side} replied with the illegal move {mv}"));
+        }
+        info!("{side} plays {mv}");
+        // the other side thinks while the gantry moves
+        let sent = if stand_in_moves {
+            opponent.human_moved(&pos, &mv)
+        } else {
+            stand_in.human_moved(&pos, &mv)
+        };
+        sent.map_err(|e| format!("Failed to send {mv} to {}: {e}", side.other()))?;
+
+        let steps: Vec<Step> = move_to_steps(
+            mv.clone(),
+            side,
+            f64::from(graveyard.captured_whites),
+            f64::from(graveyard.captured_blacks),
+            pos.board().occupied(),
+            &config.geometry,
+            config.capture_strategy,
+        )
+        .into_iter()
+        .map(|step| config.orientation.step(step, &config.geometry))
+        .collect();
+        let steps = finish_steps(steps, config, config.speed);
+        if let Err(e) = config.geometry.check(&steps) {
+            error!("Not sending {mv} to the gantry: {e}");
+        } else if let Some(gantry) = gantry.as_mut() {
+            if let Err(e) = gantry.send(&steps) {
+                error!("Failed to send steps to gantry: {e}");
+            }
+        }
+        emitted.extend_from_slice(&steps);
+
+        let san = San::from_move(&pos, &mv).to_string();
+        graveyard.record(&mv, side);
+        pos = pos.play(&mv).expect("moves are checked before they're played");
+        if config.verbose_board {
+            print_ply(console, &pos, &san).map_err(console_error)?;
+        }
+        if config.keyboard {
+            let theme = &config.theme;
+            let rgb = get_rgb(&pos, State::Idle, theme, false, Some(&mv), Waiting::Opponent);
+            leds.show(console, rgb).map_err(console_error)?;
+        }
+    };
+    info!("game ended with {outcome} by {reason}");
+    if config.keyboard {
+        let rgb = game_over_rgb(&pos, outcome, &config.theme);
+        leds.show_final(console, rgb).map_err(console_error)?;
+    }
+    Ok(GameSummary {
+        position: pos,
+        result: Some((outcome, reason)),
+        steps: emitted,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::opponent::Wrapper;
+    use crate::tests::Shared;
+    use shakmaty::{Chess, Color};
+    use std::io::Cursor;
+
+    #[test]
+    fn engines_play_fools_mate_through_the_gantry() {
+        let config = GameConfig::builder().human(Color::White).build();
+        let mut white = Wrapper::connect(Cursor::new("f3\ng4\n"), std::io::sink());
+        let mut black = Wrapper::connect(Cursor::new("e5\nQh4\n"), std::io::sink());
+        let sent = Shared::default();
+        let mut gantry = Gantry::new(sent.clone(), None, None);
+        let mut console = Vec::new();
+        let summary =
+            play_out(&config, &mut black, &mut white, Some(&mut gantry), &mut console).unwrap();
+        let mut expected = Chess::default();
+        for san in ["f3", "e5", "g4", "Qh4#"] {
+            let mv = san.parse::<San>().unwrap().to_move(&expected).unwrap();
+            expected = expected.play(&mv).unwrap();
+        }
+        assert_eq!(summary.position, expected);
+        assert_eq!(summary.result, Some((Outcome::Decisive { winner: Color::Black }, "checkmate")));
+        // every move went to the gantry, the queen ending on h4
+        assert_eq!(sent.text().lines().count(), summary.steps.len());
+        assert!(sent.text().ends_with("X8.0 Y4.0 M1\n"));
+    }
+}
@@ -0,0 +1,171 @@
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use shakmaty::{fen::Fen, Bitboard, Chess, EnPassantMode, Position, Square};
+use std::collections::{HashSet, VecDeque};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+
+use crate::{update_state, State, StateConfig};
+
+// the same runs every time, so a failure can be chased down
+const SEED: u64 = 0x5eed_f1a9_fa11;
+
+// how far into a random game each run starts
+const MAX_PLAYOUT_PLIES: u32 = 60;
+
+// longest instruction sequence fed in per run
+const MAX_SEQUENCE: usize = 8;
+
+// most placements searched for a way back to Idle
+const RECOVERY_DEPTH: usize = 4;
+
+// feeds `iterations` random instruction sequences into update_state, each from
+// a position some random moves into a game, and checks it never panics and
+// that every state it reaches short of Error can still get back to Idle.
+// returns each failure, cut down to the fewest instructions that still fail
+pub fn fuzz_state_machine(iterations: u32) -> Vec<String> {
+    let config = StateConfig::default();
+    let mut rng = StdRng::seed_from_u64(SEED);
+    let mut failures = Vec::new();
+    // panics are reported as failures, not printed as they happen
+    let hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(|_| {}));
+    for _ in 0..iterations {
+        let pos = random_position(&mut rng);
+        let len = rng.gen_range(1..=MAX_SEQUENCE);
+        let sequence: Vec<Square> = (0..len).map(|_| pick_square(&pos, &mut rng)).collect();
+        let Some(reason) = run(&pos, &sequence, config) else {
+            continue;
+        };
+        let sequence = minimise(&pos, sequence, config);
+        let fen = Fen::from_position(pos, EnPassantMode::Legal);
+        let squares: Vec<String> = sequence.iter().map(ToString::to_string).collect();
+        failures.push(format!("{fen} after {}: {reason}", squares.join(" ")));
+    }
+    std::panic::set_hook(hook);
+    failures
+}
+
+fn random_position(rng: &mut StdRng) -> Chess {
+    let mut pos = Chess::default();
+    let plies = rng.gen_range(0..MAX_PLAYOUT_PLIES);
+    for _ in 0..plies {
+        let moves = pos.legal_moves();
+        if moves.is_empty() {
+            break;
+        }
+        let mv = moves[rng.gen_range(0..moves.len())].clone();
+        pos = pos.play(&mv).unwrap();
+    }
+    pos
+}
+
+// mostly squares something can happen on, now and then any square at all
+fn pick_square(pos: &Chess, rng: &mut StdRng) -> Square {
+    let targets = pos
+        .legal_moves()
+        .iter()
+        .fold(Bitboard::EMPTY, |acc, mv| acc.with(Bitboard::from_square(mv.to())));
+    let likely: Vec<Square> = pos.board().occupied().with(targets).into_iter().collect();
+    if likely.is_empty() || rng.gen_range(0..4) == 0 {
+        Square::new(rng.gen_range(0..64))
+    } else {
+        likely[rng.gen_range(0..likely.len())]
+    }
+}
+
+// what went wrong feeding `sequence` in from Idle, if anything. a committed
+// move is played, as the session would
+fn run(pos: &Chess, sequence: &[Square], config: StateConfig) -> Option<String> {
+    let mut pos = pos.clone();
+    let mut state = State::Idle;
+    for &square in sequence {
+        let update = catch_unwind(AssertUnwindSafe(|| {
+            update_state(&pos, u32::from(square), state, config)
+        }));
+        let Ok(update) = update else {
+            return Some(format!("panicked in {state:?} on {square}"));
+        };
+        state = update.next;
+        if let Some(mv) = update.committed {
+            if !pos.is_legal(&mv) {
+                return Some(format!("committed the illegal move {mv}"));
+            }
+            pos = pos.play(&mv).unwrap();
+        }
+    }
+    (!can_recover(&pos, state, config)).then(|| format!("stuck in {state:?}"))
+}
+
+// states the state machine isn't expected to leave by itself: Error, the ends
+// of a game, and the ones the session clears from outside
+const fn exempt(state: State) -> bool {
+    matches!(
+        state,
+        State::Error
+            | State::Resigned
+            | State::DrawOffered
+            | State::DrawAgreed
+            | State::DrawClaimed
+            | State::Paused
+            | State::UnexpectedOccupancy(_)
+            | State::MissingPiece(_)
+    )
+}
+
+// whether some run of up to RECOVERY_DEPTH placements takes `state` to Idle
+fn can_recover(pos: &Chess, state: State, config: StateConfig) -> bool {
+    if exempt(state) {
+        return true;
+    }
+    let mut seen = HashSet::from([state]);
+    let mut queue = VecDeque::from([(state, 0)]);
+    while let Some((state, depth)) = queue.pop_front() {
+        if state == State::Idle {
+            return true;
+        }
+        if depth == RECOVERY_DEPTH {
+            continue;
+        }
+        for square in (0..64).map(Square::new) {
+            let update = catch_unwind(AssertUnwindSafe(|| {
+                update_state(pos, u32::from(square), state, config)
+            }));
+            if let Ok(update) = update {
+                if seen.insert(update.next) {
+                    queue.push_back((update.next, depth + 1));
+                }
+            }
+        }
+    }
+    false
+}
+
+// drops instructions one at a time for as long as the run still fails
+fn minimise(pos: &Chess, mut sequence: Vec<Square>, config: StateConfig) -> Vec<Square> {
+    let mut i = 0;
+    while i < sequence.len() {
+        let mut shorter = sequence.clone();
+        shorter.remove(i);
+        if !shorter.is_empty() && run(pos, &shorter, config).is_some() {
+            sequence = shorter;
+        } else {
+            i += 1;
+        }
+    }
+    sequence
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seeded_runs_find_no_failures() {
+        assert_eq!(fuzz_state_machine(200), Vec::<String>::new());
+    }
+
+    #[test]
+    fn a_legal_move_leaves_nothing_to_recover_from() {
+        let squares = [Square::E2, Square::E4, Square::E7, Square::E5];
+        assert_eq!(run(&Chess::default(), &squares, StateConfig::default()), None);
+    }
+}
@@ -0,0 +1,390 @@
+use log::{info, warn};
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::path::PathBuf;
+
+use crate::config::{BoardGeometry, MagnetWiring, StepProtocol};
+use crate::{Magnet, Step};
+
+// where the carriage parks when it has nothing to do, in board units
+pub const HOME: Step = Step {
+    x: 0.0,
+    y: 0.0,
+    magnet: Magnet::Released,
+    feedrate: None,
+};
+
+// drives the core-xy controller: steps are written a line at a time and the
+// controller answers each line with "ok" once it has finished moving. a line
+// is normally one step, but with batching up to `batch` steps share a line,
+// separated by "; ", and a single ack. the magnet field carries whatever
+// `wiring` says the carriage wants for each state. with the binary protocol
+// each line is a frame from encode_frame instead, acked the same way
+pub struct Gantry {
+    output: Box<dyn Write>,
+    acks: Option<Box<dyn BufRead>>,
+    // holds the last acknowledged step while a sequence is in flight, so an
+    // interrupted move can be recovered on the next boot
+    journal: Option<PathBuf>,
+    // most steps sent per line, 1 sends every step on its own
+    batch: usize,
+    wiring: MagnetWiring,
+    protocol: StepProtocol,
+    // turns steps into millimetres on the way out, None sends board units
+    mm: Option<BoardGeometry>,
+    // the last step the controller acknowledged, where the carriage is now
+    last: Option<Step>,
+}
+
+impl Gantry {
+    pub fn open(device: &str, journal: PathBuf) -> std::io::Result<Self> {
+        let port = File::options().read(true).write(true).open(device)?;
+        let acks = BufReader::new(port.try_clone()?);
+        Ok(Self {
+            output: Box::new(port),
+            acks: Some(Box::new(acks)),
+            journal: Some(journal),
+            batch: 1,
+            wiring: MagnetWiring::default(),
+            protocol: StepProtocol::Text,
+            mm: None,
+            last: None,
+        })
+    }
+
+    pub fn new(
+        output: impl Write + 'static,
+        acks: Option<Box<dyn BufRead>>,
+        journal: Option<PathBuf>,
+    ) -> Self {
+        Self {
+            output: Box::new(output),
+            acks,
+            journal,
+            batch: 1,
+            wiring: MagnetWiring::default(),
+            protocol: StepProtocol::Text,
+            mm: None,
+            last: None,
+        }
+    }
+
+    pub fn batch(mut self, steps: usize) -> Self {
+        self.batch = steps.max(1);
+        self
+    }
+
+    pub const fn wiring(mut self, wiring: MagnetWiring) -> Self {
+        self.wiring = wiring;
+        self
+    }
+
+    pub const fn protocol(mut self, protocol: StepProtocol) -> Self {
+        self.protocol = protocol;
+        self
+    }
+
+    pub const fn millimetres(mut self, geometry: Option<BoardGeometry>) -> Self {
+        self.mm = geometry;
+        self
+    }
+
+    pub fn send(&mut self, steps: &[Step]) -> std::io::Result<()> {
+        for line in batch_lines(steps, self.batch) {
+            self.send_line(&line)?;
+        }
+        // the sequence finished, nothing is being carried any more
+        self.record(None)
+    }
+
+    fn send_line(&mut self, line: &[Step]) -> std::io::Result<()> {
+        let Some(&last) = line.last() else {
+            return Ok(());
+        };
+        if line.len() > self.batch {
+            // batching was turned off partway through the sequence
+            return line.iter().try_for_each(|&step| self.send_line(&[step]));
+        }
+        match self.send_frame(line) {
+            Ok(()) => {
+                self.last = Some(last);
+                self.record(Some(last))
+            }
+            // a controller that doesn't understand batches refuses the whole
+            // line, so nothing moved and it can go again a step at a time
+            Err(e) if line.len() > 1 && e.kind() == std::io::ErrorKind::InvalidData => {
+                warn!("{e}, falling back to sending one step at a time");
+                self.batch = 1;
+                self.send_line(line)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    // lets go of whatever the magnet may still hold where the carriage
+    // stands, since a move ends with the magnet on over its last piece, then
+    // drives home with it off
+    pub fn park(&mut self) -> std::io::Result<()> {
+        let release = Step {
+            magnet: Magnet::Released,
+            ..self.last.unwrap_or(HOME)
+        };
+        self.send(&[release, HOME])?;
+        info!("gantry parked");
+        Ok(())
+    }
+
+    // if the last run died mid-sequence, drop whatever the magnet may be
+    // holding where it stopped and go home before anything else moves
+    pub fn recover(&mut self) -> std::io::Result<()> {
+        let Some(journal) = &self.journal else {
+            return Ok(());
+        };
+        let Ok(contents) = std::fs::read_to_string(journal) else {
+            return Ok(());
+        };
+        let Ok(last) = contents.trim().parse::<Step>() else {
+            warn!("ignoring unreadable gantry journal {}", journal.display());
+            return self.record(None);
+        };
+        warn!("previous move was interrupted at {last:?}, recovering");
+        let release = Step {
+            magnet: Magnet::Released,
+            ..last
+        };
+        self.send(&[release, HOME])?;
+        info!("gantry recovered and homed");
+        Ok(())
+    }
+
+    fn send_frame(&mut self, steps: &[Step]) -> std::io::Result<()> {
+        match self.protocol {
+            StepProtocol::Text => {
+                let line = |step: &Step| {
+                    let step = self.mm.map_or(*step, |geometry| geometry.in_mm(*step));
+                    let magnet = self.wiring.wire(step.magnet);
+                    let feed = step
+                        .feedrate
+                        .map(|f| format!(" F{f:?}"))
+                        .unwrap_or_default();
+                    format!("X{:?} Y{:?} M{magnet}{feed}", step.x, step.y)
+                };
+                let frame: Vec<String> = steps.iter().map(line).collect();
+                writeln!(self.output, "{}", frame.join("; "))?;
+            }
+            StepProtocol::Binary => {
+                let frame = encode_frame(steps, self.wiring)
+                    .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+                self.output.write_all(&frame)?;
+            }
+        }
+        self.output.flush()?;
+        let Some(acks) = self.acks.as_mut() else {
+            return Ok(());
+        };
+        let mut reply = String::new();
+        if acks.read_line(&mut reply)? == 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "gantry controller closed the connection",
+            ));
+        }
+        if reply.trim() == "ok" {
+            Ok(())
+        } else {
+            Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("gantry controller replied {:?} instead of ok", reply.trim()),
+            ))
+        }
+    }
+
+    fn record(&self, step: Option<Step>) -> std::io::Result<()> {
+        let Some(journal) = &self.journal else {
+            return Ok(());
+        };
+        match step {
+            Some(step) => std::fs::write(journal, step.to_string()),
+            None if journal.exists() => std::fs::remove_file(journal),
+            None => Ok(()),
+        }
+    }
+}
+
+// splits a sequence into the lines send writes, at most `batch` steps each. a
+// step that switches the magnet always gets a line of its own, so the
+// controller has confirmed a piece is held or let go before anything else moves
+fn batch_lines(steps: &[Step], batch: usize) -> Vec<Vec<Step>> {
+    let mut lines: Vec<Vec<Step>> = Vec::new();
+    let mut magnet = Magnet::Released;
+    let mut line = Vec::new();
+    for &step in steps {
+        let toggles = step.magnet != magnet;
+        magnet = step.magnet;
+        if toggles || line.len() >= batch {
+            lines.extend((!line.is_empty()).then(|| std::mem::take(&mut line)));
+        }
+        if toggles {
+            lines.push(vec![step]);
+        } else {
+            line.push(step);
+        }
+    }
+    lines.extend((!line.is_empty()).then_some(line));
+    lines
+}
+
+// the binary protocol's frame: a count byte, then that many four byte records
+// of x and y in half board units, the magnet's wire value, and a CRC-8 of the
+// three bytes before it. there's no room for a feedrate, so the controller
+// runs every step at its own speed. a step off the half unit grid or past
+// 127.5 can't be sent exactly, so it's refused rather than rounded
+pub fn encode_frame(steps: &[Step], wiring: MagnetWiring) -> Result<Vec<u8>, String> {
+    let count = u8::try_from(steps.len()).map_err(|_| "too many steps for one frame")?;
+    let mut frame = vec![count];
+    for step in steps {
+        let (Some(x), Some(y)) = (half_units(step.x), half_units(step.y)) else {
+            return Err(format!(
+                "step {step} is not on the binary protocol's half unit grid"
+            ));
+        };
+        let record = [x, y, wiring.wire(step.magnet)];
+        frame.extend_from_slice(&record);
+        frame.push(crc8(&record));
+    }
+    Ok(frame)
+}
+
+// the steps in a frame from encode_frame, checking every record's CRC
+pub fn decode_frame(frame: &[u8], wiring: MagnetWiring) -> Result<Vec<Step>, String> {
+    let (&count, records) = frame.split_first().ok_or("empty frame")?;
+    if records.len() != usize::from(count) * 4 {
+        return Err(format!(
+            "frame of {count} steps has {} record bytes",
+            records.len()
+        ));
+    }
+    records
+        .chunks_exact(4)
+        .map(|record| {
+            let (data, crc) = record.split_at(3);
+            if crc8(data) != crc[0] {
+                return Err(format!("record {data:?} fails its CRC"));
+            }
+            let magnet = match data[2] {
+                value if value == wiring.engage => Magnet::Engaged,
+                value if value == wiring.release => Magnet::Released,
+                value => return Err(format!("{value} is neither magnet value")),
+            };
+            Ok(Step {
+                x: f64::from(data[0]) / 2.0,
+                y: f64::from(data[1]) / 2.0,
+                magnet,
+                feedrate: None,
+            })
+        })
+        .collect()
+}
+
+#[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+fn half_units(value: f64) -> Option<u8> {
+    let halves = value * 2.0;
+    // the range check keeps the cast exact
+    (halves.fract() == 0.0 && (0.0..=255.0).contains(&halves)).then_some(halves as u8)
+}
+
+// CRC-8 with polynomial 0x07, as in SMBus
+fn crc8(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0, |crc, &byte| {
+        (0..8).fold(crc ^ byte, |crc, _| {
+            if crc & 0x80 == 0 {
+                crc << 1
+            } else {
+                (crc << 1) ^ 0x07
+            }
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::Shared;
+    use std::io::Cursor;
+
+    const fn step(x: f64, y: f64, magnet: Magnet) -> Step {
+        Step {
+            x,
+            y,
+            magnet,
+            feedrate: None,
+        }
+    }
+
+    // a knight's trip from b1 to c3 along the lanes
+    const TRIP: [Step; 6] = [
+        step(2.0, 1.0, Magnet::Released),
+        step(2.5, 1.5, Magnet::Engaged),
+        step(2.5, 2.5, Magnet::Engaged),
+        step(3.0, 2.5, Magnet::Engaged),
+        step(3.0, 3.0, Magnet::Engaged),
+        step(3.0, 3.0, Magnet::Released),
+    ];
+
+    #[test]
+    fn magnet_switches_end_a_batch() {
+        let lines = batch_lines(&TRIP, 3);
+        let lengths: Vec<_> = lines.iter().map(Vec::len).collect();
+        // picking up and letting go each go alone, the moves between share a line
+        assert_eq!(lengths, [1, 1, 3, 1]);
+        let lines = batch_lines(&TRIP, 2);
+        let lengths: Vec<_> = lines.iter().map(Vec::len).collect();
+        assert_eq!(lengths, [1, 1, 2, 1, 1]);
+    }
+
+    #[test]
+    fn refused_batch_is_resent_a_step_at_a_time() {
+        let sent = Shared::default();
+        let acks = Cursor::new("ok\nok\nbad frame\nok\nok\nok\nok\n");
+        let mut gantry = Gantry::new(sent.clone(), Some(Box::new(acks)), None).batch(3);
+        gantry.send(&TRIP).unwrap();
+        let text = sent.text();
+        let lines: Vec<_> = text.lines().collect();
+        assert_eq!(lines[2], "X2.5 Y2.5 M1; X3.0 Y2.5 M1; X3.0 Y3.0 M1");
+        assert_eq!(lines[3..], ["X2.5 Y2.5 M1", "X3.0 Y2.5 M1", "X3.0 Y3.0 M1", "X3.0 Y3.0 M0"]);
+    }
+
+    #[test]
+    fn wiring_maps_engage_and_release_to_the_carriage_values() {
+        let sent_with = |wiring: &str| {
+            let sent = Shared::default();
+            let wiring = wiring.parse().unwrap();
+            Gantry::new(sent.clone(), None, None).wiring(wiring).send(&TRIP[..2]).unwrap();
+            sent.text()
+        };
+        assert_eq!(sent_with("1,0"), "X2.0 Y1.0 M0\nX2.5 Y1.5 M1\n");
+        // inverted polarity
+        assert_eq!(sent_with("0,1"), "X2.0 Y1.0 M1\nX2.5 Y1.5 M0\n");
+        // the second of two magnets
+        assert_eq!(sent_with("2,0"), "X2.0 Y1.0 M0\nX2.5 Y1.5 M2\n");
+        assert!("1,1".parse::<MagnetWiring>().is_err());
+    }
+
+    #[test]
+    fn binary_frames_round_trip_half_squares() {
+        let wiring = MagnetWiring::default();
+        let frame = encode_frame(&TRIP, wiring).unwrap();
+        assert_eq!(frame.len(), 1 + 4 * TRIP.len());
+        let decoded = decode_frame(&frame, wiring).unwrap();
+        let points = |steps: &[Step]| -> Vec<_> {
+            steps.iter().map(|step| (step.x, step.y, step.magnet)).collect()
+        };
+        assert_eq!(points(&decoded), points(&TRIP));
+        // a flipped bit fails that record's CRC
+        let mut corrupted = frame;
+        corrupted[5] ^= 1;
+        assert!(decode_frame(&corrupted, wiring).is_err());
+        // and a quarter square can't be sent exactly
+        assert!(encode_frame(&[step(2.25, 1.0, Magnet::Released)], wiring).is_err());
+    }
+}
@@ -0,0 +1,129 @@
+use std::io::Write;
+use std::time::{Duration, Instant};
+
+use crate::{print_leds, RGB};
+
+// the LEDs as last sent. a frame they already show isn't sent again, which
+// some panels flicker on, and changing frames go out at least `frame_interval`
+// apart. a frame held back for that waits in `held` until `flush`
+pub struct Leds {
+    grid: bool,
+    frame_interval: Duration,
+    shown: Option<RGB>,
+    sent_at: Option<Instant>,
+    held: Option<RGB>,
+}
+
+impl Leds {
+    pub const fn new(grid: bool, frame_interval: Duration) -> Self {
+        Self {
+            grid,
+            frame_interval,
+            shown: None,
+            sent_at: None,
+            held: None,
+        }
+    }
+
+    pub fn show(&mut self, out: &mut dyn Write, rgb: RGB) -> std::io::Result<()> {
+        self.show_at(out, rgb, Instant::now())
+    }
+
+    fn show_at(&mut self, out: &mut dyn Write, rgb: RGB, now: Instant) -> std::io::Result<()> {
+        if self.shown == Some(rgb) {
+            self.held = None;
+            return Ok(());
+        }
+        if self.sent_at.is_some_and(|sent| now < sent + self.frame_interval) {
+            self.held = Some(rgb);
+            return Ok(());
+        }
+        print_leds(out, rgb, self.grid)?;
+        self.shown = Some(rgb);
+        self.sent_at = Some(now);
+        self.held = None;
+        Ok(())
+    }
+
+    // the last frame before the board stops, sent whatever the frame rate as
+    // nothing would flush it
+    pub fn show_final(&mut self, out: &mut dyn Write, rgb: RGB) -> std::io::Result<()> {
+        self.sent_at = None;
+        self.show(out, rgb)
+    }
+
+    // sends the frame held back by the frame rate, if its time has come
+    pub fn flush(&mut self, out: &mut dyn Write) -> std::io::Result<()> {
+        self.flush_at(out, Instant::now())
+    }
+
+    fn flush_at(&mut self, out: &mut dyn Write, now: Instant) -> std::io::Result<()> {
+        self.held.map_or(Ok(()), |rgb| self.show_at(out, rgb, now))
+    }
+
+    // how long to wait before a held frame can go out
+    pub fn holding(&self) -> Option<Duration> {
+        self.held.map(|_| self.frame_interval)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shakmaty::{Bitboard, Square};
+
+    const INTERVAL: Duration = Duration::from_millis(100);
+
+    fn lit(square: Square) -> RGB {
+        RGB {
+            r: Bitboard::from_square(square),
+            ..RGB::default()
+        }
+    }
+
+    #[test]
+    fn unchanged_frame_is_not_sent_again() {
+        let mut leds = Leds::new(false, INTERVAL);
+        let mut out = Vec::new();
+        let start = Instant::now();
+        leds.show_at(&mut out, lit(Square::E4), start).unwrap();
+        let sent = out.len();
+        assert!(sent > 0);
+        leds.show_at(&mut out, lit(Square::E4), start + INTERVAL * 2).unwrap();
+        assert_eq!(out.len(), sent);
+        assert_eq!(leds.holding(), None);
+    }
+
+    #[test]
+    fn frame_too_soon_is_held_until_flushed() {
+        let mut leds = Leds::new(false, INTERVAL);
+        let mut out = Vec::new();
+        let start = Instant::now();
+        leds.show_at(&mut out, lit(Square::E4), start).unwrap();
+        let sent = out.len();
+        leds.show_at(&mut out, lit(Square::D4), start + INTERVAL / 2).unwrap();
+        assert_eq!(out.len(), sent);
+        assert_eq!(leds.holding(), Some(INTERVAL));
+        // still too soon, so it stays held
+        leds.flush_at(&mut out, start + INTERVAL / 2).unwrap();
+        assert_eq!(out.len(), sent);
+        leds.flush_at(&mut out, start + INTERVAL).unwrap();
+        assert!(out.len() > sent);
+        assert_eq!(leds.holding(), None);
+        assert_eq!(leds.shown, Some(lit(Square::D4)));
+    }
+
+    #[test]
+    fn going_back_to_the_shown_frame_drops_the_held_one() {
+        let mut leds = Leds::new(false, INTERVAL);
+        let mut out = Vec::new();
+        let start = Instant::now();
+        leds.show_at(&mut out, lit(Square::E4), start).unwrap();
+        leds.show_at(&mut out, lit(Square::D4), start + INTERVAL / 2).unwrap();
+        leds.show_at(&mut out, lit(Square::E4), start + INTERVAL / 2).unwrap();
+        assert_eq!(leds.holding(), None);
+        let sent = out.len();
+        leds.flush_at(&mut out, start + INTERVAL).unwrap();
+        assert_eq!(out.len(), sent);
+    }
+}
@@ -1,13 +1,61 @@
 #![warn(clippy::all, clippy::pedantic, clippy::nursery)]
 #![allow(dead_code)]
 
-use log::{info, error};
+mod adjudicator;
+#[cfg(feature = "async-loop")]
+mod async_loop;
+mod book;
+mod calibrate;
+mod clock;
+mod config;
+mod demo;
+mod fuzz;
+mod gantry;
+mod leds;
+mod metrics;
+mod narrate;
+mod opponent;
+mod reed_matrix;
+mod replay;
+mod scan;
+mod session;
+mod settings;
+mod snapshot;
+#[cfg(feature = "spectators")]
+mod spectators;
+mod telemetry;
+mod theme;
+mod validate;
+mod view;
+
+use log::{info, error, warn};
+use regex::Regex;
+use serde::{Serialize, Serializer};
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
 use shakmaty::{
-    san::San, Bitboard, Chess, Color, File, Move, Position, Rank, Role,
-    Square,
+    attacks, fen::Fen, Bitboard, CastlingMode, CastlingSide, Chess, Color, EnPassantMode, File, Move,
+    Outcome, Position, Rank, Role, Square,
+};
+use std::collections::VecDeque;
+use std::io::{BufRead, Write};
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::time::{Duration, Instant};
+use adjudicator::Adjudicator;
+use book::Book;
+use clock::Clocks;
+use config::{
+    Adjudication, BoardGeometry, CaptureStrategy, EngineStrength, FeedProfile, GameConfig,
+    MagnetWiring, MatrixPins, OpponentBackend, Speed, SquareMapping, StepProtocol, StepUnits,
+    TimeControl,
 };
-use std::io::{BufReader, BufRead};
-use std::io::Write;
+use gantry::Gantry;
+use opponent::{Nobody, Opponent, RemoteEngine, UciEngine, Wrapper};
+use session::{Board, GameSession, Tick};
+use settings::Settings;
+use theme::{paint, Theme};
+use view::ViewServer;
 
 // handle exe paths on windows & unix
 #[cfg(windows)]
@@ -15,6 +63,69 @@ const OPPONENT_WRAPPER_EXE_PATH: &str = "opponent-wrapper.exe";
 #[cfg(unix)]
 const OPPONENT_WRAPPER_EXE_PATH: &str = "opponent-wrapper";
 
+// a line from the opponent wrapper on boot that wants an answer typed back
+const DEFAULT_WRAPPER_PROMPT: &str = r"[?:]\s*$";
+
+// where the last acknowledged gantry step is kept while a move is in flight
+const DEFAULT_GANTRY_JOURNAL: &str = "gantry-journal.txt";
+
+// how often the reed loop wakes up to check timeouts when no input arrives
+const REED_TICK: Duration = Duration::from_millis(250);
+
+// how long the reeds have to stay quiet after a move before the gantry may move
+const SETTLE_WINDOW: Duration = Duration::from_millis(300);
+
+// how close together two king presses have to be to count as a resign gesture
+const DOUBLE_PRESS_WINDOW: Duration = Duration::from_millis(1500);
+
+// how many instructions lead up to an Error in the log, unless --error-trail says otherwise
+const DEFAULT_ERROR_TRAIL: usize = 16;
+
+// how many LED frames a second at most, unless --led-fps says otherwise
+const DEFAULT_LED_FPS: u32 = 30;
+
+// how often the reed matrix is scanned with --reed-gpio, unless --reed-scan-hz says otherwise
+const DEFAULT_REED_SCAN_HZ: u32 = 200;
+
+// how many scans in a row a reed has to read switched before it counts,
+// unless --reed-debounce says otherwise
+const DEFAULT_REED_DEBOUNCE: u32 = 3;
+
+// how many plies in a row a side has to stay past the adjudication threshold
+const DEFAULT_ADJUDICATE_PLIES: u32 = 6;
+
+// the reed line the firmware sends after a scan that found nothing changed.
+// it only shows the board is alive, so it never moves the game on or counts as
+// the board being touched
+const HEARTBEAT_LINE: &str = "-1";
+
+// the reed line the pause button sends; pressing it again resumes
+const PAUSE_LINE: &str = "-2";
+
+// the reed line the move complete button sends, once the human has let go of
+// their last piece. the move itself is still read from the reeds, this only
+// lets the opponent's reply start without waiting out SETTLE_WINDOW
+const MOVE_DONE_LINE: &str = "-3";
+
+// the reed line the reset button sends: the game so far is abandoned and the
+// board starts over from the starting position, against the same opponent
+const RESET_LINE: &str = "-4";
+
+// the reed lines the speed buttons send, each press a Speed::STEP faster or slower
+const FASTER_LINE: &str = "-5";
+const SLOWER_LINE: &str = "-6";
+
+// put into every board's reed lines when SIGINT or SIGTERM arrives, see
+// watch_signals. the firmware never sends it
+const SHUTDOWN_LINE: &str = "shutdown";
+
+// the reed line prefix for a transient touch: a square that closed and opened
+// again within one scan, as when a lifted piece is held just over it, e.g. "t28"
+const TOUCH_PREFIX: &str = "t";
+
+// the reason given for a game cut short by --max-moves
+const MOVE_LIMIT: &str = "move limit";
+
 // 1. SETUP BOARD (kinda handwaved, user probably does it)
 // 2. SETUP GAME PARAMETERS (time control, human playing colour, etc)
 // 3. READ REED-SWITCH OUTPUT
@@ -28,233 +139,1615 @@ const OPPONENT_WRAPPER_EXE_PATH: &str = "opponent-wrapper";
 // 11. GOTO 3 UNTIL GAME ENDS
 // 12. EXIT
 
+#[allow(clippy::struct_excessive_bools)]
+#[derive(Debug, Clone, Default)]
+struct Options {
+    verbose_board: bool,
+    keyboard: bool,
+    telemetry: Option<String>,
+    view: Option<String>,
+    spectate: Option<String>,
+    default_promotion: Option<Role>,
+    gantry: Option<String>,
+    gantry_journal: Option<String>,
+    gantry_batch: Option<usize>,
+    magnet_wiring: Option<MagnetWiring>,
+    step_protocol: StepProtocol,
+    step_units: StepUnits,
+    feed_profile: Option<FeedProfile>,
+    speed: Option<Speed>,
+    error_trail: Option<usize>,
+    capture_strategy: CaptureStrategy,
+    square_mapping: SquareMapping,
+    send_steps: Option<String>,
+    pickup_timeout: Option<Duration>,
+    validate_steps: Option<u32>,
+    fuzz_states: Option<u32>,
+    dump_moves: Option<Chess>,
+    engine: Option<String>,
+    remote_engine: Option<String>,
+    engine_vs_engine: Option<String>,
+    no_engine: bool,
+    engine_strength: EngineStrength,
+    book: Option<String>,
+    book_moves: Option<u32>,
+    seed: Option<u64>,
+    wrapper_prompt: Option<Regex>,
+    wrapper_answers: Vec<String>,
+    adjudicate_cp: Option<i32>,
+    adjudicate_plies: Option<u32>,
+    max_moves: Option<u32>,
+    event_log: Option<String>,
+    replay_log: Option<String>,
+    theme: Option<String>,
+    blindfold: bool,
+    flip: bool,
+    snapshot: bool,
+    calibrate: bool,
+    scan_setup: bool,
+    async_loop: bool,
+    touch_move: bool,
+    swap_promotion: bool,
+    strictness: Strictness,
+    home_after_move: bool,
+    scratch: Option<(f64, f64)>,
+    geometry: Option<BoardGeometry>,
+    teach: bool,
+    threat_preview: bool,
+    coach: bool,
+    led_grid: bool,
+    led_fps: Option<u32>,
+    human: Option<Color>,
+    time_control: Option<TimeControl>,
+    start: Option<Chess>,
+    boards: Vec<BoardPort>,
+    reed_gpio: Option<MatrixPins>,
+    reed_scan_hz: Option<u32>,
+    reed_debounce: Option<u32>,
+}
+
+// a board given with --board: the serial port its reeds report on, and
+// optionally the one its gantry listens on
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct BoardPort {
+    reeds: String,
+    gantry: Option<String>,
+}
+
+#[allow(clippy::too_many_lines)]
+fn parse_args(args: impl Iterator<Item = String>) -> Result<Options, String> {
+    let args: Vec<String> = args.collect();
+    let mut options = Options::default();
+    // the file is read first so that flags given alongside it win
+    if let Some(at) = args.iter().position(|arg| arg == "--config") {
+        let path = args.get(at + 1).ok_or("--config needs a TOML file")?;
+        apply_settings(&mut options, Settings::load(path)?);
+    }
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--config" => {
+                args.next();
+            }
+            "--verbose-board" => options.verbose_board = true,
+            "--keyboard" => options.keyboard = true,
+            "--flip" => options.flip = true,
+            "--snapshot" => options.snapshot = true,
+            "--calibrate" => options.calibrate = true,
+            "--scan-setup" => options.scan_setup = true,
+            "--async" => options.async_loop = true,
+            "--touch-move" => options.touch_move = true,
+            "--swap-promotion" => options.swap_promotion = true,
+            "--strictness" => {
+                let strictness = args.next().ok_or("--strictness needs lenient or strict")?;
+                options.strictness = strictness.parse()?;
+            }
+            "--home-after-move" => options.home_after_move = true,
+            "--scratch" => {
+                let spot = args.next().ok_or("--scratch needs x,y in board units")?;
+                let bad = || format!("{spot} is not a scratch spot, expected x,y like 9,9");
+                let (x, y) = spot.split_once(',').ok_or_else(bad)?;
+                let x = x.parse().map_err(|_| bad())?;
+                let y = y.parse().map_err(|_| bad())?;
+                options.scratch = Some((x, y));
+            }
+            "--teach" => options.teach = true,
+            "--threat-preview" => options.threat_preview = true,
+            "--coach" => options.coach = true,
+            "--led-grid" => options.led_grid = true,
+            "--led-fps" => {
+                let fps = args.next().ok_or("--led-fps needs a number of frames a second")?;
+                let fps = fps
+                    .parse()
+                    .ok()
+                    .filter(|&fps| fps > 0)
+                    .ok_or_else(|| format!("{fps} is not a number of frames a second"))?;
+                options.led_fps = Some(fps);
+            }
+            "--human-color" => {
+                let color = args.next().ok_or("--human-color needs white or black")?;
+                options.human = Some(parse_color(&color)?);
+            }
+            "--time-control" => {
+                let time_control = args.next().ok_or("--time-control needs minutes+increment")?;
+                options.time_control = Some(time_control.parse()?);
+            }
+            "--fen" => {
+                let fen = args.next().ok_or("--fen needs a starting position")?;
+                options.start = Some(parse_position(&fen)?);
+            }
+            "--default-promotion" => {
+                let role = args.next().ok_or("--default-promotion needs one of q, r, b, n")?;
+                options.default_promotion = Some(parse_promotion_role(&role)?);
+            }
+            "--gantry" => {
+                options.gantry = Some(args.next().ok_or("--gantry needs a serial device")?);
+            }
+            "--gantry-journal" => {
+                options.gantry_journal = Some(args.next().ok_or("--gantry-journal needs a path")?);
+            }
+            "--gantry-batch" => {
+                let steps = args.next().ok_or("--gantry-batch needs a number of steps")?;
+                let steps = steps
+                    .parse()
+                    .ok()
+                    .filter(|&steps| steps > 0)
+                    .ok_or_else(|| format!("{steps} is not a number of steps"))?;
+                options.gantry_batch = Some(steps);
+            }
+            "--magnet-wiring" => {
+                let wiring = args.next().ok_or("--magnet-wiring needs engage,release values")?;
+                options.magnet_wiring = Some(wiring.parse()?);
+            }
+            "--step-proto" => {
+                let protocol = args.next().ok_or("--step-proto needs text or binary")?;
+                options.step_protocol = protocol.parse()?;
+            }
+            "--step-units" => {
+                let units = args.next().ok_or("--step-units needs board or mm")?;
+                options.step_units = units.parse()?;
+            }
+            "--feed-profile" => {
+                let profile = args.next().ok_or("--feed-profile needs cruise,taper,ramp")?;
+                options.feed_profile = Some(profile.parse()?);
+            }
+            "--speed" => {
+                let speed = args.next().ok_or("--speed needs a multiplier like 0.5 or 1.5")?;
+                options.speed = Some(speed.parse()?);
+            }
+            "--capture-strategy" => {
+                let strategy =
+                    args.next().ok_or("--capture-strategy needs clear-first or stage-first")?;
+                options.capture_strategy = strategy.parse()?;
+            }
+            "--square-mapping" => {
+                let mapping =
+                    args.next().ok_or("--square-mapping needs a mapping like a8-row-major")?;
+                options.square_mapping = mapping.parse()?;
+            }
+            "--error-trail" => {
+                let length = args.next().ok_or("--error-trail needs a number of instructions")?;
+                let length = length
+                    .parse()
+                    .map_err(|_| format!("{length} is not a number of instructions"))?;
+                options.error_trail = Some(length);
+            }
+            "--send-steps" => {
+                options.send_steps = Some(args.next().ok_or("--send-steps needs a step file")?);
+            }
+            "--pickup-timeout" => {
+                let secs = args.next().ok_or("--pickup-timeout needs a number of seconds")?;
+                let secs = secs
+                    .parse::<f64>()
+                    .map_err(|_| format!("{secs} is not a number of seconds"))?;
+                options.pickup_timeout = Some(Duration::from_secs_f64(secs));
+            }
+            "--validate-steps" => {
+                let depth = args.next().ok_or("--validate-steps needs a depth")?;
+                let depth = depth.parse().map_err(|_| format!("{depth} is not a depth"))?;
+                options.validate_steps = Some(depth);
+            }
+            "--fuzz-states" => {
+                let runs = args.next().ok_or("--fuzz-states needs a number of runs")?;
+                let runs = runs.parse().map_err(|_| format!("{runs} is not a number of runs"))?;
+                options.fuzz_states = Some(runs);
+            }
+            "--dump-moves" => {
+                let fen = args.next().ok_or("--dump-moves needs a position")?;
+                options.dump_moves = Some(parse_position(&fen)?);
+            }
+            "--engine" => {
+                options.engine = Some(args.next().ok_or("--engine needs a UCI engine path")?);
+            }
+            "--remote-engine" => {
+                let address = args.next().ok_or("--remote-engine needs a host:port")?;
+                options.remote_engine = Some(address);
+            }
+            "--no-engine" => options.no_engine = true,
+            "--engine-vs-engine" => {
+                let engine = args.next().ok_or("--engine-vs-engine needs a UCI engine path")?;
+                options.engine_vs_engine = Some(engine);
+            }
+            "--engine-depth" => {
+                let depth = args.next().ok_or("--engine-depth needs a number of plies")?;
+                let depth = depth.parse().map_err(|_| format!("{depth} is not a search depth"))?;
+                options.engine_strength.depth = Some(depth);
+            }
+            "--engine-movetime" => {
+                let ms = args.next().ok_or("--engine-movetime needs milliseconds")?;
+                let ms = ms.parse().map_err(|_| format!("{ms} is not a number of milliseconds"))?;
+                options.engine_strength.movetime_ms = Some(ms);
+            }
+            "--engine-skill" => {
+                let skill = args.next().ok_or("--engine-skill needs a skill level")?;
+                let skill = skill.parse().map_err(|_| format!("{skill} is not a skill level"))?;
+                options.engine_strength.skill = Some(skill);
+            }
+            "--book" => {
+                options.book = Some(args.next().ok_or("--book needs a Polyglot book file")?);
+            }
+            "--book-moves" => {
+                let moves = args.next().ok_or("--book-moves needs a number of moves")?;
+                let moves = moves.parse().map_err(|_| format!("{moves} is not a number of moves"))?;
+                options.book_moves = Some(moves);
+            }
+            "--seed" => {
+                let seed = args.next().ok_or("--seed needs a number")?;
+                let seed = seed.parse().map_err(|_| format!("{seed} is not a seed"))?;
+                options.seed = Some(seed);
+            }
+            "--wrapper-prompt" => {
+                let prompt = args.next().ok_or("--wrapper-prompt needs a regex")?;
+                let prompt = Regex::new(&prompt)
+                    .map_err(|e| format!("{prompt} is not a prompt regex: {e}"))?;
+                options.wrapper_prompt = Some(prompt);
+            }
+            // once per boot question, in the order the wrapper asks them
+            "--wrapper-answer" => {
+                let answer = args.next().ok_or("--wrapper-answer needs an answer")?;
+                options.wrapper_answers.push(answer);
+            }
+            "--adjudicate-cp" => {
+                let cp = args.next().ok_or("--adjudicate-cp needs a centipawn threshold")?;
+                let cp = cp.parse().map_err(|_| format!("{cp} is not a centipawn threshold"))?;
+                options.adjudicate_cp = Some(cp);
+            }
+            "--adjudicate-plies" => {
+                let plies = args.next().ok_or("--adjudicate-plies needs a number of plies")?;
+                let plies = plies.parse().map_err(|_| format!("{plies} is not a number of plies"))?;
+                options.adjudicate_plies = Some(plies);
+            }
+            "--max-moves" => {
+                let plies = args.next().ok_or("--max-moves needs a number of plies")?;
+                let plies = plies.parse().map_err(|_| format!("{plies} is not a number of plies"))?;
+                options.max_moves = Some(plies);
+            }
+            "--event-log" => {
+                options.event_log = Some(args.next().ok_or("--event-log needs a path")?);
+            }
+            "--replay-log" => {
+                options.replay_log = Some(args.next().ok_or("--replay-log needs an event log")?);
+            }
+            "--blindfold" => options.blindfold = true,
+            "--theme" => {
+                options.theme = Some(args.next().ok_or("--theme needs a theme file")?);
+            }
+            "--telemetry" => {
+                options.telemetry = Some(args.next().ok_or("--telemetry needs a path or fd")?);
+            }
+            "--board" => {
+                let ports = args.next().ok_or("--board needs a reed port, then optionally ,gantry")?;
+                let (reeds, gantry) = match ports.split_once(',') {
+                    Some((reeds, gantry)) => (reeds.to_string(), Some(gantry.to_string())),
+                    None => (ports, None),
+                };
+                options.boards.push(BoardPort { reeds, gantry });
+            }
+            "--reed-gpio" => {
+                let pins = args.next().ok_or("--reed-gpio needs row pins/column pins")?;
+                options.reed_gpio = Some(pins.parse()?);
+            }
+            "--reed-scan-hz" => {
+                let hz = args.next().ok_or("--reed-scan-hz needs a number of scans a second")?;
+                let hz = hz
+                    .parse()
+                    .ok()
+                    .filter(|&hz| hz > 0)
+                    .ok_or_else(|| format!("{hz} is not a number of scans a second"))?;
+                options.reed_scan_hz = Some(hz);
+            }
+            "--reed-debounce" => {
+                let scans = args.next().ok_or("--reed-debounce needs a number of scans")?;
+                let scans = scans
+                    .parse()
+                    .ok()
+                    .filter(|&scans| scans > 0)
+                    .ok_or_else(|| format!("{scans} is not a number of scans"))?;
+                options.reed_debounce = Some(scans);
+            }
+            "--view" => {
+                options.view = Some(args.next().ok_or("--view needs an address to listen on")?);
+            }
+            "--spectate" => {
+                let addr = args.next().ok_or("--spectate needs an address to listen on")?;
+                options.spectate = Some(addr);
+            }
+            _ => return Err(format!("unknown argument {arg}")),
+        }
+    }
+    if options.step_units == StepUnits::Millimetres && options.step_protocol == StepProtocol::Binary {
+        let e = "binary frames carry half board units, --step-units mm needs --step-proto text";
+        return Err(e.to_string());
+    }
+    Ok(options)
+}
+
+// the settings a --config file gives, in place of the defaults
+fn apply_settings(options: &mut Options, settings: Settings) {
+    options.human = settings.human_color;
+    options.time_control = settings.time_control;
+    options.engine = settings.engine;
+    options.remote_engine = settings.remote_engine;
+    options.engine_strength = EngineStrength {
+        depth: settings.engine_depth,
+        movetime_ms: settings.engine_movetime,
+        skill: settings.engine_skill,
+    };
+    options.wrapper_prompt = settings.wrapper_prompt;
+    options.theme = settings.theme;
+    options.flip = settings.flip.unwrap_or_default();
+    options.square_mapping = settings.square_mapping.unwrap_or_default();
+    options.magnet_wiring = settings.magnet_wiring;
+    options.speed = settings.speed;
+    options.geometry = settings.geometry;
+}
+
+fn parse_color(text: &str) -> Result<Color, String> {
+    match text {
+        "white" => Ok(Color::White),
+        "black" => Ok(Color::Black),
+        _ => Err(format!("{text} is not a colour, expected white or black")),
+    }
+}
+
+// castling rights on rooks a standard game can't have, or a king off the e
+// file with rights at all, make it a chess960 game
+fn parse_position(fen: &str) -> Result<Chess, String> {
+    let parsed = fen.parse::<Fen>().map_err(|e| format!("{fen} is not a FEN: {e}"))?;
+    let mode = CastlingMode::detect(parsed.as_setup());
+    parsed
+        .into_position(mode)
+        .map_err(|e| format!("{fen} is not a playable position: {e}"))
+}
+
+fn parse_promotion_role(text: &str) -> Result<Role, String> {
+    match text {
+        "q" => Ok(Role::Queen),
+        "r" => Ok(Role::Rook),
+        "b" => Ok(Role::Bishop),
+        "n" => Ok(Role::Knight),
+        _ => Err(format!("{text} is not a promotion piece, expected one of q, r, b, n")),
+    }
+}
+
+#[allow(clippy::too_many_lines)]
 fn main() {
     env_logger::init();
 
-    // STEP 1: SETUP BOARD
-    let mut pos = Chess::default();
-    let (mut captured_whites, mut captured_blacks) = (0u8, 0u8);
-    let mut state = State::Idle;
-    info!("Entered starting position: {fen}", fen = pos.board());
-
-    // STEP 2: SETUP GAME PARAMETERS
-    let mut opponent_wrapper_proc = std::process::Command::new(OPPONENT_WRAPPER_EXE_PATH)
-        .arg("-e")
-        .stdin(std::process::Stdio::piped())
-        .stdout(std::process::Stdio::piped())
-        .spawn()
-        .expect("Failed to spawn opponent-wrapper process");
-    let opponent_wrapper_stdout = BufReader::new(opponent_wrapper_proc.stdout.take().unwrap());
-    let mut opponent_wrapper_stdin = opponent_wrapper_proc.stdin.take().unwrap();
-    let mut stdout_lines = opponent_wrapper_stdout.lines();
-
-    // the opponent wrapper gives two prompts on boot, we need to pipe them through and pipe the responses back
-    let mut user_input = String::new();
-    let first_line = stdout_lines.next().unwrap().unwrap();
-    println!("{first_line}");
-    std::io::stdin().read_line(&mut user_input).unwrap();
-    write!(opponent_wrapper_stdin, "{user_input}").unwrap();
-    let second_line = stdout_lines.next().unwrap().unwrap();
-    println!("{second_line}");
-    user_input.clear();
-    std::io::stdin().read_line(&mut user_input).unwrap();
-    write!(opponent_wrapper_stdin, "{user_input}").unwrap();
-    let mut send_line = |line: &str| {
-        let res = writeln!(opponent_wrapper_stdin, "{line}");
-        if let Err(e) = res {
-            error!("Failed to send line to opponent wrapper: {e}");
+    let options = parse_args(std::env::args().skip(1)).unwrap_or_else(|e| {
+        error!("{e}");
+        std::process::exit(2);
+    });
+
+    if let Some(depth) = options.validate_steps {
+        let violations = validate::validate_step_planner(depth);
+        for violation in &violations {
+            println!("{violation}");
         }
-    };
-    let mut recv_line = || {
-        stdout_lines.next().unwrap().unwrap()
+        info!("step planner validation found {} violations", violations.len());
+        std::process::exit(i32::from(!violations.is_empty()));
+    }
+
+    if let Some(runs) = options.fuzz_states {
+        let failures = fuzz::fuzz_state_machine(runs);
+        for failure in &failures {
+            println!("{failure}");
+        }
+        info!("state machine fuzzing found {} failures in {runs} runs", failures.len());
+        std::process::exit(i32::from(!failures.is_empty()));
+    }
+
+    let state_config = StateConfig {
+        default_promotion: options.default_promotion.unwrap_or(Role::Queen),
+        touch_move: options.touch_move,
+        swap_promotion: options.swap_promotion,
+        strictness: options.strictness,
+        // event logs don't say which side the human played
+        human: None,
     };
 
-    // Right now the program is set to loop through the input from the reed switches ONLY
-    loop {
-        if pos.is_game_over() {
-            info!("game ended with {}", pos.outcome().unwrap());
-            break;
+    // re-run a recorded game's sensor input instead of playing one
+    if let Some(path) = &options.replay_log {
+        let (_, mismatches) = replay::replay(path, state_config).unwrap_or_else(|e| {
+            error!("Failed to replay {path}: {e}");
+            std::process::exit(2);
+        });
+        std::process::exit(i32::from(mismatches > 0));
+    }
+
+    let gantry_journal = options.gantry_journal.as_deref().unwrap_or(DEFAULT_GANTRY_JOURNAL);
+    let gantry_batch = options.gantry_batch.unwrap_or(1);
+    let magnet_wiring = options.magnet_wiring.unwrap_or_default();
+    let step_protocol = options.step_protocol;
+    let geometry = options.geometry.unwrap_or_default();
+    let mm = (options.step_units == StepUnits::Millimetres).then_some(geometry);
+
+    // every legal move in a position with the steps planned for it, for eyeballing paths
+    if let Some(pos) = &options.dump_moves {
+        for line in validate::dump_moves(pos, &geometry, options.capture_strategy) {
+            println!("{line}");
+        }
+        return;
+    }
+
+    // replay a dumped step stream straight to the gantry, for debugging motor paths
+    if let Some(path) = &options.send_steps {
+        let Some(device) = &options.gantry else {
+            error!("--send-steps needs --gantry");
+            std::process::exit(2);
+        };
+        let journal = gantry_journal.into();
+        let gantry = open_gantry(device, journal, gantry_batch, magnet_wiring, step_protocol, mm);
+        let mut gantry = gantry.unwrap_or_else(|e| {
+            error!("{e}");
+            std::process::exit(2);
+        });
+        let steps = read_steps(path).unwrap_or_else(|e| {
+            error!("Failed to read steps from {path}: {e}");
+            std::process::exit(2);
+        });
+        info!("sending {} steps from {path}", steps.len());
+        if let Err(e) = gantry.send(&steps) {
+            error!("Failed to send steps to gantry: {e}");
         }
-        loop {
-            // STEP 3: READ REED-SWITCH OUTPUT
-            let mut line = String::new();
-            let newstate = state;
+        return;
+    }
+
+    // jog the gantry by hand to measure the board, printing the geometry found
+    if options.calibrate {
+        let mut gantry = options.gantry.as_ref().map(|device| {
+            let journal = gantry_journal.into();
+            open_gantry(device, journal, gantry_batch, magnet_wiring, step_protocol, mm)
+                .unwrap_or_else(|e| {
+                    error!("{e}");
+                    std::process::exit(2);
+                })
+        });
+        let geometry = calibrate::calibrate(
+            std::io::stdin().lock(),
+            gantry.as_mut(),
+            geometry,
+        );
+        std::process::exit(i32::from(geometry.is_none()));
+    }
 
-            // This is input from REED SWITCHES
-            std::io::stdin().read_line(&mut line).unwrap();
-            if user_input == "\x04" {
-                info!("received EOF from opponent wrapper, exiting");
-                return;
+    let mut config = game_config(&options).unwrap_or_else(|e| {
+        error!("{e}");
+        std::process::exit(2);
+    });
+    // a study set up by hand on the board instead of typed in as a FEN
+    if options.scan_setup {
+        config.start = scan::scan_setup(std::io::stdin().lock(), &config).unwrap_or_else(|e| {
+            error!("{e}");
+            std::process::exit(2);
+        });
+    }
+    // the reeds, opponent, clocks and gantry each in a task of their own
+    #[cfg(feature = "async-loop")]
+    if options.async_loop {
+        match async_loop::play(&config) {
+            Ok(summary) => info!("game over: {:?}", summary.result),
+            Err(e) => {
+                error!("{e}");
+                std::process::exit(2);
             }
-            let user_input = line.trim();
-            info!("received line: {user_input}");
-            if user_input == "-1" {
-                break;
+        }
+        return;
+    }
+    // two engines playing each other for a demo, nobody at the board
+    if let Some(engine) = &options.engine_vs_engine {
+        match demo::engine_vs_engine(&config, engine, &mut std::io::stdout()) {
+            Ok(summary) => info!("game over: {:?}", summary.result),
+            Err(e) => {
+                error!("{e}");
+                std::process::exit(2);
             }
+        }
+        return;
+    }
+    let mut boards = open_boards(&options, &config).unwrap_or_else(|e| {
+        error!("{e}");
+        std::process::exit(2);
+    });
+    let shutdowns = boards.iter().map(|board| board.shutdown.clone()).collect();
+    if let Err(e) = watch_signals(shutdowns) {
+        error!("{e}");
+        std::process::exit(2);
+    }
+    if let Err(e) = session::play(&mut boards) {
+        error!("{e}");
+        std::process::exit(2);
+    }
+    for board in &mut boards {
+        board.opponent.quit();
+    }
+}
+
+// the board on stdin, or one per --board port, each with its own opponent
+fn open_boards(options: &Options, config: &GameConfig) -> Result<Vec<Board>, String> {
+    if options.boards.is_empty() {
+        let opponent = spawn_opponent(config)?;
+        // the reeds are read from stdin only now, so the reader doesn't swallow the
+        // opponent wrapper's handshake responses
+        let (shutdown, reed_lines) = open_reeds(options)?;
+        // the view outlives single games, so a companion display stays connected across resets
+        let views = match &options.view {
+            Some(addr) => ViewServer::serve(addr)
+                .map_err(|e| format!("Failed to serve board view on {addr}: {e}"))?,
+            None => ViewServer::default(),
+        };
+        #[cfg(feature = "spectators")]
+        let views = match &options.spectate {
+            Some(addr) => views
+                .spectate(addr)
+                .map_err(|e| format!("Failed to serve spectators on {addr}: {e}"))?,
+            None => views,
+        };
+        return Ok(vec![Board {
+            config: config.clone(),
+            opponent,
+            reed_lines,
+            shutdown,
+            views,
+            console: Box::new(std::io::stdout()),
+        }]);
+    }
+    if options.keyboard
+        || options.view.is_some()
+        || options.spectate.is_some()
+        || options.gantry.is_some()
+    {
+        let e = "--board can't be combined with --keyboard, --view, --spectate or --gantry";
+        return Err(e.to_string());
+    }
+    options
+        .boards
+        .iter()
+        .enumerate()
+        .map(|(n, port)| {
+            // each gantry journals its own moves in flight
+            let journal = format!("{}.{n}", config.gantry_journal.display());
+            let config = GameConfig {
+                gantry: port.gantry.clone(),
+                gantry_journal: journal.into(),
+                ..config.clone()
+            };
+            let opponent = spawn_opponent(&config)?;
+            let reeds = std::fs::File::open(&port.reeds)
+                .map_err(|e| format!("Failed to open reed port {}: {e}", port.reeds))?;
+            let (shutdown, reed_lines) = spawn_reed_reader(std::io::BufReader::new(reeds));
+            Ok(Board {
+                config,
+                opponent,
+                reed_lines,
+                shutdown,
+                views: ViewServer::default(),
+                console: Box::new(std::io::stdout()),
+            })
+        })
+        .collect()
+}
+
+// the board's own reeds: the reed matrix with --reed-gpio, otherwise the
+// reed reader's lines on stdin
+#[cfg_attr(not(feature = "gpio"), allow(unused_variables, clippy::unnecessary_wraps))]
+fn open_reeds(options: &Options) -> Result<ReedInput, String> {
+    #[cfg(feature = "gpio")]
+    if let Some(pins) = options.reed_gpio {
+        let matrix = reed_matrix::GpioMatrix::open(pins)
+            .map_err(|e| format!("Failed to open the reed matrix: {e}"))?;
+        let hz = options.reed_scan_hz.unwrap_or(DEFAULT_REED_SCAN_HZ);
+        let interval = Duration::from_secs(1) / hz;
+        let debounce = options.reed_debounce.unwrap_or(DEFAULT_REED_DEBOUNCE);
+        return reed_matrix::spawn_matrix_reader(matrix, interval, debounce, options.snapshot);
+    }
+    Ok(spawn_reed_reader(std::io::BufReader::new(std::io::stdin())))
+}
+
+// the options that need an optional feature built in
+fn check_features(options: &Options) -> Result<(), String> {
+    if options.async_loop && !cfg!(feature = "async-loop") {
+        return Err("--async needs a build with the async-loop feature".to_string());
+    }
+    if options.spectate.is_some() && !cfg!(feature = "spectators") {
+        return Err("--spectate needs a build with the spectators feature".to_string());
+    }
+    if options.reed_gpio.is_some() && !cfg!(feature = "gpio") {
+        return Err("--reed-gpio needs a build with the gpio feature".to_string());
+    }
+    if options.reed_gpio.is_none()
+        && (options.reed_scan_hz.is_some() || options.reed_debounce.is_some())
+    {
+        warn!("--reed-scan-hz and --reed-debounce are ignored without --reed-gpio");
+    }
+    // modes that read their input from stdin themselves
+    let reads_stdin = options.keyboard || options.async_loop || options.scan_setup;
+    if options.reed_gpio.is_some() && (reads_stdin || !options.boards.is_empty()) {
+        let e = "--reed-gpio reads the one board wired to this Pi, \
+                 it can't be combined with --keyboard, --async, --scan-setup or --board";
+        return Err(e.to_string());
+    }
+    let plain_board = !options.snapshot
+        && options.view.is_none()
+        && options.spectate.is_none()
+        && options.boards.is_empty();
+    if options.async_loop && !plain_board {
+        let e = "the async loop plays one board from reed indices or typed squares, \
+                 it can't be combined with --snapshot, --view, --spectate or --board";
+        return Err(e.to_string());
+    }
+    Ok(())
+}
+
+#[allow(clippy::too_many_lines)]
+fn game_config(options: &Options) -> Result<GameConfig, String> {
+    let theme = match &options.theme {
+        // moves are read as usual, but nothing lights up to help
+        _ if options.blindfold => Theme::dark(),
+        Some(path) => Theme::load(path).map_err(|e| format!("Failed to load theme {path}: {e}"))?,
+        None => Theme::default(),
+    };
+    if options.blindfold && options.theme.is_some() {
+        warn!("--theme is ignored with --blindfold, the LEDs stay dark");
+    }
+    if options.blindfold && options.telemetry.is_none() {
+        warn!("--blindfold announces moves only in the --telemetry narration");
+    }
+    let opponent = if options.no_engine {
+        OpponentBackend::Nobody
+    } else if let Some(address) = &options.remote_engine {
+        OpponentBackend::Remote(address.clone())
+    } else {
+        options.engine.clone().map_or_else(
+            || OpponentBackend::Wrapper(OPPONENT_WRAPPER_EXE_PATH.to_string()),
+            OpponentBackend::Uci,
+        )
+    };
+    let wrapper = matches!(opponent, OpponentBackend::Wrapper(_));
+    let uci = matches!(opponent, OpponentBackend::Uci(_) | OpponentBackend::Remote(_));
+    let engines = options.engine.is_some() || options.remote_engine.is_some();
+    if options.no_engine && (engines || options.engine_vs_engine.is_some()) {
+        let e = "--no-engine has both sides moved by hand, \
+                 it can't be combined with --engine, --remote-engine or --engine-vs-engine";
+        return Err(e.to_string());
+    }
+    if options.engine.is_some() && options.remote_engine.is_some() {
+        return Err("--engine and --remote-engine both pick the engine, give one".to_string());
+    }
+    if options.wrapper_prompt.is_some() && uci {
+        warn!("--wrapper-prompt is ignored when playing a UCI engine");
+    }
+    if !options.wrapper_answers.is_empty() && uci {
+        warn!("--wrapper-answer is ignored when playing a UCI engine");
+    }
+    if options.start.is_some() && wrapper {
+        let e = "the opponent wrapper always starts from the standard position, --fen needs --engine";
+        return Err(e.to_string());
+    }
+    if options.scan_setup && wrapper {
+        let e = "the opponent wrapper always starts from the standard position, \
+                 --scan-setup needs --engine";
+        return Err(e.to_string());
+    }
+    if options.scan_setup && (options.start.is_some() || !options.boards.is_empty()) {
+        let e = "--scan-setup reads the board on stdin, it can't be combined with --fen or --board";
+        return Err(e.to_string());
+    }
+    check_features(options)?;
+    if options.feed_profile.is_some() && options.step_protocol == StepProtocol::Binary {
+        let e = "binary frames carry no feedrate and only steps on the half square grid, \
+                 --feed-profile needs --step-proto text";
+        return Err(e.to_string());
+    }
+    if options.speed.is_some() && options.step_protocol == StepProtocol::Binary {
+        return Err("binary frames carry no feedrate, --speed needs --step-proto text".to_string());
+    }
+    if options.engine_vs_engine.is_some() && (options.async_loop || !options.boards.is_empty()) {
+        let e = "--engine-vs-engine plays one board with no reed input, \
+                 it can't be combined with --async or --board";
+        return Err(e.to_string());
+    }
+    if options.book.is_some() && options.engine.is_none() {
+        let e = "the opponent wrapper and a remote engine pick their own moves, \
+                 --book needs --engine";
+        return Err(e.to_string());
+    }
+    if options.book_moves.is_some() && options.book.is_none() {
+        warn!("--book-moves is ignored without --book");
+    }
+    // the engine's own search, Skill Level included, is seeded by the engine
+    if options.seed.is_some() && options.book.is_none() {
+        warn!("--seed only seeds the choice of book moves, it is ignored without --book");
+    }
+    if options.engine_strength != EngineStrength::default() && !uci {
+        warn!("--engine-depth, --engine-movetime and --engine-skill need --engine");
+    }
+    if options.adjudicate_cp.is_some() && !uci {
+        warn!("the opponent wrapper gives no evaluations, --adjudicate-cp needs --engine");
+    }
+    let adjudication = options.adjudicate_cp.map(|threshold_cp| Adjudication {
+        threshold_cp,
+        plies: options.adjudicate_plies.unwrap_or(DEFAULT_ADJUDICATE_PLIES),
+    });
+    let orientation = if options.flip {
+        Orientation::Flipped
+    } else {
+        Orientation::Normal
+    };
+    let journal = options.gantry_journal.as_deref().unwrap_or(DEFAULT_GANTRY_JOURNAL);
+    let geometry = options.geometry.unwrap_or_default();
+    Ok(GameConfig::builder()
+        .human(options.human.unwrap_or(Color::White))
+        .start(options.start.clone().unwrap_or_default())
+        .time_control(options.time_control)
+        .opponent(opponent)
+        .engine_strength(options.engine_strength)
+        .book(options.book.clone(), options.book_moves)
+        .seed(options.seed)
+        .wrapper_prompt(options.wrapper_prompt.clone())
+        .wrapper_answers(options.wrapper_answers.clone())
+        .theme(theme)
+        .orientation(orientation)
+        .square_mapping(options.square_mapping)
+        .default_promotion(options.default_promotion.unwrap_or(Role::Queen))
+        .touch_move(options.touch_move)
+        .swap_promotion(options.swap_promotion)
+        .strictness(options.strictness)
+        .pickup_timeout(options.pickup_timeout)
+        .adjudication(adjudication)
+        .max_plies(options.max_moves)
+        .keyboard(options.keyboard)
+        .teach(options.teach)
+        .threat_preview(options.threat_preview)
+        .coach(options.coach)
+        .led_grid(options.led_grid)
+        .led_fps(options.led_fps.unwrap_or(DEFAULT_LED_FPS))
+        .snapshot(options.snapshot)
+        .verbose_board(options.verbose_board)
+        .gantry(options.gantry.clone(), journal.into())
+        .gantry_batch(options.gantry_batch.unwrap_or(1))
+        .magnet_wiring(options.magnet_wiring.unwrap_or_default())
+        .step_protocol(options.step_protocol)
+        .step_units(options.step_units)
+        .feed_profile(options.feed_profile)
+        .speed(options.speed)
+        .home_after_move(options.home_after_move)
+        .geometry(BoardGeometry {
+            scratch: options
+                .scratch
+                .or(geometry.scratch)
+                .map(|(x, y)| orientation.planned((x, y), &geometry)),
+            ..geometry
+        })
+        .error_trail(options.error_trail.unwrap_or(DEFAULT_ERROR_TRAIL))
+        .capture_strategy(options.capture_strategy)
+        .telemetry(options.telemetry.clone())
+        .event_log(options.event_log.clone())
+        .build())
+}
+
+fn open_gantry(
+    device: &str,
+    journal: PathBuf,
+    batch: usize,
+    wiring: MagnetWiring,
+    protocol: StepProtocol,
+    mm: Option<BoardGeometry>,
+) -> Result<Gantry, String> {
+    let mut gantry = Gantry::open(device, journal)
+        .map_err(|e| format!("Failed to open gantry {device}: {e}"))?
+        .batch(batch)
+        .wiring(wiring)
+        .protocol(protocol)
+        .millimetres(mm);
+    if let Err(e) = gantry.recover() {
+        error!("Failed to recover gantry position: {e}");
+    }
+    Ok(gantry)
+}
 
-            let mv;
-            (state, mv) = update_state(&pos, user_input.parse::<u32>().unwrap(), newstate);
-            let copied_pos = pos.clone();
-            if let Some(mv) = mv {
-                info!("got full move, playing {mv}");
-                pos = copied_pos.play(&mv).unwrap();
-                let move_san = San::from_move(&pos, &mv).to_string();
-                info!("sending move {move_san} to opponent wrapper");
-                send_line(&move_san);
-                break;
+fn spawn_opponent(config: &GameConfig) -> Result<Box<dyn Opponent>, String> {
+    Ok(match &config.opponent {
+        OpponentBackend::Uci(path) => {
+            let mut engine = UciEngine::spawn(path, &config.start, config.engine_strength)
+                .map_err(|e| format!("Failed to start engine {path}: {e}"))?;
+            if let Some(book) = &config.book {
+                let opened = Book::open(book)
+                    .map_err(|e| format!("Failed to read opening book {book}: {e}"))?;
+                engine = engine.book(opened, config.book_moves).seed(config.seed);
             }
+            Box::new(engine)
         }
+        OpponentBackend::Wrapper(path) => Box::new(
+            Wrapper::spawn(path, &config.wrapper_prompt, &config.wrapper_answers)
+                .map_err(|e| format!("Failed to spawn opponent wrapper {path}: {e}"))?,
+        ),
+        OpponentBackend::Remote(address) => Box::new(
+            RemoteEngine::connect(address, &config.start, config.engine_strength)
+                .map_err(|e| format!("Failed to connect to remote engine {address}: {e}"))?,
+        ),
+        OpponentBackend::Nobody => Box::new(Nobody),
+    })
+}
 
-        let move_from_opponent = recv_line();
-        let san: San = move_from_opponent.parse().expect("Moves from opponent should always be valid SAN.");
-        let mv = san.to_move(&pos).expect("SANs from opponent should always be legal moves.");
-        info!("got move {mv} from opponent wrapper");
+// how a game run went, for whoever drove it
+#[derive(Debug, Clone)]
+struct GameSummary {
+    position: Chess,
+    // how the game ended and why, None if it was abandoned
+    result: Option<(Outcome, &'static str)>,
+    // every step planned for the opponent's moves, in the order they were sent
+    steps: Vec<Step>,
+}
 
-        // STEP 9: CONVERT MOVE TO MOVEMENT STEPS
+#[allow(clippy::needless_pass_by_value)]
+fn console_error(e: std::io::Error) -> String {
+    format!("Failed to write to console: {e}")
+}
 
-        let steps = move_to_steps(mv, pos.turn(), f64::from(captured_whites), f64::from(captured_blacks));
-        info!("produced steps: {steps:?}", steps = steps);
+// plays one game from the starting position until it ends or the reed input
+// does. reed lines come from `reed_lines` (see spawn_reed_reader) and keyboard
+// mode output goes to `console`, so a scripted game can be driven without any
+// hardware. the board as it stands is published to `views` every tick. the
+// opponent is left running for another game
+fn run(
+    config: &GameConfig,
+    opponent: &mut Box<dyn Opponent>,
+    reed_lines: &Receiver<Option<String>>,
+    views: &ViewServer,
+    console: &mut dyn Write,
+) -> Result<GameSummary, String> {
+    let mut session = GameSession::new(0, config, opponent, reed_lines, views, console)?;
+    loop {
+        if let Tick::Over(result) = session.poll(None)? {
+            return session.finish(result);
+        }
     }
+}
 
-    //The input of SAN is gonna access through this method:
-    //convert_san_to_steps(INPUT, pos, captured_blacks, captured_whites)
-    //the method also gives an output for CORE-XY in the form of a list of structs
-    //TODO: make sure that moves coming from SAN are committed by using Chess.play()
+// ends the game if the side to move has run out of time
+fn out_of_time(
+    opponent: &mut dyn Opponent,
+    clocks: Option<&Clocks>,
+    human: Color,
+) -> Option<Outcome> {
+    let loser = clocks?.flagged(Instant::now())?;
+    if loser == human {
+        if let Err(e) = opponent.resign() {
+            error!("Failed to send resignation to opponent: {e}");
+        }
+    }
+    Some(Outcome::Decisive { winner: loser.other() })
+}
 
-    // wait for opponent wrapper to finish
-    let opponent_wrapper_output = opponent_wrapper_proc.wait().unwrap();
-    info!("opponent wrapper exited with status {status}", status = opponent_wrapper_output);
+// asks the opponent for an evaluation of the position just reached and
+// resigns for whoever has been lost for too long
+fn adjudicate(
+    opponent: &mut dyn Opponent,
+    adjudicator: Option<&mut Adjudicator>,
+    pos: &Chess,
+    human: Color,
+) -> Option<Outcome> {
+    let adjudicator = adjudicator?;
+    let score = match opponent.evaluate(pos) {
+        Ok(score) => score?,
+        Err(e) => {
+            error!("Failed to get evaluation from opponent: {e}");
+            return None;
+        }
+    };
+    let loser = adjudicator.observe(score)?;
+    info!("adjudicated at {score}cp");
+    if loser == human {
+        if let Err(e) = opponent.resign() {
+            error!("Failed to send resignation to opponent: {e}");
+        }
+    }
+    Some(Outcome::Decisive { winner: loser.other() })
 }
 
-#[allow(clippy::too_many_lines)]
-fn get_rgb(position: &Chess, state: State) -> RGB {
-    let color = position.turn();
-    let occupied = position.board().occupied();
-    let enemies = position.them();
-    match state {
-        State::Idle => RGB {
-            r: Bitboard::EMPTY,
-            g: Bitboard::EMPTY,
-            b: Bitboard::EMPTY,
-        },
-        State::FriendlyPU(square) => {
-            let mut canmv_to: Bitboard;
-            let mut is_promotion: bool = false;
-            if position.board().role_at(square).unwrap() == Role::Pawn {
-                let shift_direction = if color.is_white() { 1 } else { -1 };
-                canmv_to = Bitboard::from_square(square).shift(8 * shift_direction);
-                if (square.rank() == Rank::Second && color.is_white()
-                    || square.rank() == Rank::Seventh && color.is_black())
-                    && canmv_to.without(occupied).any()
-                {
-                    canmv_to =
-                        canmv_to.with(Bitboard::from_square(square).shift(16 * shift_direction));
-                }
-                canmv_to = canmv_to.without(occupied);
+// plies played since move 1, going by the position's move counters
+fn plies_played(pos: &Chess) -> u32 {
+    (pos.fullmoves().get() - 1) * 2 + u32::from(pos.turn().is_black())
+}
 
-                if (square.rank() == Rank::Second && color.is_black()
-                    || square.rank() == Rank::Seventh && color.is_white())
-                    && canmv_to.without(occupied).any()
-                {
-                    is_promotion = true;
-                }
-            } else {
-                canmv_to = position.board().attacks_from(square).without(occupied);
-            }
+fn game_over_reason(pos: &Chess) -> &'static str {
+    if pos.is_checkmate() {
+        "checkmate"
+    } else if pos.is_stalemate() {
+        "stalemate"
+    } else {
+        "insufficient material"
+    }
+}
 
-            let can_capture = position.board().attacks_from(square).intersect(enemies);
+// update_state plus the gestures that need to know when the input arrived
+fn apply_instruction(
+    position: &Chess,
+    instruction: u32,
+    state: State,
+    config: StateConfig,
+    king_presses: &mut DoublePress,
+    now: Instant,
+) -> StateUpdate {
+    let update = update_state(position, instruction, state, config);
 
-            if is_promotion {
-                RGB {
-                    r: canmv_to.with(can_capture),
-                    g: can_capture,
-                    b: canmv_to,
-                }
-            } else {
-                RGB {
-                    r: can_capture,
-                    g: canmv_to.with(can_capture),
-                    b: Bitboard::EMPTY,
-                }
-            }
-        }
-        State::EnemyPU(square) => {
-            let attackers = position.board().attacks_to(square, color, occupied);
-            RGB {
-                r: Bitboard::EMPTY,
-                g: attackers,
-                b: Bitboard::EMPTY,
-            }
+    // lifting and replacing the king twice in a row asks to resign, touch-move or not
+    if let (State::FriendlyPU(square), State::Idle | State::TouchMove(_)) = (state, update.next) {
+        if position.board().king_of(position.turn()) == Some(square)
+            && king_presses.press(square, now)
+        {
+            info!("resign gesture recognised, waiting for confirmation");
+            return StateUpdate {
+                next: State::ConfirmResign(square),
+                ..update
+            };
         }
-        State::FriendlyAndEnemyPU(_, enemy_square) => RGB {
-            r: Bitboard::EMPTY,
-            g: Bitboard::from_square(enemy_square),
-            b: Bitboard::EMPTY,
-        },
-        State::Castling(_, rook_square) => {
-            let target_square = match (color, rook_square) {
-                (Color::White, Square::A1) => Square::C1,
-                (Color::White, _) => Square::G1,
-                (Color::Black, Square::A8) => Square::C8,
-                (Color::Black, _) => Square::G8,
+    }
+
+    // doing the same with the opponent's king offers (or accepts) a draw
+    if let (State::InvalidPiecePU(None, square), State::Idle) = (state, update.next) {
+        if position.board().king_of(position.turn().other()) == Some(square)
+            && king_presses.press(square, now)
+        {
+            info!("draw gesture recognised, waiting for confirmation");
+            return StateUpdate {
+                next: State::ConfirmDraw(square),
+                ..update
             };
+        }
+    }
+    update
+}
 
-            RGB {
-                r: Bitboard::from_square(target_square),
-                g: Bitboard::EMPTY,
-                b: Bitboard::from_square(target_square),
-            }
+// which way round the board sits in front of the player. only the raw reed
+// indices coming in and the steps going out to the gantry are physical, so
+// those are the only two things this is applied to
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+enum Orientation {
+    #[default]
+    Normal,
+    // rotated 180 degrees, with the human on the far side
+    Flipped,
+}
+
+impl Orientation {
+    // `instruction` must already be a valid square index
+    fn instruction(self, instruction: u32) -> u32 {
+        match self {
+            Self::Normal => instruction,
+            Self::Flipped => u32::from(Self::rotate(Square::new(instruction))),
+        }
+    }
+
+    // a whole occupancy frame from the reeds in snapshot mode
+    fn frame(self, frame: Bitboard) -> Bitboard {
+        match self {
+            Self::Normal => frame,
+            Self::Flipped => frame.into_iter().map(Self::rotate).collect(),
+        }
+    }
+
+    fn rotate(square: Square) -> Square {
+        square.flip_vertical().flip_horizontal()
+    }
+
+    // a point on the gantry's travel given as it sits physically, where the
+    // planner sees it. flipping is its own inverse, so this is `step` again
+    const fn planned(self, (x, y): (f64, f64), geom: &BoardGeometry) -> (f64, f64) {
+        let step = Step {
+            x,
+            y,
+            magnet: Magnet::Released,
+            feedrate: None,
+        };
+        let step = self.step(step, geom);
+        (step.x, step.y)
+    }
+
+    const fn step(self, step: Step, geom: &BoardGeometry) -> Step {
+        match self {
+            Self::Normal => step,
+            Self::Flipped => geom.rotated(step),
         }
-        State::CastlingPutRookDown(_, _, target_square) => RGB {
-            r: Bitboard::from_square(target_square),
-            g: Bitboard::EMPTY,
-            b: Bitboard::from_square(target_square),
-        },
-        State::InvalidPiecePU(_, square) | State::InvalidMove(_, square) => RGB {
-            r: Bitboard::from_square(square),
-            g: Bitboard::EMPTY,
-            b: Bitboard::EMPTY,
-        },
-        State::Error => RGB {
-            r: Bitboard::FULL,
-            g: Bitboard::EMPTY,
-            b: Bitboard::EMPTY,
-        },
     }
 }
 
-#[allow(clippy::upper_case_acronyms)]
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
-struct RGB {
-    r: Bitboard,
-    g: Bitboard,
-    b: Bitboard,
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum InstructionError {
+    NotANumber(String),
+    OutOfRange(u32),
 }
 
-fn print_rgb(rgb: RGB) {
-    print_bitboard(rgb.r);
-    print_bitboard(rgb.g);
-    print_bitboard(rgb.b);
+impl std::fmt::Display for InstructionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NotANumber(text) => write!(f, "{text:?} is not a square index"),
+            Self::OutOfRange(index) => write!(f, "square index {index} is out of range 0..64"),
+        }
+    }
 }
 
-#[allow(clippy::too_many_lines, clippy::cognitive_complexity)]
-fn update_state(position: &Chess, instruction: u32, state: State) -> (State, Option<Move>) {
-    let color = position.turn();
-    let square = Square::new(instruction);
-    let occupied = position.board().occupied();
-    let friendlies = position.us();
-    let enemies = position.them();
+fn parse_instruction(text: &str) -> Result<u32, InstructionError> {
+    let instruction = text
+        .parse::<u32>()
+        .map_err(|_| InstructionError::NotANumber(text.to_string()))?;
+    square_from_instruction(instruction)?;
+    Ok(instruction)
+}
 
-    match state {
-        State::Idle => {
+// sensor glitches can report any number, so never hand shakmaty an index >= 64
+const fn square_from_instruction(instruction: u32) -> Result<Square, InstructionError> {
+    if instruction < 64 {
+        Ok(Square::new(instruction))
+    } else {
+        Err(InstructionError::OutOfRange(instruction))
+    }
+}
+
+fn read_steps(path: &str) -> Result<Vec<Step>, String> {
+    std::fs::read_to_string(path)
+        .map_err(|e| e.to_string())?
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| line.parse::<Step>().map_err(|e| e.to_string()))
+        .collect()
+}
+
+// a board's reed lines, and a sender into them for watch_signals
+type ReedInput = (Sender<Option<String>>, Receiver<Option<String>>);
+
+// reads reed lines on their own thread so the main loop can wake up on a
+// timer; None is sent once the stream ends
+// also returns a sender into the same lines, for watch_signals
+fn spawn_reed_reader(mut reeds: impl BufRead + Send + 'static) -> ReedInput {
+    let (sender, receiver) = mpsc::channel();
+    let shutdown = sender.clone();
+    std::thread::spawn(move || loop {
+        let reed_line = read_reed_line(&mut reeds);
+        let done = reed_line.is_none();
+        if sender.send(reed_line).is_err() || done {
+            break;
+        }
+    });
+    (shutdown, receiver)
+}
+
+// on SIGINT or SIGTERM every board gets SHUTDOWN_LINE, so each session can
+// let go of its piece, park its gantry and darken its LEDs before play
+// returns and the opponents are told to quit. a second signal doesn't wait
+fn watch_signals(boards: Vec<Sender<Option<String>>>) -> Result<(), String> {
+    let mut signals = Signals::new([SIGINT, SIGTERM])
+        .map_err(|e| format!("Failed to install signal handlers: {e}"))?;
+    std::thread::spawn(move || {
+        let mut received = signals.forever();
+        if let Some(signal) = received.next() {
+            warn!("received signal {signal}, shutting down");
+            for board in &boards {
+                let _ = board.send(Some(SHUTDOWN_LINE.to_string()));
+            }
+        }
+        if received.next().is_some() {
+            error!("received a second signal, exiting without shutting down");
+            std::process::exit(130);
+        }
+    });
+    Ok(())
+}
+
+// reads one trimmed line of reed input, or None once the stream has ended
+// (either a real EOF or an explicit ^D sent down the pipe). a line garbled
+// by serial noise is skipped, any other read error ends the stream
+fn read_reed_line(reader: &mut impl BufRead) -> Option<String> {
+    let mut reed_line = String::new();
+    let bytes_read = loop {
+        match reader.read_line(&mut reed_line) {
+            Ok(bytes_read) => break bytes_read,
+            Err(e) if e.kind() == std::io::ErrorKind::InvalidData => {
+                warn!("skipping a reed line that isn't text: {e}");
+                reed_line.clear();
+            }
+            Err(e) => {
+                error!("Failed to read reed input: {e}");
+                return None;
+            }
+        }
+    };
+    let reed_line = reed_line.trim();
+    if bytes_read == 0 || reed_line == "\x04" {
+        None
+    } else {
+        Some(reed_line.to_string())
+    }
+}
+
+// K v K, K+B v K, K+N v K, and any number of bishops that all share a square colour
+fn is_insufficient_material(position: &Chess) -> bool {
+    let board = position.board();
+    let non_kings = board.occupied().without(board.kings());
+    if non_kings.is_empty() {
+        return true;
+    }
+    if non_kings.without(board.knights()).without(board.bishops()).any() {
+        return false;
+    }
+    if non_kings.count() == 1 {
+        return true;
+    }
+    let bishops = board.bishops();
+    board.knights().is_empty()
+        && (bishops.intersect(Bitboard::DARK_SQUARES).is_empty()
+            || bishops.intersect(Bitboard::LIGHT_SQUARES).is_empty())
+}
+
+// fifty moves each without a capture or a pawn move, which the side to move
+// may claim a draw on but which doesn't end the game by itself
+fn can_claim_fifty_moves(position: &Chess) -> bool {
+    position.halfmoves() >= 100
+}
+
+// the enemy pieces the piece on `from` would attack once it stood on `to`,
+// whatever is on `to` now being taken
+fn threat_preview(position: &Chess, from: Square, to: Square) -> Bitboard {
+    let mut board = position.board().clone();
+    let Some(piece) = board.remove_piece_at(from) else {
+        return Bitboard::EMPTY;
+    };
+    board.set_piece_at(to, piece);
+    board.attacks_from(to).intersect(board.by_color(!piece.color))
+}
+
+// a lifted piece's destinations as in FriendlyPU, with the threats from the
+// one it's held over on top
+fn threat_preview_rgb(position: &Chess, from: Square, to: Square, theme: &Theme) -> RGB {
+    let mut rgb = get_rgb(position, State::FriendlyPU(from), theme, false, None, Waiting::Human);
+    paint(&mut rgb, threat_preview(position, from, to), theme.threat);
+    rgb
+}
+
+// the square of the piece `mv` just moved if it can now be taken and nothing
+// of its own guards it. `position` is after the move, with the opponent to move
+fn hanging_piece(position: &Chess, mv: &Move) -> Option<Square> {
+    if mv.is_castle() || mv.role() == Role::King {
+        return None;
+    }
+    let square = mv.to();
+    let board = position.board();
+    let occupied = board.occupied();
+    let attacked = board.attacks_to(square, position.turn(), occupied).any();
+    let defended = board.attacks_to(square, !position.turn(), occupied).any();
+    (attacked && !defended).then_some(square)
+}
+
+// flashed with --coach when the human leaves the piece they moved en prise
+fn hanging_rgb(square: Square, theme: &Theme) -> RGB {
+    let mut rgb = RGB::default();
+    paint(&mut rgb, Bitboard::from_square(square), theme.hanging);
+    rgb
+}
+
+// flashed when a pickup times out, showing where the piece should be
+fn pickup_reminder_rgb(square: Square, theme: &Theme) -> RGB {
+    let mut rgb = RGB::default();
+    paint(&mut rgb, Bitboard::from_square(square), theme.pickup_reminder);
+    rgb
+}
+
+// squares still to fill and squares to clear before the game can start
+fn setup_rgb(missing: Bitboard, extra: Bitboard, theme: &Theme) -> RGB {
+    let mut rgb = RGB::default();
+    paint(&mut rgb, missing, theme.pickup_reminder);
+    paint(&mut rgb, extra, theme.error);
+    rgb
+}
+
+// an opponent move the gantry can't make, for someone to make by hand
+fn unreachable_move_rgb(mv: &Move, theme: &Theme) -> RGB {
+    let mut rgb = RGB::default();
+    let from = mv.from().map_or(Bitboard::EMPTY, Bitboard::from_square);
+    paint(&mut rgb, from.with(Bitboard::from_square(mv.to())), theme.error);
+    rgb
+}
+
+// the opponent's board no longer matches ours, so nothing it says can be played
+fn desync_rgb(theme: &Theme) -> RGB {
+    let mut rgb = RGB::default();
+    paint(&mut rgb, Bitboard::FULL, theme.desync);
+    rgb
+}
+
+// draws light the whole board, wins light the winner's pieces
+fn game_over_rgb(position: &Chess, outcome: Outcome, theme: &Theme) -> RGB {
+    let mut rgb = RGB::default();
+    match outcome {
+        Outcome::Draw => paint(&mut rgb, Bitboard::FULL, theme.draw),
+        Outcome::Decisive { winner } => {
+            paint(&mut rgb, position.board().by_color(winner), theme.win);
+        }
+    }
+    rgb
+}
+
+// whose move the board is waiting on, shown round its edge while idle
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Waiting {
+    Human,
+    // an opponent query is in flight
+    Opponent,
+}
+
+// `teach` adds hints for learners: every piece that can move while idle, and
+// the king's castling squares while it is lifted
+#[allow(clippy::too_many_lines)]
+fn get_rgb(
+    position: &Chess,
+    state: State,
+    theme: &Theme,
+    teach: bool,
+    last_move: Option<&Move>,
+    waiting: Waiting,
+) -> RGB {
+    let color = position.turn();
+    let occupied = position.board().occupied();
+    let mut rgb = RGB::default();
+    match state {
+        State::Idle => {
+            if teach {
+                paint(&mut rgb, legal_origins(position), theme.legal_origin);
+            }
+            if let Some(mv) = last_move {
+                let from = mv.from().map_or(Bitboard::EMPTY, Bitboard::from_square);
+                paint(&mut rgb, from.with(Bitboard::from_square(mv.to())), theme.last_move);
+            }
+            if position.is_check() {
+                let king = position.board().king_of(color);
+                paint(&mut rgb, king.map_or(Bitboard::EMPTY, Bitboard::from_square), theme.check);
+            }
+            // only where nothing else is lit, so it never changes another hint's colour
+            let edge = [
+                Bitboard::from_rank(Rank::First),
+                Bitboard::from_rank(Rank::Eighth),
+                Bitboard::from_file(File::A),
+                Bitboard::from_file(File::H),
+            ]
+            .into_iter()
+            .fold(Bitboard::EMPTY, Bitboard::with);
+            let shade = match waiting {
+                Waiting::Human => theme.your_turn,
+                Waiting::Opponent => theme.opponent_thinking,
+            };
+            let lit = rgb.r.with(rgb.g).with(rgb.b);
+            paint(&mut rgb, edge.without(lit), shade);
+        }
+        State::FriendlyPU(square) => {
+            let destinations = split_legal_destinations(position, square);
+            if destinations.promotion {
+                paint(&mut rgb, destinations.quiet, theme.promotion_move);
+                paint(&mut rgb, destinations.captures, theme.promotion_capture);
+            } else {
+                paint(&mut rgb, destinations.quiet, theme.legal_move);
+                paint(&mut rgb, destinations.captures, theme.capture);
+            }
+            // the pawn to take off, not the empty square the capturer lands on
+            paint(&mut rgb, destinations.en_passant, theme.capture);
+            if teach {
+                paint(&mut rgb, castle_destinations(position, square), theme.castle_target);
+            }
+        }
+        State::EnemyPU(square) => {
+            let attackers = position.board().attacks_to(square, color, occupied);
+            let en_passant = en_passant_capturers(position, square);
+            paint(&mut rgb, attackers.with(en_passant), theme.attacker);
+        }
+        State::FriendlyAndEnemyPU(friendly_square, enemy_square) => {
+            paint(&mut rgb, Bitboard::from_square(enemy_square), theme.capture_target);
+            // en passant lands behind the pawn taken, so show where
+            if let Some(mv) = en_passant_capture(position, friendly_square, enemy_square) {
+                paint(&mut rgb, Bitboard::from_square(mv.to()), theme.legal_move);
+            }
+        }
+        // either piece may go down first, so both targets light up
+        State::Castling(king_square, rook_square) => {
+            let (king_to, rook_to) = castling_targets(color, king_square, rook_square);
+            let targets = Bitboard::from_square(king_to).with(Bitboard::from_square(rook_to));
+            paint(&mut rgb, targets, theme.castle_target);
+        }
+        State::CastlingPutRookDown(_, _, target_square)
+        | State::CastlingPutKingDown(_, _, target_square) => {
+            paint(&mut rgb, Bitboard::from_square(target_square), theme.castle_target);
+        }
+        State::CastlingMisplaced(king_square, rook_square, misplaced, down) => {
+            let (king_to, rook_to) = castling_targets(color, king_square, rook_square);
+            let targets = Bitboard::from_square(king_to).with(Bitboard::from_square(rook_to));
+            let waiting = down.map_or(targets, |down| targets.without(Bitboard::from_square(down)));
+            paint(&mut rgb, waiting, theme.castle_target);
+            paint(&mut rgb, Bitboard::from_square(misplaced), theme.error);
+        }
+        State::PromotionSelect(_, to, _) => {
+            let choices = promotion_choice_squares(to)
+                .into_iter()
+                .fold(Bitboard::EMPTY, |acc, square| acc.with(Bitboard::from_square(square)));
+            paint(&mut rgb, choices, theme.promotion_choice);
+            paint(&mut rgb, Bitboard::from_square(to), theme.promotion_square);
+        }
+        State::PromotionSwap(_, to, _, _) | State::PromotionPlace(_, to, _, _) => {
+            paint(&mut rgb, Bitboard::from_square(to), theme.promotion_square);
+        }
+        State::ConfirmResign(king_square) => {
+            paint(&mut rgb, Bitboard::from_square(king_square), theme.confirm_resign);
+        }
+        State::Resigned => paint(&mut rgb, position.us(), theme.resigned),
+        State::ConfirmDraw(king_square) | State::ClaimDraw(king_square) => {
+            paint(&mut rgb, Bitboard::from_square(king_square), theme.draw_offer);
+        }
+        State::DrawOffered => paint(&mut rgb, position.board().kings(), theme.draw_offer),
+        State::DrawAgreed | State::DrawClaimed => paint(&mut rgb, Bitboard::FULL, theme.draw),
+        State::ClaimRejected => paint(&mut rgb, position.board().kings(), theme.error),
+        // only the kings stay lit, the rest of the board goes dark
+        State::Paused => paint(&mut rgb, position.board().kings(), theme.paused),
+        State::IllegalCastle(king_square, rook_square) => {
+            let both = Bitboard::from_square(king_square).with(Bitboard::from_square(rook_square));
+            paint(&mut rgb, both, theme.error);
+        }
+        State::InvalidPiecePU(_, square)
+        | State::InvalidMove(_, square)
+        | State::UnexpectedOccupancy(square) => {
+            paint(&mut rgb, Bitboard::from_square(square), theme.error);
+        }
+        State::MissingPiece(square) | State::TouchMove(square) => {
+            paint(&mut rgb, Bitboard::from_square(square), theme.pickup_reminder);
+        }
+        State::TouchMoveViolation(touched, other) => {
+            paint(&mut rgb, Bitboard::from_square(touched), theme.pickup_reminder);
+            paint(&mut rgb, Bitboard::from_square(other), theme.error);
+        }
+        State::Error => paint(&mut rgb, Bitboard::FULL, theme.error),
+    }
+    rgb
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Destinations {
+    quiet: Bitboard,
+    captures: Bitboard,
+    // pawns an en passant would take, which stand beside the lifted pawn
+    // rather than on the square it lands on; that square counts as quiet
+    en_passant: Bitboard,
+    promotion: bool,
+}
+
+// en passant's landing square is in, the pawn it takes isn't
+fn legal_destinations(position: &Chess, square: Square) -> Bitboard {
+    let destinations = split_legal_destinations(position, square);
+    destinations.quiet.with(destinations.captures)
+}
+
+// how many legal moves the piece on `square` has, each promotion choice and
+// castle counted as its own, for feedback that can't show the destinations
+fn legal_move_count(position: &Chess, square: Square) -> usize {
+    position.legal_moves().iter().filter(|mv| mv.from() == Some(square)).count()
+}
+
+// squares holding a piece with at least one legal move
+fn legal_origins(position: &Chess) -> Bitboard {
+    position
+        .legal_moves()
+        .iter()
+        .filter_map(Move::from)
+        .fold(Bitboard::EMPTY, |acc, square| acc.with(Bitboard::from_square(square)))
+}
+
+// where the king on `square` lands for each castle still legal; castling is
+// played by lifting the king and rook, so split_legal_destinations leaves these out
+fn castle_destinations(position: &Chess, square: Square) -> Bitboard {
+    position
+        .legal_moves()
+        .iter()
+        .filter(|mv| mv.from() == Some(square))
+        .filter_map(Move::castling_side)
+        .fold(Bitboard::EMPTY, |acc, side| {
+            acc.with(Bitboard::from_square(side.king_to(position.turn())))
+        })
+}
+
+// asks shakmaty for the legal moves of the piece on `square`, so pins and
+// checks are respected; castling is left out as the king's target is the rook
+fn split_legal_destinations(position: &Chess, square: Square) -> Destinations {
+    let mut destinations = Destinations {
+        quiet: Bitboard::EMPTY,
+        captures: Bitboard::EMPTY,
+        en_passant: Bitboard::EMPTY,
+        promotion: false,
+    };
+    for mv in position.legal_moves() {
+        if mv.from() != Some(square) || mv.is_castle() {
+            continue;
+        }
+        let to = Bitboard::from_square(mv.to());
+        if let Some(victim) = captured_square(&mv).filter(|_| mv.is_en_passant()) {
+            destinations.quiet = destinations.quiet.with(to);
+            destinations.en_passant = destinations.en_passant.with(Bitboard::from_square(victim));
+        } else if mv.is_capture() {
+            destinations.captures = destinations.captures.with(to);
+        } else {
+            destinations.quiet = destinations.quiet.with(to);
+        }
+        destinations.promotion |= mv.is_promotion();
+    }
+    destinations
+}
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+struct RGB {
+    r: Bitboard,
+    g: Bitboard,
+    b: Bitboard,
+}
+
+fn print_rgb(out: &mut dyn Write, rgb: RGB) -> std::io::Result<()> {
+    print_bitboard(out, rgb.r)?;
+    print_bitboard(out, rgb.g)?;
+    print_bitboard(out, rgb.b)
+}
+
+// keyboard mode's stand-in for the LEDs, as three bitboards or as a grid
+fn print_leds(out: &mut dyn Write, rgb: RGB, grid: bool) -> std::io::Result<()> {
+    if grid {
+        write!(out, "{}", render_led_grid(rgb))
+    } else {
+        print_rgb(out, rgb)
+    }
+}
+
+// what the LEDs would show as an 8x8 grid of ANSI truecolor blocks, rank 8 at
+// the top. unlit squares keep a faint checkerboard so the grid stays readable
+fn render_led_grid(rgb: RGB) -> String {
+    use std::fmt::Write as _;
+    let mut grid = String::new();
+    for rank in (0..8).rev() {
+        for file in 0..8 {
+            let square = Square::new(rank * 8 + file);
+            let lit = |channel: Bitboard| if channel.contains(square) { 255 } else { 0 };
+            let (r, g, b) = match (lit(rgb.r), lit(rgb.g), lit(rgb.b)) {
+                (0, 0, 0) if square.is_light() => (60, 60, 60),
+                (0, 0, 0) => (30, 30, 30),
+                shade => shade,
+            };
+            write!(grid, "\x1b[48;2;{r};{g};{b}m  ").unwrap();
+        }
+        grid.push_str("\x1b[0m\n");
+    }
+    grid
+}
+
+// what a reed input did to the state machine: the next state, the move it
+// committed if any, and how it should be reported
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct StateUpdate {
+    next: State,
+    committed: Option<Move>,
+    feedback: Feedback,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Feedback {
+    Normal,
+    // a piece that can't take part in this move was lifted
+    InvalidPickup,
+    // a piece was set down somewhere it can't legally go
+    InvalidMove,
+    // the input was ignored, or the board has to be put right before play goes on
+    RecoverableError,
+    // the state machine lost track of the board, only a reset gets it back
+    FatalError,
+    // it's not the human's move, so the input wasn't read at all
+    OutOfTurn,
+}
+
+impl Feedback {
+    const fn of(state: State) -> Self {
+        match state {
+            State::InvalidPiecePU(_, _)
+            | State::IllegalCastle(_, _)
+            | State::TouchMoveViolation(_, _) => Self::InvalidPickup,
+            State::InvalidMove(_, _) | State::CastlingMisplaced(_, _, _, _) => Self::InvalidMove,
+            State::UnexpectedOccupancy(_) | State::MissingPiece(_) => Self::RecoverableError,
+            State::Error => Self::FatalError,
+            _ => Self::Normal,
+        }
+    }
+}
+
+// the old (State, Option<Move>) shape, for callers that haven't moved over yet
+impl From<StateUpdate> for (State, Option<Move>) {
+    fn from(update: StateUpdate) -> Self {
+        (update.next, update.committed)
+    }
+}
+
+fn update_state(
+    position: &Chess,
+    instruction: u32,
+    state: State,
+    config: StateConfig,
+) -> StateUpdate {
+    let Ok(square) = square_from_instruction(instruction) else {
+        error!("ignoring out of range instruction {instruction}");
+        return StateUpdate {
+            next: state,
+            committed: None,
+            feedback: Feedback::RecoverableError,
+        };
+    };
+    // the reeds only ever speak for the human. on the opponent's move a pickup
+    // would be read as the wrong side's pieces, so nothing is read until the
+    // opponent's move has been applied
+    if matches!(config.human, Some(human) if position.turn() != human) {
+        warn!("ignoring {square}, it's not the human's move");
+        return StateUpdate {
+            next: state,
+            committed: None,
+            feedback: Feedback::OutOfTurn,
+        };
+    }
+    let (next, committed) = next_state(position, square, state, config);
+    StateUpdate {
+        next,
+        committed,
+        feedback: Feedback::of(next),
+    }
+}
+
+#[allow(clippy::too_many_lines, clippy::cognitive_complexity)]
+fn next_state(
+    position: &Chess,
+    square: Square,
+    state: State,
+    config: StateConfig,
+) -> (State, Option<Move>) {
+    let color = position.turn();
+    let occupied = position.board().occupied();
+    let friendlies = position.us();
+    let enemies = position.them();
+
+    match state {
+        State::Idle => {
             if friendlies.contains(square) {
                 (State::FriendlyPU(square), None)
             } else if enemies.contains(square) {
-                if position.board().attacks_to(square, color, occupied).any() {
+                let en_passant_victim = en_passant_capturers(position, square).any();
+                if position.board().attacks_to(square, color, occupied).any() || en_passant_victim
+                {
                     (State::EnemyPU(square), None)
                 } else {
                     (State::InvalidPiecePU(None, square), None)
                 }
+            } else if config.strictness == Strictness::Lenient {
+                // nothing stands there to be lifted, so it's far more likely a
+                // reed bouncing than a piece appearing from nowhere
+                warn!("ignoring {square} in Idle, nothing stands there, taking it for reed noise");
+                (State::Idle, None)
             } else {
                 (State::Error, None)
             }
@@ -266,10 +1759,16 @@ fn update_state(position: &Chess, instruction: u32, state: State) -> (State, Opt
                 .attacks_from(prev_square)
                 .intersect(enemies);
             if prev_square == square {
-                (State::Idle, None)
+                let movable = position.legal_moves().iter().any(|mv| mv.from() == Some(square));
+                if config.touch_move && movable {
+                    (State::TouchMove(square), None)
+                } else {
+                    (State::Idle, None)
+                }
             } else if role_picked_up == Role::Rook
                 && position.board().role_at(square).is_some()
                 && position.board().role_at(square).unwrap() == Role::King
+                && friendlies.contains(square)
             {
                 //castling
                 let mv = Move::Castle {
@@ -279,11 +1778,12 @@ fn update_state(position: &Chess, instruction: u32, state: State) -> (State, Opt
                 if position.is_legal(&mv) {
                     (State::Castling(square, prev_square), None)
                 } else {
-                    (State::InvalidPiecePU(Some(prev_square), square), None)
+                    (State::IllegalCastle(square, prev_square), None)
                 }
             } else if role_picked_up == Role::King
                 && position.board().role_at(square).is_some()
                 && position.board().role_at(square).unwrap() == Role::Rook
+                && friendlies.contains(square)
             {
                 //castling
                 let mv = Move::Castle {
@@ -293,8 +1793,10 @@ fn update_state(position: &Chess, instruction: u32, state: State) -> (State, Opt
                 if position.is_legal(&mv) {
                     (State::Castling(prev_square, square), None)
                 } else {
-                    (State::InvalidPiecePU(Some(prev_square), square), None)
+                    (State::IllegalCastle(prev_square, square), None)
                 }
+            } else if en_passant_capture(position, prev_square, square).is_some() {
+                (State::FriendlyAndEnemyPU(prev_square, square), None)
             } else if friendlies.contains(square)
                 || (enemies.contains(square) && !can_capture.contains(square))
             {
@@ -304,16 +1806,7 @@ fn update_state(position: &Chess, instruction: u32, state: State) -> (State, Opt
             } else if role_picked_up == Role::Pawn
                 && (square.rank() == Rank::First || square.rank() == Rank::Eighth)
             {
-                //promotions
-                let mv = Move::Normal {
-                    role: (Role::Pawn),
-                    from: (prev_square),
-                    capture: (None),
-                    to: (square),
-                    promotion: (Some(Role::Queen)),
-                }; //Right now we're just assuming the player will promote to queen
-                println!("PROMOTED");
-                (State::Idle, Some(mv))
+                promotion_landed(position, prev_square, square)
             } else {
                 let mv = Move::Normal {
                     role: (role_picked_up),
@@ -333,118 +1826,189 @@ fn update_state(position: &Chess, instruction: u32, state: State) -> (State, Opt
         State::EnemyPU(prev_square) => {
             if prev_square == square {
                 (State::Idle, None)
-            } else if !position
-                .board()
-                .attacks_to(prev_square, color, occupied)
-                .contains(square)
-                || enemies.contains(square)
-                || (position.board().role_at(square).unwrap() == Role::King
-                    && position
-                        .king_attackers(prev_square, color.other(), occupied)
-                        .any())
-            {
-                (State::InvalidPiecePU(Some(prev_square), square), None)
-            } else if position
-                .board()
-                .attacks_to(prev_square, color, occupied)
-                .contains(square)
+            } else if en_passant_capture(position, square, prev_square).is_some()
+                || legal_capture(position, square, prev_square)
             {
                 (State::FriendlyAndEnemyPU(square, prev_square), None)
             } else {
-                (State::Error, None)
+                // not ours, can't reach, pinned, or a king taking a defended piece
+                (State::InvalidPiecePU(Some(prev_square), square), None)
             }
         }
         State::FriendlyAndEnemyPU(prev_friendly_square, prev_enemy_square) => {
             let role_picked_up = position.board().role_at(prev_friendly_square).unwrap();
             if square == prev_friendly_square {
                 (State::EnemyPU(prev_enemy_square), None)
+            } else if let Some(mv) =
+                en_passant_capture(position, prev_friendly_square, prev_enemy_square)
+            {
+                // the pawn lands behind the one it took, not on its square
+                if square == mv.to() {
+                    (State::Idle, Some(mv))
+                } else {
+                    (State::Error, None)
+                }
             } else if square == prev_enemy_square {
-                println!("CAPTURED");
+                let mv = Move::Normal {
+                    role: (role_picked_up),
+                    from: (prev_friendly_square),
+                    capture: (position.board().role_at(prev_enemy_square)),
+                    to: (square),
+                    promotion: None,
+                };
                 if role_picked_up == Role::Pawn
                     && (square.rank() == Rank::First || square.rank() == Rank::Eighth)
                 {
-                    println!("PROMOTED");
-                    let mv = Move::Normal {
-                        role: (role_picked_up),
-                        from: (prev_friendly_square),
-                        capture: (position.board().role_at(prev_enemy_square)),
-                        to: (square),
-                        promotion: (Some(Role::Queen)),
-                    }; //assuming player will pick queen
+                    promotion_landed(position, prev_friendly_square, square)
+                } else if position.is_legal(&mv) {
                     (State::Idle, Some(mv))
                 } else {
-                    let mv = Move::Normal {
-                        role: (role_picked_up),
-                        from: (prev_friendly_square),
-                        capture: (position.board().role_at(prev_enemy_square)),
-                        to: (square),
-                        promotion: (None),
-                    };
-                    (State::Idle, Some(mv))
+                    // the piece can reach, but is pinned or leaves the king in check
+                    (State::InvalidMove(prev_friendly_square, square), None)
                 }
             } else {
                 (State::Error, None)
             }
         }
-        State::Castling(king_square, rook_square) =>
-        //make it more robust
-        {
-            match color {
-                Color::White => {
-                    if rook_square.file() == File::A {
-                        //queen side
-                        if square == Square::C1 {
-                            (
-                                State::CastlingPutRookDown(king_square, rook_square, Square::D1),
-                                None,
-                            )
-                        } else {
-                            (State::Error, None)
-                        }
-                    } else {
-                        //king side
-                        if square == Square::G1 {
-                            (
-                                State::CastlingPutRookDown(king_square, rook_square, Square::F1),
-                                None,
-                            )
-                        } else {
-                            (State::Error, None)
-                        }
-                    }
-                }
-                Color::Black => {
-                    if rook_square.file() == File::A {
-                        //queen side
-                        if square == Square::C8 {
-                            (
-                                State::CastlingPutRookDown(king_square, rook_square, Square::D8),
-                                None,
-                            )
-                        } else {
-                            (State::Error, None)
-                        }
-                    } else {
-                        //king side
-                        if square == Square::G8 {
-                            (
-                                State::CastlingPutRookDown(king_square, rook_square, Square::F8),
-                                None,
-                            )
-                        } else {
-                            (State::Error, None)
-                        }
-                    }
-                }
+        State::Castling(king_square, rook_square) => {
+            let (king_to, rook_to) = castling_targets(color, king_square, rook_square);
+            if square == king_to {
+                (State::CastlingPutRookDown(king_square, rook_square, rook_to), None)
+            } else if square == rook_to {
+                // the rook set down first, the king still in hand
+                (State::CastlingPutKingDown(king_square, rook_square, king_to), None)
+            } else if square == king_square {
+                // thought better of it, the rook is still up
+                (State::FriendlyPU(rook_square), None)
+            } else if square == rook_square {
+                (State::FriendlyPU(king_square), None)
+            } else if castling_gap(position, king_square, rook_square).contains(square) {
+                (State::CastlingMisplaced(king_square, rook_square, square, None), None)
+            } else {
+                (State::Error, None)
             }
         }
         State::CastlingPutRookDown(king_square, rook_square, target_square) => {
+            let (king_to, _) = castling_targets(color, king_square, rook_square);
             if square == target_square {
-                let mv = Move::Castle {
-                    king: king_square,
-                    rook: rook_square,
-                };
-                (State::Idle, Some(mv))
+                complete_castle(position, king_square, rook_square, target_square, king_to)
+            } else if square == king_to {
+                (State::Castling(king_square, rook_square), None)
+            } else if castling_gap(position, king_square, rook_square).contains(square) {
+                let down = Some(king_to);
+                (State::CastlingMisplaced(king_square, rook_square, square, down), None)
+            } else {
+                (State::Error, None)
+            }
+        }
+        State::CastlingPutKingDown(king_square, rook_square, target_square) => {
+            let (_, rook_to) = castling_targets(color, king_square, rook_square);
+            if square == target_square {
+                complete_castle(position, king_square, rook_square, target_square, rook_to)
+            } else if square == rook_to {
+                (State::Castling(king_square, rook_square), None)
+            } else if castling_gap(position, king_square, rook_square).contains(square) {
+                let down = Some(rook_to);
+                (State::CastlingMisplaced(king_square, rook_square, square, down), None)
+            } else {
+                (State::Error, None)
+            }
+        }
+        // only lifting the misplaced piece again picks castling back up
+        State::CastlingMisplaced(king_square, rook_square, misplaced, down) => {
+            let (king_to, rook_to) = castling_targets(color, king_square, rook_square);
+            if square != misplaced {
+                (State::Error, None)
+            } else if down == Some(king_to) {
+                (State::CastlingPutRookDown(king_square, rook_square, rook_to), None)
+            } else if down == Some(rook_to) {
+                (State::CastlingPutKingDown(king_square, rook_square, king_to), None)
+            } else {
+                (State::Castling(king_square, rook_square), None)
+            }
+        }
+        State::PromotionSelect(from, to, capture) => {
+            let choice = promotion_choices(to, config.default_promotion)
+                .into_iter()
+                .find(|&(choice_square, _)| choice_square == square);
+            if let Some((choice_square, role)) = choice.filter(|_| config.swap_promotion) {
+                info!("promoting to {role:?}, swap the pawn on {to} for one");
+                // picking the default role lifts the pawn off the promotion square itself
+                if choice_square == to {
+                    (State::PromotionPlace(from, to, capture, role), None)
+                } else {
+                    (State::PromotionSwap(from, to, capture, role), None)
+                }
+            } else if let Some((_, role)) = choice {
+                commit_promotion(position, from, to, capture, role)
+            } else {
+                (State::PromotionSelect(from, to, capture), None)
+            }
+        }
+        State::PromotionSwap(from, to, capture, role) => {
+            if square == to {
+                (State::PromotionPlace(from, to, capture, role), None)
+            } else {
+                (State::PromotionSwap(from, to, capture, role), None)
+            }
+        }
+        State::PromotionPlace(from, to, capture, role) => {
+            if square == to {
+                commit_promotion(position, from, to, capture, role)
+            } else {
+                (State::PromotionPlace(from, to, capture, role), None)
+            }
+        }
+        State::ConfirmResign(king_square) => {
+            if square == king_square {
+                (State::Resigned, None)
+            } else {
+                // anything else cancels the resignation and is treated as a normal pickup
+                next_state(position, square, State::Idle, config)
+            }
+        }
+        State::Resigned => (State::Resigned, None),
+        State::ConfirmDraw(king_square) => {
+            if square == king_square {
+                (State::DrawOffered, None)
+            } else if position.board().king_of(position.turn()) == Some(square) {
+                // lifting their own king instead claims a draw rather than offering one
+                (State::ClaimDraw(square), None)
+            } else {
+                // anything else cancels the offer and is treated as a normal pickup
+                next_state(position, square, State::Idle, config)
+            }
+        }
+        State::ClaimDraw(king_square) => {
+            if square != king_square {
+                // the king was carried off somewhere else, so it's a king move after all
+                next_state(position, square, State::FriendlyPU(king_square), config)
+            } else if can_claim_fifty_moves(position) {
+                (State::DrawClaimed, None)
+            } else {
+                (State::ClaimRejected, None)
+            }
+        }
+        State::DrawClaimed => (State::DrawClaimed, None),
+        // only there to be seen, the next pickup carries on as usual
+        State::ClaimRejected => next_state(position, square, State::Idle, config),
+        // run() settles the offer with the opponent straight away
+        State::DrawOffered => (State::DrawOffered, None),
+        State::DrawAgreed => (State::DrawAgreed, None),
+        // the session ignores the reeds while paused
+        State::Paused => (State::Paused, None),
+        // only a matching occupancy frame clears this, see the snapshot input path
+        State::UnexpectedOccupancy(_) | State::MissingPiece(_) => (state, None),
+        State::TouchMove(touched) => {
+            if square == touched {
+                (State::FriendlyPU(touched), None)
+            } else {
+                (State::TouchMoveViolation(touched, square), None)
+            }
+        }
+        State::TouchMoveViolation(touched, other) => {
+            if square == other {
+                (State::TouchMove(touched), None)
             } else {
                 (State::Error, None)
             }
@@ -460,9 +2024,39 @@ fn update_state(position: &Chess, instruction: u32, state: State) -> (State, Opt
                 (State::Error, None)
             }
         }
+        // either piece going back leaves the other one up, as if it alone had been lifted
+        State::IllegalCastle(king_square, rook_square) => {
+            if square == king_square {
+                (State::FriendlyPU(rook_square), None)
+            } else if square == rook_square {
+                (State::FriendlyPU(king_square), None)
+            } else {
+                (State::Error, None)
+            }
+        }
         State::InvalidMove(prev_prev_square, prev_square) => {
-            if square == prev_square {
+            // an illegal capture, with the captured piece still in hand
+            let captured = position.them().contains(prev_square);
+            // players tend to slide the piece straight on to a square it can
+            // go to rather than lift it back first, so a quiet move there stands
+            let slid = position.legal_moves().into_iter().find(|mv| {
+                mv.from() == Some(prev_prev_square)
+                    && mv.to() == square
+                    && matches!(mv, Move::Normal { capture: None, .. })
+            });
+            if square == prev_square && captured {
+                (State::FriendlyAndEnemyPU(prev_prev_square, prev_square), None)
+            } else if square == prev_square {
                 (State::FriendlyPU(prev_prev_square), None)
+            } else if captured {
+                (State::Error, None)
+            } else if let Some(mv) = slid {
+                if mv.is_promotion() {
+                    promotion_landed(position, prev_prev_square, square)
+                } else {
+                    info!("MOVE COMMITTED");
+                    (State::Idle, Some(mv))
+                }
             } else {
                 (State::Error, None)
             }
@@ -471,6 +2065,92 @@ fn update_state(position: &Chess, instruction: u32, state: State) -> (State, Opt
     }
 }
 
+// where the king and the rook of a castle end up
+fn castling_targets(color: Color, king: Square, rook: Square) -> (Square, Square) {
+    let side = CastlingSide::from_king_side(king.file() < rook.file());
+    (side.king_to(color), side.rook_to(color))
+}
+
+// the castle once its second piece is set down on `landed`, `down` holding the
+// first. it's checked again rather than trusted from when both were lifted,
+// and if it can't be played the last piece counts as misplaced, so lifting it
+// off and putting both back home gets the board back
+fn complete_castle(
+    position: &Chess,
+    king: Square,
+    rook: Square,
+    landed: Square,
+    down: Square,
+) -> (State, Option<Move>) {
+    let mv = Move::Castle { king, rook };
+    if position.is_legal(&mv) {
+        (State::Idle, Some(mv))
+    } else {
+        warn!("castling {king} with {rook} isn't legal here, not playing it");
+        (State::CastlingMisplaced(king, rook, landed, Some(down)), None)
+    }
+}
+
+// the empty squares on a castling king and rook's rank, where one of them
+// set down in the wrong place most likely ends up
+fn castling_gap(position: &Chess, king: Square, rook: Square) -> Bitboard {
+    let (king_to, rook_to) = castling_targets(position.turn(), king, rook);
+    let lifted = Bitboard::from_square(king).with(Bitboard::from_square(rook));
+    let empty = !position.board().occupied() | lifted;
+    Bitboard::from_rank(king.rank())
+        .intersect(empty)
+        .without(Bitboard::from_square(king_to))
+        .without(Bitboard::from_square(rook_to))
+}
+
+// how much update_state forgives input that can't be a real piece moving.
+// lenient takes an event on an empty square in Idle for noise and ignores it,
+// strict treats it as the board being lost, as any impossible sequence is
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+enum Strictness {
+    #[default]
+    Lenient,
+    Strict,
+}
+
+impl std::str::FromStr for Strictness {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        match text {
+            "lenient" => Ok(Self::Lenient),
+            "strict" => Ok(Self::Strict),
+            _ => Err(format!("{text} is not a strictness, expected lenient or strict")),
+        }
+    }
+}
+
+// knobs that change how update_state interprets the reed input
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct StateConfig {
+    default_promotion: Role,
+    // a lifted piece with a legal move has to be moved, see State::TouchMove
+    touch_move: bool,
+    // the set has no spare pieces to promote to, so the pawn has to be swapped
+    // by hand before a promotion is played, see State::PromotionSwap
+    swap_promotion: bool,
+    strictness: Strictness,
+    // the side the reeds are read for, None reads them for whoever is to move
+    human: Option<Color>,
+}
+
+impl Default for StateConfig {
+    fn default() -> Self {
+        Self {
+            default_promotion: Role::Queen,
+            touch_move: false,
+            swap_promotion: false,
+            strictness: Strictness::Lenient,
+            human: None,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 enum State {
     Idle,
@@ -479,57 +2159,304 @@ enum State {
     FriendlyAndEnemyPU(Square, Square),
     Castling(Square, Square),
     CastlingPutRookDown(Square, Square, Square),
+    // the rook set down on its square first, waiting for the king on the last square
+    CastlingPutKingDown(Square, Square, Square),
+    // a castling piece set down on the third square, which it has to be lifted
+    // from again. the last square is the target already filled, if any
+    CastlingMisplaced(Square, Square, Square, Option<Square>),
+    PromotionSelect(Square, Square, Option<Role>),
+    // with swap-promotion on, the role is picked but the pawn still stands on
+    // the promotion square. lifting it leads to PromotionPlace
+    PromotionSwap(Square, Square, Option<Role>, Role),
+    // the pawn is off, setting the promoted piece down on the square plays the move
+    PromotionPlace(Square, Square, Option<Role>, Role),
+    ConfirmResign(Square),
+    Resigned,
     InvalidPiecePU(Option<Square>, Square),
+    // the king and a rook are both up but can't castle (rights lost, or the
+    // way is blocked or attacked), so both have to go back
+    IllegalCastle(Square, Square),
     InvalidMove(Square, Square),
+    // with touch-move on, a piece that was lifted and put back but still has to
+    // move. lifting it again carries on as FriendlyPU, and capturing with it
+    // means lifting it before the piece it takes
+    TouchMove(Square),
+    // something other than the touched piece was lifted, it has to go back
+    TouchMoveViolation(Square, Square),
+    // a piece was set down on a square the model still thinks is occupied
+    UnexpectedOccupancy(Square),
+    // in snapshot mode, a lifted piece whose square stayed empty past the
+    // pickup timeout, e.g. one knocked off the table, or a square the gantry
+    // never got its piece to
+    MissingPiece(Square),
+    // the draw gesture was made on the opponent's king, press it once more to offer
+    ConfirmDraw(Square),
+    // waiting on the opponent's answer
+    DrawOffered,
+    DrawAgreed,
+    // from ConfirmDraw the player lifted their own king, putting it back claims
+    // a draw under the fifty-move rule
+    ClaimDraw(Square),
+    DrawClaimed,
+    // the claim came before the fifty moves were up
+    ClaimRejected,
+    // the pause button froze the game and its clocks, see PAUSE_LINE
+    Paused,
     Error,
 }
 
-fn print_state_name(state: State) {
-    match state {
-        State::Idle => println!("Idle"),
-        State::FriendlyPU(_) => println!("FriendlyPU"),
-        State::EnemyPU(_) => println!("EnemyPU"),
-        State::FriendlyAndEnemyPU(_, _) => println!("FriendlyAndEnemyPU"),
-        State::Castling(_, _) => println!("Castling"),
-        State::CastlingPutRookDown(_, _, _) => println!("CastlingPutRookDown"),
-        State::InvalidPiecePU(_, _) => println!("InvalidPiecePU"),
-        State::InvalidMove(_, _) => println!("InvalidMove"),
-        State::Error => println!("Error"),
-    }
+// recognises the same square being pressed twice within DOUBLE_PRESS_WINDOW
+#[derive(Debug, Clone, Copy, Default)]
+struct DoublePress {
+    last: Option<(Square, Instant)>,
 }
 
-fn print_board_from_fen(fen: &str) {
-    use std::fmt::Write;
-    let mut output: String = String::new();
-    let mut counter = 0;
-    output.push(' ');
-    for c in fen.chars() {
-        if counter == 8 {
-            counter = 0;
-            output.push('\n');
+impl DoublePress {
+    fn press(&mut self, square: Square, now: Instant) -> bool {
+        match self.last {
+            Some((last_square, last_time))
+                if last_square == square && now.duration_since(last_time) <= DOUBLE_PRESS_WINDOW =>
+            {
+                self.last = None;
+                true
+            }
+            _ => {
+                self.last = Some((square, now));
+                false
+            }
+        }
+    }
+}
+
+// the last `capacity` instructions and the transitions they made, written out
+// at WARN the moment one of them lands in Error so a report of the board
+// giving up comes with the sequence that got it there
+#[derive(Debug, Clone, Default)]
+struct Trail {
+    entries: VecDeque<(u32, State, State)>,
+    capacity: usize,
+}
+
+impl Trail {
+    fn new(capacity: usize) -> Self {
+        Self {
+            entries: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    // returns the dump when this transition entered Error
+    fn record(&mut self, instruction: u32, from: State, to: State) -> Option<String> {
+        if self.capacity == 0 {
+            return None;
         }
-        match c {
-            c @ ('r' | 'R' | 'n' | 'N' | 'b' | 'B' | 'q' | 'Q' | 'k' | 'K' | 'p' | 'P') => {
-                write!(output, "{c} ").unwrap();
-                counter += 1;
+        if self.entries.len() == self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back((instruction, from, to));
+        if to != State::Error || from == State::Error {
+            return None;
+        }
+        let dump = self.to_string();
+        warn!("entered Error, the last {} instructions were: {dump}", self.entries.len());
+        Some(dump)
+    }
+}
+
+impl std::fmt::Display for Trail {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, (instruction, from, to)) in self.entries.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
             }
-            n @ '1'..='8' => {
-                let n = n.to_digit(10).unwrap();
-                for _ in 0..n {
-                    output.push_str(". ");
-                }
-                counter += n;
+            match square_from_instruction(*instruction) {
+                Ok(square) => write!(f, "{square} {from:?} -> {to:?}")?,
+                Err(_) => write!(f, "{instruction} {from:?} -> {to:?}")?,
             }
-            _ => {
-                output.push(' ');
-                counter += 0;
+        }
+        Ok(())
+    }
+}
+
+// our pawns that could take the pawn on `victim` en passant
+fn en_passant_capturers(position: &Chess, victim: Square) -> Bitboard {
+    position
+        .our(Role::Pawn)
+        .into_iter()
+        .filter(|&pawn| en_passant_capture(position, pawn, victim).is_some())
+        .collect()
+}
+
+// the en passant capture of the pawn on `victim` by the pawn on `capturer`, if
+// there is one. the target comes from the position's own en passant square, so
+// one loaded from a FEN is honoured for its single ply like any other
+fn en_passant_capture(position: &Chess, capturer: Square, victim: Square) -> Option<Move> {
+    let to = position.ep_square(EnPassantMode::Legal)?;
+    let mv = Move::EnPassant { from: capturer, to };
+    let behind = Square::from_coords(to.file(), capturer.rank());
+    (behind == victim && position.is_legal(&mv)).then_some(mv)
+}
+
+// whether the piece on `from` can take the one on `to`. a pawn taking onto
+// the last rank is checked as a queening, whatever it goes on to become
+fn legal_capture(position: &Chess, from: Square, to: Square) -> bool {
+    let Some(piece) = position.board().piece_at(from) else {
+        return false;
+    };
+    let last_rank = to.rank() == Rank::First || to.rank() == Rank::Eighth;
+    let mv = Move::Normal {
+        role: piece.role,
+        from,
+        capture: position.board().role_at(to),
+        to,
+        promotion: (piece.role == Role::Pawn && last_rank).then_some(Role::Queen),
+    };
+    position.is_legal(&mv)
+}
+
+// a pawn set down on the last rank, pushed there or capturing: the player
+// picks what it becomes next, if the move is legal at all. legality doesn't
+// depend on the piece chosen, so a queen stands in for it
+fn promotion_landed(position: &Chess, from: Square, to: Square) -> (State, Option<Move>) {
+    let capture = position.board().role_at(to);
+    if position.is_legal(&promotion(from, to, capture, Role::Queen)) {
+        (State::PromotionSelect(from, to, capture), None)
+    } else {
+        (State::InvalidMove(from, to), None)
+    }
+}
+
+// the promotion the player chose, checked again before it's played
+fn commit_promotion(
+    position: &Chess,
+    from: Square,
+    to: Square,
+    capture: Option<Role>,
+    role: Role,
+) -> (State, Option<Move>) {
+    let mv = promotion(from, to, capture, role);
+    if position.is_legal(&mv) {
+        info!("PROMOTED to {role:?}");
+        (State::Idle, Some(mv))
+    } else {
+        (State::InvalidMove(from, to), None)
+    }
+}
+
+const fn promotion(from: Square, to: Square, capture: Option<Role>, role: Role) -> Move {
+    Move::Normal {
+        role: Role::Pawn,
+        from,
+        capture,
+        to,
+        promotion: Some(role),
+    }
+}
+
+// the promotion square itself and the next three squares back down the file
+fn promotion_choice_squares(to: Square) -> [Square; 4] {
+    let direction = if to.rank() == Rank::Eighth { -8 } else { 8 };
+    let mut squares = [to; 4];
+    for (i, square) in squares.iter_mut().enumerate().skip(1) {
+        let steps = i32::try_from(i).unwrap();
+        *square = to.offset(direction * steps).unwrap();
+    }
+    squares
+}
+
+// the promotion square itself picks the default role, the squares behind it
+// pick the remaining ones in queen, rook, bishop, knight order
+fn promotion_choices(to: Square, default_promotion: Role) -> [(Square, Role); 4] {
+    let mut roles = [Role::Queen, Role::Rook, Role::Bishop, Role::Knight];
+    if let Some(i) = roles.iter().position(|&role| role == default_promotion) {
+        roles[..=i].rotate_right(1);
+    }
+    let squares = promotion_choice_squares(to);
+    [
+        (squares[0], roles[0]),
+        (squares[1], roles[1]),
+        (squares[2], roles[2]),
+        (squares[3], roles[3]),
+    ]
+}
+
+// squares and roles don't implement serde traits, so states go out in their debug form
+impl Serialize for State {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(&format_args!("{self:?}"))
+    }
+}
+
+fn print_state_name(out: &mut dyn Write, state: State) -> std::io::Result<()> {
+    let name = match state {
+        State::Idle => "Idle",
+        State::FriendlyPU(_) => "FriendlyPU",
+        State::EnemyPU(_) => "EnemyPU",
+        State::FriendlyAndEnemyPU(_, _) => "FriendlyAndEnemyPU",
+        State::Castling(_, _) => "Castling",
+        State::CastlingPutRookDown(_, _, _) => "CastlingPutRookDown",
+        State::CastlingPutKingDown(_, _, _) => "CastlingPutKingDown",
+        State::CastlingMisplaced(_, _, _, _) => "CastlingMisplaced",
+        State::PromotionSelect(_, _, _) => "PromotionSelect",
+        State::PromotionSwap(_, _, _, _) => "PromotionSwap",
+        State::PromotionPlace(_, _, _, _) => "PromotionPlace",
+        State::ConfirmResign(_) => "ConfirmResign",
+        State::Resigned => "Resigned",
+        State::InvalidPiecePU(_, _) => "InvalidPiecePU",
+        State::IllegalCastle(_, _) => "IllegalCastle",
+        State::InvalidMove(_, _) => "InvalidMove",
+        State::TouchMove(_) => "TouchMove",
+        State::TouchMoveViolation(_, _) => "TouchMoveViolation",
+        State::UnexpectedOccupancy(_) => "UnexpectedOccupancy",
+        State::MissingPiece(_) => "MissingPiece",
+        State::ConfirmDraw(_) => "ConfirmDraw",
+        State::DrawOffered => "DrawOffered",
+        State::DrawAgreed => "DrawAgreed",
+        State::ClaimDraw(_) => "ClaimDraw",
+        State::DrawClaimed => "DrawClaimed",
+        State::ClaimRejected => "ClaimRejected",
+        State::Paused => "Paused",
+        State::Error => "Error",
+    };
+    writeln!(out, "{name}")
+}
+
+fn print_board_from_fen(out: &mut dyn Write, fen: &str) -> std::io::Result<()> {
+    writeln!(out, "{}", board_from_fen(fen))
+}
+
+fn board_from_fen(fen: &str) -> String {
+    // only the piece placement field is drawn, side to move and clocks are skipped
+    let placement = fen.split_whitespace().next().unwrap_or_default();
+    let mut rows = Vec::new();
+    for rank in placement.split('/') {
+        let mut row = String::new();
+        for c in rank.chars() {
+            match c {
+                c @ ('r' | 'R' | 'n' | 'N' | 'b' | 'B' | 'q' | 'Q' | 'k' | 'K' | 'p' | 'P') => {
+                    row.push(c);
+                    row.push(' ');
+                }
+                n @ '1'..='8' => {
+                    for _ in 0..n.to_digit(10).unwrap() {
+                        row.push_str(". ");
+                    }
+                }
+                _ => {}
             }
         }
+        rows.push(row.trim_end().to_string());
     }
-    println!("{output}");
+    rows.join("\n")
+}
+
+fn print_ply(out: &mut dyn Write, position: &Chess, move_san: &str) -> std::io::Result<()> {
+    let to_move = if position.turn().is_white() { "white" } else { "black" };
+    print_board_from_fen(out, &position.board().to_string())?;
+    writeln!(out, "{move_san} played, {to_move} to move")
 }
 
-fn print_bitboard(bitboard: Bitboard) {
+fn print_bitboard(out: &mut dyn Write, bitboard: Bitboard) -> std::io::Result<()> {
     let y = format!("{bitboard:064b}");
 
     let mut output: String = String::new();
@@ -545,232 +2472,695 @@ fn print_bitboard(bitboard: Bitboard) {
         line.push(' ');
     }
     output.push_str(line.chars().rev().collect::<String>().as_str());
-    println!("{}", output.as_str());
+    writeln!(out, "{}", output.as_str())
 }
 
-#[allow(clippy::too_many_lines, clippy::needless_pass_by_value)]
+#[allow(clippy::needless_pass_by_value)]
 fn move_to_steps(
     mv: Move,
     current_color: Color,
     captured_whites: f64,
     captured_blacks: f64,
+    occupied: Bitboard,
+    geom: &BoardGeometry,
+    strategy: CaptureStrategy,
 ) -> Vec<Step> {
-    #![allow(clippy::similar_names)]
-    let mut steps = Vec::new();
-
-    let from_x: f64 = file_to_float(mv.from().unwrap().file());
-    let from_y: f64 = rank_to_float(mv.from().unwrap().rank());
-    let to_x: f64 = file_to_float(mv.to().file());
-    let to_y: f64 = rank_to_float(mv.to().rank());
-
-    if mv.is_castle() {
-        //from = king, to = rook
-        let direction = if current_color == Color::White {
-            -0.5
-        } else {
-            0.5
-        };
-        let (offset, queenside_king) = if (to_x - 8.0).abs() < f64::EPSILON {
-            (-1.0, 0.0)
-        } else {
-            (1.0, 1.0)
-        }; // king side castling; else queen side castling
-        steps.push(Step {
-            x: from_x,
-            y: from_y,
-            magnet: false,
-        });
+    move_to_journeys(mv, current_color, captured_whites, captured_blacks, occupied, geom, strategy)
+        .into_iter()
+        .flat_map(|journey| journey.steps)
+        .collect()
+}
 
-        steps.push(Step {
-            x: to_x + offset + queenside_king,
-            y: to_y,
-            magnet: true,
-        });
+// the steps for a move as the gantry sees them, `oriented` already, with
+// straight runs merged, the magnet-off trip home on the end when the build
+// wants the carriage parked, eased in and out of its stops and turns by the
+// feed profile if there is one, and run at `speed`
+fn finish_steps(oriented: Vec<Step>, config: &GameConfig, speed: Option<Speed>) -> Vec<Step> {
+    let mut merged = merge_collinear(oriented);
+    if config.home_after_move && !merged.is_empty() {
+        merged.push(config.geometry.home_step());
+    }
+    let shaped = match config.feed_profile {
+        Some(profile) => profile.shape(&merged),
+        None => merged,
+    };
+    match speed {
+        Some(speed) => speed.scale(shaped),
+        None => shaped,
+    }
+}
 
-        steps.push(Step {
-            x: to_x,
-            y: to_y,
-            magnet: false,
+// the same path in fewer steps for the controller: a magnet-on step carrying
+// straight on the way the one before it went is folded into the next, and a
+// step that goes nowhere and leaves the magnet as it was is dropped. the
+// carriage passes the same points with the magnet on and off in the same places
+fn merge_collinear(steps: Vec<Step>) -> Vec<Step> {
+    let mut merged: Vec<Step> = Vec::with_capacity(steps.len());
+    for step in steps {
+        let still = merged.last().is_some_and(|last| {
+            last.magnet == step.magnet
+                && (last.x - step.x).abs() < f64::EPSILON
+                && (last.y - step.y).abs() < f64::EPSILON
         });
+        if still {
+            continue;
+        }
+        match merged[..] {
+            [.., before, at] if straight_on(before, at, step) => {
+                *merged.last_mut().unwrap() = step;
+            }
+            _ => merged.push(step),
+        }
+    }
+    merged
+}
 
-        steps.push(Step {
-            x: to_x,
-            y: to_y + direction,
-            magnet: true,
-        });
+// whether `next` carries the piece on from `at` in the direction it came from
+// `before`, at the same feedrate, so `at` needn't be stopped at
+fn straight_on(before: Step, at: Step, next: Step) -> bool {
+    if at.magnet != Magnet::Engaged || next.magnet != Magnet::Engaged {
+        return false;
+    }
+    if at.feedrate != next.feedrate {
+        return false;
+    }
+    let (in_x, in_y) = (at.x - before.x, at.y - before.y);
+    let (out_x, out_y) = (next.x - at.x, next.y - at.y);
+    let cross = in_x.mul_add(out_y, -(in_y * out_x));
+    let dot = in_x.mul_add(out_x, in_y * out_y);
+    cross.abs() < 1e-9 && dot > 0.0
+}
 
-        steps.push(Step {
-            x: from_x - offset,
-            y: to_y + direction,
-            magnet: true,
-        });
+// one piece being picked up, carried and dropped; a move is one or more of these in order
+#[derive(Debug, Clone, Default, Serialize)]
+struct PieceJourney {
+    steps: Vec<Step>,
+}
 
-        steps.push(Step {
-            x: from_x - offset,
-            y: from_y,
-            magnet: true,
-        });
+impl PieceJourney {
+    fn pickup(&self) -> Option<Step> {
+        self.steps.first().copied()
+    }
 
-        return steps;
+    fn drop_off(&self) -> Option<Step> {
+        self.steps.last().copied()
     }
+}
 
-    if mv.is_en_passant() {
-        let offset = if current_color == Color::White {
-            -1.0
-        } else {
-            1.0
-        };
-        let mut capturemvs: Vec<Step> = capture_piece(
-            to_x,
-            to_y + offset,
+#[allow(clippy::needless_pass_by_value)]
+// `occupied` is the board before the move, which captured pieces are steered around
+fn move_to_journeys(
+    mv: Move,
+    current_color: Color,
+    captured_whites: f64,
+    captured_blacks: f64,
+    occupied: Bitboard,
+    geom: &BoardGeometry,
+    strategy: CaptureStrategy,
+) -> Vec<PieceJourney> {
+    if let Move::Castle { king, rook } = mv {
+        return castle_journeys(king, rook, current_color, occupied, geom);
+    }
+    let from = mv.from().unwrap();
+    let Some(captured) = captured_square(&mv) else {
+        return mover_journeys(from, mv.to(), occupied, geom);
+    };
+
+    let capture = |parked| PieceJourney {
+        steps: capture_piece(
+            file_to_float(captured.file()),
+            rank_to_float(captured.rank()),
             current_color,
             captured_whites,
             captured_blacks,
-        );
-        steps.append(&mut capturemvs);
+            occupied,
+            parked,
+        ),
+    };
+    // by now the captured piece is in the graveyard and out of the mover's way
+    let cleared = occupied.without(Bitboard::from_square(captured));
+    match strategy {
+        CaptureStrategy::ClearFirst => {
+            let mut journeys = vec![capture(None)];
+            journeys.extend(mover_journeys(from, mv.to(), cleared, geom));
+            journeys
+        }
+        CaptureStrategy::StageFirst => {
+            let (staged, last_leg) = stage_short_of(path_between(from, mv.to(), occupied, geom));
+            let parked = staged.drop_off().map(|step| (step.x, step.y));
+            vec![staged, capture(parked), last_leg]
+        }
     }
+}
 
-    if mv.is_capture() && !mv.is_en_passant() {
-        let mut capturemvs: Vec<Step> =
-            capture_piece(to_x, to_y, current_color, captured_whites, captured_blacks);
-        steps.append(&mut capturemvs);
+// the mover's way from `from` to `to`. where a single piece is all that keeps
+// every route from being clear and the build has a scratch spot, that piece is
+// parked there first and brought back after, so the scratch spot is empty
+// again by the end of every move
+fn mover_journeys(
+    from: Square,
+    to: Square,
+    occupied: Bitboard,
+    geom: &BoardGeometry,
+) -> Vec<PieceJourney> {
+    let journey = |steps| PieceJourney { steps };
+    let direct = path_between(from, to, occupied, geom);
+    let Some(scratch) = geom.scratch else {
+        return vec![journey(direct)];
+    };
+    let ends = Bitboard::from_square(from).with(Bitboard::from_square(to));
+    let clear = |occupied: Bitboard| {
+        let path = path_between(from, to, occupied, geom);
+        (route_crowding(&path, occupied.without(ends)) == 0).then_some(path)
+    };
+    if route_crowding(&direct, occupied.without(ends)) == 0 {
+        return vec![journey(direct)];
     }
-
-    let engage: Step = Step {
-        x: from_x,
-        y: from_y,
-        magnet: false,
+    let parked = occupied.without(ends).into_iter().find_map(|square| {
+        let without = occupied.without(Bitboard::from_square(square));
+        clear(without).map(|path| (square, without, path))
+    });
+    let Some((square, without, path)) = parked else {
+        return vec![journey(direct)];
     };
+    let centre = (file_to_float(square.file()), rank_to_float(square.rank()));
+    let lane_x = scratch.0.clamp(0.5, 8.5);
+    let after = without.without(Bitboard::from_square(from)).with(Bitboard::from_square(to));
+    vec![
+        journey(lane_route(centre, lane_x, scratch, occupied, None)),
+        journey(path),
+        journey(carried_back(lane_route(centre, lane_x, scratch, after, None))),
+    ]
+}
 
-    steps.push(engage);
-
-    if mv.role() == Role::Knight {
-        let step1: Step = Step {
-            x: (from_x + to_x) / 2.0,
-            y: from_y,
-            magnet: true,
+// splits a mover's path into the way up to the lane corner or edge it last
+// passes before its destination, where it waits, and the leg on from there
+fn stage_short_of(mut steps: Vec<Step>) -> (PieceJourney, PieceJourney) {
+    let to = steps.pop().unwrap();
+    let before = *steps.last().unwrap();
+    // a straight route has no corner, so stop half a square out along it
+    let staging = if steps.len() == 1 {
+        let short = |from: f64, to: f64| {
+            if (to - from).abs() < f64::EPSILON {
+                to
+            } else {
+                (to - from).signum().mul_add(-0.5, to)
+            }
         };
-        let step2: Step = Step {
-            x: (from_x + to_x) / 2.0,
-            y: to_y,
-            magnet: true,
+        let staging = Step {
+            x: short(before.x, to.x),
+            y: short(before.y, to.y),
+            magnet: Magnet::Engaged,
+            feedrate: None,
         };
-        let step3: Step = Step {
-            x: to_x,
-            y: to_y,
-            magnet: true,
+        steps.push(staging);
+        staging
+    } else {
+        before
+    };
+    let last_leg = vec![
+        Step {
+            magnet: Magnet::Released,
+            ..staging
+        },
+        to,
+    ];
+    (PieceJourney { steps }, PieceJourney { steps: last_leg })
+}
+
+// shakmaty encodes castling as king takes own rook, so the squares both
+// pieces end up on come from the side rather than from `to`. that holds in
+// chess960 too, where either piece may already stand on its new square, or on
+// the other one's: the king goes first unless it would land on the rook, and
+// when the two swap the rook waits in the lane behind the back rank
+fn castle_journeys(
+    king: Square,
+    rook: Square,
+    color: Color,
+    occupied: Bitboard,
+    geom: &BoardGeometry,
+) -> Vec<PieceJourney> {
+    let side = CastlingSide::from_king_side(king.file() < rook.file());
+    let king_to = side.king_to(color);
+    let rook_to = side.rook_to(color);
+    let moved = |board: Bitboard, from: Square, to: Square| {
+        board.without(Bitboard::from_square(from)).with(Bitboard::from_square(to))
+    };
+    let journey = |steps| PieceJourney { steps };
+
+    if king_to == rook && rook_to == king {
+        let lane_y = if color == Color::White { 0.5 } else { 8.5 };
+        let (rook_x, rook_y) = (file_to_float(rook.file()), rank_to_float(rook.rank()));
+        let rook_to_x = file_to_float(rook_to.file());
+        let step = |x, y, magnet: bool| Step {
+            x,
+            y,
+            magnet: magnet.into(),
+            feedrate: None,
         };
+        // the two only swap side by side, so the king slides straight along
+        // the rank rather than being routed, which could take it through the
+        // lane the rook is parked in
+        return vec![
+            journey(vec![step(rook_x, rook_y, false), step(rook_x, lane_y, true)]),
+            journey(vec![step(rook_to_x, rook_y, false), step(rook_x, rook_y, true)]),
+            journey(vec![
+                step(rook_x, lane_y, false),
+                step(rook_to_x, lane_y, true),
+                step(rook_to_x, rank_to_float(rook_to.rank()), true),
+            ]),
+        ];
+    }
+
+    let mut journeys = Vec::new();
+    if king_to == rook {
+        // the rook clears the king's square first
+        journeys.push(journey(path_between(rook, rook_to, occupied, geom)));
+        let after_rook = moved(occupied, rook, rook_to);
+        if king != king_to {
+            journeys.push(journey(path_between(king, king_to, after_rook, geom)));
+        }
+    } else {
+        // the king lands first, so the rook has to find its way round it
+        if king != king_to {
+            journeys.push(journey(path_between(king, king_to, occupied, geom)));
+        }
+        let after_king = moved(occupied, king, king_to);
+        if rook != rook_to {
+            journeys.push(journey(path_between(rook, rook_to, after_king, geom)));
+        }
+    }
+    journeys
+}
 
-        steps.push(step1);
-        steps.push(step2);
-        steps.push(step3);
+// how many pieces of each colour have been taken off the board, which decides
+// the next free graveyard slot in capture_piece
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct Graveyard {
+    captured_whites: u8,
+    captured_blacks: u8,
+}
+
+impl Graveyard {
+    const fn record(&mut self, mv: &Move, mover: Color) {
+        // covers en passant and capture-promotions too
+        if !mv.is_capture() {
+            return;
+        }
+        match mover {
+            Color::White => self.captured_blacks += 1,
+            Color::Black => self.captured_whites += 1,
+        }
     }
-    //move to position
-    else {
-        let step: Step = Step {
-            x: to_x,
-            y: to_y,
-            magnet: true,
+
+    // frees the most recently filled slot for `color`, which is the one a takeback restores from
+    fn release(&mut self, color: Color) -> Option<u8> {
+        let count = match color {
+            Color::White => &mut self.captured_whites,
+            Color::Black => &mut self.captured_blacks,
         };
-        steps.push(step);
+        *count = count.checked_sub(1)?;
+        Some(*count)
     }
+}
 
+// the square the captured piece actually stood on, which for en passant is
+// behind the destination rather than on it
+fn captured_square(mv: &Move) -> Option<Square> {
+    if mv.is_en_passant() {
+        let to = mv.to();
+        Some(Square::from_coords(to.file(), mv.from()?.rank()))
+    } else if mv.is_capture() {
+        Some(mv.to())
+    } else {
+        None
+    }
+}
+
+// when a capturing move is taken back, brings the captured piece back out of
+// the graveyard and frees its slot; `occupied` is the board as it stands
+fn undo_capture_steps(
+    mv: &Move,
+    mover: Color,
+    graveyard: &mut Graveyard,
+    occupied: Bitboard,
+) -> Vec<Step> {
+    let Some(square) = captured_square(mv) else {
+        return Vec::new();
+    };
+    let captured_color = mover.other();
+    graveyard
+        .release(captured_color)
+        .map_or_else(Vec::new, |slot| {
+            uncapture_steps(square, captured_color, slot, occupied)
+        })
+}
+
+// capture_piece run backwards: pick the piece up from its graveyard slot and
+// carry it along the same lanes back onto `square`
+fn uncapture_steps(
+    square: Square,
+    color: Color,
+    graveyard_slot: u8,
+    occupied: Bitboard,
+) -> Vec<Step> {
+    let slot = f64::from(graveyard_slot);
+    carried_back(capture_piece(
+        file_to_float(square.file()),
+        rank_to_float(square.rank()),
+        color.other(),
+        slot,
+        slot,
+        occupied,
+        None,
+    ))
+}
+
+// a piece's steps reversed, picking it up where it was dropped and carrying
+// it back to where it started
+fn carried_back(mut steps: Vec<Step>) -> Vec<Step> {
+    steps.reverse();
+    for (i, step) in steps.iter_mut().enumerate() {
+        step.magnet = Magnet::from(i != 0);
+    }
     steps
 }
 
+// carries a captured piece from (from_x, from_y) into the next free graveyard
+// slot along half-square lanes, which run between square centres: out to a
+// corner of its square, along a file lane to the top or bottom edge, round the
+// edge to the lane beside the graveyard and down that to the slot. of the four
+// corner and edge combinations, the one squeezing past the fewest `occupied`
+// squares wins, then the shortest. a route through `parked`, where a staged
+// attacker waits on a lane, is only taken when every route runs through it
 fn capture_piece(
     from_x: f64,
     from_y: f64,
     current_color: Color,
     captured_whites: f64,
     captured_blacks: f64,
+    occupied: Bitboard,
+    parked: Option<(f64, f64)>,
 ) -> Vec<Step> {
-    let mut steps: Vec<Step> = Vec::new();
-    steps.push(Step {
-        x: from_x,
-        y: from_y,
-        magnet: false,
+    // black pieces go right of the h file filling up from rank 1, white ones
+    // left of the a file filling down from rank 8
+    let (graveyard_lane_x, graveyard_x, slot_y) = if current_color == Color::White {
+        (8.5, 9.0, 0.5 + captured_blacks / 2.0)
+    } else {
+        (0.5, 0.0, 8.5 - captured_whites / 2.0)
+    };
+    let to = (graveyard_x, slot_y);
+    lane_route((from_x, from_y), graveyard_lane_x, to, occupied, parked)
+}
+
+// carries a piece from the centre of its square to `to` off the board: out
+// to a corner, along a file lane to the top or bottom edge, round the edge to
+// the file lane at `lane_x` and along that to level with `to`. the route
+// choice is capture_piece's
+fn lane_route(
+    (from_x, from_y): (f64, f64),
+    lane_x: f64,
+    to: (f64, f64),
+    occupied: Bitboard,
+    parked: Option<(f64, f64)>,
+) -> Vec<Step> {
+    let routes = [0.5, 8.5].into_iter().flat_map(|edge_y| {
+        [-0.5, 0.5].into_iter().map(move |corner_dx: f64| {
+            let corner_dy: f64 = if edge_y < from_y { -0.5 } else { 0.5 };
+            [
+                (from_x, from_y),
+                (from_x + corner_dx, from_y + corner_dy),
+                (from_x + corner_dx, edge_y),
+                (lane_x, edge_y),
+                (lane_x, to.1),
+                to,
+            ]
+        })
     });
-    let direction: f64;
+    let route = routes
+        .map(|route| {
+            let crowding: usize = route
+                .windows(2)
+                .map(|leg| lane_crowding(occupied, leg[0], leg[1]))
+                .sum();
+            let length: f64 = route
+                .windows(2)
+                .map(|leg| (leg[1].0 - leg[0].0).abs() + (leg[1].1 - leg[0].1).abs())
+                .sum();
+            let blocked = parked.is_some_and(|point| {
+                route.windows(2).any(|leg| on_leg(point, leg[0], leg[1]))
+            });
+            (blocked, crowding, length, route)
+        })
+        .min_by(|a, b| a.0.cmp(&b.0).then(a.1.cmp(&b.1)).then(a.2.total_cmp(&b.2)))
+        .map(|(_, _, _, route)| route)
+        .unwrap();
 
-    if current_color == Color::White {
-        //BLACK IS CAPTURED
-        if captured_blacks / 2.0 < from_y {
-            direction = -0.5;
-        } else {
-            direction = 0.5;
+    let mut steps: Vec<Step> = Vec::new();
+    for (x, y) in route {
+        // legs of no length, e.g. when the piece already sits on the edge rank
+        let repeated = steps.last().is_some_and(|last| {
+            (last.x - x).abs() < f64::EPSILON && (last.y - y).abs() < f64::EPSILON
+        });
+        if !repeated {
+            steps.push(Step {
+                x,
+                y,
+                magnet: (!steps.is_empty()).into(),
+                feedrate: None,
+            });
         }
+    }
+    steps
+}
 
-        steps.push(Step {
+// carries one piece from `from` to `to` without running into anything in
+// `occupancy`: straight there when the squares in between are empty, otherwise
+// out to a corner of its square, along a half-square lane one way, along
+// another the other way and in through a corner of `to`. of the lane routes
+// that stay within `geom`, the one squeezing past the fewest pieces wins, then
+// the shortest. works the same either way round, so undoing a move is just the
+// path back
+fn path_between(from: Square, to: Square, occupancy: Bitboard, geom: &BoardGeometry) -> Vec<Step> {
+    let occupancy = occupancy
+        .without(Bitboard::from_square(from))
+        .without(Bitboard::from_square(to));
+    let centre = |square: Square| (file_to_float(square.file()), rank_to_float(square.rank()));
+    let (from_x, from_y) = centre(from);
+    let (to_x, to_y) = centre(to);
+    let towards = |a: f64, b: f64| if b < a { -0.5 } else { 0.5 };
+    if from == to {
+        return vec![Step {
             x: from_x,
-            y: (from_y + direction),
-            magnet: true,
-        });
+            y: from_y,
+            magnet: Magnet::Released,
+            feedrate: None,
+        }];
+    }
 
-        steps.push(Step {
-            x: (8.5),
-            y: (from_y + direction),
-            magnet: true,
-        });
+    let mut routes: Vec<(usize, Vec<(f64, f64)>)> = Vec::new();
+    let in_line = attacks::queen_attacks(from, Bitboard(0)).contains(to);
+    if in_line && attacks::between(from, to).intersect(occupancy).is_empty() {
+        routes.push((0, vec![(from_x, from_y), (to_x, to_y)]));
+    }
+    for from_dx in [-0.5, 0.5] {
+        for from_dy in [-0.5, 0.5] {
+            let (lane_x, lane_y) = (from_x + from_dx, from_y + from_dy);
+            // along a rank lane then a file lane, or a file lane then a rank lane
+            let across_first = (to_x + towards(to_x, lane_x), lane_y);
+            let up_first = (lane_x, to_y + towards(to_y, lane_y));
+            for turn in [across_first, up_first] {
+                let into = (
+                    to_x + towards(to_x, turn.0),
+                    to_y + towards(to_y, turn.1),
+                );
+                let route = vec![(from_x, from_y), (lane_x, lane_y), turn, into, (to_x, to_y)];
+                let crowding = route
+                    .windows(2)
+                    .map(|leg| lane_crowding(occupancy, leg[0], leg[1]))
+                    .sum();
+                routes.push((crowding, route));
+            }
+        }
+    }
 
-        steps.push(Step {
-            x: (8.5),
-            y: (0.5 + captured_blacks / 2.0),
-            magnet: true,
-        });
+    let to_steps = |route: &[(f64, f64)]| {
+        let mut steps: Vec<Step> = Vec::new();
+        for &(x, y) in route {
+            // legs of no length, e.g. when the lane already runs past `to`
+            let repeated = steps.last().is_some_and(|last| {
+                (last.x - x).abs() < f64::EPSILON && (last.y - y).abs() < f64::EPSILON
+            });
+            if !repeated {
+                steps.push(Step {
+                    x,
+                    y,
+                    magnet: (!steps.is_empty()).into(),
+                    feedrate: None,
+                });
+            }
+        }
+        steps
+    };
+    let length = |steps: &[Step]| -> f64 {
+        steps
+            .windows(2)
+            .map(|leg| (leg[1].x - leg[0].x).hypot(leg[1].y - leg[0].y))
+            .sum()
+    };
+    routes
+        .iter()
+        .map(|(crowding, route)| (*crowding, to_steps(route)))
+        .filter(|(_, steps)| geom.check(steps).is_ok())
+        .min_by(|a, b| a.0.cmp(&b.0).then(length(&a.1).total_cmp(&length(&b.1))))
+        .map_or_else(|| to_steps(&[(from_x, from_y), (to_x, to_y)]), |(_, steps)| steps)
+}
 
-        steps.push(Step {
-            x: (9.0),
-            y: (0.5 + captured_blacks / 2.0),
-            magnet: true,
-        });
-    } else {
-        //WHITE IS CAPTURED
-        if 8.5 - captured_whites / 2.0 < from_y {
-            direction = -0.5;
+// how many `occupied` squares a route's legs pass within half a square of
+fn route_crowding(steps: &[Step], occupied: Bitboard) -> usize {
+    steps
+        .windows(2)
+        .map(|leg| lane_crowding(occupied, (leg[0].x, leg[0].y), (leg[1].x, leg[1].y)))
+        .sum()
+}
+
+// how many occupied squares a straight, axis aligned lane passes within half
+// a square of. the corner legs of capture_piece only cut across the piece's
+// own square, so they count nothing
+fn lane_crowding(occupied: Bitboard, from: (f64, f64), to: (f64, f64)) -> usize {
+    let beside = |centre: f64, a: f64, b: f64| {
+        let (low, high) = (a.min(b), a.max(b));
+        if (high - low).abs() < f64::EPSILON {
+            (centre - low).abs() <= 0.5
         } else {
-            direction = 0.5;
+            low - 0.5 < centre && centre < high + 0.5
         }
+    };
+    let diagonal = (from.0 - to.0).abs() > f64::EPSILON && (from.1 - to.1).abs() > f64::EPSILON;
+    if diagonal {
+        return 0;
+    }
+    occupied
+        .into_iter()
+        .filter(|square| {
+            beside(file_to_float(square.file()), from.0, to.0)
+                && beside(rank_to_float(square.rank()), from.1, to.1)
+        })
+        .count()
+}
 
-        steps.push(Step {
-            x: from_x,
-            y: (from_y + direction),
-            magnet: true,
-        });
+// whether `point` lies on the leg from `from` to `to`. legs are axis aligned
+// apart from the corner ones, which only touch lanes at their ends
+fn on_leg(point: (f64, f64), from: (f64, f64), to: (f64, f64)) -> bool {
+    let within = |p: f64, a: f64, b: f64| {
+        a.min(b) - f64::EPSILON <= p && p <= a.max(b) + f64::EPSILON
+    };
+    let level = |p: f64, a: f64, b: f64| {
+        (p - a).abs() < f64::EPSILON && (p - b).abs() < f64::EPSILON
+    };
+    let ends = [from, to].iter().any(|end| {
+        (end.0 - point.0).abs() < f64::EPSILON && (end.1 - point.1).abs() < f64::EPSILON
+    });
+    ends || (level(point.0, from.0, to.0) && within(point.1, from.1, to.1))
+        || (level(point.1, from.1, to.1) && within(point.0, from.0, to.0))
+}
 
-        steps.push(Step {
-            x: (0.5),
-            y: (from_y + direction),
-            magnet: true,
-        });
+#[derive(Debug, Clone, Copy, Serialize)]
+struct Step {
+    x: f64,
+    y: f64,
+    magnet: Magnet,
+    // how fast to travel to this step, in the controller's own feedrate
+    // units. None leaves it to the controller, as every step did before
+    #[serde(skip_serializing_if = "Option::is_none")]
+    feedrate: Option<f64>,
+}
 
-        steps.push(Step {
-            x: (0.5),
-            y: (8.5 - captured_whites / 2.0),
-            magnet: true,
-        });
+// whether the carriage is holding a piece. steps are planned in these terms,
+// and the gantry's MagnetWiring turns them into what the controller is sent
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Magnet {
+    Released,
+    Engaged,
+}
 
-        steps.push(Step {
-            x: (0.0),
-            y: (8.5 - captured_whites / 2.0),
-            magnet: true,
-        });
+impl From<bool> for Magnet {
+    fn from(engaged: bool) -> Self {
+        if engaged {
+            Self::Engaged
+        } else {
+            Self::Released
+        }
     }
+}
 
-    steps
+// telemetry has always shown the magnet as a bool
+impl Serialize for Magnet {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bool(*self == Self::Engaged)
+    }
 }
 
-#[derive(Debug, Clone, Copy)]
-struct Step {
-    x: f64,
-    y: f64,
-    magnet: bool,
+// text form used in step dumps and the gantry journal, e.g. `X3.5 Y2.0 M1`,
+// where M1 is engaged whatever the carriage's wiring, and `F1200.0` on the
+// end when the step has a feedrate; floats are written in their debug form
+// so they always parse back exactly
+impl std::fmt::Display for Step {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let magnet = u8::from(self.magnet == Magnet::Engaged);
+        write!(f, "X{:?} Y{:?} M{magnet}", self.x, self.y)?;
+        self.feedrate.map_or(Ok(()), |feedrate| write!(f, " F{feedrate:?}"))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct ParseStepError(String);
+
+impl std::fmt::Display for ParseStepError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?} is not a step, expected something like X3.5 Y2.0 M1", self.0)
+    }
+}
+
+impl std::str::FromStr for Step {
+    type Err = ParseStepError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || ParseStepError(s.to_string());
+        let mut fields = s.split_whitespace();
+        let mut field = |prefix| {
+            fields
+                .next()
+                .and_then(|f: &str| f.strip_prefix(prefix))
+                .ok_or_else(err)
+        };
+        let x = field('X')?.parse().map_err(|_| err())?;
+        let y = field('Y')?.parse().map_err(|_| err())?;
+        let magnet = match field('M')? {
+            "0" => Magnet::Released,
+            "1" => Magnet::Engaged,
+            _ => return Err(err()),
+        };
+        let feedrate = match fields.next() {
+            Some(f) => Some(f.strip_prefix('F').and_then(|f| f.parse().ok()).ok_or_else(err)?),
+            None => None,
+        };
+        if fields.next().is_some() {
+            return Err(err());
+        }
+        Ok(Self {
+            x,
+            y,
+            magnet,
+            feedrate,
+        })
+    }
 }
 
 fn print_step(step: Step) {
     println!("x: {}", step.x);
     println!("y: {}", step.y);
-    println!("magnet: {}", step.magnet);
+    println!("magnet: {:?}", step.magnet);
 }
 
 const fn rank_to_float(rank: Rank) -> f64 {
@@ -798,3 +3188,1247 @@ const fn file_to_float(file: File) -> f64 {
         File::H => 8.0,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shakmaty::san::San;
+
+    fn position(fen: &str) -> Chess {
+        parse_position(fen).unwrap()
+    }
+
+    // feeds `squares` to next_state from Idle, returning where it ends up and
+    // the last move committed on the way
+    fn play(position: &Chess, squares: &[Square]) -> (State, Option<Move>) {
+        let config = StateConfig::default();
+        let mut state = State::Idle;
+        let mut committed = None;
+        for &square in squares {
+            let (next, mv) = next_state(position, square, state, config);
+            state = next;
+            committed = mv.or(committed);
+        }
+        (state, committed)
+    }
+
+    // feeds `squares` through apply_instruction from Idle, `gap` apart, for
+    // the gestures that depend on timing
+    fn gesture(position: &Chess, squares: &[Square], gap: Duration) -> State {
+        let config = StateConfig::default();
+        let mut presses = DoublePress::default();
+        let start = Instant::now();
+        let mut state = State::Idle;
+        for (i, &square) in squares.iter().enumerate() {
+            let now = start + gap * u32::try_from(i).unwrap();
+            state = apply_instruction(position, u32::from(square), state, config, &mut presses, now)
+                .next;
+        }
+        state
+    }
+
+    fn choice_square(to: Square, role: Role) -> Square {
+        let choices = promotion_choices(to, Role::Queen);
+        choices.iter().find(|&&(_, choice)| choice == role).unwrap().0
+    }
+
+    #[test]
+    fn reed_lines_end_at_eof_or_ctrl_d() {
+        let mut reeds = std::io::Cursor::new("12\n 13 \n\x04\n14\n");
+        assert_eq!(read_reed_line(&mut reeds).as_deref(), Some("12"));
+        assert_eq!(read_reed_line(&mut reeds).as_deref(), Some("13"));
+        assert_eq!(read_reed_line(&mut reeds), None);
+        let mut reeds = std::io::Cursor::new("");
+        assert_eq!(read_reed_line(&mut reeds), None);
+    }
+
+    #[test]
+    fn garbled_reed_line_is_skipped() {
+        let mut reeds = std::io::Cursor::new(b"\xff\xfe noise\n21\n".to_vec());
+        assert_eq!(read_reed_line(&mut reeds).as_deref(), Some("21"));
+        assert_eq!(read_reed_line(&mut reeds), None);
+    }
+
+    #[test]
+    fn capture_promotion_underpromotes() {
+        let pos = position("r6k/1P6/8/8/8/8/8/K7 w - - 0 1");
+        let knight = choice_square(Square::A8, Role::Knight);
+        // rook lifted, pawn lifted, pawn set down on the rook's square
+        let (state, mv) = play(&pos, &[Square::A8, Square::B7, Square::A8]);
+        assert_eq!(state, State::PromotionSelect(Square::B7, Square::A8, Some(Role::Rook)));
+        assert_eq!(mv, None);
+        let (state, mv) = play(&pos, &[Square::A8, Square::B7, Square::A8, knight]);
+        assert_eq!(state, State::Idle);
+        let expected = Move::Normal {
+            role: Role::Pawn,
+            from: Square::B7,
+            capture: Some(Role::Rook),
+            to: Square::A8,
+            promotion: Some(Role::Knight),
+        };
+        assert_eq!(mv, Some(expected));
+    }
+
+    fn point(step: Step) -> (f64, f64, Magnet) {
+        (step.x, step.y, step.magnet)
+    }
+
+    #[test]
+    fn undoing_a_capture_brings_the_piece_back() {
+        let pos = position("4k3/8/8/4p3/8/5N2/8/4K3 w - - 0 1");
+        let mv = Move::Normal {
+            role: Role::Knight,
+            from: Square::F3,
+            capture: Some(Role::Pawn),
+            to: Square::E5,
+            promotion: None,
+        };
+        let mut graveyard = Graveyard::default();
+        graveyard.record(&mv, Color::White);
+        let after = pos.play(&mv).unwrap();
+        let steps = undo_capture_steps(&mv, Color::White, &mut graveyard, after.board().occupied());
+        assert_eq!(graveyard, Graveyard::default());
+        // picked up from the first black slot, set down on e5
+        assert_eq!(point(steps[0]), (9.0, 0.5, Magnet::Released));
+        assert_eq!(point(*steps.last().unwrap()), (5.0, 5.0, Magnet::Engaged));
+        assert!(steps[1..].iter().all(|step| step.magnet == Magnet::Engaged));
+        // the same lanes the capture took, the other way
+        let taken = capture_piece(5.0, 5.0, Color::White, 0.0, 0.0, after.board().occupied(), None);
+        let back: Vec<_> = steps.iter().rev().map(|&step| (step.x, step.y)).collect();
+        let out: Vec<_> = taken.iter().map(|step| (step.x, step.y)).collect();
+        assert_eq!(back, out);
+    }
+
+    #[test]
+    fn undoing_en_passant_returns_the_pawn_behind() {
+        let pos = position("4k3/8/8/3pP3/8/8/8/4K3 w - d6 0 2");
+        let mv = Move::EnPassant {
+            from: Square::E5,
+            to: Square::D6,
+        };
+        let mut graveyard = Graveyard::default();
+        graveyard.record(&mv, Color::White);
+        let after = pos.play(&mv).unwrap();
+        let steps = undo_capture_steps(&mv, Color::White, &mut graveyard, after.board().occupied());
+        assert_eq!(graveyard, Graveyard::default());
+        // back onto d5, where the pawn was taken, not d6 where the capturer went
+        assert_eq!(point(*steps.last().unwrap()), (4.0, 5.0, Magnet::Engaged));
+    }
+
+    #[test]
+    fn undoing_a_quiet_move_moves_nothing() {
+        let mv = Move::Normal {
+            role: Role::Knight,
+            from: Square::G1,
+            capture: None,
+            to: Square::F3,
+            promotion: None,
+        };
+        let mut graveyard = Graveyard {
+            captured_whites: 1,
+            captured_blacks: 2,
+        };
+        let before = graveyard;
+        assert!(undo_capture_steps(&mv, Color::White, &mut graveyard, Bitboard::EMPTY).is_empty());
+        assert_eq!(graveyard, before);
+    }
+
+    // a writer whose bytes can still be read once it's been handed over
+    #[derive(Clone, Default)]
+    pub struct Shared(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Shared {
+        pub fn text(&self) -> String {
+            String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+        }
+    }
+
+    // typed squares for the reeds, then the end of input
+    pub fn reed_input(squares: &[&str]) -> Receiver<Option<String>> {
+        let (sender, receiver) = std::sync::mpsc::channel();
+        for square in squares {
+            sender.send(Some((*square).to_string())).unwrap();
+        }
+        sender.send(None).unwrap();
+        receiver
+    }
+
+    #[test]
+    fn scripted_game_against_the_wrapper() {
+        let config = GameConfig::builder().human(Color::White).keyboard(true).build();
+        let sent = Shared::default();
+        let replies = std::io::Cursor::new("e5\nQh4\n");
+        let mut opponent: Box<dyn Opponent> = Box::new(Wrapper::connect(replies, sent.clone()));
+        let reeds = reed_input(&["f2", "f3", "g2", "g4"]);
+        let mut console = Vec::new();
+        let views = ViewServer::default();
+        let summary = run(&config, &mut opponent, &reeds, &views, &mut console).unwrap();
+        assert_eq!(sent.text(), "f3\ng4\n");
+        let fen = Fen::from_position(summary.position, EnPassantMode::Legal).to_string();
+        assert_eq!(fen, "rnb1kbnr/pppp1ppp/8/4p3/6Pq/5P2/PPPPP2P/RNBQKBNR w KQkq - 1 3");
+        let black_wins = Outcome::Decisive {
+            winner: Color::Black,
+        };
+        assert_eq!(summary.result, Some((black_wins, "checkmate")));
+        // the opponent's two moves, each a pickup and a carry at least
+        assert!(summary.steps.len() >= 4);
+    }
+
+    #[test]
+    fn scripted_game_against_a_uci_engine() {
+        let config = GameConfig::builder().human(Color::Black).keyboard(true).build();
+        // without adjudication the engine only searches for its own replies
+        let engine = std::io::Cursor::new("uciok\nreadyok\nbestmove f2f3\nbestmove g2g4\n");
+        let start = Chess::default();
+        let strength = EngineStrength::default();
+        let engine = UciEngine::connect(engine, std::io::sink(), &start, strength).unwrap();
+        let mut opponent: Box<dyn Opponent> = Box::new(engine);
+        let reeds = reed_input(&["e7", "e5", "d8", "h4"]);
+        let mut console = Vec::new();
+        let views = ViewServer::default();
+        let summary = run(&config, &mut opponent, &reeds, &views, &mut console).unwrap();
+        assert!(summary.position.is_checkmate());
+        let black_wins = Outcome::Decisive {
+            winner: Color::Black,
+        };
+        assert_eq!(summary.result, Some((black_wins, "checkmate")));
+    }
+
+    #[test]
+    fn king_takes_an_undefended_neighbour() {
+        let pos = position("4k3/8/8/8/8/8/3q4/4K3 w - - 0 1");
+        assert!(legal_capture(&pos, Square::E1, Square::D2));
+        let (state, mv) = play(&pos, &[Square::D2, Square::E1, Square::D2]);
+        assert_eq!(state, State::Idle);
+        let expected = Move::Normal {
+            role: Role::King,
+            from: Square::E1,
+            capture: Some(Role::Queen),
+            to: Square::D2,
+            promotion: None,
+        };
+        assert_eq!(mv, Some(expected));
+    }
+
+    #[test]
+    fn king_cannot_take_a_defended_neighbour() {
+        // the rook on d8 covers d2
+        let pos = position("3rk3/8/8/8/8/8/3q4/4K3 w - - 0 1");
+        assert!(!legal_capture(&pos, Square::E1, Square::D2));
+        let (state, mv) = play(&pos, &[Square::D2, Square::E1]);
+        assert_eq!(state, State::InvalidPiecePU(Some(Square::D2), Square::E1));
+        assert_eq!(mv, None);
+    }
+
+    fn step(x: f64, y: f64, engaged: bool) -> Step {
+        Step {
+            x,
+            y,
+            magnet: engaged.into(),
+            feedrate: None,
+        }
+    }
+
+    fn points(steps: &[Step]) -> Vec<(f64, f64, Magnet)> {
+        steps.iter().map(|&step| point(step)).collect()
+    }
+
+    #[test]
+    fn collinear_steps_collapse_to_their_ends() {
+        let steps = vec![
+            step(1.0, 1.0, true),
+            step(1.0, 1.0, true),
+            step(2.0, 1.0, true),
+            step(3.0, 1.0, true),
+        ];
+        let merged = merge_collinear(steps.clone());
+        assert_eq!(points(&merged), points(&[steps[0], steps[3]]));
+    }
+
+    #[test]
+    fn corners_are_kept() {
+        let steps = vec![step(1.0, 1.0, true), step(2.0, 1.0, true), step(2.0, 2.0, true)];
+        assert_eq!(points(&merge_collinear(steps.clone())), points(&steps));
+    }
+
+    #[test]
+    fn a_released_magnet_splits_a_straight_line() {
+        let steps = vec![
+            step(1.0, 1.0, true),
+            step(2.0, 1.0, true),
+            step(3.0, 1.0, false),
+            step(4.0, 1.0, true),
+        ];
+        assert_eq!(points(&merge_collinear(steps.clone())), points(&steps));
+    }
+
+    // king on f1 between rooks on b1 and g1, black the same way round
+    const CHESS960: &str = "1r3k2/8/8/8/8/8/8/1R3KR1 w GBb - 0 1";
+
+    #[test]
+    fn chess960_fen_castles_the_chess960_way() {
+        assert_eq!(position(CHESS960).castles().mode(), CastlingMode::Chess960);
+        assert_eq!(Chess::default().castles().mode(), CastlingMode::Standard);
+        let start = "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1";
+        assert_eq!(position(start).castles().mode(), CastlingMode::Standard);
+    }
+
+    #[test]
+    fn chess960_king_and_rook_swap_by_hand() {
+        let pos = position(CHESS960);
+        // king lifted, rook lifted, king set down where the rook was, then the rook
+        let squares = [Square::F1, Square::G1, Square::G1, Square::F1];
+        let (state, mv) = play(&pos, &squares);
+        assert_eq!(state, State::Idle);
+        let castle = Move::Castle {
+            king: Square::F1,
+            rook: Square::G1,
+        };
+        assert_eq!(mv, Some(castle));
+    }
+
+    #[test]
+    fn chess960_swap_keeps_the_king_out_of_the_rook_lane() {
+        let pos = position(CHESS960);
+        let geom = BoardGeometry::default();
+        let occupied = pos.board().occupied();
+        let journeys = castle_journeys(Square::F1, Square::G1, Color::White, occupied, &geom);
+        let [park, king, unpark] = &journeys[..] else {
+            panic!("expected three journeys, got {}", journeys.len());
+        };
+        assert_eq!(point(park.drop_off().unwrap()), (7.0, 0.5, Magnet::Engaged));
+        assert!(king.steps.iter().all(|step| (step.y - 1.0).abs() < f64::EPSILON));
+        assert_eq!(point(king.pickup().unwrap()), (6.0, 1.0, Magnet::Released));
+        assert_eq!(point(king.drop_off().unwrap()), (7.0, 1.0, Magnet::Engaged));
+        assert_eq!(point(unpark.pickup().unwrap()), (7.0, 0.5, Magnet::Released));
+        assert_eq!(point(unpark.drop_off().unwrap()), (6.0, 1.0, Magnet::Engaged));
+    }
+
+    #[test]
+    fn chess960_king_already_home_leaves_only_the_rook_to_move() {
+        let pos = position("6kr/8/8/8/8/8/8/6KR w Hh - 0 1");
+        let geom = BoardGeometry::default();
+        let occupied = pos.board().occupied();
+        let journeys = castle_journeys(Square::G1, Square::H1, Color::White, occupied, &geom);
+        let [rook] = &journeys[..] else {
+            panic!("expected one journey, got {}", journeys.len());
+        };
+        assert_eq!(point(rook.pickup().unwrap()), (8.0, 1.0, Magnet::Released));
+        assert_eq!(point(rook.drop_off().unwrap()), (6.0, 1.0, Magnet::Engaged));
+    }
+
+    #[test]
+    fn pressing_their_king_twice_offers_a_draw() {
+        let pos = Chess::default();
+        let presses = [Square::E8; 4];
+        let state = gesture(&pos, &presses, Duration::from_millis(100));
+        assert_eq!(state, State::ConfirmDraw(Square::E8));
+        let state = gesture(&pos, &[Square::E8; 5], Duration::from_millis(100));
+        assert_eq!(state, State::DrawOffered);
+    }
+
+    #[test]
+    fn anything_else_withdraws_a_draw_offer() {
+        let pos = Chess::default();
+        let squares = [Square::E8, Square::E8, Square::E8, Square::E8, Square::E2];
+        let state = gesture(&pos, &squares, Duration::from_millis(100));
+        assert_eq!(state, State::FriendlyPU(Square::E2));
+    }
+
+    #[test]
+    fn king_takes_a_rook_rather_than_castling_with_it() {
+        let pos = position("4k3/8/8/8/8/8/3r4/4K3 w - - 0 1");
+        let (state, mv) = play(&pos, &[Square::E1, Square::D2, Square::D2]);
+        assert_eq!(state, State::Idle);
+        let mv = mv.unwrap();
+        assert_eq!(San::from_move(&pos, &mv).to_string(), "Kxd2");
+    }
+
+    #[test]
+    fn illegal_castle_unwinds_in_either_order() {
+        // no castling rights left
+        let pos = position("4k3/8/8/8/8/8/8/4K2R w - - 0 1");
+        let (state, _) = play(&pos, &[Square::E1, Square::H1]);
+        assert_eq!(state, State::IllegalCastle(Square::E1, Square::H1));
+        let (state, _) = play(&pos, &[Square::H1, Square::E1]);
+        assert_eq!(state, State::IllegalCastle(Square::E1, Square::H1));
+        for squares in [
+            [Square::E1, Square::H1, Square::E1, Square::H1],
+            [Square::E1, Square::H1, Square::H1, Square::E1],
+            [Square::H1, Square::E1, Square::E1, Square::H1],
+            [Square::H1, Square::E1, Square::H1, Square::E1],
+        ] {
+            assert_eq!(play(&pos, &squares), (State::Idle, None));
+        }
+    }
+
+    #[test]
+    fn teaching_lights_only_pieces_that_can_move() {
+        // the knight is pinned to the king by the rook
+        let pos = position("4k3/4r3/8/8/8/8/4N3/4K3 w - - 0 1");
+        let origins = legal_origins(&pos);
+        assert!(origins.contains(Square::E1));
+        assert!(!origins.contains(Square::E2));
+        let theme = Theme::default();
+        let rgb = get_rgb(&pos, State::Idle, &theme, true, None, Waiting::Human);
+        assert!(rgb.b.contains(Square::E1));
+        assert!(!rgb.b.contains(Square::E2));
+        let rgb = get_rgb(&pos, State::Idle, &theme, false, None, Waiting::Human);
+        assert!(!rgb.b.contains(Square::E1));
+    }
+
+    #[test]
+    fn board_is_not_read_on_the_opponents_move() {
+        let config = StateConfig {
+            human: Some(Color::White),
+            ..StateConfig::default()
+        };
+        let black_to_move = position("rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq - 0 1");
+        for state in [State::Idle, State::FriendlyPU(Square::E4)] {
+            let update = update_state(&black_to_move, u32::from(Square::E7), state, config);
+            assert_eq!(update.next, state);
+            assert_eq!(update.committed, None);
+            assert_eq!(update.feedback, Feedback::OutOfTurn);
+        }
+        let update = update_state(&Chess::default(), u32::from(Square::E2), State::Idle, config);
+        assert_eq!(update.feedback, Feedback::Normal);
+    }
+
+    #[test]
+    fn pushed_promotion_goes_through_the_same_choice() {
+        let pos = position("7k/1P6/8/8/8/8/8/K7 w - - 0 1");
+        let (state, mv) = play(&pos, &[Square::B7, Square::B8]);
+        assert_eq!(state, State::PromotionSelect(Square::B7, Square::B8, None));
+        assert_eq!(mv, None);
+        let queen = choice_square(Square::B8, Role::Queen);
+        let (state, mv) = play(&pos, &[Square::B7, Square::B8, queen]);
+        assert_eq!(state, State::Idle);
+        assert_eq!(mv, Some(promotion(Square::B7, Square::B8, None, Role::Queen)));
+    }
+
+    #[test]
+    fn pinned_pawn_cannot_promote() {
+        // the bishop on a8 pins the pawn to the king on d5
+        let pos = position("b6k/1P6/8/3K4/8/8/8/8 w - - 0 1");
+        let (state, mv) = play(&pos, &[Square::B7, Square::B8]);
+        assert_eq!(state, State::InvalidMove(Square::B7, Square::B8));
+        assert_eq!(mv, None);
+        let committed = commit_promotion(&pos, Square::B7, Square::B8, None, Role::Queen);
+        assert_eq!(committed, (State::InvalidMove(Square::B7, Square::B8), None));
+        // taking the pinning bishop is fine
+        let (state, _) = play(&pos, &[Square::A8, Square::B7, Square::A8]);
+        assert_eq!(state, State::PromotionSelect(Square::B7, Square::A8, Some(Role::Bishop)));
+    }
+
+    #[test]
+    fn start_position_renders_as_an_eight_rank_grid() {
+        let grid = board_from_fen("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1");
+        let expected = [
+            "r n b q k b n r",
+            "p p p p p p p p",
+            ". . . . . . . .",
+            ". . . . . . . .",
+            ". . . . . . . .",
+            ". . . . . . . .",
+            "P P P P P P P P",
+            "R N B Q K B N R",
+        ];
+        assert_eq!(grid.lines().collect::<Vec<_>>(), expected);
+    }
+
+    #[test]
+    fn ply_caption_follows_the_grid() {
+        let start = Chess::default();
+        let e4 = "e4".parse::<San>().unwrap().to_move(&start).unwrap();
+        let pos = start.play(&e4).unwrap();
+        let mut out = Vec::new();
+        print_ply(&mut out, &pos, "e4").unwrap();
+        let text = String::from_utf8(out).unwrap();
+        let lines: Vec<_> = text.lines().collect();
+        assert_eq!(lines.len(), 9);
+        assert_eq!(lines[4], ". . . . P . . .");
+        assert_eq!(lines[8], "e4 played, black to move");
+    }
+
+    #[test]
+    fn double_press_needs_the_same_square_inside_the_window() {
+        let mut presses = DoublePress::default();
+        let start = Instant::now();
+        assert!(!presses.press(Square::E1, start));
+        assert!(presses.press(Square::E1, start + Duration::from_millis(500)));
+        // a recognised pair is used up
+        assert!(!presses.press(Square::E1, start + Duration::from_millis(600)));
+        assert!(!presses.press(Square::D1, start + Duration::from_millis(700)));
+        assert!(!presses.press(Square::D1, start + DOUBLE_PRESS_WINDOW * 2));
+    }
+
+    #[test]
+    fn pressing_our_king_twice_resigns_once_confirmed() {
+        let pos = Chess::default();
+        let gap = Duration::from_millis(100);
+        let state = gesture(&pos, &[Square::E1; 4], gap);
+        assert_eq!(state, State::ConfirmResign(Square::E1));
+        assert_eq!(gesture(&pos, &[Square::E1; 5], gap), State::Resigned);
+        // touching anything else backs out into a normal pickup
+        let mut squares = vec![Square::E1; 4];
+        squares.push(Square::E2);
+        assert_eq!(gesture(&pos, &squares, gap), State::FriendlyPU(Square::E2));
+    }
+
+    #[test]
+    fn single_or_slow_king_presses_dont_resign() {
+        let pos = Chess::default();
+        assert_eq!(gesture(&pos, &[Square::E1; 2], Duration::from_millis(100)), State::Idle);
+        // the two replacements land 2s apart, outside the window
+        assert_eq!(gesture(&pos, &[Square::E1; 4], Duration::from_secs(1)), State::Idle);
+    }
+
+    #[test]
+    fn consecutive_captures_fill_different_graveyard_slots() {
+        let geom = BoardGeometry::default();
+        let mut pos = position("4k3/8/2p5/3p4/4P3/8/8/4K3 w - - 0 1");
+        let mut graveyard = Graveyard::default();
+        let mut slots = Vec::new();
+        for san in ["exd5", "Kf7", "dxc6"] {
+            let mv = san.parse::<San>().unwrap().to_move(&pos).unwrap();
+            let journeys = move_to_journeys(
+                mv.clone(),
+                pos.turn(),
+                f64::from(graveyard.captured_whites),
+                f64::from(graveyard.captured_blacks),
+                pos.board().occupied(),
+                &geom,
+                CaptureStrategy::ClearFirst,
+            );
+            if mv.is_capture() {
+                slots.push(point(journeys[0].drop_off().unwrap()));
+            }
+            graveyard.record(&mv, pos.turn());
+            pos = pos.play(&mv).unwrap();
+        }
+        assert_eq!(slots.len(), 2);
+        assert_ne!((slots[0].0, slots[0].1), (slots[1].0, slots[1].1));
+        assert_eq!(graveyard.captured_blacks, 2);
+        // a takeback frees the second slot for the next capture to reuse
+        assert_eq!(graveyard.release(Color::Black), Some(1));
+        assert_eq!(graveyard.captured_blacks, 1);
+        assert_eq!(graveyard.release(Color::White), None);
+    }
+
+    #[test]
+    fn castling_is_two_journeys_king_then_rook() {
+        let pos = position("4k3/8/8/8/8/8/8/4K2R w K - 0 1");
+        let geom = BoardGeometry::default();
+        let mv = Move::Castle {
+            king: Square::E1,
+            rook: Square::H1,
+        };
+        let journeys = move_to_journeys(
+            mv.clone(),
+            Color::White,
+            0.0,
+            0.0,
+            pos.board().occupied(),
+            &geom,
+            CaptureStrategy::ClearFirst,
+        );
+        let [king, rook] = &journeys[..] else {
+            panic!("expected two journeys, got {}", journeys.len());
+        };
+        assert_eq!(point(king.pickup().unwrap()), (5.0, 1.0, Magnet::Released));
+        assert_eq!(point(king.drop_off().unwrap()), (7.0, 1.0, Magnet::Engaged));
+        assert_eq!(point(rook.pickup().unwrap()), (8.0, 1.0, Magnet::Released));
+        assert_eq!(point(rook.drop_off().unwrap()), (6.0, 1.0, Magnet::Engaged));
+        // the flat plan is the same steps end to end
+        let flat = move_to_steps(
+            mv,
+            Color::White,
+            0.0,
+            0.0,
+            pos.board().occupied(),
+            &geom,
+            CaptureStrategy::ClearFirst,
+        );
+        assert_eq!(flat.len(), king.steps.len() + rook.steps.len());
+    }
+
+    #[test]
+    fn out_of_range_reeds_are_rejected_without_panicking() {
+        for bogus in [64, 9999] {
+            assert_eq!(
+                parse_instruction(&bogus.to_string()),
+                Err(InstructionError::OutOfRange(bogus))
+            );
+            let update = update_state(&Chess::default(), bogus, State::Idle, StateConfig::default());
+            assert_eq!(update.next, State::Idle);
+            assert_eq!(update.committed, None);
+            assert_eq!(update.feedback, Feedback::RecoverableError);
+        }
+        assert_eq!(parse_instruction("63"), Ok(63));
+        assert_eq!(parse_instruction("-1"), Err(InstructionError::NotANumber("-1".to_string())));
+    }
+
+    #[test]
+    fn pinned_knight_has_no_legal_destinations() {
+        let pinned = position("4k3/4r3/8/8/8/8/4N3/4K3 w - - 0 1");
+        assert!(legal_destinations(&pinned, Square::E2).is_empty());
+        let split = split_legal_destinations(&pinned, Square::E2);
+        assert!(split.quiet.is_empty() && split.captures.is_empty());
+        // with the rook off the file the knight has its usual moves
+        let free = position("4k3/r7/8/8/8/8/3N4/4K3 w - - 0 1");
+        assert_eq!(legal_destinations(&free, Square::D2).count(), 6);
+    }
+
+    #[test]
+    fn random_steps_survive_a_text_round_trip() {
+        use rand::{rngs::StdRng, Rng, SeedableRng};
+        let mut rng = StdRng::seed_from_u64(561);
+        for _ in 0..1000 {
+            let step = Step {
+                x: rng.gen_range(-1.0..10.0),
+                y: rng.gen_range(-1.0..10.0),
+                magnet: rng.gen::<bool>().into(),
+                feedrate: rng.gen::<bool>().then(|| rng.gen_range(100.0..5000.0)),
+            };
+            let text = step.to_string();
+            let parsed: Step = text.parse().unwrap();
+            assert_eq!(parsed.x.to_bits(), step.x.to_bits(), "{text}");
+            assert_eq!(parsed.y.to_bits(), step.y.to_bits(), "{text}");
+            assert_eq!(parsed.magnet, step.magnet, "{text}");
+            assert_eq!(parsed.feedrate.map(f64::to_bits), step.feedrate.map(f64::to_bits));
+        }
+        assert_eq!(step(3.5, 2.0, true).to_string(), "X3.5 Y2.0 M1");
+        assert!("X3.5 Y2.0 M2".parse::<Step>().is_err());
+        assert!("X3.5 Y2.0 M0 F".parse::<Step>().is_err());
+    }
+
+    #[test]
+    fn insufficient_material_is_a_draw() {
+        for fen in [
+            "4k3/8/8/8/8/8/8/4K3 w - - 0 1",
+            "4k3/8/8/8/8/8/8/2B1K3 w - - 0 1",
+            "4k3/8/8/8/8/8/8/1N2K3 b - - 0 1",
+            // c1 and f8 are both dark squares
+            "4kb2/8/8/8/8/8/8/2B1K3 w - - 0 1",
+        ] {
+            let pos = position(fen);
+            assert!(is_insufficient_material(&pos), "{fen}");
+            assert_eq!(game_over_reason(&pos), "insufficient material");
+        }
+        for fen in [
+            // opposite coloured bishops and two knights can still mate
+            "4k1b1/8/8/8/8/8/8/2B1K3 w - - 0 1",
+            "4k3/8/8/8/8/8/8/1N2KN2 w - - 0 1",
+            "4k3/8/8/8/8/8/4P3/4K3 w - - 0 1",
+        ] {
+            assert!(!is_insufficient_material(&position(fen)), "{fen}");
+        }
+    }
+
+    #[test]
+    fn flipped_board_rotates_squares_and_steps() {
+        let e2 = u32::from(Square::E2);
+        assert_eq!(Orientation::Flipped.instruction(e2), u32::from(Square::D7));
+        assert_eq!(Orientation::Normal.instruction(e2), e2);
+        let frame = Bitboard::from_square(Square::E2).with(Bitboard::from_square(Square::A1));
+        let rotated = Bitboard::from_square(Square::D7).with(Bitboard::from_square(Square::H8));
+        assert_eq!(Orientation::Flipped.frame(frame), rotated);
+
+        let geom = BoardGeometry::default();
+        // e2's centre lands on d7's, and the graveyard columns swap sides
+        let flipped = Orientation::Flipped.step(step(5.0, 2.0, true), &geom);
+        assert_eq!(point(flipped), (4.0, 7.0, Magnet::Engaged));
+        let flipped = Orientation::Flipped.step(step(9.0, 0.5, false), &geom);
+        assert_eq!(point(flipped), (0.0, 8.5, Magnet::Released));
+        assert_eq!(Orientation::Flipped.planned((4.0, 7.0), &geom), (5.0, 2.0));
+        let unchanged = Orientation::Normal.step(step(5.0, 2.0, true), &geom);
+        assert_eq!(point(unchanged), (5.0, 2.0, Magnet::Engaged));
+    }
+
+    #[test]
+    fn fifty_move_claim_needs_a_hundred_halfmoves() {
+        // their king twice, then ours lifted and put back
+        let mut squares = vec![Square::E8; 4];
+        squares.extend([Square::E1, Square::E1]);
+        let gap = Duration::from_millis(100);
+        let eligible = position("4k3/8/8/8/8/8/8/R3K3 w - - 100 80");
+        assert!(can_claim_fifty_moves(&eligible));
+        assert_eq!(gesture(&eligible, &squares, gap), State::DrawClaimed);
+        let early = position("4k3/8/8/8/8/8/8/R3K3 w - - 99 80");
+        assert!(!can_claim_fifty_moves(&early));
+        assert_eq!(gesture(&early, &squares, gap), State::ClaimRejected);
+        // the rejection is only shown, the next pickup plays on
+        squares.push(Square::A1);
+        assert_eq!(gesture(&early, &squares, gap), State::FriendlyPU(Square::A1));
+    }
+
+    #[test]
+    fn sliding_on_from_an_invalid_move_commits_it() {
+        let pos = Chess::default();
+        let (state, _) = play(&pos, &[Square::B1, Square::B3]);
+        assert_eq!(state, State::InvalidMove(Square::B1, Square::B3));
+        let (state, mv) = play(&pos, &[Square::B1, Square::B3, Square::C3]);
+        assert_eq!(state, State::Idle);
+        let nc3 = Move::Normal {
+            role: Role::Knight,
+            from: Square::B1,
+            capture: None,
+            to: Square::C3,
+            promotion: None,
+        };
+        assert_eq!(mv, Some(nc3));
+        // lifting it back off b3 is the knight in hand again
+        let (state, _) = play(&pos, &[Square::B1, Square::B3, Square::B3]);
+        assert_eq!(state, State::FriendlyPU(Square::B1));
+        // and sliding on to another square it can't go to is still an error
+        let (state, mv) = play(&pos, &[Square::B1, Square::B3, Square::D4]);
+        assert_eq!((state, mv), (State::Error, None));
+    }
+
+    #[test]
+    fn castle_pieces_can_land_in_either_order() {
+        let pos = position("4k3/8/8/8/8/8/8/4K2R w K - 0 1");
+        let castle = Move::Castle {
+            king: Square::E1,
+            rook: Square::H1,
+        };
+        for landing in [[Square::G1, Square::F1], [Square::F1, Square::G1]] {
+            let mut squares = vec![Square::E1, Square::H1];
+            squares.extend(landing);
+            assert_eq!(play(&pos, &squares), (State::Idle, Some(castle.clone())));
+        }
+        // the rook set down on e1 rather than f1 is shown, not an error
+        let squares = [Square::E1, Square::H1, Square::G1, Square::E1];
+        let down = Some(Square::G1);
+        let misplaced = State::CastlingMisplaced(Square::E1, Square::H1, Square::E1, down);
+        assert_eq!(play(&pos, &squares), (misplaced, None));
+        let squares = [Square::E1, Square::H1, Square::G1, Square::E1, Square::E1, Square::F1];
+        assert_eq!(play(&pos, &squares), (State::Idle, Some(castle)));
+    }
+
+    #[test]
+    fn castle_is_checked_again_when_it_lands() {
+        // the rook on e8 checks the king, so the castle can't be played
+        let pos = position("4r1k1/8/8/8/8/8/8/4K2R w K - 0 1");
+        let config = StateConfig::default();
+        let (king, rook) = (Square::E1, Square::H1);
+        for (state, landed, down) in [
+            (State::CastlingPutRookDown(king, rook, Square::F1), Square::F1, Square::G1),
+            (State::CastlingPutKingDown(king, rook, Square::G1), Square::G1, Square::F1),
+        ] {
+            let update = update_state(&pos, u32::from(landed), state, config);
+            let misplaced = State::CastlingMisplaced(king, rook, landed, Some(down));
+            assert_eq!(update.next, misplaced);
+            assert_eq!(update.committed, None);
+        }
+    }
+
+    #[test]
+    fn pinned_capture_is_rejected_and_can_be_put_back() {
+        // the knight on e2 attacks c3 but is pinned by the rook on e8
+        let pos = position("4r1k1/8/8/8/8/2p5/4N3/4K3 w - - 0 1");
+        let (state, _) = play(&pos, &[Square::E2, Square::C3]);
+        assert_eq!(state, State::FriendlyAndEnemyPU(Square::E2, Square::C3));
+        let (state, mv) = play(&pos, &[Square::E2, Square::C3, Square::C3]);
+        assert_eq!((state, mv), (State::InvalidMove(Square::E2, Square::C3), None));
+        // the knight back off c3 and home, then the pawn back where it was
+        let mut squares = vec![Square::E2, Square::C3, Square::C3, Square::C3];
+        assert_eq!(play(&pos, &squares).0, State::FriendlyAndEnemyPU(Square::E2, Square::C3));
+        squares.push(Square::E2);
+        assert_eq!(play(&pos, &squares).0, State::EnemyPU(Square::C3));
+        squares.push(Square::C3);
+        assert_eq!(play(&pos, &squares), (State::Idle, None));
+    }
+
+    #[test]
+    fn default_promotion_flag_picks_the_role_on_the_promotion_square() {
+        let args = |args: &[&str]| parse_args(args.iter().map(ToString::to_string)).unwrap();
+        assert_eq!(args(&[]).default_promotion, None);
+        assert_eq!(StateConfig::default().default_promotion, Role::Queen);
+        assert_eq!(args(&["--default-promotion", "n"]).default_promotion, Some(Role::Knight));
+        assert!(parse_args(["--default-promotion", "k"].map(String::from).into_iter()).is_err());
+
+        let pos = position("7k/1P6/8/8/8/8/8/K7 w - - 0 1");
+        let promote = |default_promotion| {
+            let config = StateConfig {
+                default_promotion,
+                ..StateConfig::default()
+            };
+            // the pawn pushed, then pressed again where it stands
+            let mut state = State::Idle;
+            let mut committed = None;
+            for square in [Square::B7, Square::B8, Square::B8] {
+                let (next, mv) = next_state(&pos, square, state, config);
+                (state, committed) = (next, mv.or(committed));
+            }
+            committed.and_then(|mv| mv.promotion())
+        };
+        assert_eq!(promote(Role::Queen), Some(Role::Queen));
+        assert_eq!(promote(Role::Knight), Some(Role::Knight));
+    }
+
+    #[test]
+    fn castle_journeys_end_on_each_wings_squares() {
+        let geom = BoardGeometry::default();
+        let centre = |square: Square| (file_to_float(square.file()), rank_to_float(square.rank()));
+        let ends = |journey: &PieceJourney| {
+            let (from, to) = (journey.pickup().unwrap(), journey.drop_off().unwrap());
+            ((from.x, from.y), (to.x, to.y))
+        };
+        for (turn, king, rook, king_to, rook_to) in [
+            ('w', Square::E1, Square::H1, Square::G1, Square::F1),
+            ('w', Square::E1, Square::A1, Square::C1, Square::D1),
+            ('b', Square::E8, Square::H8, Square::G8, Square::F8),
+            ('b', Square::E8, Square::A8, Square::C8, Square::D8),
+        ] {
+            let pos = position(&format!("r3k2r/8/8/8/8/8/8/R3K2R {turn} KQkq - 0 1"));
+            assert!(pos.is_legal(&Move::Castle { king, rook }));
+            let occupied = pos.board().occupied();
+            let journeys = castle_journeys(king, rook, pos.turn(), occupied, &geom);
+            let ends: Vec<_> = journeys.iter().map(ends).collect();
+            assert_eq!(ends, [(centre(king), centre(king_to)), (centre(rook), centre(rook_to))]);
+        }
+    }
+
+    #[test]
+    fn captured_piece_keeps_to_the_lanes_between_squares() {
+        // a crowded middlegame, the white queen taking on d5
+        let pos = position("r1bqk2r/pp2bppp/2n1pn2/2pp4/3P4/2NBPN2/PP3PPP/R1BQ1RK1 w kq - 0 7");
+        let occupied = pos.board().occupied();
+        let steps = capture_piece(4.0, 5.0, Color::White, 0.0, 0.0, occupied, None);
+        let centres: Vec<_> = occupied
+            .without(Bitboard::from_square(Square::D5))
+            .into_iter()
+            .map(|square| (file_to_float(square.file()), rank_to_float(square.rank())))
+            .collect();
+        let half = |v: f64| (v.fract() - 0.5).abs() < f64::EPSILON;
+        for step in &steps[1..steps.len() - 1] {
+            assert!(half(step.x) || half(step.y), "{step} is not on a lane");
+        }
+        for leg in steps.windows(2) {
+            let (from, to) = ((leg[0].x, leg[0].y), (leg[1].x, leg[1].y));
+            assert!(!centres.iter().any(|&centre| on_leg(centre, from, to)), "{from:?} {to:?}");
+        }
+        // into the first slot of black's graveyard, right of the h file
+        assert_eq!(point(*steps.last().unwrap()), (9.0, 0.5, Magnet::Engaged));
+    }
+
+    #[test]
+    fn captured_piece_takes_the_shorter_edge() {
+        // the edge lane is where the route turns towards the graveyard
+        let edge = |rank: f64, captured_blacks: f64| {
+            let steps =
+                capture_piece(4.0, rank, Color::White, 0.0, captured_blacks, Bitboard::EMPTY, None);
+            steps.iter().map(|step| step.y).find(|&y| !(1.0..=8.0).contains(&y)).unwrap()
+        };
+        // black's graveyard fills up from rank 1, so its first slot is best
+        // reached along the bottom and its fifteenth along the top
+        assert!((edge(2.0, 0.0) - 0.5).abs() < f64::EPSILON);
+        assert!((edge(7.0, 14.0) - 8.5).abs() < f64::EPSILON);
+    }
+
+    #[test]
+    fn loaded_en_passant_square_lasts_one_ply() {
+        // white has just played e2-e4 past the black pawn on d4
+        let pos = position("4k3/8/8/8/3pP3/8/8/4K3 b - e3 0 1");
+        let (state, mv) = play(&pos, &[Square::D4, Square::E4, Square::E3]);
+        assert_eq!(state, State::Idle);
+        assert_eq!(mv, Some(Move::EnPassant { from: Square::D4, to: Square::E3 }));
+        // black waits a move instead, and the chance is gone
+        let mut later = pos;
+        for san in ["Kd8", "Kd1"] {
+            let mv = san.parse::<San>().unwrap().to_move(&later).unwrap();
+            later.play_unchecked(&mv);
+        }
+        let (state, mv) = play(&later, &[Square::D4, Square::E4]);
+        assert_eq!(state, State::InvalidPiecePU(Some(Square::D4), Square::E4));
+        assert_eq!(mv, None);
+    }
+
+    #[test]
+    fn led_grid_shades_each_square() {
+        let rgb = RGB {
+            r: Bitboard::from_square(Square::E4),
+            g: Bitboard::from_square(Square::E4),
+            b: Bitboard::from_square(Square::A1),
+        };
+        let (light, dark) = ("\x1b[48;2;60;60;60m  ", "\x1b[48;2;30;30;30m  ");
+        let yellow = "\x1b[48;2;255;255;0m  ";
+        let blue = "\x1b[48;2;0;0;255m  ";
+        let end = "\x1b[0m\n";
+        let unlit = |first: &str, second: &str| [first, second].repeat(4).concat() + end;
+        let rank_4 = [light, dark].repeat(2).concat() + yellow + dark + light + dark + end;
+        let rank_1 = blue.to_owned() + &[light, dark].repeat(3).concat() + light + end;
+        let expected = [
+            unlit(light, dark),
+            unlit(dark, light),
+            unlit(light, dark),
+            unlit(dark, light),
+            rank_4,
+            unlit(dark, light),
+            unlit(light, dark),
+            rank_1,
+        ]
+        .concat();
+        assert_eq!(render_led_grid(rgb), expected);
+    }
+
+    // the closest any point along `steps` comes to the centre of a square in
+    // `occupied`, sampled every twentieth of a square
+    fn clearance(steps: &[Step], occupied: Bitboard) -> f64 {
+        let centres: Vec<_> = occupied
+            .into_iter()
+            .map(|square| (file_to_float(square.file()), rank_to_float(square.rank())))
+            .collect();
+        let mut closest = f64::INFINITY;
+        for leg in steps.windows(2) {
+            for i in 0..=20 {
+                let t = f64::from(i) / 20.0;
+                let x = (leg[1].x - leg[0].x).mul_add(t, leg[0].x);
+                let y = (leg[1].y - leg[0].y).mul_add(t, leg[0].y);
+                for &(cx, cy) in &centres {
+                    closest = closest.min((x - cx).hypot(y - cy));
+                }
+            }
+        }
+        closest
+    }
+
+    #[test]
+    fn paths_run_clear_of_other_pieces() {
+        let geom = BoardGeometry::default();
+        let occupied = Chess::default().board().occupied();
+        let others = |from: Square, to: Square| {
+            occupied.without(Bitboard::from_square(from)).without(Bitboard::from_square(to))
+        };
+        // next door and nothing in the way, so straight there
+        let adjacent = path_between(Square::E2, Square::E3, occupied, &geom);
+        let adjacent: Vec<_> = adjacent.into_iter().map(point).collect();
+        assert_eq!(adjacent, [(5.0, 2.0, Magnet::Released), (5.0, 3.0, Magnet::Engaged)]);
+        // the queen boxed in on d1 out to h5 has to squeeze between pieces
+        let distant = path_between(Square::D1, Square::H5, occupied, &geom);
+        assert_eq!(point(distant[0]), (4.0, 1.0, Magnet::Released));
+        assert_eq!(point(*distant.last().unwrap()), (8.0, 5.0, Magnet::Engaged));
+        assert!(clearance(&distant, others(Square::D1, Square::H5)) >= 0.5 - f64::EPSILON);
+        // and the way back is as clear, for undoing it
+        let back = path_between(Square::H5, Square::D1, occupied, &geom);
+        assert_eq!(point(*back.last().unwrap()), (4.0, 1.0, Magnet::Engaged));
+        assert!(clearance(&back, others(Square::H5, Square::D1)) >= 0.5 - f64::EPSILON);
+    }
+
+    #[test]
+    fn idle_edge_shows_whose_move_it_is() {
+        let pos = Chess::default();
+        let theme = Theme::default();
+        let edge = Bitboard::from_rank(Rank::First)
+            .with(Bitboard::from_rank(Rank::Eighth))
+            .with(Bitboard::from_file(File::A))
+            .with(Bitboard::from_file(File::H));
+        // a green edge for the human, a blue one while the opponent thinks
+        let yours = get_rgb(&pos, State::Idle, &theme, false, None, Waiting::Human);
+        assert_eq!(yours, RGB { r: Bitboard::EMPTY, g: edge, b: Bitboard::EMPTY });
+        let theirs = get_rgb(&pos, State::Idle, &theme, false, None, Waiting::Opponent);
+        assert_eq!(theirs, RGB { r: Bitboard::EMPTY, g: Bitboard::EMPTY, b: edge });
+        // but neither once a piece is in hand
+        let lifted = State::FriendlyPU(Square::B1);
+        let lifted = get_rgb(&pos, lifted, &theme, false, None, Waiting::Human);
+        assert!(!lifted.g.contains(Square::H1));
+    }
+
+    #[test]
+    fn knight_on_a_central_square_previews_its_targets() {
+        let pos = position("4k3/3p1p2/2n3n1/8/6P1/5N2/8/4K3 w - - 0 1");
+        let threats = threat_preview(&pos, Square::F3, Square::E5);
+        // the black pieces it would fork, not its own pawn on g4
+        let expected = [Square::D7, Square::F7, Square::C6, Square::G6];
+        assert_eq!(threats, expected.into_iter().collect::<Bitboard>());
+        let theme = Theme::default();
+        let rgb = threat_preview_rgb(&pos, Square::F3, Square::E5, &theme);
+        // lit on top of the knight's destinations
+        let lifted = State::FriendlyPU(Square::F3);
+        let lifted = get_rgb(&pos, lifted, &theme, false, None, Waiting::Human);
+        let mut previewed = lifted;
+        paint(&mut previewed, threats, theme.threat);
+        assert_ne!(previewed, lifted);
+        assert_eq!(rgb, previewed);
+    }
+
+    #[test]
+    fn coach_flags_a_hung_queen_but_not_a_defended_one() {
+        let hanging = |fen: &str| {
+            let before = position(fen);
+            let mv = "Qh5".parse::<San>().unwrap().to_move(&before).unwrap();
+            hanging_piece(&before.play(&mv).unwrap(), &mv)
+        };
+        // the pawn on g6 takes it for free
+        assert_eq!(hanging("4k3/8/6p1/8/8/8/8/3QK3 w - - 0 1"), Some(Square::H5));
+        // unless the rook on h1 takes back
+        assert_eq!(hanging("4k3/8/6p1/8/8/8/8/3QK2R w - - 0 1"), None);
+    }
+
+    #[test]
+    fn capture_strategies_order_the_journeys() {
+        let pos = position("4k3/8/8/3p4/4P3/8/8/4K3 w - - 0 1");
+        let mv = "exd5".parse::<San>().unwrap().to_move(&pos).unwrap();
+        let ends = |strategy| {
+            let occupied = pos.board().occupied();
+            let geom = BoardGeometry::default();
+            move_to_journeys(mv.clone(), Color::White, 0.0, 0.0, occupied, &geom, strategy)
+                .iter()
+                .map(|journey| {
+                    let pickup = journey.pickup().unwrap();
+                    let drop_off = journey.drop_off().unwrap();
+                    ((pickup.x, pickup.y), (drop_off.x, drop_off.y))
+                })
+                .collect::<Vec<_>>()
+        };
+        // the pawn on d5 goes to the graveyard, then e4 takes its place
+        let graveyard = (9.0, 0.5);
+        assert_eq!(
+            ends(CaptureStrategy::ClearFirst),
+            [((4.0, 5.0), graveyard), ((5.0, 4.0), (4.0, 5.0))]
+        );
+        // e4 waits beside d5 while it's cleared, then steps on
+        assert_eq!(
+            ends(CaptureStrategy::StageFirst),
+            [((5.0, 4.0), (4.5, 4.5)), ((4.0, 5.0), graveyard), ((4.5, 4.5), (4.0, 5.0))]
+        );
+    }
+
+    #[test]
+    fn touch_move_holds_a_piece_that_can_move() {
+        let config = StateConfig {
+            touch_move: true,
+            ..StateConfig::default()
+        };
+        let pos = Chess::default();
+        let touch = |squares: &[Square]| {
+            squares.iter().fold((State::Idle, None), |(state, _), &square| {
+                next_state(&pos, square, state, config)
+            })
+        };
+        // once touched the knight still has to move after being put back
+        assert_eq!(touch(&[Square::B1, Square::B1]), (State::TouchMove(Square::B1), None));
+        let other = [Square::B1, Square::B1, Square::D2];
+        assert_eq!(touch(&other), (State::TouchMoveViolation(Square::B1, Square::D2), None));
+        let moved = [Square::B1, Square::B1, Square::D2, Square::D2, Square::B1, Square::C3];
+        let (state, mv) = touch(&moved);
+        assert_eq!(state, State::Idle);
+        assert_eq!(mv.map(|mv| mv.to()), Some(Square::C3));
+        // the rook has nowhere to go, so it can be put back freely
+        assert_eq!(touch(&[Square::A1, Square::A1]), (State::Idle, None));
+    }
+
+    #[test]
+    fn entering_error_dumps_the_instructions_before_it() {
+        let pos = Chess::default();
+        let mut trail = Trail::new(2);
+        let mut state = State::Idle;
+        let mut dumps = Vec::new();
+        // a pawn lifted, an enemy pawn it can't reach lifted, then a stray square
+        for square in [Square::E2, Square::E7, Square::D4] {
+            let (next, _) = next_state(&pos, square, state, StateConfig::default());
+            dumps.extend(trail.record(u32::from(square), state, next));
+            state = next;
+        }
+        assert_eq!(state, State::Error);
+        // only the last two fit, and only entering Error dumps them
+        assert_eq!(
+            dumps,
+            ["e7 FriendlyPU(E2) -> InvalidPiecePU(Some(E2), E7), \
+              d4 InvalidPiecePU(Some(E2), E7) -> Error"]
+        );
+        // staying in Error doesn't dump again
+        assert_eq!(trail.record(u32::from(Square::A1), State::Error, State::Error), None);
+    }
+
+    #[test]
+    fn home_after_move_adds_one_magnet_off_step_home() {
+        let geometry = BoardGeometry {
+            home: (9.0, 0.0),
+            ..BoardGeometry::default()
+        };
+        let pos = Chess::default();
+        let mv = "Nf3".parse::<San>().unwrap().to_move(&pos).unwrap();
+        let occupied = pos.board().occupied();
+        let strategy = CaptureStrategy::ClearFirst;
+        let steps = move_to_steps(mv, Color::White, 0.0, 0.0, occupied, &geometry, strategy);
+        let finish = |home_after_move| {
+            let config = GameConfig::builder()
+                .geometry(geometry)
+                .home_after_move(home_after_move)
+                .build();
+            finish_steps(steps.clone(), &config, None)
+        };
+        let (stays, homes) = (finish(false), finish(true));
+        assert_eq!(homes.len(), stays.len() + 1);
+        assert_eq!(point(*homes.last().unwrap()), (9.0, 0.0, Magnet::Released));
+        // the move itself is unchanged and still ends holding the knight on f3
+        assert_eq!(point(homes[homes.len() - 2]), (6.0, 3.0, Magnet::Engaged));
+    }
+
+    #[test]
+    fn en_passant_lights_the_pawn_taken_not_the_landing_square() {
+        // black has just played d7-d5 past the pawn on e5
+        let pos = position("4k3/8/8/3pP3/8/8/8/4K3 w - d6 0 1");
+        let theme = Theme::default();
+        let rgb = get_rgb(&pos, State::FriendlyPU(Square::E5), &theme, false, None, Waiting::Human);
+        let shade = |rgb: RGB, square| {
+            (rgb.r.contains(square), rgb.g.contains(square), rgb.b.contains(square))
+        };
+        let mut capture = RGB::default();
+        paint(&mut capture, Bitboard::FULL, theme.capture);
+        let capture = shade(capture, Square::A1);
+        assert_eq!(shade(rgb, Square::D5), capture);
+        assert_ne!(shade(rgb, Square::D6), capture);
+    }
+
+    #[test]
+    fn swap_promotion_waits_for_the_new_piece() {
+        let config = StateConfig {
+            swap_promotion: true,
+            ..StateConfig::default()
+        };
+        let pos = position("7k/1P6/8/8/8/8/8/K7 w - - 0 1");
+        let knight = choice_square(Square::B8, Role::Knight);
+        let mut state = State::Idle;
+        let mut steps = Vec::new();
+        // the knight picked, something else touched, the pawn off, the knight on
+        for square in [Square::B7, Square::B8, knight, Square::A1, Square::B8, Square::B8] {
+            let (next, mv) = next_state(&pos, square, state, config);
+            steps.push((next, mv));
+            state = next;
+        }
+        let swap = State::PromotionSwap(Square::B7, Square::B8, None, Role::Knight);
+        // nothing is committed until the knight stands on b8
+        assert_eq!(steps[2], (swap, None));
+        assert_eq!(steps[3], (swap, None));
+        let place = State::PromotionPlace(Square::B7, Square::B8, None, Role::Knight);
+        assert_eq!(steps[4], (place, None));
+        let knighted = promotion(Square::B7, Square::B8, None, Role::Knight);
+        assert_eq!(steps[5], (State::Idle, Some(knighted)));
+    }
+
+    #[test]
+    fn lone_event_on_an_empty_square_is_noise_unless_strict() {
+        let pos = Chess::default();
+        // a blip on e4, then e2-e4 played as normal
+        assert_eq!(play(&pos, &[Square::E4]), (State::Idle, None));
+        let (state, mv) = play(&pos, &[Square::E4, Square::E2, Square::E4]);
+        assert_eq!((state, mv.map(|mv| mv.to())), (State::Idle, Some(Square::E4)));
+        // an impossible sequence is still an error
+        assert_eq!(play(&pos, &[Square::E2, Square::E7, Square::D4]), (State::Error, None));
+        let strict = StateConfig {
+            strictness: Strictness::Strict,
+            ..StateConfig::default()
+        };
+        assert_eq!(next_state(&pos, Square::E4, State::Idle, strict), (State::Error, None));
+    }
+
+    #[test]
+    fn lone_blocker_is_parked_on_the_scratch_spot_and_brought_back() {
+        // the pawn on b2 is in the way of every route b1 to c3 could take
+        let pos = position("4k3/8/8/8/8/8/1P6/1N2K3 w - - 0 1");
+        let mv = "Nc3".parse::<San>().unwrap().to_move(&pos).unwrap();
+        let occupied = pos.board().occupied();
+        let journeys = |scratch| {
+            let geom = BoardGeometry {
+                scratch,
+                ..BoardGeometry::default()
+            };
+            let strategy = CaptureStrategy::ClearFirst;
+            move_to_journeys(mv.clone(), Color::White, 0.0, 0.0, occupied, &geom, strategy)
+                .iter()
+                .map(|journey| {
+                    let pickup = journey.pickup().unwrap();
+                    let drop_off = journey.drop_off().unwrap();
+                    ((pickup.x, pickup.y), (drop_off.x, drop_off.y))
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(journeys(None), [((2.0, 1.0), (3.0, 3.0))]);
+        let scratch = (9.0, 9.0);
+        // the scratch spot is empty again once the move is done
+        assert_eq!(
+            journeys(Some(scratch)),
+            [((2.0, 2.0), scratch), ((2.0, 1.0), (3.0, 3.0)), (scratch, (2.0, 2.0))]
+        );
+    }
+
+    #[test]
+    fn knight_in_the_centre_counts_its_legal_moves() {
+        // all eight squares from d4, taking the black pawn on e6 among them
+        let pos = position("4k3/8/4p3/8/3N4/8/8/4K3 w - - 0 1");
+        assert_eq!(legal_move_count(&pos, Square::D4), 8);
+        // a white pawn on f5 takes one away
+        let pos = position("4k3/8/8/5P2/3N4/8/8/4K3 w - - 0 1");
+        assert_eq!(legal_move_count(&pos, Square::D4), 7);
+        // and so does a pin, entirely
+        let pos = position("4k3/6b1/8/8/3N4/8/8/K7 w - - 0 1");
+        assert_eq!(legal_move_count(&pos, Square::D4), 0);
+    }
+
+    #[test]
+    fn config_file_fills_the_game_config_and_flags_win() {
+        let dir = std::env::temp_dir();
+        let path = dir.join(format!("flagfall-config-{}.toml", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        let settings = "human_color = \"black\"\n\
+                        time_control = \"5+3\"\n\
+                        engine = \"/usr/games/stockfish\"\n\
+                        engine_depth = 8\n\
+                        square_mapping = \"a8-row-major\"\n\
+                        magnet_wiring = \"0,1\"\n\
+                        [geometry]\n\
+                        square_mm = 40.0\n\
+                        max_x = 9.5\n";
+        std::fs::write(&path, settings).unwrap();
+        let args = ["--config", &path, "--human-color", "white"].map(String::from);
+        let config = game_config(&parse_args(args.into_iter()).unwrap()).unwrap();
+        // the flag given alongside the file wins
+        assert_eq!(config.human, Color::White);
+        assert_eq!(config.time_control, Some("5+3".parse().unwrap()));
+        assert_eq!(config.opponent, OpponentBackend::Uci("/usr/games/stockfish".to_string()));
+        assert_eq!(config.engine_strength.depth, Some(8));
+        assert_eq!(config.square_mapping, SquareMapping::A8Rows);
+        assert_eq!(config.magnet_wiring, "0,1".parse().unwrap());
+        let geometry = BoardGeometry {
+            square_mm: 40.0,
+            max_x: 9.5,
+            ..BoardGeometry::default()
+        };
+        assert_eq!(config.geometry, geometry);
+        // a bad value is reported against its key
+        std::fs::write(&path, "time_control = \"soon\"\n").unwrap();
+        let e = parse_args(["--config", &path].map(String::from).into_iter()).unwrap_err();
+        std::fs::remove_file(&path).unwrap();
+        assert!(e.contains("time_control"), "{e}");
+    }
+
+    #[test]
+    fn blindfold_keeps_the_leds_dark_but_still_narrates() {
+        let args = ["--blindfold"].map(String::from);
+        let config = game_config(&parse_args(args.into_iter()).unwrap()).unwrap();
+        // white has just played into check, with a piece lifted and teach hints on
+        let pos = position("rnb1kbnr/pppp1ppp/8/4p3/5P1q/8/PPPPP1PP/RNBQKBNR w KQkq - 1 3");
+        let before = position("rnbqkbnr/pppp1ppp/8/4p3/5P2/8/PPPPP1PP/RNBQKBNR b KQkq - 0 2");
+        let last = San::from_ascii(b"Qh4+").unwrap().to_move(&before).unwrap();
+        for state in [State::Idle, State::FriendlyPU(Square::G2), State::EnemyPU(Square::H4)] {
+            for waiting in [Waiting::Human, Waiting::Opponent] {
+                let rgb = get_rgb(&pos, state, &config.theme, true, Some(&last), waiting);
+                assert_eq!(rgb, RGB::default(), "{state:?}");
+            }
+        }
+        assert!(!crate::narrate::narrate(&before, &last).is_empty());
+    }
+}
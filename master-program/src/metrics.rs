@@ -0,0 +1,131 @@
+use serde::Serialize;
+use std::time::{Duration, Instant};
+
+use crate::State;
+
+// rough numbers for tuning the state machine, collected over one game
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Metrics {
+    // when the first piece of the human's current move left the board
+    pickup: Option<Instant>,
+    human_moves: u32,
+    detection: Duration,
+    opponent_moves: u32,
+    steps: usize,
+    invalid_excursions: u32,
+}
+
+// what Metrics adds up to, for the log and the telemetry stream
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Summary {
+    pub human_moves: u32,
+    // from the first pickup to the move being committed, fumbles included
+    pub average_detection_ms: Option<f64>,
+    pub opponent_moves: u32,
+    pub average_steps: Option<f64>,
+    pub invalid_excursions: u32,
+}
+
+impl std::fmt::Display for Summary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} human moves", self.human_moves)?;
+        if let Some(ms) = self.average_detection_ms {
+            write!(f, " detected in {ms:.0}ms on average")?;
+        }
+        write!(f, ", {} opponent moves", self.opponent_moves)?;
+        if let Some(steps) = self.average_steps {
+            write!(f, " of {steps:.1} steps on average")?;
+        }
+        write!(f, ", {} invalid states", self.invalid_excursions)
+    }
+}
+
+// states the human has to back out of before the game can go on
+const fn is_invalid(state: State) -> bool {
+    matches!(
+        state,
+        State::InvalidPiecePU(_, _)
+            | State::IllegalCastle(_, _)
+            | State::InvalidMove(_, _)
+            | State::CastlingMisplaced(_, _, _, _)
+            | State::TouchMoveViolation(_, _)
+            | State::UnexpectedOccupancy(_)
+            | State::MissingPiece(_)
+            | State::Error
+    )
+}
+
+impl Metrics {
+    pub fn transition(&mut self, from: State, to: State, now: Instant) {
+        if from == State::Idle && to != State::Idle && self.pickup.is_none() {
+            self.pickup = Some(now);
+        }
+        if is_invalid(to) && !is_invalid(from) {
+            self.invalid_excursions += 1;
+        }
+    }
+
+    pub fn human_moved(&mut self, now: Instant) {
+        self.human_moves += 1;
+        if let Some(pickup) = self.pickup.take() {
+            self.detection += now.saturating_duration_since(pickup);
+        }
+    }
+
+    pub const fn opponent_moved(&mut self, steps: usize) {
+        self.opponent_moves += 1;
+        self.steps += steps;
+    }
+
+    pub fn summary(&self) -> Summary {
+        let average = |total: f64, count: u32| (count > 0).then(|| total / f64::from(count));
+        #[allow(clippy::cast_precision_loss)]
+        let steps = self.steps as f64;
+        Summary {
+            human_moves: self.human_moves,
+            average_detection_ms: average(self.detection.as_secs_f64() * 1000.0, self.human_moves),
+            opponent_moves: self.opponent_moves,
+            average_steps: average(steps, self.opponent_moves),
+            invalid_excursions: self.invalid_excursions,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shakmaty::Square;
+
+    #[test]
+    fn counters_follow_pickups_moves_and_fumbles() {
+        let mut metrics = Metrics::default();
+        let start = Instant::now();
+        let lifted = State::FriendlyPU(Square::E2);
+        metrics.transition(State::Idle, lifted, start);
+        // a fumble counts once however long it lasts
+        let invalid = State::InvalidMove(Square::E2, Square::E5);
+        metrics.transition(lifted, invalid, start);
+        metrics.transition(invalid, State::Error, start);
+        metrics.transition(State::Error, lifted, start);
+        metrics.human_moved(start + Duration::from_millis(400));
+        metrics.opponent_moved(6);
+        metrics.opponent_moved(3);
+        assert_eq!(
+            metrics.summary(),
+            Summary {
+                human_moves: 1,
+                average_detection_ms: Some(400.0),
+                opponent_moves: 2,
+                average_steps: Some(4.5),
+                invalid_excursions: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn no_moves_gives_no_averages() {
+        let summary = Metrics::default().summary();
+        assert_eq!(summary.average_detection_ms, None);
+        assert_eq!(summary.average_steps, None);
+    }
+}
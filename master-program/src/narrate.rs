@@ -0,0 +1,86 @@
+use shakmaty::{san::San, CastlingSide, Chess, Move, Position, Role};
+
+// a spoken description of `mv` played from `before`, for a text-to-speech
+// layer, e.g. "white knight from g1 to f3, check"
+pub fn narrate(before: &Chess, mv: &Move) -> String {
+    let side = if before.turn().is_white() { "white" } else { "black" };
+    let mut phrase = match mv {
+        Move::Castle { .. } => match mv.castling_side() {
+            Some(CastlingSide::KingSide) => format!("{side} castles kingside"),
+            _ => format!("{side} castles queenside"),
+        },
+        Move::EnPassant { from, to } => {
+            format!("{side} pawn from {from} takes pawn on {to} en passant")
+        }
+        Move::Normal { role, from, capture, to, promotion } => {
+            let action = capture.map_or_else(
+                || format!("to {to}"),
+                |captured| format!("takes {} on {to}", role_name(captured)),
+            );
+            let promotes = promotion
+                .map(|role| format!(", promotes to {}", role_name(role)))
+                .unwrap_or_default();
+            format!("{side} {} from {from} {action}{promotes}", role_name(*role))
+        }
+        Move::Put { .. } => format!("{side} plays {}", San::from_move(before, mv)),
+    };
+
+    let mut after = before.clone();
+    after.play_unchecked(mv);
+    if after.is_checkmate() {
+        phrase.push_str(", checkmate");
+    } else if after.is_check() {
+        phrase.push_str(", check");
+    }
+    phrase
+}
+
+const fn role_name(role: Role) -> &'static str {
+    match role {
+        Role::Pawn => "pawn",
+        Role::Knight => "knight",
+        Role::Bishop => "bishop",
+        Role::Rook => "rook",
+        Role::Queen => "queen",
+        Role::King => "king",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shakmaty::{fen::Fen, CastlingMode};
+
+    fn said(fen: &str, san: &str) -> String {
+        let pos: Chess = fen.parse::<Fen>().unwrap().into_position(CastlingMode::Standard).unwrap();
+        let mv = san.parse::<San>().unwrap().to_move(&pos).unwrap();
+        narrate(&pos, &mv)
+    }
+
+    const START: &str = "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1";
+
+    #[test]
+    fn each_kind_of_move_is_described() {
+        assert_eq!(said(START, "Nf3"), "white knight from g1 to f3");
+        let exchange = "rnbqkbnr/ppp1pppp/8/3p4/4P3/8/PPPP1PPP/RNBQKBNR w KQkq - 0 2";
+        assert_eq!(said(exchange, "exd5"), "white pawn from e4 takes pawn on d5");
+        let castles = "r3k2r/8/8/8/8/8/8/R3K2R b KQkq - 0 1";
+        assert_eq!(said(castles, "O-O"), "black castles kingside");
+        assert_eq!(said(castles, "O-O-O"), "black castles queenside");
+        let promotes = "r6k/1P6/8/8/8/8/8/K7 w - - 0 1";
+        assert_eq!(
+            said(promotes, "bxa8=N"),
+            "white pawn from b7 takes rook on a8, promotes to knight"
+        );
+        let passant = "4k3/8/8/3pP3/8/8/8/4K3 w - d6 0 2";
+        assert_eq!(said(passant, "exd6"), "white pawn from e5 takes pawn on d6 en passant");
+    }
+
+    #[test]
+    fn check_and_mate_are_added_on_the_end() {
+        let check = "4k3/8/8/8/8/8/8/R3K3 w - - 0 1";
+        assert_eq!(said(check, "Ra8+"), "white rook from a1 to a8, check");
+        let mate = "6k1/5ppp/8/8/8/8/8/R3K3 w - - 0 1";
+        assert_eq!(said(mate, "Ra8#"), "white rook from a1 to a8, checkmate");
+    }
+}
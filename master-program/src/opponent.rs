@@ -0,0 +1,1220 @@
+use log::{error, info, warn};
+use rand::{rngs::StdRng, SeedableRng};
+use regex::Regex;
+use shakmaty::{
+    fen::Fen, san::San, uci::Uci, CastlingMode, Chess, EnPassantMode, Move, Position, Role, Square,
+};
+use std::io::{BufRead, BufReader, Cursor, ErrorKind, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::time::Duration;
+
+use crate::book::Book;
+use crate::clock::TimeLeft;
+use crate::config::EngineStrength;
+
+// how long a UCI engine gets to look at a position when we only want its evaluation
+const EVALUATION_MOVETIME_MS: u32 = 200;
+
+// how long a UCI engine gets to pick its reply, unless told otherwise
+const MOVE_MOVETIME_MS: u32 = 1000;
+
+// a mate score is reported as this many centipawns, so it always beats any threshold
+const MATE_CP: i32 = 100_000;
+
+// how many questions the opponent wrapper asks on boot
+const WRAPPER_PROMPTS: usize = 2;
+
+// how long the opponent wrapper may go quiet during its boot questions before we give up
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// how many times a dropped remote engine is dialled again, and how long apart
+const RECONNECT_ATTEMPTS: u32 = 5;
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+// a UCI engine takes a draw unless it thinks it is ahead by more than this
+const DRAW_ACCEPT_CP: i32 = 0;
+
+// whatever plays the other side of the board
+pub trait Opponent {
+    // the human's move, already played on the board; `before` is the position it was played from.
+    // the opponent should start on its reply straight away, best_move only collects it
+    fn human_moved(&mut self, before: &Chess, mv: &Move) -> std::io::Result<()>;
+
+    // the opponent's reply in `pos`, which it is to move in
+    fn best_move(&mut self, pos: &Chess) -> std::io::Result<Move>;
+
+    // the time both sides have left, told before human_moved and best_move
+    // whenever the game is played on the clock
+    fn clocks(&mut self, _time_left: TimeLeft) {}
+
+    // evaluation of `pos` in centipawns from white's point of view, if the opponent can give one
+    fn evaluate(&mut self, _pos: &Chess) -> std::io::Result<Option<i32>> {
+        Ok(None)
+    }
+
+    fn resign(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    // the human offers a draw (or takes up the opponent's offer) in `pos`; true if it's accepted
+    fn draw_offered(&mut self, _pos: &Chess) -> std::io::Result<bool> {
+        Ok(false)
+    }
+
+    // whether the opponent offered a draw along with its last move
+    fn offers_draw(&mut self) -> bool {
+        false
+    }
+
+    // the board the opponent said it was on before its last move, as a FEN,
+    // when that wasn't the board we're playing on
+    fn desync(&mut self) -> Option<String> {
+        None
+    }
+
+    // brings a freshly started opponent up to the game so far, `moves` having
+    // been played from the starting position it was started on
+    fn resume(&mut self, _moves: &[Move]) -> std::io::Result<()> {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "this opponent can't pick up a game part way through",
+        ))
+    }
+
+    // forgets the game so far and starts over from the starting position
+    fn new_game(&mut self) -> std::io::Result<()> {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "this opponent can't start a new game",
+        ))
+    }
+
+    fn quit(&mut self);
+}
+
+fn invalid_data(message: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message)
+}
+
+struct Process {
+    // None when the streams don't belong to a child process of ours
+    child: Option<Child>,
+    stdin: Box<dyn Write + Send>,
+    // filled by a reader thread, so output is taken in as soon as it is written
+    // even while we're busy with the board
+    stdout: Receiver<String>,
+}
+
+impl Process {
+    fn spawn(command: &mut Command) -> std::io::Result<Self> {
+        let mut child = command.stdin(Stdio::piped()).stdout(Stdio::piped()).spawn()?;
+        let stdin = child.stdin.take().unwrap();
+        let stdout = child.stdout.take().unwrap();
+        let mut process = Self::connect(stdout, stdin);
+        process.child = Some(child);
+        Ok(process)
+    }
+
+    // `output` is what the other side writes, `input` what it reads
+    fn connect(output: impl Read + Send + 'static, input: impl Write + Send + 'static) -> Self {
+        let lines = BufReader::new(output).lines();
+        let (sender, stdout) = mpsc::channel();
+        std::thread::spawn(move || {
+            for line in lines.map_while(Result::ok) {
+                if sender.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+        Self {
+            child: None,
+            stdin: Box::new(input),
+            stdout,
+        }
+    }
+
+    fn send_line(&mut self, line: &str) -> std::io::Result<()> {
+        writeln!(self.stdin, "{line}")?;
+        self.stdin.flush()
+    }
+
+    fn recv_line(&self) -> std::io::Result<String> {
+        self.stdout.recv().map_err(|_| {
+            std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "opponent process closed its output",
+            )
+        })
+    }
+
+    fn wait(&mut self) {
+        let Some(child) = self.child.as_mut() else {
+            return;
+        };
+        match child.wait() {
+            Ok(status) => info!("opponent exited with status {status}"),
+            Err(e) => error!("Failed to wait for opponent: {e}"),
+        }
+    }
+}
+
+// the opponent-wrapper program, which speaks SAN one move per line. a draw
+// is offered to it as "draw?", answered with "accept" or anything else, and
+// it offers one by sending "draw?" just before its move. it may also echo the
+// board it thinks it's on as "fen <FEN>" before its move, which is checked
+// against ours
+pub struct Wrapper {
+    process: Process,
+    offered_draw: bool,
+    desync: Option<String>,
+}
+
+impl Wrapper {
+    // `answers` go to the boot questions first, so an unattended board can
+    // start with no keyboard. the console is only read once they run out
+    pub fn spawn(path: &str, prompt: &Regex, answers: &[String]) -> std::io::Result<Self> {
+        let mut process = Process::spawn(Command::new(path).arg("-e"))?;
+        handshake(&mut process, prompt, preset_answers(answers).chain(std::io::stdin().lock()))?;
+        Ok(Self {
+            process,
+            offered_draw: false,
+            desync: None,
+        })
+    }
+
+    // an opponent wrapper on the other end of `output` and `input` that is
+    // already past its boot questions, e.g. a scripted one
+    pub fn connect(output: impl Read + Send + 'static, input: impl Write + Send + 'static) -> Self {
+        Self {
+            process: Process::connect(output, input),
+            offered_draw: false,
+            desync: None,
+        }
+    }
+}
+
+// one answer a line, the way they'd be typed at the console
+fn preset_answers(answers: &[String]) -> Cursor<String> {
+    Cursor::new(answers.iter().flat_map(|answer| [answer.as_str(), "\n"]).collect())
+}
+
+// the opponent wrapper asks its boot questions on stdout, we need to pipe them
+// through and pipe the responses back. anything else it prints first (a
+// banner, blank lines) is passed through unanswered, only lines matching
+// `prompt` get one of `answers`
+fn handshake(process: &mut Process, prompt: &Regex, mut answers: impl BufRead) -> std::io::Result<()> {
+    let mut seen = Vec::new();
+    let mut answered = 0;
+    while answered < WRAPPER_PROMPTS {
+        let line = match process.stdout.recv_timeout(HANDSHAKE_TIMEOUT) {
+            Ok(line) => line,
+            Err(RecvTimeoutError::Timeout) => {
+                error!("opponent wrapper went quiet after {answered} prompts, it said {seen:?}");
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::TimedOut,
+                    format!("no line matching {prompt} within {HANDSHAKE_TIMEOUT:?}"),
+                ));
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                error!("opponent wrapper exited after {answered} prompts, it said {seen:?}");
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::UnexpectedEof,
+                    "opponent wrapper closed its output during the handshake",
+                ));
+            }
+        };
+        println!("{line}");
+        if prompt.is_match(&line) {
+            let mut response = String::new();
+            if answers.read_line(&mut response)? == 0 {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::UnexpectedEof,
+                    format!("no answer for {line:?}"),
+                ));
+            }
+            process.send_line(response.trim_end())?;
+            answered += 1;
+        } else if !line.trim().is_empty() {
+            info!("opponent wrapper said {line:?} before its prompts");
+        }
+        seen.push(line);
+    }
+    Ok(())
+}
+
+impl Opponent for Wrapper {
+    fn human_moved(&mut self, before: &Chess, mv: &Move) -> std::io::Result<()> {
+        let move_san = San::from_move(before, mv).to_string();
+        info!("sending move {move_san} to opponent wrapper");
+        self.process.send_line(&move_san)
+    }
+
+    fn best_move(&mut self, pos: &Chess) -> std::io::Result<Move> {
+        loop {
+            let line = self.process.recv_line()?;
+            let line = line.trim();
+            if line == "draw?" {
+                self.offered_draw = true;
+            } else if let Some(fen) = line.strip_prefix("fen ") {
+                if !same_board(pos, fen) {
+                    self.desync = Some(fen.to_string());
+                }
+            } else {
+                return parse_opponent_move(pos, line).map_err(invalid_data);
+            }
+        }
+    }
+
+    fn draw_offered(&mut self, _pos: &Chess) -> std::io::Result<bool> {
+        self.process.send_line("draw?")?;
+        Ok(self.process.recv_line()?.trim() == "accept")
+    }
+
+    fn offers_draw(&mut self) -> bool {
+        std::mem::take(&mut self.offered_draw)
+    }
+
+    fn desync(&mut self) -> Option<String> {
+        self.desync.take()
+    }
+
+    fn resign(&mut self) -> std::io::Result<()> {
+        self.process.send_line("resign")
+    }
+
+    fn quit(&mut self) {
+        // wait for opponent wrapper to finish
+        self.process.wait();
+    }
+}
+
+// a chess engine spoken to directly over UCI
+pub struct UciEngine {
+    process: Process,
+    start_fen: String,
+    // how castles are written, king takes rook in a chess960 game
+    castling: CastlingMode,
+    moves: Vec<String>,
+    // a search for the reply was started when the human moved and hasn't been read yet
+    searching: bool,
+    // the score and best move of a finished search that best_move hasn't used yet
+    pondered: Option<(Option<i32>, String)>,
+    // the go command for the engine's replies
+    go: String,
+    // with no depth or think time asked for, a game on the clock has the
+    // engine budget its own time from what's left
+    paced: bool,
+    time_left: Option<TimeLeft>,
+    // replies are taken from the book for the first `book_moves` moves, or
+    // for as long as it has them if that's None
+    book: Option<Book>,
+    book_moves: Option<u32>,
+    book_played: u32,
+    // a book reply already picked when the human moved, so no search was started
+    booked: Option<Move>,
+    // picks among book moves. with a seed it starts over from it each game,
+    // so a seeded game replays the same
+    seed: Option<u64>,
+    rng: StdRng,
+}
+
+impl UciEngine {
+    pub fn spawn(path: &str, start: &Chess, strength: EngineStrength) -> std::io::Result<Self> {
+        Self::start(Process::spawn(&mut Command::new(path))?, start, strength)
+    }
+
+    // an engine on the other end of `output` and `input`, e.g. a scripted one
+    pub fn connect(
+        output: impl Read + Send + 'static,
+        input: impl Write + Send + 'static,
+        start: &Chess,
+        strength: EngineStrength,
+    ) -> std::io::Result<Self> {
+        Self::start(Process::connect(output, input), start, strength)
+    }
+
+    fn start(process: Process, start: &Chess, strength: EngineStrength) -> std::io::Result<Self> {
+        let mut engine = Self {
+            process,
+            start_fen: Fen::from_position(start.clone(), EnPassantMode::Legal).to_string(),
+            castling: start.castles().mode(),
+            moves: Vec::new(),
+            searching: false,
+            pondered: None,
+            go: go_command(strength),
+            paced: strength.depth.is_none() && strength.movetime_ms.is_none(),
+            time_left: None,
+            book: None,
+            book_moves: None,
+            book_played: 0,
+            booked: None,
+            seed: None,
+            rng: StdRng::from_entropy(),
+        };
+        engine.process.send_line("uci")?;
+        // the engine lists its options before uciok
+        let mut has_skill = false;
+        let mut has_chess960 = false;
+        loop {
+            let line = engine.process.recv_line()?;
+            match line.trim() {
+                "uciok" => break,
+                option => {
+                    has_skill |= option.starts_with("option name Skill Level ");
+                    has_chess960 |= option.starts_with("option name UCI_Chess960 ");
+                }
+            }
+        }
+        if engine.castling == CastlingMode::Chess960 {
+            if has_chess960 {
+                engine.process.send_line("setoption name UCI_Chess960 value true")?;
+            } else {
+                warn!("the engine has no UCI_Chess960 option, it may not follow the castles");
+            }
+        }
+        if let Some(skill) = strength.skill {
+            if has_skill {
+                engine.process.send_line(&format!("setoption name Skill Level value {skill}"))?;
+            } else {
+                warn!("the engine has no Skill Level option, ignoring --engine-skill");
+            }
+        }
+        engine.process.send_line("ucinewgame")?;
+        engine.process.send_line("isready")?;
+        engine.wait_for("readyok")?;
+        Ok(engine)
+    }
+
+    pub fn book(mut self, book: Book, moves: Option<u32>) -> Self {
+        self.book = Some(book);
+        self.book_moves = moves;
+        self
+    }
+
+    pub fn seed(mut self, seed: Option<u64>) -> Self {
+        self.seed = seed;
+        self.rng = seed.map_or_else(StdRng::from_entropy, StdRng::seed_from_u64);
+        self
+    }
+
+    fn book_move(&mut self, pos: &Chess) -> Option<Move> {
+        if self.book_moves.is_some_and(|moves| self.book_played >= moves) {
+            return None;
+        }
+        let mv = self.book.as_ref()?.pick(pos, &mut self.rng)?;
+        self.book_played += 1;
+        Some(mv)
+    }
+
+    fn wait_for(&self, token: &str) -> std::io::Result<()> {
+        while self.process.recv_line()?.trim() != token {}
+        Ok(())
+    }
+
+    fn send_position(&mut self) -> std::io::Result<()> {
+        let mut command = format!("position fen {}", self.start_fen);
+        if !self.moves.is_empty() {
+            command.push_str(" moves ");
+            command.push_str(&self.moves.join(" "));
+        }
+        self.process.send_line(&command)
+    }
+
+    fn start_search(&mut self, go: &str) -> std::io::Result<()> {
+        self.send_position()?;
+        self.process.send_line(go)?;
+        self.searching = true;
+        Ok(())
+    }
+
+    // waits for the running search and returns the last reported score (side to move's view) and the best move
+    fn finish_search(&mut self) -> std::io::Result<(Option<i32>, String)> {
+        self.searching = false;
+        let mut score = None;
+        loop {
+            let line = self.process.recv_line()?;
+            let mut tokens = line.split_whitespace();
+            match tokens.next() {
+                Some("info") => score = parse_score(&line).or(score),
+                Some("bestmove") => {
+                    let best = tokens.next().unwrap_or_default().to_string();
+                    return Ok((score, best));
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn reply_go(&self) -> String {
+        match self.time_left {
+            Some(time_left) if self.paced => time_left.go_command(),
+            _ => self.go.clone(),
+        }
+    }
+
+    fn record(&mut self, mv: &Move) {
+        self.moves.push(Uci::from_move(mv, self.castling).to_string());
+    }
+}
+
+impl Opponent for UciEngine {
+    fn human_moved(&mut self, before: &Chess, mv: &Move) -> std::io::Result<()> {
+        self.record(mv);
+        self.pondered = None;
+        if self.book.is_some() {
+            let after = before.clone().play(mv).map_err(|e| invalid_data(e.to_string()))?;
+            self.booked = self.book_move(&after);
+            if self.booked.is_some() {
+                return Ok(());
+            }
+        }
+        let go = self.reply_go();
+        self.start_search(&go)
+    }
+
+    fn best_move(&mut self, pos: &Chess) -> std::io::Result<Move> {
+        // with the engine to move first there was no human move to pick one on
+        let booked = match self.booked.take() {
+            Some(mv) => Some(mv),
+            None if !self.searching => self.book_move(pos),
+            None => None,
+        };
+        if let Some(mv) = booked {
+            info!("playing {mv} from the opening book");
+            self.record(&mv);
+            return Ok(mv);
+        }
+        let (_, best) = match self.pondered.take() {
+            Some(pondered) => pondered,
+            None if self.searching => self.finish_search()?,
+            None => {
+                let go = self.reply_go();
+                self.start_search(&go)?;
+                self.finish_search()?
+            }
+        };
+        let uci: Uci = best
+            .parse()
+            .map_err(|_| invalid_data(format!("{best} is not a UCI move")))?;
+        let mv = uci
+            .to_move(pos)
+            .map_err(|_| invalid_data(format!("{best} is not a legal move")))?;
+        self.record(&mv);
+        Ok(mv)
+    }
+
+    fn clocks(&mut self, time_left: TimeLeft) {
+        self.time_left = Some(time_left);
+    }
+
+    fn evaluate(&mut self, pos: &Chess) -> std::io::Result<Option<i32>> {
+        // the search for our reply already looks at this position, so reuse it
+        let score = if self.searching {
+            let (score, best) = self.finish_search()?;
+            self.pondered = Some((score, best));
+            score
+        } else {
+            self.start_search(&format!("go movetime {EVALUATION_MOVETIME_MS}"))?;
+            self.finish_search()?.0
+        };
+        Ok(score.map(|cp| if pos.turn().is_white() { cp } else { -cp }))
+    }
+
+    fn draw_offered(&mut self, pos: &Chess) -> std::io::Result<bool> {
+        // offers come in on the human's turn, so the engine is the side not to move
+        let Some(white_cp) = self.evaluate(pos)? else {
+            return Ok(false);
+        };
+        let engine_cp = if pos.turn().is_white() { -white_cp } else { white_cp };
+        Ok(engine_cp <= DRAW_ACCEPT_CP)
+    }
+
+    fn resume(&mut self, moves: &[Move]) -> std::io::Result<()> {
+        self.moves.clear();
+        for mv in moves {
+            self.record(mv);
+        }
+        self.searching = false;
+        self.pondered = None;
+        self.booked = None;
+        // every search sends the whole game anyway, this just has the engine
+        // take it in before it's needed
+        self.send_position()?;
+        self.process.send_line("isready")?;
+        self.wait_for("readyok")
+    }
+
+    fn new_game(&mut self) -> std::io::Result<()> {
+        // a search left over from the old game would answer the new one's first go
+        if self.searching {
+            self.process.send_line("stop")?;
+            self.finish_search()?;
+        }
+        self.moves.clear();
+        self.pondered = None;
+        self.booked = None;
+        self.book_played = 0;
+        if let Some(seed) = self.seed {
+            self.rng = StdRng::seed_from_u64(seed);
+        }
+        self.process.send_line("ucinewgame")?;
+        self.process.send_line("isready")?;
+        self.wait_for("readyok")
+    }
+
+    fn quit(&mut self) {
+        if let Err(e) = self.process.send_line("quit") {
+            error!("Failed to ask engine to quit: {e}");
+        }
+        self.process.wait();
+    }
+}
+
+// a UCI engine running on another machine, spoken to over a TCP connection
+// to `address` (host:port) rather than a child process's pipes. the engine
+// may be restarted or the network may blip, so when the connection drops it's
+// dialled again and the game so far replayed before carrying on
+pub struct RemoteEngine {
+    address: String,
+    start: Chess,
+    strength: EngineStrength,
+    engine: UciEngine,
+    // our handle on the connection, to hang it up
+    stream: TcpStream,
+    moves: Vec<Move>,
+    time_left: Option<TimeLeft>,
+}
+
+impl RemoteEngine {
+    pub fn connect(
+        address: &str,
+        start: &Chess,
+        strength: EngineStrength,
+    ) -> std::io::Result<Self> {
+        let (engine, stream) = Self::dial(address, start, strength)?;
+        Ok(Self {
+            address: address.to_string(),
+            start: start.clone(),
+            strength,
+            engine,
+            stream,
+            moves: Vec::new(),
+            time_left: None,
+        })
+    }
+
+    fn dial(
+        address: &str,
+        start: &Chess,
+        strength: EngineStrength,
+    ) -> std::io::Result<(UciEngine, TcpStream)> {
+        let stream = TcpStream::connect(address)?;
+        let engine = UciEngine::connect(stream.try_clone()?, stream.try_clone()?, start, strength)?;
+        Ok((engine, stream))
+    }
+
+    // a new connection in place of the dropped one, caught up on every move so far
+    fn reconnect(&mut self, dropped: &std::io::Error) -> std::io::Result<()> {
+        warn!("lost the remote engine at {}: {dropped}, reconnecting", self.address);
+        let _ = self.stream.shutdown(Shutdown::Both);
+        let mut attempts = 0;
+        let (engine, stream) = loop {
+            attempts += 1;
+            match Self::dial(&self.address, &self.start, self.strength) {
+                Ok(dialled) => break dialled,
+                Err(e) if attempts < RECONNECT_ATTEMPTS => {
+                    warn!("Failed to reconnect to {}: {e}, trying again", self.address);
+                    std::thread::sleep(RECONNECT_DELAY);
+                }
+                Err(e) => return Err(e),
+            }
+        };
+        self.engine = engine;
+        self.stream = stream;
+        if let Some(time_left) = self.time_left {
+            self.engine.clocks(time_left);
+        }
+        self.engine.resume(&self.moves)?;
+        info!("reconnected to {} and replayed {} moves", self.address, self.moves.len());
+        Ok(())
+    }
+
+    // runs `request` on the engine, and once more on a fresh connection if
+    // the old one turns out to have dropped
+    fn retry<T>(
+        &mut self,
+        mut request: impl FnMut(&mut UciEngine) -> std::io::Result<T>,
+    ) -> std::io::Result<T> {
+        match request(&mut self.engine) {
+            Err(e) if dropped(&e) => {
+                self.reconnect(&e)?;
+                request(&mut self.engine)
+            }
+            result => result,
+        }
+    }
+}
+
+// whether `e` means the other end went away, as opposed to it saying something wrong
+fn dropped(e: &std::io::Error) -> bool {
+    matches!(
+        e.kind(),
+        ErrorKind::UnexpectedEof
+            | ErrorKind::BrokenPipe
+            | ErrorKind::ConnectionReset
+            | ErrorKind::ConnectionAborted
+            | ErrorKind::NotConnected
+    )
+}
+
+impl Opponent for RemoteEngine {
+    fn human_moved(&mut self, before: &Chess, mv: &Move) -> std::io::Result<()> {
+        self.moves.push(mv.clone());
+        match self.engine.human_moved(before, mv) {
+            // the replayed game has the move in it, and best_move starts the search
+            Err(e) if dropped(&e) => self.reconnect(&e),
+            result => result,
+        }
+    }
+
+    fn best_move(&mut self, pos: &Chess) -> std::io::Result<Move> {
+        let mv = self.retry(|engine| engine.best_move(pos))?;
+        self.moves.push(mv.clone());
+        Ok(mv)
+    }
+
+    fn clocks(&mut self, time_left: TimeLeft) {
+        self.time_left = Some(time_left);
+        self.engine.clocks(time_left);
+    }
+
+    fn evaluate(&mut self, pos: &Chess) -> std::io::Result<Option<i32>> {
+        self.retry(|engine| engine.evaluate(pos))
+    }
+
+    fn draw_offered(&mut self, pos: &Chess) -> std::io::Result<bool> {
+        self.retry(|engine| engine.draw_offered(pos))
+    }
+
+    fn resume(&mut self, moves: &[Move]) -> std::io::Result<()> {
+        self.moves = moves.to_vec();
+        self.retry(|engine| engine.resume(moves))
+    }
+
+    fn new_game(&mut self) -> std::io::Result<()> {
+        self.moves.clear();
+        self.retry(UciEngine::new_game)
+    }
+
+    fn quit(&mut self) {
+        self.engine.quit();
+        let _ = self.stream.shutdown(Shutdown::Both);
+    }
+}
+
+// the empty chair of a game between two humans: both sides move by hand, so
+// it's never asked for a move, and a draw offered over the board is taken as
+// agreed between the two players
+pub struct Nobody;
+
+impl Opponent for Nobody {
+    fn human_moved(&mut self, _before: &Chess, _mv: &Move) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn best_move(&mut self, _pos: &Chess) -> std::io::Result<Move> {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "there's no opponent, both sides move by hand",
+        ))
+    }
+
+    fn draw_offered(&mut self, _pos: &Chess) -> std::io::Result<bool> {
+        Ok(true)
+    }
+
+    fn resume(&mut self, _moves: &[Move]) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn new_game(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn quit(&mut self) {}
+}
+
+// a fixed think time unless a depth or a different time was asked for
+fn go_command(strength: EngineStrength) -> String {
+    match (strength.depth, strength.movetime_ms) {
+        (None, None) => format!("go movetime {MOVE_MOVETIME_MS}"),
+        (Some(depth), None) => format!("go depth {depth}"),
+        (None, Some(movetime)) => format!("go movetime {movetime}"),
+        (Some(depth), Some(movetime)) => format!("go depth {depth} movetime {movetime}"),
+    }
+}
+
+// pulls `score cp N` or `score mate N` out of a UCI info line
+fn parse_score(line: &str) -> Option<i32> {
+    let mut tokens = line.split_whitespace().skip_while(|&token| token != "score").skip(1);
+    let kind = tokens.next()?;
+    let value: i32 = tokens.next()?.parse().ok()?;
+    match kind {
+        "cp" => Some(value),
+        "mate" if value >= 0 => Some(MATE_CP),
+        "mate" => Some(-MATE_CP),
+        _ => None,
+    }
+}
+
+// wrappers don't agree on casing (E2E4, nf3, o-o), so coordinate moves are
+// read as UCI and SAN that doesn't parse as sent is tried again respelled
+// with lowercase squares and uppercase piece letters. a leading b is read as
+// a pawn on the b file before it is read as a bishop, unless it was a B
+fn parse_opponent_move(pos: &Chess, text: &str) -> Result<Move, String> {
+    let lower = text.to_ascii_lowercase();
+    if let Ok(uci) = lower.parse::<Uci>() {
+        return uci.to_move(pos).map_err(|_| format!("{text} is not a legal move here"));
+    }
+    if let Ok(Ok(mv)) = text.parse::<San>().map(|san| san.to_move(pos)) {
+        return Ok(mv);
+    }
+
+    let spellings = if lower.starts_with("o-o") || lower.starts_with("0-0") {
+        vec![lower.replace(['o', '0'], "O")]
+    } else {
+        match text.chars().next() {
+            Some('b') => vec![respell_san(&lower, false), respell_san(&lower, true)],
+            Some('B') => vec![respell_san(&lower, true), respell_san(&lower, false)],
+            Some('k' | 'K' | 'q' | 'Q' | 'r' | 'R' | 'n' | 'N') => vec![respell_san(&lower, true)],
+            _ => vec![respell_san(&lower, false)],
+        }
+    };
+    let mut error = format!("{text} is not a move");
+    for spelling in spellings {
+        match parse_loose_san(pos, &spelling) {
+            Ok(mv) => return Ok(mv),
+            Err(e) => error = e,
+        }
+    }
+    Err(error)
+}
+
+// whether `fen` has the same pieces, side to move and castling rights as `pos`;
+// the move counters and en passant square are left out, since wrappers don't
+// agree on those. it's read the way `pos` castles, so a chess960 FEN compares
+fn same_board(pos: &Chess, fen: &str) -> bool {
+    let theirs: Option<Chess> = fen
+        .parse::<Fen>()
+        .ok()
+        .and_then(|fen| fen.into_position(pos.castles().mode()).ok());
+    theirs.is_some_and(|theirs| {
+        theirs.board() == pos.board()
+            && theirs.turn() == pos.turn()
+            && theirs.castles().castling_rights() == pos.castles().castling_rights()
+    })
+}
+
+// `lower` with its promotion piece, and its first letter if `piece`, in uppercase
+fn respell_san(lower: &str, piece: bool) -> String {
+    let (body, promotion) = lower.split_once('=').unwrap_or((lower, ""));
+    let mut spelling = String::new();
+    let mut chars = body.chars();
+    if piece {
+        spelling.extend(chars.next().map(|c| c.to_ascii_uppercase()));
+    }
+    spelling.extend(chars);
+    if !promotion.is_empty() {
+        spelling.push('=');
+        spelling.push_str(&promotion.to_ascii_uppercase());
+    }
+    spelling
+}
+
+// some wrappers leave out disambiguation or add decorations shakmaty won't
+// accept, so when strict SAN fails fall back to matching the piece, the
+// destination and any promotion against the legal moves
+fn parse_loose_san(pos: &Chess, text: &str) -> Result<Move, String> {
+    if let Ok(Ok(mv)) = text.parse::<San>().map(|san| san.to_move(pos)) {
+        return Ok(mv);
+    }
+
+    let trimmed = text.trim_end_matches(['+', '#', '!', '?']);
+    let (body, promotion) = match trimmed.rsplit_once('=') {
+        Some((body, role)) => (body, Some(role)),
+        None => (trimmed, None),
+    };
+    let promotion = match promotion {
+        Some(role) => Some(
+            role.chars()
+                .next()
+                .and_then(|c| Role::from_char(c.to_ascii_lowercase()))
+                .ok_or_else(|| format!("{text} has no promotion piece after ="))?,
+        ),
+        None => None,
+    };
+    let role = body
+        .chars()
+        .next()
+        .filter(char::is_ascii_uppercase)
+        .and_then(|c| Role::from_char(c.to_ascii_lowercase()))
+        .unwrap_or(Role::Pawn);
+    let to: Square = body
+        .get(body.len().saturating_sub(2)..)
+        .and_then(|square| square.parse().ok())
+        .ok_or_else(|| format!("{text} is not a move"))?;
+
+    let mut candidates = pos
+        .legal_moves()
+        .into_iter()
+        .filter(|mv| mv.role() == role && mv.to() == to && mv.promotion() == promotion);
+    match (candidates.next(), candidates.next()) {
+        (Some(mv), None) => Ok(mv),
+        (None, _) => Err(format!("{text} does not match any legal move")),
+        (Some(_), Some(_)) => Err(format!("{text} is ambiguous between several legal moves")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::Shared;
+
+    fn after_e4() -> Chess {
+        let e4 = "e2e4".parse::<Uci>().unwrap().to_move(&Chess::default()).unwrap();
+        Chess::default().play(&e4).unwrap()
+    }
+
+    #[test]
+    fn wrapper_fen_matching_the_board_is_no_desync() {
+        let fen = "rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq - 0 1";
+        let said = Cursor::new(format!("fen {fen}\ne5\n"));
+        let mut wrapper = Wrapper::connect(said, std::io::sink());
+        let mv = wrapper.best_move(&after_e4()).unwrap();
+        assert_eq!(mv.to(), Square::E5);
+        assert_eq!(wrapper.desync(), None);
+    }
+
+    #[test]
+    fn wrapper_fen_off_the_board_is_a_desync() {
+        // the wrapper thinks white played d4
+        let fen = "rnbqkbnr/pppppppp/8/8/3P4/8/PPP1PPPP/RNBQKBNR b KQkq - 0 1";
+        let said = Cursor::new(format!("fen {fen}\ne5\n"));
+        let mut wrapper = Wrapper::connect(said, std::io::sink());
+        let mv = wrapper.best_move(&after_e4()).unwrap();
+        assert_eq!(mv.to(), Square::E5);
+        assert_eq!(wrapper.desync().as_deref(), Some(fen));
+        // reported once
+        assert_eq!(wrapper.desync(), None);
+    }
+
+    const START: &str = "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1";
+
+    fn uci_moves(moves: &[&str]) -> Vec<Move> {
+        let mut pos = Chess::default();
+        moves
+            .iter()
+            .map(|uci| {
+                let mv = uci.parse::<Uci>().unwrap().to_move(&pos).unwrap();
+                pos.play_unchecked(&mv);
+                mv
+            })
+            .collect()
+    }
+
+    #[test]
+    fn resumed_engine_is_sent_the_game_so_far() {
+        let said = Cursor::new("uciok\nreadyok\nreadyok\n");
+        let heard = Shared::default();
+        let strength = EngineStrength::default();
+        let mut engine =
+            UciEngine::connect(said, heard.clone(), &Chess::default(), strength).unwrap();
+        engine.resume(&uci_moves(&["e2e4", "e7e5", "g1f3"])).unwrap();
+        let text = heard.text();
+        let mut lines = text.lines().rev();
+        assert_eq!(lines.next(), Some("isready"));
+        let position = format!("position fen {START} moves e2e4 e7e5 g1f3");
+        assert_eq!(lines.next(), Some(position.as_str()));
+    }
+
+    #[test]
+    fn go_command_follows_the_depth_and_movetime_asked_for() {
+        let go = |depth, movetime_ms| {
+            go_command(EngineStrength {
+                depth,
+                movetime_ms,
+                skill: None,
+            })
+        };
+        assert_eq!(go(None, None), format!("go movetime {MOVE_MOVETIME_MS}"));
+        assert_eq!(go(Some(8), None), "go depth 8");
+        assert_eq!(go(None, Some(250)), "go movetime 250");
+        assert_eq!(go(Some(8), Some(250)), "go depth 8 movetime 250");
+    }
+
+    #[test]
+    fn skill_is_set_only_on_engines_that_have_it() {
+        let strength = EngineStrength {
+            skill: Some(3),
+            ..EngineStrength::default()
+        };
+        let skill = "option name Skill Level type spin default 20 min 0 max 20";
+        let said = Cursor::new(format!("{skill}\nuciok\nreadyok\n"));
+        let heard = Shared::default();
+        UciEngine::connect(said, heard.clone(), &Chess::default(), strength).unwrap();
+        assert!(heard.text().contains("setoption name Skill Level value 3\n"));
+        let said = Cursor::new("uciok\nreadyok\n");
+        let heard = Shared::default();
+        UciEngine::connect(said, heard.clone(), &Chess::default(), strength).unwrap();
+        assert!(!heard.text().contains("setoption"));
+    }
+
+    // plays a UCI engine on `stream`, answering each go with the next of
+    // `best`, or hanging up on it once they run out. returns every line it was sent
+    fn serve(stream: TcpStream, best: &[&str]) -> Vec<String> {
+        let mut reply = stream.try_clone().unwrap();
+        let mut heard = Vec::new();
+        let mut best = best.iter();
+        for line in BufReader::new(stream).lines().map_while(Result::ok) {
+            heard.push(line.clone());
+            let answer = match line.split_whitespace().next() {
+                Some("uci") => "uciok".to_string(),
+                Some("isready") => "readyok".to_string(),
+                Some("go") => match best.next() {
+                    Some(best) => format!("bestmove {best}"),
+                    None => break,
+                },
+                Some("quit") => break,
+                _ => continue,
+            };
+            writeln!(reply, "{answer}").unwrap();
+        }
+        heard
+    }
+
+    #[test]
+    fn remote_engine_plays_the_moves_its_server_sends() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server =
+            std::thread::spawn(move || serve(listener.accept().unwrap().0, &["e7e5", "b8c6"]));
+        let mut pos = Chess::default();
+        let mut remote = RemoteEngine::connect(&address, &pos, EngineStrength::default()).unwrap();
+        for (human, reply) in [("e2e4", "e7e5"), ("g1f3", "b8c6")] {
+            let mv = uci_moves(&[human]).remove(0);
+            remote.human_moved(&pos, &mv).unwrap();
+            pos = pos.play(&mv).unwrap();
+            let answer = remote.best_move(&pos).unwrap();
+            assert_eq!(Uci::from_standard(&answer).to_string(), reply);
+            pos = pos.play(&answer).unwrap();
+        }
+        remote.quit();
+        let heard = server.join().unwrap();
+        // the second search is sent the whole game so far
+        let game = format!("position fen {START} moves e2e4 e7e5 g1f3");
+        assert!(heard.contains(&game), "{heard:?}");
+    }
+
+    #[test]
+    fn reconnected_engine_picks_up_where_it_left_off() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = std::thread::spawn(move || {
+            // the engine restarts partway through its first search
+            serve(listener.accept().unwrap().0, &[]);
+            serve(listener.accept().unwrap().0, &["e7e5"])
+        });
+        let start = Chess::default();
+        let strength = EngineStrength::default();
+        let mut remote = RemoteEngine::connect(&address, &start, strength).unwrap();
+        let e4 = uci_moves(&["e2e4"]).remove(0);
+        remote.human_moved(&start, &e4).unwrap();
+        let after_e4 = start.play(&e4).unwrap();
+        let reply = remote.best_move(&after_e4).unwrap();
+        assert_eq!(Uci::from_standard(&reply).to_string(), "e7e5");
+        remote.quit();
+        let heard = server.join().unwrap();
+        let resumed = format!("position fen {START} moves e2e4");
+        let replayed = heard.iter().position(|line| *line == resumed).unwrap();
+        // caught up before being asked for anything
+        assert_eq!(heard[replayed + 1], "isready");
+        assert!(heard[replayed + 2..].iter().any(|line| line.starts_with("go ")));
+    }
+
+    #[test]
+    fn chess960_engine_is_told_castles_as_king_takes_rook() {
+        let start = crate::parse_position("1r3k2/8/8/8/8/8/8/1R3KR1 w GBb - 0 1").unwrap();
+        let said = Cursor::new(
+            "option name UCI_Chess960 type check default false\nuciok\nreadyok\nreadyok\n",
+        );
+        let heard = Shared::default();
+        let mut engine =
+            UciEngine::connect(said, heard.clone(), &start, EngineStrength::default()).unwrap();
+        let castle = Move::Castle {
+            king: Square::F1,
+            rook: Square::G1,
+        };
+        engine.resume(&[castle]).unwrap();
+        let text = heard.text();
+        assert!(text.lines().any(|line| line == "setoption name UCI_Chess960 value true"));
+        let position = text.lines().rev().nth(1).unwrap();
+        assert!(position.starts_with("position fen "));
+        assert!(position.ends_with(" moves f1g1"));
+    }
+
+    #[test]
+    fn chess960_wrapper_fen_matching_the_board_is_no_desync() {
+        let fen = "1r3k2/8/8/8/8/8/8/1R3KR1 b GBb - 0 1";
+        let pos = crate::parse_position(fen).unwrap();
+        let said = Cursor::new(format!("fen {fen}\nKe8\n"));
+        let mut wrapper = Wrapper::connect(said, std::io::sink());
+        let mv = wrapper.best_move(&pos).unwrap();
+        assert_eq!(mv.to(), Square::E8);
+        assert_eq!(wrapper.desync(), None);
+    }
+
+    fn position(fen: &str) -> Chess {
+        fen.parse::<Fen>().unwrap().into_position(CastlingMode::Standard).unwrap()
+    }
+
+    fn uci(pos: &Chess, text: &str) -> Result<String, String> {
+        parse_opponent_move(pos, text).map(|mv| Uci::from_standard(&mv).to_string())
+    }
+
+    #[test]
+    fn loose_san_is_matched_against_the_legal_moves() {
+        // the d2 knight is pinned by the bishop, so only g1 can reach f3
+        let pinned = position("4k3/8/8/b7/8/8/3N4/4K1N1 w - - 0 1");
+        assert_eq!(uci(&pinned, "Nf3"), Ok("g1f3".to_string()));
+        assert_eq!(uci(&pinned, "Nf3!?"), Ok("g1f3".to_string()));
+        let free = position("4k3/8/8/8/8/8/3N4/6NK w - - 0 1");
+        let ambiguous = parse_opponent_move(&free, "Nf3").unwrap_err();
+        assert!(ambiguous.contains("ambiguous"), "{ambiguous}");
+        assert!(parse_opponent_move(&free, "Nf5").is_err());
+    }
+
+    #[test]
+    fn odd_casing_is_respelled() {
+        let start = Chess::default();
+        assert_eq!(uci(&start, "E2E4"), Ok("e2e4".to_string()));
+        assert_eq!(uci(&start, "nf3"), Ok("g1f3".to_string()));
+        assert_eq!(uci(&start, "b4"), Ok("b2b4".to_string()));
+        let castles = position("4k3/8/8/8/8/8/8/4K2R w K - 0 1");
+        for text in ["O-O", "o-o", "0-0"] {
+            assert_eq!(uci(&castles, text), Ok("e1g1".to_string()), "{text}");
+        }
+        assert!(parse_opponent_move(&start, "E2E5").is_err());
+    }
+
+    #[test]
+    fn unreadable_reply_is_an_error() {
+        let mut wrapper = Wrapper::connect(Cursor::new("resign please\n"), std::io::sink());
+        let e = wrapper.best_move(&Chess::default()).unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn engine_plays_from_the_book_then_searches() {
+        use shakmaty::zobrist::{Zobrist64, ZobristHash};
+        // d4 is the only book move, and only from the start
+        let key = Chess::default().zobrist_hash::<Zobrist64>(EnPassantMode::Legal).0;
+        let d2d4 = u16::try_from(u32::from(Square::D2) << 6 | u32::from(Square::D4)).unwrap();
+        let mut bytes = key.to_be_bytes().to_vec();
+        bytes.extend_from_slice(&d2d4.to_be_bytes());
+        bytes.extend_from_slice(&[0, 1, 0, 0, 0, 0]);
+        let said = Cursor::new("uciok\nreadyok\nbestmove g1f3\n");
+        let heard = Shared::default();
+        let strength = EngineStrength::default();
+        let mut engine = UciEngine::connect(said, heard.clone(), &Chess::default(), strength)
+            .unwrap()
+            .book(Book::from_bytes(&bytes), None);
+        let start = Chess::default();
+        let d4 = engine.best_move(&start).unwrap();
+        assert_eq!(Uci::from_standard(&d4).to_string(), "d2d4");
+        let searched = |heard: &Shared| heard.text().lines().any(|line| line.starts_with("go"));
+        assert!(!searched(&heard));
+        // after d4 d5 the book has nothing, so the engine is asked
+        let after_d4 = start.play(&d4).unwrap();
+        let d5 = "d7d5".parse::<Uci>().unwrap().to_move(&after_d4).unwrap();
+        engine.human_moved(&after_d4, &d5).unwrap();
+        let after_d5 = after_d4.play(&d5).unwrap();
+        let nf3 = engine.best_move(&after_d5).unwrap();
+        assert_eq!(Uci::from_standard(&nf3).to_string(), "g1f3");
+        assert!(heard.text().contains(&format!("position fen {START} moves d2d4 d7d5")));
+        assert!(searched(&heard));
+    }
+
+    #[test]
+    fn handshake_answers_prompts_past_a_banner() {
+        let prompt = Regex::new(crate::DEFAULT_WRAPPER_PROMPT).unwrap();
+        let said = "opponent wrapper v2\n\nplay as white or black?\nengine depth:\ne5\n";
+        let heard = Shared::default();
+        let mut process = Process::connect(Cursor::new(said), heard.clone());
+        handshake(&mut process, &prompt, Cursor::new("black\n3\n")).unwrap();
+        assert_eq!(heard.text(), "black\n3\n");
+        // the first move after the handshake is still there to be read
+        assert_eq!(process.recv_line().unwrap().trim(), "e5");
+    }
+
+    #[test]
+    fn handshake_fails_when_the_wrapper_stops_short() {
+        let prompt = Regex::new(crate::DEFAULT_WRAPPER_PROMPT).unwrap();
+        let mut process = Process::connect(Cursor::new("banner\ncolour?\n"), std::io::sink());
+        let e = handshake(&mut process, &prompt, Cursor::new("white\n")).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn preset_answers_come_before_the_console() {
+        let prompt = Regex::new(crate::DEFAULT_WRAPPER_PROMPT).unwrap();
+        let said = "play as white or black?\nengine depth:\n";
+        let answers = ["black".to_string(), "3".to_string()];
+        // an empty console, which would fail the handshake if it were read
+        let heard = Shared::default();
+        let mut process = Process::connect(Cursor::new(said), heard.clone());
+        let console = Cursor::new("");
+        handshake(&mut process, &prompt, preset_answers(&answers).chain(console)).unwrap();
+        assert_eq!(heard.text(), "black\n3\n");
+        // with only the first preset the console answers the second
+        let heard = Shared::default();
+        let mut process = Process::connect(Cursor::new(said), heard.clone());
+        let console = Cursor::new("5\n");
+        handshake(&mut process, &prompt, preset_answers(&answers[..1]).chain(console)).unwrap();
+        assert_eq!(heard.text(), "black\n5\n");
+    }
+
+    #[test]
+    fn engine_on_the_clock_is_sent_the_time_left() {
+        use crate::clock::Clocks;
+        use std::time::{Duration, Instant};
+        let mut clocks = Clocks::new("5+3".parse().unwrap());
+        let start = Instant::now();
+        clocks.start(shakmaty::Color::White, start);
+        // white took two seconds over e4
+        let time_left = clocks.time_left(start + Duration::from_secs(2));
+        let go = |strength| {
+            let said = Cursor::new("uciok\nreadyok\nbestmove e7e5\n");
+            let heard = Shared::default();
+            let mut engine =
+                UciEngine::connect(said, heard.clone(), &Chess::default(), strength).unwrap();
+            engine.clocks(time_left);
+            let e4 = uci_moves(&["e2e4"]).remove(0);
+            engine.human_moved(&Chess::default(), &e4).unwrap();
+            engine.best_move(&after_e4()).unwrap();
+            let text = heard.text();
+            text.lines().find(|line| line.starts_with("go")).unwrap().to_string()
+        };
+        assert_eq!(
+            go(EngineStrength::default()),
+            "go wtime 298000 btime 300000 winc 3000 binc 3000"
+        );
+        // a depth asked for outright still wins over the clocks
+        let strength = EngineStrength {
+            depth: Some(8),
+            ..EngineStrength::default()
+        };
+        assert_eq!(go(strength), "go depth 8");
+    }
+}
@@ -0,0 +1,223 @@
+use log::{error, info};
+use shakmaty::{Bitboard, Square};
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "gpio")]
+use crate::config::MatrixPins;
+use crate::ReedInput;
+
+// the reeds read straight off the board's matrix rather than from a reed
+// reader process on stdin. bit n of a scan is reed index n in the firmware's
+// numbering, so --square-mapping and --flip apply as they do to the firmware
+pub trait ReedSource {
+    fn scan(&mut self) -> Result<Bitboard, String>;
+}
+
+// a reed only counts as switched once it has read the other way for
+// `scans` scans in a row, so a contact bouncing as a piece lands isn't a
+// flurry of lifts and drops
+pub struct Debouncer {
+    scans: u32,
+    stable: Bitboard,
+    // how many scans in a row each square has disagreed with `stable`
+    disagreed: [u32; 64],
+}
+
+impl Debouncer {
+    pub const fn new(scans: u32, stable: Bitboard) -> Self {
+        Self {
+            scans,
+            stable,
+            disagreed: [0; 64],
+        }
+    }
+
+    // the squares that switched with this scan
+    pub fn scan(&mut self, raw: Bitboard) -> Bitboard {
+        let mut switched = Bitboard::EMPTY;
+        for square in Bitboard::FULL {
+            let count = &mut self.disagreed[usize::from(square)];
+            if raw.contains(square) == self.stable.contains(square) {
+                *count = 0;
+                continue;
+            }
+            *count += 1;
+            if *count >= self.scans {
+                *count = 0;
+                switched = switched.with(Bitboard::from_square(square));
+            }
+        }
+        self.stable = Bitboard(self.stable.0 ^ switched.0);
+        switched
+    }
+
+    pub const fn stable(&self) -> Bitboard {
+        self.stable
+    }
+}
+
+// scans `source` every `interval` on a thread of its own and feeds the reed
+// lines the firmware would have sent down the same kind of channel as
+// spawn_reed_reader: each switched reed's index, or the whole frame in
+// snapshot mode. the board as first scanned is taken as where it starts
+pub fn spawn_matrix_reader(
+    mut source: impl ReedSource + Send + 'static,
+    interval: Duration,
+    debounce: u32,
+    snapshot: bool,
+) -> Result<ReedInput, String> {
+    let first = source.scan()?;
+    info!("reed matrix reads {:#018x} to start with", first.0);
+    let (sender, receiver) = mpsc::channel();
+    let shutdown = sender.clone();
+    std::thread::spawn(move || {
+        let mut debouncer = Debouncer::new(debounce, first);
+        let mut next_scan = Instant::now();
+        loop {
+            next_scan += interval;
+            std::thread::sleep(next_scan.saturating_duration_since(Instant::now()));
+            let raw = match source.scan() {
+                Ok(raw) => raw,
+                Err(e) => {
+                    error!("Failed to scan the reed matrix: {e}");
+                    let _ = sender.send(None);
+                    return;
+                }
+            };
+            let switched = debouncer.scan(raw);
+            let lines = if snapshot && switched.any() {
+                vec![format!("{:x}", debouncer.stable().0)]
+            } else if snapshot {
+                Vec::new()
+            } else {
+                switched.into_iter().map(|square| u32::from(square).to_string()).collect()
+            };
+            for line in lines {
+                if sender.send(Some(line)).is_err() {
+                    return;
+                }
+            }
+        }
+    });
+    Ok((shutdown, receiver))
+}
+
+// the row and column lines of the reed matrix, which scan_matrix drives and
+// reads. the Pi's GPIO pins are one, the tests stand in their own
+pub trait MatrixLines {
+    // drives `row` low to read its reeds, or back high once they're read
+    fn set_row(&mut self, row: usize, low: bool);
+    // whether `col` reads low, a closed reed on the row driven low
+    fn col_low(&self, col: usize) -> bool;
+}
+
+// each row is driven low in turn and the columns, pulled up, read low where
+// a reed is closed. bit row * 8 + col of the result is that reed
+pub fn scan_matrix(lines: &mut impl MatrixLines) -> Bitboard {
+    let mut closed = Bitboard::EMPTY;
+    for row in 0..8 {
+        lines.set_row(row, true);
+        for col in (0..8).filter(|&col| lines.col_low(col)) {
+            let square = Square::new(u32::try_from(row * 8 + col).unwrap());
+            closed = closed.with(Bitboard::from_square(square));
+        }
+        lines.set_row(row, false);
+    }
+    closed
+}
+
+// the reed matrix wired to the Pi's GPIO header
+#[cfg(feature = "gpio")]
+pub struct GpioMatrix {
+    rows: Vec<rppal::gpio::OutputPin>,
+    cols: Vec<rppal::gpio::InputPin>,
+}
+
+#[cfg(feature = "gpio")]
+impl GpioMatrix {
+    // how long a row has to be driven before its columns read true
+    const SETTLE: Duration = Duration::from_micros(10);
+
+    pub fn open(pins: MatrixPins) -> Result<Self, rppal::gpio::Error> {
+        let gpio = rppal::gpio::Gpio::new()?;
+        let rows = pins
+            .rows
+            .iter()
+            .map(|&pin| Ok(gpio.get(pin)?.into_output_high()))
+            .collect::<Result<_, rppal::gpio::Error>>()?;
+        let cols = pins
+            .cols
+            .iter()
+            .map(|&pin| Ok(gpio.get(pin)?.into_input_pullup()))
+            .collect::<Result<_, rppal::gpio::Error>>()?;
+        Ok(Self { rows, cols })
+    }
+}
+
+#[cfg(feature = "gpio")]
+impl MatrixLines for GpioMatrix {
+    fn set_row(&mut self, row: usize, low: bool) {
+        if low {
+            self.rows[row].set_low();
+            std::thread::sleep(Self::SETTLE);
+        } else {
+            self.rows[row].set_high();
+        }
+    }
+
+    fn col_low(&self, col: usize) -> bool {
+        self.cols[col].is_low()
+    }
+}
+
+#[cfg(feature = "gpio")]
+impl ReedSource for GpioMatrix {
+    fn scan(&mut self) -> Result<Bitboard, String> {
+        Ok(scan_matrix(self))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a matrix with `closed` reeds, only reading them on the row driven low
+    struct Mock {
+        closed: Bitboard,
+        driven: Vec<usize>,
+    }
+
+    impl MatrixLines for Mock {
+        fn set_row(&mut self, row: usize, low: bool) {
+            if low {
+                self.driven.push(row);
+            } else {
+                assert_eq!(self.driven.pop(), Some(row), "released a row that wasn't driven");
+            }
+        }
+
+        fn col_low(&self, col: usize) -> bool {
+            assert_eq!(self.driven.len(), 1, "columns read with {:?} driven", self.driven);
+            let square = Square::new(u32::try_from(self.driven[0] * 8 + col).unwrap());
+            self.closed.contains(square)
+        }
+    }
+
+    #[test]
+    fn scan_reads_each_row_in_turn() {
+        for closed in [
+            Bitboard::EMPTY,
+            Bitboard::FULL,
+            Bitboard(0xffff_0000_0000_ffff),
+            [Square::A1, Square::B2, Square::E2, Square::H8].into_iter().collect(),
+        ] {
+            let mut mock = Mock {
+                closed,
+                driven: Vec::new(),
+            };
+            assert_eq!(scan_matrix(&mut mock), closed);
+            assert!(mock.driven.is_empty());
+        }
+    }
+}
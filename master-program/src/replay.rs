@@ -0,0 +1,198 @@
+use log::{error, info, warn};
+use shakmaty::{fen::Fen, uci::Uci, CastlingMode, Chess, EnPassantMode, Move, Position};
+use std::fs::File;
+use std::io::Write;
+use std::time::{Duration, Instant};
+
+use crate::{apply_instruction, DoublePress, State, StateConfig};
+
+// an append-only record of everything the sensors said, one line per event:
+//   <ms> reed <instruction> <resulting state>
+//   <ms> timeout
+//   <ms> move <uci>
+//   <ms> draw accept|decline
+//   <ms> pause|resume
+//   <ms> reset
+// where <ms> counts from when the log was opened, so gestures that depend on
+// timing replay the same way
+#[derive(Default)]
+pub struct EventLog {
+    sink: Option<(File, Instant)>,
+}
+
+impl EventLog {
+    pub fn open(path: &str) -> std::io::Result<Self> {
+        let file = File::options().create(true).append(true).open(path)?;
+        Ok(Self {
+            sink: Some((file, Instant::now())),
+        })
+    }
+
+    pub fn reed(&mut self, instruction: u32, state: State) {
+        self.write(&format!("reed {instruction} {state:?}"));
+    }
+
+    // a pickup was abandoned and the state reverted to Idle
+    pub fn timeout(&mut self) {
+        self.write("timeout");
+    }
+
+    // the opponent's answer to a draw offer
+    pub fn draw(&mut self, accepted: bool) {
+        self.write(if accepted { "draw accept" } else { "draw decline" });
+    }
+
+    // the pause button was pressed, freezing or resuming the game
+    pub fn paused(&mut self, paused: bool) {
+        self.write(if paused { "pause" } else { "resume" });
+    }
+
+    // the game was abandoned and the board started over
+    pub fn reset(&mut self) {
+        self.write("reset");
+    }
+
+    pub fn moved(&mut self, mv: &Move) {
+        self.write(&format!("move {}", Uci::from_move(mv, CastlingMode::Standard)));
+    }
+
+    fn write(&mut self, event: &str) {
+        let Some((file, start)) = self.sink.as_mut() else {
+            return;
+        };
+        let res = writeln!(file, "{} {event}", start.elapsed().as_millis());
+        if let Err(e) = res {
+            error!("Failed to write to event log: {e}");
+        }
+    }
+}
+
+// feeds a recorded event log back through the state machine, printing every
+// state it goes through, and returns the position it ends in and how many
+// times the replay disagreed with what was recorded
+pub fn replay(path: &str, config: StateConfig) -> Result<(Chess, usize), String> {
+    let contents = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+    let start = Instant::now();
+    let mut pos = Chess::default();
+    let mut state = State::Idle;
+    let mut king_presses = DoublePress::default();
+    let mut committed: Option<Move> = None;
+    // the state to go back to on resume
+    let mut before_pause = State::Idle;
+    let mut mismatches = 0;
+
+    for (number, line) in contents.lines().enumerate().filter(|(_, l)| !l.trim().is_empty()) {
+        let number = number + 1;
+        let mut fields = line.splitn(3, ' ');
+        let (Some(ms), Some(kind)) = (fields.next(), fields.next()) else {
+            return Err(format!("line {number} is not an event: {line:?}"));
+        };
+        let ms: u64 = ms
+            .parse()
+            .map_err(|_| format!("line {number} has no timestamp: {line:?}"))?;
+        let rest = fields.next().unwrap_or_default();
+
+        match kind {
+            "reed" => {
+                let (instruction, recorded) = rest.split_once(' ').unwrap_or((rest, ""));
+                let instruction: u32 = instruction
+                    .parse()
+                    .map_err(|_| format!("line {number} has no instruction: {line:?}"))?;
+                let mv;
+                (state, mv) = apply_instruction(
+                    &pos,
+                    instruction,
+                    state,
+                    config,
+                    &mut king_presses,
+                    start + Duration::from_millis(ms),
+                )
+                .into();
+                println!("{ms} {instruction} {state:?}");
+                if format!("{state:?}") != recorded {
+                    warn!("line {number}: replay reached {state:?} but {recorded} was recorded");
+                    mismatches += 1;
+                }
+                if mv.is_some() {
+                    committed = mv;
+                }
+            }
+            "draw" => {
+                state = if rest == "accept" {
+                    State::DrawAgreed
+                } else {
+                    State::Idle
+                };
+            }
+            "pause" => before_pause = std::mem::replace(&mut state, State::Paused),
+            "resume" => state = before_pause,
+            "reset" => {
+                pos = Chess::default();
+                state = State::Idle;
+                king_presses = DoublePress::default();
+                committed = None;
+                before_pause = State::Idle;
+            }
+            "timeout" => {
+                if let State::FriendlyPU(_) = state {
+                    state = State::Idle;
+                }
+            }
+            "move" => {
+                let uci: Uci = rest
+                    .parse()
+                    .map_err(|_| format!("line {number} has no UCI move: {line:?}"))?;
+                let mv = uci
+                    .to_move(&pos)
+                    .map_err(|_| format!("line {number}: {uci} is not legal here"))?;
+                if let Some(replayed) = committed.take() {
+                    if replayed != mv {
+                        warn!("line {number}: replay committed {replayed} but {mv} was recorded");
+                        mismatches += 1;
+                    }
+                }
+                pos = pos.play(&mv).map_err(|e| e.to_string())?;
+            }
+            _ => return Err(format!("line {number} has unknown event {kind:?}")),
+        }
+    }
+
+    let fen = Fen::from_position(pos.clone(), EnPassantMode::Legal);
+    println!("{fen}");
+    info!("replay finished in {fen} with {mismatches} mismatches");
+    Ok((pos, mismatches))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::opponent::Wrapper;
+    use crate::tests::reed_input;
+    use crate::view::ViewServer;
+    use crate::{plies_played, GameConfig, Opponent};
+    use shakmaty::Color;
+
+    #[test]
+    fn recorded_game_replays_to_the_same_position() {
+        let path = std::env::temp_dir().join(format!("flagfall-replay-{}.log", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        let config = GameConfig::builder()
+            .human(Color::White)
+            .keyboard(true)
+            .event_log(Some(path.clone()))
+            .build();
+        let wrapper = Wrapper::connect(std::io::Cursor::new("e5\nNc6\n"), std::io::sink());
+        let mut opponent: Box<dyn Opponent> = Box::new(wrapper);
+        // a fumbled pickup of the d pawn on the way to e4 and Nf3
+        let reeds = reed_input(&["d2", "d2", "e2", "e4", "g1", "f3"]);
+        let views = ViewServer::default();
+        let mut console = Vec::new();
+        let summary = crate::run(&config, &mut opponent, &reeds, &views, &mut console).unwrap();
+        let replayed = replay(&path, config.state);
+        std::fs::remove_file(&path).unwrap();
+        let (position, mismatches) = replayed.unwrap();
+        assert_eq!(plies_played(&position), 4);
+        assert_eq!(position, summary.position);
+        assert_eq!(mismatches, 0);
+    }
+}
@@ -0,0 +1,104 @@
+use log::info;
+use shakmaty::{fen::Fen, Bitboard, Board, CastlingMode, Chess, Color, Piece, Square};
+use std::io::BufRead;
+
+use crate::config::GameConfig;
+use crate::{parse_instruction, snapshot, HEARTBEAT_LINE, MOVE_DONE_LINE};
+
+// reads a position set up by hand, for endgame studies. the reeds come first,
+// as toggles from an empty board (a square in keyboard mode, a whole frame in
+// snapshot mode), until "done" or the move complete button. then each occupied
+// square from a1 up is asked for as a FEN letter, upper case for white, and
+// last the side to move as w or b. castling and en passant are never available
+pub fn scan_setup(input: impl BufRead, config: &GameConfig) -> Result<Chess, String> {
+    let mut lines = input.lines().map_while(Result::ok);
+    let mut occupied = Bitboard::EMPTY;
+    println!("place the pieces, then send done");
+    loop {
+        let line = lines.next().ok_or("input ended before the board was scanned")?;
+        let line = line.trim();
+        if line == HEARTBEAT_LINE || line.is_empty() {
+            continue;
+        }
+        if line == MOVE_DONE_LINE || line == "done" {
+            break;
+        }
+        match read_occupancy(line, config, occupied) {
+            Ok(next) => occupied = next,
+            Err(e) => println!("{e}"),
+        }
+    }
+    info!("scanned {} occupied squares", occupied.count());
+
+    let mut board = Board::empty();
+    for square in occupied {
+        let piece = loop {
+            println!("{square}? K Q R B N P, lower case for black");
+            let line = lines.next().ok_or("input ended before every piece was named")?;
+            match line.trim().chars().collect::<Vec<_>>()[..] {
+                [letter] if Piece::from_char(letter).is_some() => {
+                    break Piece::from_char(letter).unwrap();
+                }
+                _ => println!("{:?} is not a piece", line.trim()),
+            }
+        };
+        board.set_piece_at(square, piece);
+    }
+    let turn = loop {
+        println!("to move? w or b");
+        let line = lines.next().ok_or("input ended before the side to move was given")?;
+        match line.trim() {
+            "w" => break Color::White,
+            "b" => break Color::Black,
+            other => println!("{other:?} is not a side"),
+        }
+    };
+
+    let fen = format!("{board} {} - - 0 1", turn.char());
+    let position = fen
+        .parse::<Fen>()
+        .map_err(|e| format!("{fen} is not a FEN: {e}"))?
+        .into_position(CastlingMode::Standard)
+        .map_err(|e| format!("the scanned position {fen} can't be played: {e}"))?;
+    info!("scanned {fen}");
+    Ok(position)
+}
+
+// the occupancy after one line of reed input, read the way the game would read it
+fn read_occupancy(line: &str, config: &GameConfig, occupied: Bitboard) -> Result<Bitboard, String> {
+    let square = if config.keyboard {
+        line.parse::<Square>().map_err(|_| format!("not a square: {line}"))?
+    } else if config.snapshot {
+        return Ok(config.reed_frame(snapshot::parse_frame(line)?));
+    } else {
+        let instruction = parse_instruction(line).map_err(|e| e.to_string())?;
+        Square::new(config.reed_square(instruction))
+    };
+    Ok(occupied ^ Bitboard::from_square(square))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn scan(input: &str) -> Result<Chess, String> {
+        let config = GameConfig::builder().keyboard(true).build();
+        scan_setup(Cursor::new(input), &config)
+    }
+
+    #[test]
+    fn scripted_scan_builds_queen_and_king_against_king() {
+        // e8 is put down and taken off again, then the pieces are named from a1 up
+        let position = scan("e1\nd1\ne8\ne8\ne7\ndone\nQ\nK\nx\nk\nb\n").unwrap();
+        let fen = Fen::from_position(position, shakmaty::EnPassantMode::Legal);
+        assert_eq!(fen.to_string(), "8/4k3/8/8/8/8/8/3QK3 b - - 0 1");
+    }
+
+    #[test]
+    fn scanned_position_has_to_be_legal() {
+        // black is in check with white to move
+        let scanned = scan("e1\nd7\ne8\ndone\nK\nQ\nk\nw\n");
+        assert!(scanned.is_err());
+    }
+}
@@ -0,0 +1,1465 @@
+use log::{error, info, warn};
+use shakmaty::{
+    fen::Fen, san::San, Bitboard, Chess, EnPassantMode, Move, Outcome, Position, Square,
+};
+use std::collections::VecDeque;
+use std::io::Write;
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender};
+use std::time::{Duration, Instant};
+
+use crate::adjudicator::Adjudicator;
+use crate::clock::Clocks;
+use crate::config::{GameConfig, OpponentBackend, Speed, StepProtocol};
+use crate::gantry::Gantry;
+use crate::leds::Leds;
+use crate::metrics::Metrics;
+use crate::opponent::Opponent;
+use crate::replay::EventLog;
+use crate::snapshot;
+use crate::telemetry::{Event, Telemetry};
+use crate::view::{BoardView, ViewServer};
+use crate::{
+    adjudicate, apply_instruction, console_error, desync_rgb, finish_steps, game_over_reason,
+    game_over_rgb, get_rgb, hanging_piece, hanging_rgb, is_insufficient_material,
+    legal_destinations, move_to_steps, open_gantry, out_of_time, parse_instruction,
+    pickup_reminder_rgb, plies_played, print_ply, print_state_name, setup_rgb,
+    spawn_opponent, threat_preview, threat_preview_rgb, unreachable_move_rgb, DoublePress, Feedback,
+    GameSummary, Graveyard, RGB, State, Step, Trail, Waiting, FASTER_LINE, HEARTBEAT_LINE,
+    MOVE_DONE_LINE, MOVE_LIMIT, PAUSE_LINE, REED_TICK, RESET_LINE, SETTLE_WINDOW, SHUTDOWN_LINE,
+    SLOWER_LINE, TOUCH_PREFIX,
+};
+
+// how long the scheduler waits on one board's reeds before looking at the next
+const POLL_WAIT: Duration = Duration::from_millis(20);
+
+// how long after the gantry finishes a move the frames have to show it arrived
+const LANDING_WINDOW: Duration = Duration::from_secs(2);
+
+// where a session's game stands after a poll
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Tick {
+    Playing,
+    // how the game ended and why, None if it was abandoned
+    Over(Option<(Outcome, &'static str)>),
+}
+
+// everything that belongs to one game and starts over with the next
+struct Game {
+    pos: Chess,
+    graveyard: Graveyard,
+    state: State,
+    state_entered: Instant,
+    king_presses: DoublePress,
+    // recent instructions, for the log when one lands in Error
+    trail: Trail,
+    last_move: Option<Move>,
+    // every move played since the starting position, for a restarted opponent
+    moves: Vec<Move>,
+    adjudicator: Option<Adjudicator>,
+    clocks: Option<Clocks>,
+    emitted_steps: Vec<Step>,
+    metrics: Metrics,
+    // reed lines that arrived while waiting for the board to settle, handled before new ones
+    backlog: VecDeque<Option<String>>,
+    // when the human's last move went to the opponent
+    sent: Instant,
+    // the last occupancy frame the reeds reported, in snapshot mode
+    last_frame: Bitboard,
+    // set when the opponent offers a draw with its move, until the human plays on
+    opponent_offered_draw: bool,
+    // what a paused game goes back to on resume
+    before_pause: State,
+    // whether the pieces have been seen standing where the game starts; only
+    // snapshot frames can show that, so other modes take it on trust
+    set_up: bool,
+}
+
+impl Game {
+    fn new(config: &GameConfig) -> Self {
+        // STEP 1: SETUP BOARD
+        let pos = config.start.clone();
+        info!("Entered starting position: {fen}", fen = pos.board());
+
+        // STEP 2: SETUP GAME PARAMETERS
+        let adjudicator = config
+            .adjudication
+            .map(|adjudication| Adjudicator::new(adjudication.threshold_cp, adjudication.plies));
+        let mut clocks = config.time_control.map(Clocks::new);
+        if let Some(clocks) = clocks.as_mut() {
+            clocks.start(pos.turn(), Instant::now());
+        }
+        Self {
+            graveyard: Graveyard::default(),
+            state: State::Idle,
+            state_entered: Instant::now(),
+            king_presses: DoublePress::default(),
+            trail: Trail::new(config.error_trail),
+            last_move: None,
+            adjudicator,
+            clocks,
+            emitted_steps: Vec::new(),
+            metrics: Metrics::default(),
+            backlog: VecDeque::new(),
+            sent: Instant::now(),
+            last_frame: pos.board().occupied(),
+            moves: Vec::new(),
+            opponent_offered_draw: false,
+            before_pause: State::Idle,
+            set_up: !config.snapshot,
+            pos,
+        }
+    }
+
+    fn view(&self) -> BoardView {
+        BoardView::new(
+            &self.pos,
+            self.state,
+            self.last_move.as_ref(),
+            self.clocks.as_ref(),
+            self.graveyard,
+            Instant::now(),
+        )
+    }
+}
+
+// one physical board's game: its position and state machine, the opponent
+// across from it, and where its reeds come from and its output goes
+pub struct GameSession<'a> {
+    // which board this is, for the log
+    board: usize,
+    config: &'a GameConfig,
+    // boxed so a restarted opponent can take the place of one that died
+    opponent: &'a mut Box<dyn Opponent>,
+    reed_lines: &'a Receiver<Option<String>>,
+    views: &'a ViewServer,
+    console: &'a mut dyn Write,
+    leds: Leds,
+    telemetry: Telemetry,
+    event_log: EventLog,
+    gantry: Option<Gantry>,
+    // --speed, as the speed buttons have since changed it. kept across resets
+    speed: Option<Speed>,
+    game: Game,
+}
+
+impl<'a> GameSession<'a> {
+    pub fn new(
+        board: usize,
+        config: &'a GameConfig,
+        opponent: &'a mut Box<dyn Opponent>,
+        reed_lines: &'a Receiver<Option<String>>,
+        views: &'a ViewServer,
+        console: &'a mut dyn Write,
+    ) -> Result<Self, String> {
+        let telemetry = match &config.telemetry {
+            Some(target) => Telemetry::open(target)
+                .map_err(|e| format!("Failed to open telemetry sink {target}: {e}"))?,
+            None => Telemetry::default(),
+        };
+        let event_log = match &config.event_log {
+            Some(path) => {
+                EventLog::open(path).map_err(|e| format!("Failed to open event log {path}: {e}"))?
+            }
+            None => EventLog::default(),
+        };
+        let gantry = match &config.gantry {
+            Some(device) => Some(open_gantry(
+                device,
+                config.gantry_journal.clone(),
+                config.gantry_batch,
+                config.magnet_wiring,
+                config.step_protocol,
+                config.mm_geometry(),
+            )?),
+            None => None,
+        };
+        Ok(Self {
+            board,
+            config,
+            opponent,
+            reed_lines,
+            views,
+            console,
+            leds: Leds::new(config.led_grid, config.led_frame_interval),
+            telemetry,
+            event_log,
+            gantry,
+            speed: config.speed,
+            game: Game::new(config),
+        })
+    }
+
+    // starts over from the starting position against the same opponent: no
+    // moves, captures or clock time, the state back to Idle, and in snapshot
+    // mode the pieces have to be seen set up again
+    pub fn reset(&mut self) -> Result<(), String> {
+        self.opponent
+            .new_game()
+            .map_err(|e| format!("Failed to start a new game with opponent: {e}"))?;
+        info!("board {}: resetting to the starting position for a new game", self.board);
+        self.game = Game::new(self.config);
+        self.event_log.reset();
+        Ok(())
+    }
+
+    // a lifted piece held over one of its destinations, which with
+    // --threat-preview lights what it would attack from there until the next
+    // reed line redraws the board. touches never move the game on
+    fn touched(&mut self, to: Square) -> Result<Tick, String> {
+        let config = self.config;
+        let game = &self.game;
+        let State::FriendlyPU(from) = game.state else {
+            info!("touch on {to} in {:?}, nothing lifted", game.state);
+            return Ok(Tick::Playing);
+        };
+        if !config.threat_preview || !legal_destinations(&game.pos, from).contains(to) {
+            return Ok(Tick::Playing);
+        }
+        let threats = threat_preview(&game.pos, from, to);
+        info!("{from} to {to} would attack {:?}", threats.into_iter().collect::<Vec<_>>());
+        if config.keyboard {
+            let rgb = threat_preview_rgb(&game.pos, from, to, &config.theme);
+            self.leds.show(self.console, rgb).map_err(console_error)?;
+        }
+        Ok(Tick::Playing)
+    }
+
+    // the reset button, from wherever the game has got to
+    fn reset_pressed(&mut self) -> Result<Tick, String> {
+        match self.reset() {
+            Ok(()) => {
+                if self.config.keyboard {
+                    writeln!(self.console, "reset, set the board up for a new game")
+                        .map_err(console_error)?;
+                }
+            }
+            // the old game carries on rather than the board stopping
+            Err(e) => error!("{e}"),
+        }
+        Ok(Tick::Playing)
+    }
+
+    // handles one reed line, or one opponent move, whichever side is to move.
+    // `wait` bounds how long to wait for the reeds; None waits for as long as
+    // nothing is ticking
+    pub fn poll(&mut self, wait: Option<Duration>) -> Result<Tick, String> {
+        self.leds.flush(self.console).map_err(console_error)?;
+        let game = &mut self.game;
+        self.views.publish(game.view());
+        if game.pos.is_game_over() || is_insufficient_material(&game.pos) {
+            // neither side can mate any more, so don't wait on the reeds forever
+            let outcome = game.pos.outcome().unwrap_or(Outcome::Draw);
+            return Ok(Tick::Over(Some((outcome, game_over_reason(&game.pos)))));
+        }
+        let plies = plies_played(&game.pos).saturating_sub(plies_played(&self.config.start));
+        if matches!(self.config.max_plies, Some(limit) if plies >= limit) {
+            warn!("no result after {plies} plies, calling the game a draw");
+            return Ok(Tick::Over(Some((Outcome::Draw, MOVE_LIMIT))));
+        }
+        if !game.set_up {
+            self.verify_setup(wait)
+        } else if self.config.human_plays(game.pos.turn()) {
+            self.human_turn(wait)
+        } else {
+            self.opponent_turn()
+        }
+    }
+
+    // holds the game back until a frame shows the starting position, lighting
+    // the squares that are still wrong
+    fn verify_setup(&mut self, wait: Option<Duration>) -> Result<Tick, String> {
+        let config = self.config;
+        let game = &mut self.game;
+        let received = match wait {
+            Some(wait) => self.reed_lines.recv_timeout(wait),
+            None => self.reed_lines.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        let reed_line = match received {
+            Ok(Some(reed_line)) => reed_line,
+            Ok(None) | Err(RecvTimeoutError::Disconnected) => {
+                info!("board {}: received EOF from reed input, exiting", self.board);
+                return Ok(Tick::Over(None));
+            }
+            Err(RecvTimeoutError::Timeout) => return Ok(Tick::Playing),
+        };
+        if reed_line == HEARTBEAT_LINE {
+            return Ok(Tick::Playing);
+        }
+        if reed_line == SHUTDOWN_LINE {
+            return self.shut_down();
+        }
+        if is_reset(config, &reed_line) {
+            return self.reset_pressed();
+        }
+        let frame = match snapshot::parse_frame(&reed_line) {
+            Ok(frame) => config.reed_frame(frame),
+            Err(e) => {
+                error!("ignoring reed input: {e}");
+                return Ok(Tick::Playing);
+            }
+        };
+        let expected = game.pos.board().occupied();
+        if frame != expected {
+            let missing = expected.without(frame);
+            let extra = frame.without(expected);
+            let squares = |bitboard: Bitboard| {
+                bitboard.into_iter().map(|square| square.to_string()).collect::<Vec<_>>().join(" ")
+            };
+            warn!("board not set up, missing [{}] extra [{}]", squares(missing), squares(extra));
+            let rgb = setup_rgb(missing, extra, &config.theme);
+            self.leds.show(self.console, rgb).map_err(console_error)?;
+            return Ok(Tick::Playing);
+        }
+        info!("board {}: set up, starting the game", self.board);
+        let now = Instant::now();
+        game.set_up = true;
+        game.last_frame = frame;
+        game.state_entered = now;
+        game.sent = now;
+        // setting up doesn't come off anyone's time
+        if let Some(clocks) = game.clocks.as_mut() {
+            clocks.start(game.pos.turn(), now);
+        }
+        Ok(Tick::Playing)
+    }
+
+    #[allow(clippy::too_many_lines)]
+    fn human_turn(&mut self, wait: Option<Duration>) -> Result<Tick, String> {
+        let config = self.config;
+        let theme = &config.theme;
+        let human = config.human;
+        let console = &mut *self.console;
+        let leds = &mut self.leds;
+        let game = &mut self.game;
+
+        // STEP 3: READ REED-SWITCH OUTPUT
+        // This is input from REED SWITCHES
+        let ticking = config.pickup_timeout.is_some() || game.clocks.is_some();
+        let wait = wait.or_else(|| ticking.then_some(REED_TICK)).or_else(|| leds.holding());
+        let received = match (game.backlog.pop_front(), wait) {
+            (Some(reed_line), _) => Ok(reed_line),
+            (None, Some(wait)) => self.reed_lines.recv_timeout(wait),
+            (None, None) => self.reed_lines.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        let reed_line = match received {
+            Ok(Some(reed_line)) => reed_line,
+            Ok(None) | Err(RecvTimeoutError::Disconnected) => {
+                info!("board {}: received EOF from reed input, exiting", self.board);
+                return Ok(Tick::Over(None));
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                let opponent = self.opponent.as_mut();
+                if let Some(outcome) = out_of_time(opponent, game.clocks.as_ref(), human) {
+                    return Ok(Tick::Over(Some((outcome, "time"))));
+                }
+                // a piece left hanging in the air is assumed to have been put back,
+                // unless the frames show its square still empty
+                let timeout = config.pickup_timeout.unwrap_or(Duration::MAX);
+                let lifted = match game.state {
+                    State::FriendlyPU(square) => Some(square),
+                    State::EnemyPU(square) | State::InvalidPiecePU(None, square)
+                        if config.snapshot =>
+                    {
+                        Some(square)
+                    }
+                    _ => None,
+                };
+                if let Some(square) = lifted.filter(|_| game.state_entered.elapsed() >= timeout) {
+                    if config.keyboard {
+                        leds.show(console, pickup_reminder_rgb(square, theme))
+                            .map_err(console_error)?;
+                    }
+                    let now = Instant::now();
+                    if config.snapshot {
+                        warn!("piece from {square} never came back, waiting for it");
+                        let missing = State::MissingPiece(square);
+                        game.metrics.transition(game.state, missing, now);
+                        game.state = missing;
+                    } else {
+                        warn!("pickup from {square} timed out, reverting to Idle");
+                        game.state = State::Idle;
+                        self.event_log.timeout();
+                    }
+                    game.state_entered = now;
+                }
+                return Ok(Tick::Playing);
+            }
+        };
+        let user_input = reed_line.as_str();
+        if user_input == HEARTBEAT_LINE {
+            return Ok(Tick::Playing);
+        }
+        info!("received line: {user_input}");
+        if user_input == SHUTDOWN_LINE {
+            return self.shut_down();
+        }
+        if is_reset(config, user_input) {
+            return self.reset_pressed();
+        }
+        if user_input == MOVE_DONE_LINE || (config.keyboard && user_input == "done") {
+            // only means something while the opponent's turn waits for the board to settle
+            info!("move complete signalled in {:?}, nothing to hurry", game.state);
+            return Ok(Tick::Playing);
+        }
+        if let Some(faster) = speed_change(config, user_input) {
+            if config.step_protocol == StepProtocol::Binary {
+                warn!("binary frames carry no feedrate, ignoring the speed buttons");
+                return Ok(Tick::Playing);
+            }
+            let speed = self.speed.unwrap_or_default();
+            let speed = if faster { speed.faster() } else { speed.slower() };
+            info!("gantry speed now {}x", speed.multiplier());
+            self.speed = Some(speed);
+            return Ok(Tick::Playing);
+        }
+        if user_input == PAUSE_LINE || (config.keyboard && user_input == "pause") {
+            let now = Instant::now();
+            let paused = game.state != State::Paused;
+            if paused {
+                info!("game paused");
+                game.before_pause = std::mem::replace(&mut game.state, State::Paused);
+                if let Some(clocks) = game.clocks.as_mut() {
+                    clocks.stop(now);
+                }
+            } else {
+                info!("game resumed");
+                game.state = game.before_pause;
+                if let Some(clocks) = game.clocks.as_mut() {
+                    clocks.start(game.pos.turn(), now);
+                }
+            }
+            // a piece left in the air over the break gets its full timeout again
+            game.state_entered = now;
+            self.event_log.paused(paused);
+            if config.keyboard {
+                let last_move = game.last_move.as_ref();
+                let rgb =
+                    get_rgb(&game.pos, game.state, theme, config.teach, last_move, Waiting::Human);
+                print_state_name(console, game.state).map_err(console_error)?;
+                leds.show(console, rgb).map_err(console_error)?;
+            }
+            return Ok(Tick::Playing);
+        }
+        if game.state == State::Paused {
+            info!("paused, ignoring {user_input}");
+            return Ok(Tick::Playing);
+        }
+        if let Some(square) = touched_square(config, user_input) {
+            return self.touched(square);
+        }
+
+        let instructions = if config.keyboard {
+            // the operator types squares like e2 instead of reed indices
+            if user_input == "quit" {
+                info!("quit requested from keyboard, exiting");
+                return Ok(Tick::Over(None));
+            }
+            if let Ok(square) = user_input.parse::<Square>() {
+                vec![u32::from(square)]
+            } else {
+                writeln!(console, "not a square: {user_input}").map_err(console_error)?;
+                return Ok(Tick::Playing);
+            }
+        } else if config.snapshot {
+            let frame = match snapshot::parse_frame(user_input) {
+                Ok(frame) => config.reed_frame(frame),
+                Err(e) => {
+                    error!("ignoring reed input: {e}");
+                    return Ok(Tick::Playing);
+                }
+            };
+            let previous = std::mem::replace(&mut game.last_frame, frame);
+            if let State::UnexpectedOccupancy(square) | State::MissingPiece(square) = game.state {
+                // wait for the board to look like the position again
+                if frame == game.pos.board().occupied() {
+                    info!("board around {square} restored");
+                    game.state = State::Idle;
+                    game.state_entered = Instant::now();
+                }
+                return Ok(Tick::Playing);
+            }
+            if let Some(toggles) = snapshot::castle_toggles(&game.pos, game.state, frame) {
+                toggles.into_iter().map(u32::from).collect()
+            } else {
+                let expected = snapshot::expected_occupancy(&game.pos, game.state);
+                match snapshot::diff_frame(previous, frame, expected) {
+                    Ok(squares) => squares.into_iter().map(u32::from).collect(),
+                    Err(square) => {
+                        warn!("piece set down on {square}, which should still be occupied");
+                        let stacked = State::UnexpectedOccupancy(square);
+                        game.metrics.transition(game.state, stacked, Instant::now());
+                        game.state = stacked;
+                        game.state_entered = Instant::now();
+                        return Ok(Tick::Playing);
+                    }
+                }
+            }
+        } else {
+            match parse_instruction(user_input) {
+                Ok(instruction) => vec![config.reed_square(instruction)],
+                Err(e) => {
+                    error!("ignoring reed input: {e}");
+                    return Ok(Tick::Playing);
+                }
+            }
+        };
+
+        for instruction in instructions {
+            let newstate = game.state;
+            let update = apply_instruction(
+                &game.pos,
+                instruction,
+                newstate,
+                config.state,
+                &mut game.king_presses,
+                Instant::now(),
+            );
+            game.state = update.next;
+            let mv = update.committed;
+            let state = game.state;
+            match update.feedback {
+                Feedback::Normal => {}
+                Feedback::InvalidPickup => warn!("invalid pickup, {state:?}"),
+                Feedback::InvalidMove => warn!("invalid move, {state:?}"),
+                Feedback::RecoverableError => warn!("reed input {instruction} left {state:?}"),
+                Feedback::FatalError => error!("lost track of the board, reset to carry on"),
+                Feedback::OutOfTurn => {
+                    if config.keyboard {
+                        let rgb = desync_rgb(theme);
+                        leds.show(console, rgb).map_err(console_error)?;
+                    }
+                    continue;
+                }
+            }
+            game.trail.record(instruction, newstate, state);
+            self.event_log.reed(instruction, state);
+            if state != newstate {
+                game.state_entered = Instant::now();
+                game.metrics.transition(newstate, state, game.state_entered);
+            }
+            self.telemetry.emit(&Event::Transition {
+                instruction,
+                from: newstate,
+                to: state,
+            });
+            if config.keyboard {
+                let last_move = game.last_move.as_ref();
+                let rgb = get_rgb(&game.pos, state, theme, config.teach, last_move, Waiting::Human);
+                print_state_name(console, state).map_err(console_error)?;
+                leds.show(console, rgb).map_err(console_error)?;
+            }
+            if state == State::Resigned {
+                if let Err(e) = self.opponent.resign() {
+                    error!("Failed to send resignation to opponent: {e}");
+                }
+                let winner = game.pos.turn().other();
+                return Ok(Tick::Over(Some((Outcome::Decisive { winner }, "resignation"))));
+            }
+            if state == State::DrawOffered {
+                // the opponent is always told, even when it's its own offer being taken up
+                let answer = self.opponent.draw_offered(&game.pos).unwrap_or_else(|e| {
+                    error!("Failed to offer opponent a draw: {e}");
+                    false
+                });
+                let accepted = game.opponent_offered_draw || answer;
+                self.event_log.draw(accepted);
+                if accepted {
+                    game.state = State::DrawAgreed;
+                } else {
+                    info!("opponent declined the draw");
+                    game.state = State::Idle;
+                    if config.keyboard {
+                        print_state_name(console, game.state).map_err(console_error)?;
+                    }
+                }
+            }
+            if game.state == State::DrawAgreed {
+                return Ok(Tick::Over(Some((Outcome::Draw, "agreement"))));
+            }
+            if game.state == State::DrawClaimed {
+                info!("draw claimed after {} halfmoves", game.pos.halfmoves());
+                return Ok(Tick::Over(Some((Outcome::Draw, "fifty-move rule"))));
+            }
+            if game.state == State::ClaimRejected {
+                warn!(
+                    "draw claim rejected, only {} of the 100 halfmoves needed",
+                    game.pos.halfmoves()
+                );
+            }
+
+            let copied_pos = game.pos.clone();
+            if let Some(mv) = mv {
+                info!("got full move, playing {mv}");
+                let move_san = San::from_move(&copied_pos, &mv).to_string();
+                game.pos = copied_pos.clone().play(&mv).unwrap();
+                game.graveyard.record(&mv, copied_pos.turn());
+                game.last_move = Some(mv.clone());
+                game.moves.push(mv.clone());
+                self.event_log.moved(&mv);
+                game.metrics.human_moved(Instant::now());
+                let event = Event::from_move(&copied_pos, &mv, &game.pos, &[]);
+                self.telemetry.emit(&event);
+                self.views.moved(&event);
+                if config.verbose_board {
+                    print_ply(console, &game.pos, &move_san).map_err(console_error)?;
+                }
+                if let Some(square) = hanging_piece(&game.pos, &mv).filter(|_| config.coach) {
+                    info!("the piece on {square} is left hanging");
+                    if config.keyboard {
+                        leds.show(console, hanging_rgb(square, theme))
+                            .map_err(console_error)?;
+                    }
+                }
+                game.sent = Instant::now();
+                if let Some(clocks) = game.clocks.as_mut() {
+                    clocks.press(game.sent);
+                    self.opponent.clocks(clocks.time_left(game.sent));
+                }
+                if let Err(e) = self.opponent.human_moved(&copied_pos, &mv) {
+                    error!("Failed to send move to opponent: {e}");
+                }
+                game.opponent_offered_draw = false;
+                let adjudicator = game.adjudicator.as_mut();
+                let opponent = self.opponent.as_mut();
+                if let Some(outcome) = adjudicate(opponent, adjudicator, &game.pos, human) {
+                    return Ok(Tick::Over(Some((outcome, "adjudication"))));
+                }
+                // back to the top, so a game the human just finished is noticed
+                // before the opponent is asked for a reply
+                return Ok(Tick::Playing);
+            }
+        }
+        Ok(Tick::Playing)
+    }
+
+    fn opponent_turn(&mut self) -> Result<Tick, String> {
+        let config = self.config;
+        let human = config.human;
+        if !self.settle() {
+            return self.shut_down();
+        }
+        let settled = Instant::now();
+        self.show_turn(Waiting::Opponent)?;
+        let mv = self.opponent_reply()?;
+        let replied = Instant::now();
+        if self.desynced()? {
+            return Ok(Tick::Over(None));
+        }
+        let game = &mut self.game;
+        if let Some(outcome) = out_of_time(self.opponent.as_mut(), game.clocks.as_ref(), human) {
+            return Ok(Tick::Over(Some((outcome, "time"))));
+        }
+        if let Some(clocks) = game.clocks.as_mut() {
+            clocks.press(replied);
+        }
+        info!(
+            "got move {mv} from opponent {:?} after the board settled, {:?} of its thinking overlapped the human",
+            replied - settled,
+            (settled - game.sent).min(replied - game.sent),
+        );
+        let san = San::from_move(&game.pos, &mv);
+
+        // STEP 9: CONVERT MOVE TO MOVEMENT STEPS
+
+        let steps: Vec<Step> = move_to_steps(
+            mv.clone(),
+            game.pos.turn(),
+            f64::from(game.graveyard.captured_whites),
+            f64::from(game.graveyard.captured_blacks),
+            game.pos.board().occupied(),
+            &config.geometry,
+            config.capture_strategy,
+        )
+        .into_iter()
+        .map(|step| config.orientation.step(step, &config.geometry))
+        .collect();
+        let steps = finish_steps(steps, config, self.speed);
+        info!("produced steps: {steps:?}");
+
+        // STEP 10: SEND STEPS TO LEVY'S PROGRAM
+        let mut carried = false;
+        let reachable = config.geometry.check(&steps);
+        if let Err(e) = &reachable {
+            // the move still stands, so whoever is at the board has to make it by hand
+            error!("Not sending {mv} to the gantry: {e}");
+            if config.keyboard {
+                let rgb = unreachable_move_rgb(&mv, &config.theme);
+                self.leds.show(self.console, rgb).map_err(console_error)?;
+            }
+        } else if let Some(gantry) = self.gantry.as_mut() {
+            carried = gantry
+                .send(&steps)
+                .map_err(|e| error!("Failed to send steps to gantry: {e}"))
+                .is_ok();
+        }
+        game.emitted_steps.extend_from_slice(&steps);
+        game.metrics.opponent_moved(steps.len());
+
+        let before = game.pos.clone();
+        game.pos = before.clone().play(&mv).expect("Moves from opponent should always be legal.");
+        game.graveyard.record(&mv, before.turn());
+        game.last_move = Some(mv.clone());
+        game.moves.push(mv.clone());
+        self.event_log.moved(&mv);
+        let event = Event::from_move(&before, &mv, &game.pos, &steps);
+        self.telemetry.emit(&event);
+        self.views.moved(&event);
+        if config.verbose_board {
+            print_ply(self.console, &game.pos, &san.to_string()).map_err(console_error)?;
+        }
+        if carried && config.snapshot {
+            let landing = snapshot::landing(&before, &game.pos);
+            self.watch_landing(landing);
+        }
+        // an unreachable move keeps its squares lit until someone makes it by hand
+        if reachable.is_ok() {
+            self.show_turn(Waiting::Human)?;
+        }
+        let game = &mut self.game;
+        if self.opponent.offers_draw() {
+            info!("opponent offers a draw");
+            if config.keyboard {
+                writeln!(
+                    self.console,
+                    "draw offered, press their king twice then once more to accept"
+                )
+                .map_err(console_error)?;
+            }
+            game.opponent_offered_draw = true;
+        }
+        let adjudicator = game.adjudicator.as_mut();
+        let opponent = self.opponent.as_mut();
+        if let Some(outcome) = adjudicate(opponent, adjudicator, &game.pos, human) {
+            return Ok(Tick::Over(Some((outcome, "adjudication"))));
+        }
+        Ok(Tick::Playing)
+    }
+
+    // in snapshot mode, once the gantry says it's done: the frames should
+    // soon show every `landing` square filled. one still empty means the
+    // magnet lost its piece on the way, so the carriage goes back for it once
+    // where the frames show it fell, and after that the human is asked to put
+    // it in place
+    fn watch_landing(&mut self, landing: Bitboard) {
+        let mut regrabbed = false;
+        loop {
+            let frame = self.landed_frame();
+            let expected = self.game.pos.board().occupied();
+            let Some((square, stray)) = snapshot::lost_piece(landing, expected, frame) else {
+                return;
+            };
+            if let Some(stray) = stray.filter(|_| !regrabbed) {
+                warn!("piece for {square} was dropped on {stray} on the way, going back for it");
+                regrabbed = true;
+                if self.regrab(stray, square) {
+                    continue;
+                }
+            }
+            warn!("piece for {square} never arrived, waiting for it to be put there");
+            let game = &mut self.game;
+            let missing = State::MissingPiece(square);
+            let now = Instant::now();
+            game.metrics.transition(game.state, missing, now);
+            game.state = missing;
+            game.state_entered = now;
+            return;
+        }
+    }
+
+    // the board once the gantry is done, read from the frames until one
+    // matches the position or LANDING_WINDOW passes. frames from before then
+    // are the gantry's doing, anything else waits in the backlog
+    fn landed_frame(&mut self) -> Bitboard {
+        let game = &mut self.game;
+        let expected = game.pos.board().occupied();
+        let deadline = Instant::now() + LANDING_WINDOW;
+        while game.last_frame != expected {
+            let left = deadline.saturating_duration_since(Instant::now());
+            let Ok(reed_line) = self.reed_lines.recv_timeout(left) else {
+                break;
+            };
+            match reed_line.as_deref().map(snapshot::parse_frame) {
+                Some(Ok(frame)) => game.last_frame = self.config.reed_frame(frame),
+                _ => game.backlog.push_back(reed_line),
+            }
+        }
+        game.last_frame
+    }
+
+    // carries the piece that belongs on `to` over from `stray`, where it was
+    // dropped. false if the gantry couldn't
+    fn regrab(&mut self, stray: Square, to: Square) -> bool {
+        let config = self.config;
+        let game = &mut self.game;
+        let (Some(piece), Some(gantry)) = (game.pos.board().piece_at(to), self.gantry.as_mut())
+        else {
+            return false;
+        };
+        let mv = Move::Normal {
+            role: piece.role,
+            from: stray,
+            capture: None,
+            to,
+            promotion: None,
+        };
+        let steps: Vec<Step> = move_to_steps(
+            mv,
+            piece.color,
+            f64::from(game.graveyard.captured_whites),
+            f64::from(game.graveyard.captured_blacks),
+            game.last_frame,
+            &config.geometry,
+            config.capture_strategy,
+        )
+        .into_iter()
+        .map(|step| config.orientation.step(step, &config.geometry))
+        .collect();
+        let steps = finish_steps(steps, config, self.speed);
+        if let Err(e) = config.geometry.check(&steps) {
+            error!("Not sending the way back to {stray} to the gantry: {e}");
+            return false;
+        }
+        game.emitted_steps.extend_from_slice(&steps);
+        gantry.send(&steps).map_err(|e| error!("Failed to send steps to gantry: {e}")).is_ok()
+    }
+
+    // the opponent has been working on its reply since the move was sent, but
+    // nothing may move until the human's hand is off the board: either the
+    // reeds stay quiet for SETTLE_WINDOW or the human says they're done.
+    // false if a shutdown came in instead
+    fn settle(&mut self) -> bool {
+        let keyboard = self.config.keyboard;
+        let mut quiet_since = Instant::now();
+        loop {
+            let left = SETTLE_WINDOW.saturating_sub(quiet_since.elapsed());
+            let Ok(reed_line) = self.reed_lines.recv_timeout(left) else {
+                return true;
+            };
+            match reed_line.as_deref() {
+                Some(HEARTBEAT_LINE) => {}
+                Some(SHUTDOWN_LINE) => return false,
+                Some(line) if line == MOVE_DONE_LINE || (keyboard && line == "done") => {
+                    info!("move complete signalled, not waiting for the board to settle");
+                    return true;
+                }
+                _ => {
+                    self.game.backlog.push_back(reed_line);
+                    quiet_since = Instant::now();
+                }
+            }
+        }
+    }
+
+    // the opponent's move, restarting it once if it has stopped answering
+    fn opponent_reply(&mut self) -> Result<Move, String> {
+        self.tell_clocks();
+        let e = match self.opponent.best_move(&self.game.pos) {
+            Ok(mv) => return Ok(mv),
+            Err(e) => e,
+        };
+        if !matches!(self.config.opponent, OpponentBackend::Uci(_)) {
+            return Err(format!("Failed to get a move from opponent: {e}"));
+        }
+        error!("Failed to get a move from opponent: {e}, restarting it");
+        self.restart_opponent()?;
+        self.tell_clocks();
+        self.opponent
+            .best_move(&self.game.pos)
+            .map_err(|e| format!("Failed to get a move from restarted opponent: {e}"))
+    }
+
+    fn tell_clocks(&mut self) {
+        if let Some(clocks) = self.game.clocks {
+            self.opponent.clocks(clocks.time_left(Instant::now()));
+        }
+    }
+
+    // a fresh engine in place of the old one, caught up by replaying every
+    // move so far. the opponent wrapper can't be restarted this way, as its
+    // boot questions are answered from stdin, which the reeds have by now
+    fn restart_opponent(&mut self) -> Result<(), String> {
+        let mut opponent = spawn_opponent(self.config)?;
+        opponent
+            .resume(&self.game.moves)
+            .map_err(|e| format!("Failed to replay the game to restarted opponent: {e}"))?;
+        info!(
+            "board {}: opponent restarted and caught up on {} moves",
+            self.board,
+            self.game.moves.len()
+        );
+        std::mem::replace(self.opponent, opponent).quit();
+        Ok(())
+    }
+
+    // whether the opponent said it was on a different board from ours before
+    // its last move, in which case the game can't go on
+    fn desynced(&mut self) -> Result<bool, String> {
+        let Some(fen) = self.opponent.desync() else {
+            return Ok(false);
+        };
+        let ours = Fen::from_position(self.game.pos.clone(), EnPassantMode::Legal);
+        error!("opponent is playing from {fen}, not {ours}; stopping the game");
+        if self.config.keyboard {
+            let rgb = desync_rgb(&self.config.theme);
+            self.leds.show_final(self.console, rgb).map_err(console_error)?;
+        }
+        Ok(true)
+    }
+
+    // a signal asked for the program to stop: let go of anything on the
+    // magnet, park the carriage and turn the LEDs off. the opponent is told
+    // to quit along with the others once play returns
+    fn shut_down(&mut self) -> Result<Tick, String> {
+        info!("board {}: shutting down", self.board);
+        if let Some(gantry) = self.gantry.as_mut() {
+            if let Err(e) = gantry.park() {
+                error!("Failed to park gantry: {e}");
+            }
+        }
+        if self.config.keyboard {
+            self.leds.show_final(self.console, RGB::default()).map_err(console_error)?;
+        }
+        Ok(Tick::Over(None))
+    }
+
+    // the idle board with its edge showing whose move it is, in keyboard mode
+    fn show_turn(&mut self, waiting: Waiting) -> Result<(), String> {
+        let config = self.config;
+        if !config.keyboard {
+            return Ok(());
+        }
+        let game = &self.game;
+        let teach = config.teach && waiting == Waiting::Human;
+        let last_move = game.last_move.as_ref();
+        let rgb = get_rgb(&game.pos, State::Idle, &config.theme, teach, last_move, waiting);
+        self.leds.show(self.console, rgb).map_err(console_error)
+    }
+
+    // wraps up a game that poll reported over
+    pub fn finish(
+        &mut self,
+        result: Option<(Outcome, &'static str)>,
+    ) -> Result<GameSummary, String> {
+        let game = &mut self.game;
+        self.views.publish(game.view());
+        if let Some((outcome, reason)) = result {
+            info!("board {}: game ended with {outcome} by {reason}", self.board);
+            self.telemetry.emit(&Event::GameOver {
+                result: outcome.to_string(),
+                reason,
+            });
+            if self.config.keyboard {
+                let rgb = game_over_rgb(&game.pos, outcome, &self.config.theme);
+                self.leds.show_final(self.console, rgb).map_err(console_error)?;
+                if reason == MOVE_LIMIT {
+                    writeln!(self.console, "move limit reached, set the board up for a new game")
+                        .map_err(console_error)?;
+                }
+            }
+        }
+        let summary = game.metrics.summary();
+        info!("metrics: {summary}");
+        self.telemetry.emit(&Event::Metrics(summary));
+
+        //The input of SAN is gonna access through this method:
+        //convert_san_to_steps(INPUT, pos, captured_blacks, captured_whites)
+        //the method also gives an output for CORE-XY in the form of a list of structs
+        //TODO: make sure that moves coming from SAN are committed by using Chess.play()
+
+        Ok(GameSummary {
+            position: game.pos.clone(),
+            result,
+            steps: std::mem::take(&mut game.emitted_steps),
+        })
+    }
+}
+
+// one physical board and everything that serves it
+pub struct Board {
+    pub config: GameConfig,
+    pub opponent: Box<dyn Opponent>,
+    pub reed_lines: Receiver<Option<String>>,
+    // feeds SHUTDOWN_LINE into reed_lines when a signal arrives
+    pub shutdown: Sender<Option<String>>,
+    pub views: ViewServer,
+    pub console: Box<dyn Write>,
+}
+
+fn is_reset(config: &GameConfig, line: &str) -> bool {
+    line == RESET_LINE || (config.keyboard && line == "reset")
+}
+
+// Some(true) for a press of the faster button, Some(false) for the slower one
+fn speed_change(config: &GameConfig, line: &str) -> Option<bool> {
+    if line == FASTER_LINE || (config.keyboard && line == "faster") {
+        Some(true)
+    } else if line == SLOWER_LINE || (config.keyboard && line == "slower") {
+        Some(false)
+    } else {
+        None
+    }
+}
+
+// the square in a transient touch line, typed as "hover e4" in keyboard mode
+fn touched_square(config: &GameConfig, line: &str) -> Option<Square> {
+    if config.keyboard {
+        return line.strip_prefix("hover ")?.parse().ok();
+    }
+    let instruction = parse_instruction(line.strip_prefix(TOUCH_PREFIX)?).ok()?;
+    Some(Square::new(config.reed_square(instruction)))
+}
+
+// plays on every board at once, taking turns polling each for input. games
+// that hit the move limit start over, and it returns once every board's reed
+// input has ended. an opponent thinking holds up the other boards, but their
+// reed lines queue up meanwhile
+
+pub fn play(boards: &mut [Board]) -> Result<(), String> {
+    let mut sessions = boards
+        .iter_mut()
+        .enumerate()
+        .map(|(board, b)| {
+            GameSession::new(
+                board,
+                &b.config,
+                &mut b.opponent,
+                &b.reed_lines,
+                &b.views,
+                b.console.as_mut(),
+            )
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    // a single board can block on its reeds
+    let wait = (sessions.len() > 1).then_some(POLL_WAIT);
+    while !sessions.is_empty() {
+        let mut i = 0;
+        while i < sessions.len() {
+            let Tick::Over(result) = sessions[i].poll(wait)? else {
+                i += 1;
+                continue;
+            };
+            let summary = sessions[i].finish(result)?;
+            // an unattended board that hit the move limit starts over for the next visitor
+            if summary.result.map(|(_, reason)| reason) == Some(MOVE_LIMIT) {
+                match sessions[i].reset() {
+                    Ok(()) => {
+                        i += 1;
+                        continue;
+                    }
+                    Err(e) => error!("{e}"),
+                }
+            }
+            sessions.swap_remove(i);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::BoardGeometry;
+    use crate::opponent::Wrapper;
+    use crate::GameSummary;
+    use crate::print_leds;
+    use crate::tests::{reed_input, Shared};
+    use shakmaty::{CastlingMode, Color, Role};
+    use std::sync::{Arc, Mutex};
+
+    // an opponent replying with scripted SAN, noting what it's told
+    #[derive(Default)]
+    struct Scripted {
+        replies: VecDeque<&'static str>,
+        heard: Arc<Mutex<Vec<String>>>,
+        takes_draws: bool,
+    }
+
+    impl Scripted {
+        fn new(replies: &[&'static str]) -> Self {
+            Self {
+                replies: replies.iter().copied().collect(),
+                heard: Arc::default(),
+                takes_draws: false,
+            }
+        }
+    }
+
+    impl Opponent for Scripted {
+        fn human_moved(&mut self, before: &Chess, mv: &Move) -> std::io::Result<()> {
+            self.heard.lock().unwrap().push(San::from_move(before, mv).to_string());
+            Ok(())
+        }
+
+        fn best_move(&mut self, pos: &Chess) -> std::io::Result<Move> {
+            let reply = self.replies.pop_front().ok_or_else(|| {
+                std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "out of replies")
+            })?;
+            Ok(reply.parse::<San>().unwrap().to_move(pos).unwrap())
+        }
+
+        fn draw_offered(&mut self, _pos: &Chess) -> std::io::Result<bool> {
+            self.heard.lock().unwrap().push("draw?".to_string());
+            Ok(self.takes_draws)
+        }
+
+        fn new_game(&mut self) -> std::io::Result<()> {
+            self.heard.lock().unwrap().push("new game".to_string());
+            Ok(())
+        }
+
+        fn quit(&mut self) {}
+    }
+
+    fn board(config: GameConfig, opponent: Scripted, squares: &[&str], console: &Shared) -> Board {
+        let (shutdown, _) = std::sync::mpsc::channel();
+        Board {
+            config,
+            opponent: Box::new(opponent),
+            reed_lines: reed_input(squares),
+            shutdown,
+            views: ViewServer::default(),
+            console: Box::new(console.clone()),
+        }
+    }
+
+    #[test]
+    fn move_limit_ends_the_game_and_starts_over() {
+        let config = GameConfig::builder()
+            .human(Color::White)
+            .keyboard(true)
+            .max_plies(Some(2))
+            .build();
+        let opponent = Scripted::new(&["e5", "d5"]);
+        let heard = Arc::clone(&opponent.heard);
+        let console = Shared::default();
+        let mut boards = [board(config, opponent, &["e2", "e4"], &console)];
+        play(&mut boards).unwrap();
+        // e4 e5 is the limit, so white never gets to move again
+        assert_eq!(*heard.lock().unwrap(), ["e4", "new game"]);
+        assert_eq!(console.text().matches("move limit reached").count(), 1);
+    }
+
+    #[test]
+    fn shutdown_parks_the_gantry_and_clears_the_leds() {
+        let config = GameConfig::builder().human(Color::Black).keyboard(true).build();
+        let wrapper = Wrapper::connect(std::io::Cursor::new("e4\n"), std::io::sink());
+        let mut opponent: Box<dyn Opponent> = Box::new(wrapper);
+        let (reeds, reed_lines) = std::sync::mpsc::channel();
+        let views = ViewServer::default();
+        let mut console = Vec::new();
+        let sent = Shared::default();
+        let mut session =
+            GameSession::new(0, &config, &mut opponent, &reed_lines, &views, &mut console).unwrap();
+        session.gantry = Some(Gantry::new(sent.clone(), None, None));
+        // the reeds stay quiet, so e4 goes to the gantry once the board settles
+        assert!(matches!(session.poll(None).unwrap(), Tick::Playing));
+        let carried = sent.text();
+        assert!(carried.ends_with("X5.0 Y4.0 M1\n"));
+        reeds.send(Some(SHUTDOWN_LINE.to_string())).unwrap();
+        assert!(matches!(session.poll(None).unwrap(), Tick::Over(None)));
+        drop(session);
+        // the pawn let go of where it was set down, then home with the magnet off
+        assert_eq!(sent.text()[carried.len()..], *"X5.0 Y4.0 M0\nX0.0 Y0.0 M0\n");
+        let mut dark = Vec::new();
+        print_leds(&mut dark, RGB::default(), false).unwrap();
+        assert!(console.ends_with(&dark));
+    }
+
+    #[test]
+    fn pause_freezes_the_clocks_and_resumes_where_it_left_off() {
+        let time_control = "5+0".parse().unwrap();
+        let config = GameConfig::builder()
+            .human(Color::White)
+            .keyboard(true)
+            .time_control(Some(time_control))
+            .build();
+        let mut opponent: Box<dyn Opponent> = Box::new(Scripted::new(&["e5"]));
+        let (reeds, reed_lines) = std::sync::mpsc::channel();
+        let views = ViewServer::default();
+        let mut console = Vec::new();
+        let mut session =
+            GameSession::new(0, &config, &mut opponent, &reed_lines, &views, &mut console).unwrap();
+        let press = |session: &mut GameSession, line: &str| {
+            reeds.send(Some(line.to_string())).unwrap();
+            assert!(matches!(session.poll(None).unwrap(), Tick::Playing));
+            session.game.state
+        };
+        assert_eq!(press(&mut session, "e2"), State::FriendlyPU(Square::E2));
+        assert_eq!(press(&mut session, "pause"), State::Paused);
+        let remaining = |session: &GameSession| {
+            session.game.clocks.as_ref().unwrap().remaining(Color::White, Instant::now())
+        };
+        let frozen = remaining(&session);
+        std::thread::sleep(Duration::from_millis(50));
+        assert_eq!(remaining(&session), frozen);
+        // the board is ignored until the game resumes
+        assert_eq!(press(&mut session, "e4"), State::Paused);
+        assert_eq!(press(&mut session, "pause"), State::FriendlyPU(Square::E2));
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(remaining(&session) < frozen);
+        assert_eq!(press(&mut session, "e4"), State::Idle);
+        assert_eq!(plies_played(&session.game.pos), 1);
+    }
+
+    // white offers a draw by pressing the black king twice and confirming,
+    // then plays e4 if the game is still on
+    fn offer_draw(takes_draws: bool) -> (GameSummary, Vec<String>) {
+        let config = GameConfig::builder().human(Color::White).keyboard(true).build();
+        let scripted = Scripted {
+            takes_draws,
+            ..Scripted::new(&["e5"])
+        };
+        let heard = Arc::clone(&scripted.heard);
+        let mut opponent: Box<dyn Opponent> = Box::new(scripted);
+        let reeds = reed_input(&["e8", "e8", "e8", "e8", "e8", "e2", "e4"]);
+        let views = ViewServer::default();
+        let mut console = Vec::new();
+        let summary = crate::run(&config, &mut opponent, &reeds, &views, &mut console).unwrap();
+        let heard = heard.lock().unwrap().clone();
+        (summary, heard)
+    }
+
+    #[test]
+    fn accepted_draw_offer_ends_the_game() {
+        let (summary, heard) = offer_draw(true);
+        assert_eq!(heard, ["draw?"]);
+        assert_eq!(summary.result, Some((Outcome::Draw, "agreement")));
+        assert_eq!(summary.position, Chess::default());
+    }
+
+    #[test]
+    fn declined_draw_offer_plays_on() {
+        let (summary, heard) = offer_draw(false);
+        assert_eq!(heard, ["draw?", "e4"]);
+        assert_eq!(summary.result, None);
+        assert_eq!(plies_played(&summary.position), 2);
+    }
+
+    #[test]
+    fn bare_kings_end_in_a_draw_without_waiting_on_the_reeds() {
+        let start = "4k3/8/8/8/8/8/8/4K3 w - - 0 1".parse::<Fen>().unwrap();
+        let config = GameConfig::builder()
+            .human(Color::White)
+            .keyboard(true)
+            .start(start.into_position(CastlingMode::Standard).unwrap())
+            .build();
+        let mut opponent: Box<dyn Opponent> = Box::new(Scripted::default());
+        let (_reeds, reed_lines) = std::sync::mpsc::channel();
+        let views = ViewServer::default();
+        let mut console = Vec::new();
+        let summary = crate::run(&config, &mut opponent, &reed_lines, &views, &mut console).unwrap();
+        assert_eq!(summary.result, Some((Outcome::Draw, "insufficient material")));
+    }
+
+    // a gantry whose frames show the board put right once it has been sent
+    // anything, as they would once the carriage fetched a dropped piece
+    struct Fetching {
+        sent: Shared,
+        reeds: std::sync::mpsc::Sender<Option<String>>,
+        fixed: Bitboard,
+    }
+
+    impl Write for Fetching {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            let _ = self.reeds.send(Some(format!("{:x}", self.fixed.0)));
+            self.sent.write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn dropped_piece_is_regrabbed_where_it_fell() {
+        let config = GameConfig::builder().human(Color::Black).snapshot(true).build();
+        let mut opponent: Box<dyn Opponent> = Box::new(Scripted::default());
+        let (reeds, reed_lines) = std::sync::mpsc::channel();
+        let views = ViewServer::default();
+        let mut console = Vec::new();
+        let mut session =
+            GameSession::new(0, &config, &mut opponent, &reed_lines, &views, &mut console).unwrap();
+        // the gantry has just carried out e4
+        let before = Chess::default();
+        let e4 = "e4".parse::<San>().unwrap().to_move(&before).unwrap();
+        let after = before.clone().play(&e4).unwrap();
+        session.game.pos = after.clone();
+        session.game.last_frame = before.board().occupied();
+        let landed = after.board().occupied();
+        let sent = Shared::default();
+        let gantry = Fetching {
+            sent: sent.clone(),
+            reeds: reeds.clone(),
+            fixed: landed,
+        };
+        session.gantry = Some(Gantry::new(gantry, None, None));
+        // but the pawn came off the magnet on e3
+        let dropped = landed.without(Bitboard::from_square(Square::E4));
+        let dropped = dropped.with(Bitboard::from_square(Square::E3));
+        reeds.send(Some(format!("{:x}", dropped.0))).unwrap();
+        session.watch_landing(snapshot::landing(&before, &after));
+        assert_eq!(session.game.state, State::Idle);
+        assert_eq!(session.game.last_frame, landed);
+        let carried = sent.text();
+        assert!(carried.starts_with("X5.0 Y3.0 M0\n"), "{carried}");
+        assert!(carried.contains("X5.0 Y4.0 M1\n"), "{carried}");
+    }
+
+    #[test]
+    fn move_the_gantry_cant_reach_is_left_to_the_human() {
+        // a carriage that only reaches the first four files
+        let geometry = BoardGeometry {
+            max_x: 4.0,
+            ..BoardGeometry::default()
+        };
+        // and LEDs quick enough that no frame is held back
+        let config = GameConfig::builder()
+            .human(Color::Black)
+            .keyboard(true)
+            .geometry(geometry)
+            .led_fps(1_000_000_000)
+            .build();
+        let wrapper = Wrapper::connect(std::io::Cursor::new("e4\n"), std::io::sink());
+        let mut opponent: Box<dyn Opponent> = Box::new(wrapper);
+        let (_reeds, reed_lines) = std::sync::mpsc::channel();
+        let views = ViewServer::default();
+        let mut console = Vec::new();
+        let sent = Shared::default();
+        let mut session =
+            GameSession::new(0, &config, &mut opponent, &reed_lines, &views, &mut console).unwrap();
+        session.gantry = Some(Gantry::new(sent.clone(), None, None));
+        assert!(matches!(session.poll(None).unwrap(), Tick::Playing));
+        // nothing went to the gantry but the move stands, lit up for the human
+        assert_eq!(sent.text(), "");
+        assert_eq!(session.game.pos.board().role_at(Square::E4), Some(Role::Pawn));
+        let mv = session.game.last_move.clone().unwrap();
+        drop(session);
+        let mut lit = Vec::new();
+        print_leds(&mut lit, unreachable_move_rgb(&mv, &config.theme), false).unwrap();
+        assert!(console.windows(lit.len()).any(|shown| shown == lit.as_slice()));
+    }
+
+    #[test]
+    fn piece_that_never_comes_back_is_waited_for() {
+        let config = GameConfig::builder()
+            .human(Color::White)
+            .snapshot(true)
+            .pickup_timeout(Some(Duration::from_millis(10)))
+            .build();
+        let mut opponent: Box<dyn Opponent> = Box::new(Scripted::default());
+        let (reeds, reed_lines) = std::sync::mpsc::channel();
+        let views = ViewServer::default();
+        let mut console = Vec::new();
+        let mut session =
+            GameSession::new(0, &config, &mut opponent, &reed_lines, &views, &mut console).unwrap();
+        let start = Chess::default().board().occupied();
+        let lifted = start.without(Bitboard::from_square(Square::E2));
+        let wait = Some(Duration::from_millis(20));
+        for frame in [start, lifted] {
+            reeds.send(Some(format!("{:x}", frame.0))).unwrap();
+            session.poll(wait).unwrap();
+        }
+        assert_eq!(session.game.state, State::FriendlyPU(Square::E2));
+        // no frame puts it down anywhere before the timeout
+        session.poll(wait).unwrap();
+        assert_eq!(session.game.state, State::MissingPiece(Square::E2));
+        // still waiting while the square stays empty
+        reeds.send(Some(format!("{:x}", lifted.0))).unwrap();
+        session.poll(wait).unwrap();
+        assert_eq!(session.game.state, State::MissingPiece(Square::E2));
+        reeds.send(Some(format!("{:x}", start.0))).unwrap();
+        session.poll(wait).unwrap();
+        assert_eq!(session.game.state, State::Idle);
+    }
+
+    #[test]
+    fn misplaced_piece_holds_the_game_until_it_is_moved() {
+        // a loaded position, as a resumed game would be
+        let start = "4k3/4p3/8/8/8/8/4P3/4K3 w - - 0 1".parse::<Fen>().unwrap();
+        let start = start.into_position::<Chess>(CastlingMode::Standard).unwrap();
+        let config = GameConfig::builder()
+            .human(Color::White)
+            .snapshot(true)
+            .start(start.clone())
+            .led_fps(1_000_000_000)
+            .build();
+        let mut opponent: Box<dyn Opponent> = Box::new(Scripted::default());
+        let (reeds, reed_lines) = std::sync::mpsc::channel();
+        let views = ViewServer::default();
+        let mut console = Vec::new();
+        let mut session =
+            GameSession::new(0, &config, &mut opponent, &reed_lines, &views, &mut console).unwrap();
+        // the white pawn was set down on e3
+        let expected = start.board().occupied();
+        let (e2, e3) = (Bitboard::from_square(Square::E2), Bitboard::from_square(Square::E3));
+        for frame in [expected.without(e2).with(e3), expected] {
+            reeds.send(Some(format!("{:x}", frame.0))).unwrap();
+        }
+        session.poll(None).unwrap();
+        assert!(!session.game.set_up);
+        session.poll(None).unwrap();
+        assert!(session.game.set_up);
+        drop(session);
+        let mut lit = Vec::new();
+        print_leds(&mut lit, setup_rgb(e2, e3, &config.theme), false).unwrap();
+        assert!(console.starts_with(&lit));
+    }
+
+    #[test]
+    fn reset_after_a_game_starts_over_from_scratch() {
+        let config = GameConfig::builder()
+            .human(Color::White)
+            .keyboard(true)
+            .time_control(Some("5+0".parse().unwrap()))
+            .build();
+        let scripted = Scripted::new(&["e5", "Nc6", "Nf6"]);
+        let heard = Arc::clone(&scripted.heard);
+        let mut opponent: Box<dyn Opponent> = Box::new(scripted);
+        // scholar's mate, ending in Qxf7
+        let squares = ["e2", "e4", "f1", "c4", "d1", "h5", "h5", "f7", "f7"];
+        let reed_lines = reed_input(&squares);
+        let views = ViewServer::default();
+        let mut console = Vec::new();
+        let mut session =
+            GameSession::new(0, &config, &mut opponent, &reed_lines, &views, &mut console).unwrap();
+        let result = loop {
+            if let Tick::Over(result) = session.poll(None).unwrap() {
+                break result;
+            }
+        };
+        assert_eq!(result, Some((Outcome::Decisive { winner: Color::White }, "checkmate")));
+        assert_eq!(session.game.graveyard.captured_blacks, 1);
+        session.reset().unwrap();
+        let game = &session.game;
+        assert_eq!(game.pos, Chess::default());
+        assert_eq!(game.state, State::Idle);
+        assert_eq!(game.graveyard, Graveyard::default());
+        assert!(game.moves.is_empty() && game.emitted_steps.is_empty());
+        assert_eq!(game.last_move, None);
+        let white = game.clocks.as_ref().unwrap().remaining(Color::White, Instant::now());
+        assert!(white > Duration::from_secs(299));
+        assert_eq!(heard.lock().unwrap().last().map(String::as_str), Some("new game"));
+    }
+
+    #[test]
+    fn two_humans_take_turns_with_no_opponent() {
+        let config = GameConfig::builder()
+            .human(Color::White)
+            .keyboard(true)
+            .opponent(OpponentBackend::Nobody)
+            .build();
+        let mut opponent: Box<dyn Opponent> = Box::new(crate::opponent::Nobody);
+        let reeds = reed_input(&["e2", "e4", "e7", "e5", "g1", "f3"]);
+        let views = ViewServer::default();
+        let mut console = Vec::new();
+        let summary = crate::run(&config, &mut opponent, &reeds, &views, &mut console).unwrap();
+        let mut expected = Chess::default();
+        for san in ["e4", "e5", "Nf3"] {
+            let mv = san.parse::<San>().unwrap().to_move(&expected).unwrap();
+            expected = expected.play(&mv).unwrap();
+        }
+        assert_eq!(summary.position, expected);
+        // the humans moved every piece themselves
+        assert!(summary.steps.is_empty());
+    }
+}
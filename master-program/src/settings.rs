@@ -0,0 +1,73 @@
+use regex::Regex;
+use serde::de::Error;
+use serde::{Deserialize, Deserializer};
+use shakmaty::Color;
+use std::str::FromStr;
+
+use crate::config::{BoardGeometry, MagnetWiring, Speed, SquareMapping, TimeControl};
+use crate::parse_color;
+
+// a game set up ahead of time with --config. each key is named after the flag
+// it stands in for and takes the same values, and a flag given as well wins.
+// the [geometry] table is the one --calibrate prints
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    #[serde(deserialize_with = "color")]
+    pub human_color: Option<Color>,
+    #[serde(deserialize_with = "parsed")]
+    pub time_control: Option<TimeControl>,
+    pub engine: Option<String>,
+    pub remote_engine: Option<String>,
+    pub engine_depth: Option<u32>,
+    pub engine_movetime: Option<u32>,
+    pub engine_skill: Option<u32>,
+    #[serde(deserialize_with = "regex")]
+    pub wrapper_prompt: Option<Regex>,
+    pub theme: Option<String>,
+    pub flip: Option<bool>,
+    #[serde(deserialize_with = "parsed")]
+    pub square_mapping: Option<SquareMapping>,
+    #[serde(deserialize_with = "speed")]
+    pub speed: Option<Speed>,
+    #[serde(deserialize_with = "parsed")]
+    pub magnet_wiring: Option<MagnetWiring>,
+    pub geometry: Option<BoardGeometry>,
+}
+
+impl Settings {
+    pub fn load(path: &str) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read config {path}: {e}"))?;
+        toml::from_str(&text).map_err(|e| format!("Invalid config {path}: {e}"))
+    }
+}
+
+// a value read with the same parser as its flag, so a bad one is reported the
+// same way, along with where it is in the file
+fn parsed<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr<Err = String>,
+{
+    let text = String::deserialize(deserializer)?;
+    text.parse().map(Some).map_err(D::Error::custom)
+}
+
+fn color<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Color>, D::Error> {
+    let text = String::deserialize(deserializer)?;
+    parse_color(&text).map(Some).map_err(D::Error::custom)
+}
+
+fn regex<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Regex>, D::Error> {
+    let text = String::deserialize(deserializer)?;
+    Regex::new(&text)
+        .map(Some)
+        .map_err(|e| D::Error::custom(format!("{text} is not a prompt regex: {e}")))
+}
+
+// a number like the one --speed takes
+fn speed<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Speed>, D::Error> {
+    let multiplier = f64::deserialize(deserializer)?;
+    Speed::try_from(multiplier).map(Some).map_err(D::Error::custom)
+}
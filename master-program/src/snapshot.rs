@@ -0,0 +1,226 @@
+use shakmaty::{Bitboard, CastlingSide, Chess, Move, Position, Square};
+
+use crate::State;
+
+// in snapshot mode the reeds report every square at once: a 64 bit occupancy
+// in hex with a1 as the lowest bit, instead of a single toggled square
+pub fn parse_frame(text: &str) -> Result<Bitboard, String> {
+    let digits = text.trim_start_matches("0x");
+    u64::from_str_radix(digits, 16)
+        .map(Bitboard)
+        .map_err(|_| format!("{text:?} is not an occupancy frame"))
+}
+
+// where the model believes pieces are standing right now, given what's in the air
+pub fn expected_occupancy(position: &Chess, state: State) -> Bitboard {
+    let occupied = position.board().occupied();
+    let lifted = |squares: &[Square]| {
+        squares
+            .iter()
+            .fold(occupied, |acc, &square| acc.without(Bitboard::from_square(square)))
+    };
+    match state {
+        State::Idle
+        | State::ConfirmResign(_)
+        | State::Resigned
+        | State::ConfirmDraw(_)
+        | State::DrawOffered
+        | State::DrawAgreed
+        | State::DrawClaimed
+        | State::ClaimRejected
+        | State::Paused
+        | State::TouchMove(_)
+        | State::Error => occupied,
+        State::FriendlyPU(square)
+        | State::EnemyPU(square)
+        | State::InvalidPiecePU(None, square)
+        | State::MissingPiece(square)
+        | State::TouchMoveViolation(_, square)
+        | State::ClaimDraw(square) => lifted(&[square]),
+        State::FriendlyAndEnemyPU(first, second)
+        | State::Castling(first, second)
+        | State::IllegalCastle(first, second)
+        | State::InvalidPiecePU(Some(first), second) => lifted(&[first, second]),
+        State::CastlingPutRookDown(king, rook, _) => {
+            let side = CastlingSide::from_king_side(king.file() < rook.file());
+            let king_to = side.king_to(position.turn());
+            lifted(&[king, rook]).with(Bitboard::from_square(king_to))
+        }
+        State::CastlingPutKingDown(king, rook, _) => {
+            let side = CastlingSide::from_king_side(king.file() < rook.file());
+            let rook_to = side.rook_to(position.turn());
+            lifted(&[king, rook]).with(Bitboard::from_square(rook_to))
+        }
+        State::CastlingMisplaced(king, rook, misplaced, down) => {
+            let down = down.map_or(Bitboard::EMPTY, Bitboard::from_square);
+            lifted(&[king, rook]).with(Bitboard::from_square(misplaced)).with(down)
+        }
+        State::PromotionSelect(from, to, _)
+        | State::PromotionSwap(from, to, _, _)
+        | State::InvalidMove(from, to) => lifted(&[from]).with(Bitboard::from_square(to)),
+        State::PromotionPlace(from, to, _, _) => lifted(&[from, to]),
+        State::UnexpectedOccupancy(square) => occupied.with(Bitboard::from_square(square)),
+    }
+}
+
+// the squares a move sets a piece down on, which the frames should show
+// filled once the gantry has carried it out
+pub fn landing(before: &Chess, after: &Chess) -> Bitboard {
+    let (before, after) = (before.board(), after.board());
+    after
+        .occupied()
+        .into_iter()
+        .filter(|&square| before.piece_at(square) != after.piece_at(square))
+        .collect()
+}
+
+// a piece the magnet lost on the way: the first of the `landing` squares that
+// `frame` still shows empty, and where the frame shows a piece the position
+// doesn't have instead, if anywhere, which is likely where it fell
+pub fn lost_piece(
+    landing: Bitboard,
+    expected: Bitboard,
+    frame: Bitboard,
+) -> Option<(Square, Option<Square>)> {
+    let missing = landing.without(frame).first()?;
+    Some((missing, frame.without(expected).first()))
+}
+
+// turns the change between two frames into toggled squares for update_state,
+// lifts before placements. a square filling up while the model thinks it is
+// still occupied means a piece was set down on top of another (usually a
+// capture that was never cleared), and is returned as the error
+pub fn diff_frame(
+    previous: Bitboard,
+    frame: Bitboard,
+    expected: Bitboard,
+) -> Result<Vec<Square>, Square> {
+    let lifts = previous.without(frame);
+    let placements = frame.without(previous);
+    if let Some(stacked) = placements.intersect(expected).first() {
+        return Err(stacked);
+    }
+    Ok(lifts.into_iter().chain(placements).collect())
+}
+
+// castling read from whole frames, since the king and rook may be lifted and
+// set down in any order or all at once, which the square by square castling
+// states can't follow. once `frame` shows a legal castle finished this gives
+// the toggles that take `state` through it, while one is under way it gives
+// none, and when `frame` isn't castling at all it gives None
+pub fn castle_toggles(position: &Chess, state: State, frame: Bitboard) -> Option<Vec<Square>> {
+    let occupied = position.board().occupied();
+    let color = position.turn();
+    position.legal_moves().into_iter().find_map(|mv| {
+        let Move::Castle { king, rook } = mv else {
+            return None;
+        };
+        let side = CastlingSide::from_king_side(king.file() < rook.file());
+        let (king_to, rook_to) = (side.king_to(color), side.rook_to(color));
+        let home = occupied
+            .without(Bitboard::from_square(king))
+            .without(Bitboard::from_square(rook));
+        let landing = Bitboard::from_square(king_to).with(Bitboard::from_square(rook_to));
+        let done = frame == home.with(landing);
+        match state {
+            State::Idle if done => Some(vec![king, rook, king_to, rook_to]),
+            State::FriendlyPU(lifted) if done && lifted == king => {
+                Some(vec![rook, king_to, rook_to])
+            }
+            State::FriendlyPU(lifted) if done && lifted == rook => {
+                Some(vec![king, king_to, rook_to])
+            }
+            State::Castling(k, r) if k == king && r == rook => {
+                if done {
+                    Some(vec![king_to, rook_to])
+                } else {
+                    (frame.without(landing) == home).then(Vec::new)
+                }
+            }
+            State::CastlingPutRookDown(k, r, _) if k == king && r == rook => {
+                if done {
+                    Some(vec![rook_to])
+                } else {
+                    (frame.without(landing) == home).then(Vec::new)
+                }
+            }
+            State::CastlingPutKingDown(k, r, _) if k == king && r == rook => {
+                if done {
+                    Some(vec![king_to])
+                } else {
+                    (frame.without(landing) == home).then(Vec::new)
+                }
+            }
+            _ => None,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shakmaty::{fen::Fen, CastlingMode};
+
+    fn position(fen: &str) -> Chess {
+        fen.parse::<Fen>().unwrap().into_position(CastlingMode::Standard).unwrap()
+    }
+
+    fn squares(squares: &[Square]) -> Bitboard {
+        squares.iter().copied().collect()
+    }
+
+    #[test]
+    fn diff_gives_lifts_before_placements() {
+        let start = Chess::default().board().occupied();
+        let e2 = squares(&[Square::E2]);
+        let e4 = squares(&[Square::E4]);
+        let moved = start.without(e2).with(e4);
+        // both reeds changed in one frame, e2 lifted first
+        assert_eq!(diff_frame(start, moved, start), Ok(vec![Square::E2, Square::E4]));
+        assert_eq!(diff_frame(start, start.without(e2), start), Ok(vec![Square::E2]));
+        assert_eq!(diff_frame(start, start, start), Ok(vec![]));
+    }
+
+    #[test]
+    fn piece_set_down_on_an_occupied_square_is_stacking() {
+        // the model still has a black pawn on d5 that the reeds lost sight
+        // of, and a piece has just been set down there
+        let pos = position("4k3/8/8/3p4/8/8/8/4K3 w - - 0 1");
+        let expected = expected_occupancy(&pos, State::Idle);
+        let d5 = squares(&[Square::D5]);
+        let previous = expected.without(d5);
+        assert_eq!(diff_frame(previous, expected, expected), Err(Square::D5));
+        // once the model has it lifted the same frame is a placement
+        let lifted = expected_occupancy(&pos, State::EnemyPU(Square::D5));
+        assert_eq!(lifted, expected.without(d5));
+        assert_eq!(diff_frame(previous, expected, lifted), Ok(vec![Square::D5]));
+    }
+
+    #[test]
+    fn castling_frames_give_the_toggles_to_finish_it() {
+        let pos = position("r3k2r/8/8/8/8/8/8/R3K2R w KQkq - 0 1");
+        let start = pos.board().occupied();
+        let kingside = start.without(squares(&[Square::E1, Square::H1]));
+        let kingside = kingside.with(squares(&[Square::G1, Square::F1]));
+        // king and rook moved between two frames
+        assert_eq!(
+            castle_toggles(&pos, State::Idle, kingside),
+            Some(vec![Square::E1, Square::H1, Square::G1, Square::F1])
+        );
+        // the king was seen lifted first
+        assert_eq!(
+            castle_toggles(&pos, State::FriendlyPU(Square::E1), kingside),
+            Some(vec![Square::H1, Square::G1, Square::F1])
+        );
+        // queenside, with the king set down and the rook still in the air
+        let queenside = start.without(squares(&[Square::E1, Square::A1]));
+        let castling = State::Castling(Square::E1, Square::A1);
+        let half_done = queenside.with(squares(&[Square::C1]));
+        assert_eq!(castle_toggles(&pos, castling, half_done), Some(vec![]));
+        let done = half_done.with(squares(&[Square::D1]));
+        assert_eq!(castle_toggles(&pos, castling, done), Some(vec![Square::C1, Square::D1]));
+        // a rook move isn't castling
+        let rook_move = start.without(squares(&[Square::H1])).with(squares(&[Square::H5]));
+        assert_eq!(castle_toggles(&pos, State::Idle, rook_move), None);
+    }
+}
@@ -0,0 +1,149 @@
+use log::{info, warn};
+use serde::Serialize;
+use std::net::{TcpListener, TcpStream};
+use std::sync::mpsc;
+use std::time::Duration;
+use tungstenite::{Message, WebSocket};
+
+use crate::telemetry::Event;
+use crate::view::BoardView;
+
+// a spectator that stops reading is dropped rather than left to hold up the rest
+const WRITE_TIMEOUT: Duration = Duration::from_secs(2);
+
+// what the broadcast thread is handed, already turned into JSON
+enum Outgoing {
+    Joined(Box<WebSocket<TcpStream>>),
+    View(String),
+    Move(String),
+}
+
+// the view goes out tagged the same way as telemetry events, so a client can
+// tell it from a move by its "event" field
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum Spectated<'a> {
+    View(&'a BoardView),
+}
+
+// WebSocket clients watching the game, for a web frontend that mirrors the
+// board. each gets the latest view when it connects, then every view that
+// differs from the one before and every committed move as a text message.
+// clients come and go on threads of their own, so the game never waits on them
+#[derive(Clone)]
+pub struct Spectators {
+    outgoing: mpsc::Sender<Outgoing>,
+}
+
+impl Spectators {
+    pub fn serve(addr: &str) -> std::io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        info!("broadcasting to spectators on ws://{}", listener.local_addr()?);
+        let (outgoing, rx) = mpsc::channel();
+        let joined = outgoing.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let client = stream
+                    .and_then(|stream| {
+                        stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+                        Ok(stream)
+                    })
+                    .map_err(|e| e.to_string())
+                    .and_then(|stream| tungstenite::accept(stream).map_err(|e| e.to_string()));
+                match client {
+                    Ok(client) => {
+                        if joined.send(Outgoing::Joined(Box::new(client))).is_err() {
+                            return;
+                        }
+                    }
+                    Err(e) => warn!("spectator failed to connect: {e}"),
+                }
+            }
+        });
+        std::thread::spawn(move || broadcast(&rx));
+        Ok(Self { outgoing })
+    }
+
+    pub fn view(&self, view: &BoardView) {
+        self.send(Outgoing::View, &Spectated::View(view));
+    }
+
+    // `event` is the telemetry event for the move
+    pub fn moved(&self, event: &Event) {
+        self.send(Outgoing::Move, event);
+    }
+
+    fn send(&self, outgoing: fn(String) -> Outgoing, message: &impl Serialize) {
+        match serde_json::to_string(message) {
+            // the broadcast thread only ends with the program
+            Ok(json) => drop(self.outgoing.send(outgoing(json))),
+            Err(e) => warn!("Failed to encode spectator message: {e}"),
+        }
+    }
+}
+
+fn broadcast(rx: &mpsc::Receiver<Outgoing>) {
+    let mut clients = Vec::new();
+    let mut latest: Option<String> = None;
+    for outgoing in rx {
+        match outgoing {
+            Outgoing::Joined(mut client) => {
+                if let Some(view) = &latest {
+                    if let Err(e) = client.write_message(Message::Text(view.clone())) {
+                        info!("spectator left: {e}");
+                        continue;
+                    }
+                }
+                clients.push(*client);
+                info!("{} spectators watching", clients.len());
+            }
+            Outgoing::View(json) => {
+                send_all(&mut clients, &json);
+                latest = Some(json);
+            }
+            Outgoing::Move(json) => send_all(&mut clients, &json),
+        }
+    }
+}
+
+// drops every client the message can't be written to
+fn send_all(clients: &mut Vec<WebSocket<TcpStream>>, json: &str) {
+    clients.retain_mut(|client| match client.write_message(Message::Text(json.to_string())) {
+        Ok(()) => true,
+        Err(e) => {
+            info!("spectator left: {e}");
+            false
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shakmaty::{san::San, Chess, Position};
+
+    #[test]
+    fn committed_move_is_broadcast_to_a_spectator() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        let watcher = std::thread::spawn(move || {
+            let (mut socket, _) = tungstenite::connect(url.as_str()).unwrap();
+            socket.read_message().unwrap().into_text().unwrap()
+        });
+        let stream = listener.accept().unwrap().0;
+        let client = tungstenite::accept(stream).unwrap();
+        let (outgoing, rx) = mpsc::channel();
+        outgoing.send(Outgoing::Joined(Box::new(client))).unwrap();
+        let spectators = Spectators { outgoing };
+        let before = Chess::default();
+        let e4 = "e4".parse::<San>().unwrap().to_move(&before).unwrap();
+        let after = before.clone().play(&e4).unwrap();
+        spectators.moved(&Event::from_move(&before, &e4, &after, &[]));
+        drop(spectators);
+        broadcast(&rx);
+        let heard: serde_json::Value = serde_json::from_str(&watcher.join().unwrap()).unwrap();
+        assert_eq!(heard["event"], "move");
+        assert_eq!(heard["san"], "e4");
+        assert_eq!(heard["uci"], "e2e4");
+    }
+}
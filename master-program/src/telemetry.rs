@@ -0,0 +1,155 @@
+use log::error;
+use serde::Serialize;
+use shakmaty::{fen::Fen, san::San, uci::Uci, Chess, EnPassantMode, Move, Position};
+use std::fs::File;
+use std::io::Write;
+
+use crate::metrics::Summary;
+use crate::narrate::narrate;
+use crate::{State, Step};
+
+// one JSON object per line, written next to (not into) the human readable log
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event<'a> {
+    Transition {
+        instruction: u32,
+        from: State,
+        to: State,
+    },
+    Move {
+        side: &'static str,
+        san: String,
+        uci: String,
+        fen: String,
+        halfmoves: u32,
+        fullmoves: u32,
+        // the move in words, for a text-to-speech layer
+        narration: String,
+        steps: &'a [Step],
+    },
+    GameOver {
+        // 1-0, 0-1 or 1/2-1/2
+        result: String,
+        reason: &'static str,
+    },
+    Metrics(Summary),
+}
+
+impl<'a> Event<'a> {
+    // `before` is the position the move was played from, `after` the one it produced
+    pub fn from_move(before: &Chess, mv: &Move, after: &Chess, steps: &'a [Step]) -> Self {
+        Event::Move {
+            side: if before.turn().is_white() { "white" } else { "black" },
+            san: San::from_move(before, mv).to_string(),
+            uci: Uci::from_move(mv, before.castles().mode()).to_string(),
+            fen: Fen::from_position(after.clone(), EnPassantMode::Legal).to_string(),
+            halfmoves: after.halfmoves(),
+            fullmoves: after.fullmoves().get(),
+            narration: narrate(before, mv),
+            steps,
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Telemetry {
+    sink: Option<Box<dyn Write>>,
+}
+
+impl Telemetry {
+    // `target` is either a file path or, on unix, an already open file descriptor number
+    pub fn open(target: &str) -> std::io::Result<Self> {
+        #[cfg(unix)]
+        if let Ok(fd) = target.parse::<i32>() {
+            use std::os::unix::io::FromRawFd;
+            // SAFETY: the fd is handed to us by whoever launched the program and is not used elsewhere
+            let file = unsafe { File::from_raw_fd(fd) };
+            return Ok(Self::from_writer(file));
+        }
+        let file = File::options().create(true).append(true).open(target)?;
+        Ok(Self::from_writer(file))
+    }
+
+    pub fn from_writer(writer: impl Write + 'static) -> Self {
+        Self {
+            sink: Some(Box::new(writer)),
+        }
+    }
+
+    pub fn emit(&mut self, event: &Event) {
+        let Some(sink) = self.sink.as_mut() else {
+            return;
+        };
+        let res = serde_json::to_writer(&mut *sink, event)
+            .map_err(std::io::Error::from)
+            .and_then(|()| writeln!(sink))
+            .and_then(|()| sink.flush());
+        if let Err(e) = res {
+            error!("Failed to write telemetry event: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::Shared;
+    use crate::Magnet;
+    use serde_json::{json, Value};
+
+    #[test]
+    fn committed_move_is_one_json_line() {
+        let out = Shared::default();
+        let mut telemetry = Telemetry::from_writer(out.clone());
+        let before = Chess::default();
+        let e4 = "e4".parse::<San>().unwrap().to_move(&before).unwrap();
+        let after = before.clone().play(&e4).unwrap();
+        let steps = [
+            Step {
+                x: 5.0,
+                y: 2.0,
+                magnet: Magnet::Released,
+                feedrate: None,
+            },
+            Step {
+                x: 5.0,
+                y: 4.0,
+                magnet: Magnet::Engaged,
+                feedrate: None,
+            },
+        ];
+        telemetry.emit(&Event::Transition {
+            instruction: 12,
+            from: State::Idle,
+            to: State::FriendlyPU(shakmaty::Square::E2),
+        });
+        telemetry.emit(&Event::from_move(&before, &e4, &after, &steps));
+        let text = out.text();
+        let lines: Vec<Value> =
+            text.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        let transition = json!({
+            "event": "transition",
+            "instruction": 12,
+            "from": "Idle",
+            "to": "FriendlyPU(E2)",
+        });
+        assert_eq!(lines[0], transition);
+        let mv = &lines[1];
+        assert_eq!(mv["event"], "move");
+        assert_eq!(mv["side"], "white");
+        assert_eq!(mv["san"], "e4");
+        assert_eq!(mv["uci"], "e2e4");
+        assert_eq!(mv["fen"], "rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq - 0 1");
+        assert_eq!(mv["halfmoves"], 0);
+        assert_eq!(mv["fullmoves"], 1);
+        assert!(mv["narration"].is_string());
+        assert_eq!(
+            mv["steps"],
+            json!([
+                {"x": 5.0, "y": 2.0, "magnet": false},
+                {"x": 5.0, "y": 4.0, "magnet": true}
+            ])
+        );
+    }
+}
@@ -0,0 +1,233 @@
+use serde::Deserialize;
+use shakmaty::Bitboard;
+
+use crate::RGB;
+
+// which LED channels light up for one meaning; the LEDs are switched per
+// channel, so any non-zero component of the [r, g, b] triple turns it on
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(from = "[u8; 3]")]
+pub struct Shade {
+    r: bool,
+    g: bool,
+    b: bool,
+}
+
+impl From<[u8; 3]> for Shade {
+    fn from([r, g, b]: [u8; 3]) -> Self {
+        Self {
+            r: r > 0,
+            g: g > 0,
+            b: b > 0,
+        }
+    }
+}
+
+const OFF: Shade = Shade {
+    r: false,
+    g: false,
+    b: false,
+};
+const RED: Shade = Shade {
+    r: true,
+    g: false,
+    b: false,
+};
+const GREEN: Shade = Shade {
+    r: false,
+    g: true,
+    b: false,
+};
+const BLUE: Shade = Shade {
+    r: false,
+    g: false,
+    b: true,
+};
+const YELLOW: Shade = Shade {
+    r: true,
+    g: true,
+    b: false,
+};
+const MAGENTA: Shade = Shade {
+    r: true,
+    g: false,
+    b: true,
+};
+
+// the colour for every meaning the board can show; a theme file only needs
+// the entries it wants to change
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Theme {
+    pub legal_move: Shade,
+    pub capture: Shade,
+    pub promotion_move: Shade,
+    pub promotion_capture: Shade,
+    // pieces that can take an enemy piece which has been lifted
+    pub attacker: Shade,
+    // the lifted enemy piece while a friendly one is also in the air
+    pub capture_target: Shade,
+    pub castle_target: Shade,
+    pub promotion_square: Shade,
+    pub promotion_choice: Shade,
+    pub confirm_resign: Shade,
+    pub draw_offer: Shade,
+    pub resigned: Shade,
+    pub error: Shade,
+    pub pickup_reminder: Shade,
+    pub draw: Shade,
+    pub win: Shade,
+    // shown while idle; off by default
+    pub check: Shade,
+    pub last_move: Shade,
+    // pieces with a legal move, shown while idle in --teach mode. the LEDs
+    // have no brightness control, so the default keeps to a single channel
+    pub legal_origin: Shade,
+    // the kings while paused, with the rest of the board dark
+    pub paused: Shade,
+    // the edge of an idle board, by whose move it is
+    pub your_turn: Shade,
+    pub opponent_thinking: Shade,
+    // the whole board when the opponent turns out to be playing a different game
+    pub desync: Shade,
+    // enemy pieces a lifted piece would attack from the square it's held over
+    pub threat: Shade,
+    // a piece the human just moved that can be taken for free, with --coach
+    pub hanging: Shade,
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Self {
+            legal_move: GREEN,
+            capture: YELLOW,
+            promotion_move: MAGENTA,
+            promotion_capture: YELLOW,
+            attacker: GREEN,
+            capture_target: GREEN,
+            castle_target: MAGENTA,
+            promotion_square: RED,
+            promotion_choice: BLUE,
+            confirm_resign: YELLOW,
+            draw_offer: BLUE,
+            resigned: RED,
+            error: RED,
+            pickup_reminder: BLUE,
+            draw: BLUE,
+            win: GREEN,
+            check: OFF,
+            last_move: OFF,
+            legal_origin: BLUE,
+            paused: BLUE,
+            your_turn: GREEN,
+            opponent_thinking: BLUE,
+            desync: MAGENTA,
+            threat: RED,
+            hanging: YELLOW,
+        }
+    }
+}
+
+impl Theme {
+    pub fn load(path: &str) -> Result<Self, String> {
+        let contents = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+        serde_json::from_str(&contents).map_err(|e| e.to_string())
+    }
+
+    // every meaning unlit, for --blindfold: the board stays dark all game
+    pub const fn dark() -> Self {
+        Self {
+            legal_move: OFF,
+            capture: OFF,
+            promotion_move: OFF,
+            promotion_capture: OFF,
+            attacker: OFF,
+            capture_target: OFF,
+            castle_target: OFF,
+            promotion_square: OFF,
+            promotion_choice: OFF,
+            confirm_resign: OFF,
+            draw_offer: OFF,
+            resigned: OFF,
+            error: OFF,
+            pickup_reminder: OFF,
+            draw: OFF,
+            win: OFF,
+            check: OFF,
+            last_move: OFF,
+            legal_origin: OFF,
+            paused: OFF,
+            your_turn: OFF,
+            opponent_thinking: OFF,
+            desync: OFF,
+            threat: OFF,
+            hanging: OFF,
+        }
+    }
+}
+
+// lights `squares` in `shade` on top of whatever is already lit
+pub fn paint(rgb: &mut RGB, squares: Bitboard, shade: Shade) {
+    if shade.r {
+        rgb.r = rgb.r.with(squares);
+    }
+    if shade.g {
+        rgb.g = rgb.g.with(squares);
+    }
+    if shade.b {
+        rgb.b = rgb.b.with(squares);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{get_rgb, State, Waiting};
+    use shakmaty::{fen::Fen, CastlingMode, Chess, Square};
+
+    fn squares(squares: &[Square]) -> Bitboard {
+        squares.iter().copied().collect()
+    }
+
+    // the channels get_rgb lit before there were themes
+    #[test]
+    fn default_theme_keeps_the_original_colours() {
+        let rgb = |fen: &str, state| {
+            let fen: Fen = fen.parse().unwrap();
+            let pos: Chess = fen.into_position(CastlingMode::Standard).unwrap();
+            get_rgb(&pos, state, &Theme::default(), false, None, Waiting::Human)
+        };
+        let pawns = "4k3/8/8/3p4/4P3/8/8/4K3 w - - 0 1";
+        let lifted = rgb(pawns, State::FriendlyPU(Square::E4));
+        let captures = squares(&[Square::D5]);
+        assert_eq!(lifted.r, captures);
+        assert_eq!(lifted.g, squares(&[Square::D5, Square::E5]));
+        assert_eq!(lifted.b, Bitboard::EMPTY);
+        let enemy = rgb(pawns, State::EnemyPU(Square::D5));
+        let attacker = squares(&[Square::E4]);
+        assert_eq!((enemy.r, enemy.g, enemy.b), (Bitboard::EMPTY, attacker, Bitboard::EMPTY));
+        let both = rgb(pawns, State::FriendlyAndEnemyPU(Square::E4, Square::D5));
+        assert_eq!((both.r, both.g, both.b), (Bitboard::EMPTY, captures, Bitboard::EMPTY));
+        let castles = "4k3/8/8/8/8/8/8/4K2R w K - 0 1";
+        let castling = rgb(castles, State::CastlingPutRookDown(Square::E1, Square::H1, Square::F1));
+        let f1 = squares(&[Square::F1]);
+        assert_eq!((castling.r, castling.g, castling.b), (f1, Bitboard::EMPTY, f1));
+    }
+
+    #[test]
+    fn dark_theme_lights_nothing() {
+        let mut rgb = RGB::default();
+        for shade in [Theme::dark().legal_move, Theme::dark().error, Theme::dark().your_turn] {
+            paint(&mut rgb, Bitboard::FULL, shade);
+        }
+        assert_eq!(rgb, RGB::default());
+    }
+
+    #[test]
+    fn theme_file_overrides_only_what_it_names() {
+        let theme: Theme = serde_json::from_str(r#"{"error": [255, 0, 255]}"#).unwrap();
+        assert_eq!(theme.error, MAGENTA);
+        assert_eq!(Theme { error: RED, ..theme }, Theme::default());
+        assert!(serde_json::from_str::<Theme>(r#"{"eror": [255, 0, 0]}"#).is_err());
+    }
+}
@@ -0,0 +1,291 @@
+use shakmaty::{fen::Fen, san::San, CastlingMode, Chess, File, Move, Position, Square};
+
+use crate::config::{BoardGeometry, CaptureStrategy};
+use crate::{
+    file_to_float, move_to_journeys, move_to_steps, rank_to_float, Magnet, PieceJourney, Step,
+};
+
+// positions picked to cover castling both ways, en passant, promotions and
+// capture-promotions, on top of the opening
+const TEST_POSITIONS: [&str; 5] = [
+    "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1",
+    "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1",
+    "8/2p5/3p4/KP5r/1R3p1k/8/4P1P1/8 w - - 0 1",
+    "r3k2r/Pppp1ppp/1b3nbN/nP6/BBP1P3/q4N2/Pp1P2PP/R2Q1RK1 w kq - 0 1",
+    "rnbq1k1r/pp1Pbppp/2p5/8/2B5/8/PPP1NnPP/RNBQK2R w KQ - 1 8",
+];
+
+// chess960 castles: the king and rook swapping squares, the king already on
+// its square, the rook standing on the king's, and a queenside castle with the
+// king right beside its rook
+const CHESS960_POSITIONS: [&str; 4] = [
+    "1r3k2/8/8/8/8/8/8/1R3KR1 w GBb - 0 1",
+    "6kr/8/8/8/8/8/8/6KR w Hh - 0 1",
+    "4k3/8/8/8/8/8/8/4K1R1 w G - 0 1",
+    "rk6/8/8/8/8/8/8/RK6 w Aa - 0 1",
+];
+
+// the board plus the half-square lanes and the graveyard columns either side
+const MIN_COORD: f64 = 0.0;
+const MAX_COORD: f64 = 9.0;
+
+// the emptiest and fullest graveyards, so slot arithmetic is checked at both ends
+const GRAVEYARD_FILLS: [f64; 2] = [0.0, 15.0];
+
+const STRATEGIES: [CaptureStrategy; 2] = [CaptureStrategy::ClearFirst, CaptureStrategy::StageFirst];
+
+// a scratch spot in the corner past the black graveyard, for the runs that park pieces
+const SCRATCH: (f64, f64) = (9.0, 9.0);
+
+// each legal move in `pos` with its SAN and the steps planned for it, one
+// line a move, with both graveyards empty
+pub fn dump_moves(pos: &Chess, geom: &BoardGeometry, strategy: CaptureStrategy) -> Vec<String> {
+    pos.legal_moves()
+        .into_iter()
+        .map(|mv| {
+            let san = San::from_move(pos, &mv);
+            let occupied = pos.board().occupied();
+            let steps = move_to_steps(mv, pos.turn(), 0.0, 0.0, occupied, geom, strategy);
+            format!("{san}: {steps:?}")
+        })
+        .collect()
+}
+
+// walks every legal move `depth` plies deep from each test position and
+// returns a description of every move whose steps break an invariant
+pub fn validate_step_planner(depth: u32) -> Vec<String> {
+    let mut violations = Vec::new();
+    let positions = TEST_POSITIONS
+        .map(|fen| (fen, CastlingMode::Standard))
+        .into_iter()
+        .chain(CHESS960_POSITIONS.map(|fen| (fen, CastlingMode::Chess960)));
+    for (fen, mode) in positions {
+        let pos: Chess = fen
+            .parse::<Fen>()
+            .expect("test positions are valid FENs")
+            .into_position(mode)
+            .expect("test positions are legal");
+        walk(&pos, depth, &mut violations);
+    }
+    violations
+}
+
+fn walk(pos: &Chess, depth: u32, violations: &mut Vec<String>) {
+    if depth == 0 {
+        return;
+    }
+    // the planner's own lanes are what's under test, not a particular gantry's travel
+    let geometries = [
+        BoardGeometry::default(),
+        BoardGeometry {
+            scratch: Some(SCRATCH),
+            ..BoardGeometry::default()
+        },
+    ];
+    for mv in pos.legal_moves() {
+        for (fill, strategy, geom) in GRAVEYARD_FILLS.into_iter().flat_map(|fill| {
+            STRATEGIES
+                .into_iter()
+                .flat_map(move |strategy| geometries.map(|geom| (fill, strategy, geom)))
+        }) {
+            let occupied = pos.board().occupied();
+            let journeys =
+                move_to_journeys(mv.clone(), pos.turn(), fill, fill, occupied, &geom, strategy);
+            let checked = unpark(&journeys, geom.scratch)
+                .and_then(|journeys| check_journeys(&mv, &journeys, strategy));
+            if let Err(reason) = checked {
+                let fen = Fen::from_position(pos.clone(), shakmaty::EnPassantMode::Legal);
+                violations.push(format!("{fen} {mv} {strategy:?}: {reason}"));
+            }
+        }
+        walk(&pos.clone().play(&mv).unwrap(), depth - 1, violations);
+    }
+}
+
+fn check_journeys(
+    mv: &Move,
+    journeys: &[PieceJourney],
+    strategy: CaptureStrategy,
+) -> Result<(), String> {
+    for journey in journeys {
+        for (i, step) in journey.steps.iter().enumerate() {
+            if step.magnet == Magnet::from(i == 0) {
+                return Err(format!("step {i} of a journey has the magnet {:?}", step.magnet));
+            }
+            let bounds = MIN_COORD..=MAX_COORD;
+            if !bounds.contains(&step.x) || !bounds.contains(&step.y) {
+                return Err(format!("step {step} is outside the board and graveyard"));
+            }
+        }
+    }
+
+    if let Move::Castle { king, rook } = *mv {
+        let (king_file, rook_file) = if king.file() < rook.file() {
+            (File::G, File::F)
+        } else {
+            (File::C, File::D)
+        };
+        // in chess960 either piece may not move at all, or the rook may have to
+        // wait off its square, so follow both pieces journey by journey
+        let mut pieces = [king, rook].map(|square| Step {
+            x: file_to_float(square.file()),
+            y: rank_to_float(square.rank()),
+            magnet: Magnet::Released,
+            feedrate: None,
+        });
+        for journey in journeys {
+            let (Some(pickup), Some(drop_off)) = (journey.pickup(), journey.drop_off()) else {
+                return Err("empty journey".to_string());
+            };
+            let Some(piece) = pieces.iter_mut().find(|piece| same_place(**piece, pickup)) else {
+                return Err(format!("journey starts at {pickup}, away from the king and rook"));
+            };
+            *piece = drop_off;
+        }
+        let targets = [
+            Square::from_coords(king_file, king.rank()),
+            Square::from_coords(rook_file, rook.rank()),
+        ];
+        for (piece, target) in pieces.into_iter().zip(targets) {
+            if !is_at(piece, target) {
+                return Err(format!("castling left a piece at {piece} instead of {target}"));
+            }
+        }
+        return Ok(());
+    }
+
+    if mv.is_capture() && strategy == CaptureStrategy::StageFirst {
+        return check_staged(mv, journeys);
+    }
+    let Some((mover, captures)) = journeys.split_last() else {
+        return Err("no journeys".to_string());
+    };
+    check_endpoints(mover, mv.from().unwrap(), mv.to())?;
+    let expected_captures = usize::from(mv.is_capture());
+    if captures.len() != expected_captures {
+        return Err(format!(
+            "{} journeys precede the mover, expected {expected_captures}",
+            captures.len()
+        ));
+    }
+    check_captures(captures)
+}
+
+// the journeys left once those parking a piece on the scratch spot and
+// bringing it back are taken out. each parked piece has to come back to the
+// square it left, and the spot has to be empty again by the end
+fn unpark(
+    journeys: &[PieceJourney],
+    scratch: Option<(f64, f64)>,
+) -> Result<Vec<PieceJourney>, String> {
+    let Some((x, y)) = scratch else {
+        return Ok(journeys.to_vec());
+    };
+    let spot = Step {
+        x,
+        y,
+        magnet: Magnet::Released,
+        feedrate: None,
+    };
+    let mut parked: Option<Step> = None;
+    let mut rest = Vec::new();
+    for journey in journeys {
+        let (Some(pickup), Some(drop_off)) = (journey.pickup(), journey.drop_off()) else {
+            return Err("empty journey".to_string());
+        };
+        if same_place(drop_off, spot) {
+            if let Some(waiting) = parked {
+                return Err(format!("parked {pickup} on the scratch spot {waiting} still holds"));
+            }
+            parked = Some(pickup);
+        } else if same_place(pickup, spot) {
+            let Some(from) = parked.take() else {
+                return Err("picked up from an empty scratch spot".to_string());
+            };
+            if !same_place(from, drop_off) {
+                return Err(format!("piece parked from {from} came back to {drop_off}"));
+            }
+        } else {
+            rest.push(journey.clone());
+        }
+    }
+    parked.map_or(Ok(rest), |from| Err(format!("piece from {from} left on the scratch spot")))
+}
+
+// the attacker goes to its staging spot, the captured piece to the graveyard,
+// and then the attacker on from exactly where it waited
+fn check_staged(mv: &Move, journeys: &[PieceJourney]) -> Result<(), String> {
+    let [staged, capture, last_leg] = journeys else {
+        return Err(format!("{} journeys for a staged capture, expected 3", journeys.len()));
+    };
+    let (Some(pickup), Some(waited)) = (staged.pickup(), staged.drop_off()) else {
+        return Err("empty journey".to_string());
+    };
+    let (Some(resumed), Some(drop_off)) = (last_leg.pickup(), last_leg.drop_off()) else {
+        return Err("empty journey".to_string());
+    };
+    let (from, to) = (mv.from().unwrap(), mv.to());
+    if !is_at(pickup, from) {
+        return Err(format!("journey starts at {pickup} instead of {from}"));
+    }
+    if !same_place(waited, resumed) {
+        return Err(format!("attacker staged at {waited} but picked up from {resumed}"));
+    }
+    if !is_at(drop_off, to) {
+        return Err(format!("journey ends at {drop_off} instead of {to}"));
+    }
+    check_captures(std::slice::from_ref(capture))
+}
+
+fn check_captures(captures: &[PieceJourney]) -> Result<(), String> {
+    for capture in captures {
+        let drop_off = capture.drop_off().unwrap();
+        if !is_graveyard(drop_off) {
+            return Err(format!("captured piece dropped at {drop_off} instead of the graveyard"));
+        }
+    }
+    Ok(())
+}
+
+fn check_endpoints(journey: &PieceJourney, from: Square, to: Square) -> Result<(), String> {
+    let (Some(pickup), Some(drop_off)) = (journey.pickup(), journey.drop_off()) else {
+        return Err("empty journey".to_string());
+    };
+    if !is_at(pickup, from) {
+        return Err(format!("journey starts at {pickup} instead of {from}"));
+    }
+    if !is_at(drop_off, to) {
+        return Err(format!("journey ends at {drop_off} instead of {to}"));
+    }
+    Ok(())
+}
+
+fn is_at(step: Step, square: Square) -> bool {
+    (step.x - file_to_float(square.file())).abs() < f64::EPSILON
+        && (step.y - rank_to_float(square.rank())).abs() < f64::EPSILON
+}
+
+fn same_place(a: Step, b: Step) -> bool {
+    (a.x - b.x).abs() < f64::EPSILON && (a.y - b.y).abs() < f64::EPSILON
+}
+
+fn is_graveyard(step: Step) -> bool {
+    (step.x - MIN_COORD).abs() < f64::EPSILON || (step.x - MAX_COORD).abs() < f64::EPSILON
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn start_position_dumps_twenty_moves_with_steps() {
+        let geometry = BoardGeometry::default();
+        let lines = dump_moves(&Chess::default(), &geometry, CaptureStrategy::default());
+        assert_eq!(lines.len(), 20);
+        for line in &lines {
+            let (san, steps) = line.split_once(": ").unwrap();
+            assert!(san.parse::<San>().is_ok(), "{line}");
+            assert_ne!(steps, "[]", "{line}");
+        }
+    }
+}
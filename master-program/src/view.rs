@@ -0,0 +1,190 @@
+use log::{error, info};
+use serde::Serialize;
+use shakmaty::{fen::Fen, uci::Uci, Chess, Color, EnPassantMode, Move, Position};
+use std::io::Write;
+use std::net::TcpListener;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use crate::clock::Clocks;
+#[cfg(feature = "spectators")]
+use crate::spectators::Spectators;
+use crate::telemetry::Event;
+use crate::{can_claim_fifty_moves, legal_move_count, Graveyard, State};
+
+// the board as it stands right now, for a companion display. unlike telemetry
+// this is not a history, just the latest picture
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct BoardView {
+    pub fen: String,
+    pub turn: &'static str,
+    pub state: State,
+    // the last move played by either side, in UCI
+    pub last_move: Option<String>,
+    // None when playing without clocks
+    pub clocks: Option<ClockView>,
+    pub captured: CapturedView,
+    // whether the side to move could claim a draw under the fifty-move rule
+    pub can_claim_draw: bool,
+    // how many legal moves the piece in hand has, for a buzzer or tone to
+    // convey to players who can't see the LEDs well. None with nothing lifted
+    pub lifted_moves: Option<usize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct ClockView {
+    pub white_ms: u64,
+    pub black_ms: u64,
+}
+
+// how many pieces of each colour are in the graveyards
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct CapturedView {
+    pub white: u8,
+    pub black: u8,
+}
+
+impl BoardView {
+    pub fn new(
+        position: &Chess,
+        state: State,
+        last_move: Option<&Move>,
+        clocks: Option<&Clocks>,
+        graveyard: Graveyard,
+        now: Instant,
+    ) -> Self {
+        let ms = |color| {
+            let remaining = clocks.map(|clocks| clocks.remaining(color, now)).unwrap_or_default();
+            u64::try_from(remaining.as_millis()).unwrap_or(u64::MAX)
+        };
+        Self {
+            fen: Fen::from_position(position.clone(), EnPassantMode::Legal).to_string(),
+            turn: if position.turn().is_white() { "white" } else { "black" },
+            state,
+            last_move: last_move
+                .map(|mv| Uci::from_move(mv, position.castles().mode()).to_string()),
+            clocks: clocks.map(|_| ClockView {
+                white_ms: ms(Color::White),
+                black_ms: ms(Color::Black),
+            }),
+            captured: CapturedView {
+                white: graveyard.captured_whites,
+                black: graveyard.captured_blacks,
+            },
+            can_claim_draw: can_claim_fifty_moves(position),
+            lifted_moves: match state {
+                State::FriendlyPU(square) => Some(legal_move_count(position, square)),
+                _ => None,
+            },
+        }
+    }
+}
+
+// holds the latest view for anyone who asks: in process through `latest`, or
+// over a local socket that answers every connection with the view as one JSON
+// line and hangs up. with the spectators feature it can also broadcast the
+// view and each move over WebSocket
+#[derive(Default, Clone)]
+pub struct ViewServer {
+    latest: Arc<Mutex<Option<BoardView>>>,
+    #[cfg(feature = "spectators")]
+    spectators: Option<Spectators>,
+}
+
+impl ViewServer {
+    pub fn serve(addr: &str) -> std::io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        info!("serving the board view on {}", listener.local_addr()?);
+        let server = Self::default();
+        let latest = Arc::clone(&server.latest);
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let view = latest.lock().unwrap().clone();
+                let res = stream.and_then(|mut stream| {
+                    serde_json::to_writer(&mut stream, &view)?;
+                    writeln!(stream)
+                });
+                if let Err(e) = res {
+                    error!("Failed to send board view: {e}");
+                }
+            }
+        });
+        Ok(server)
+    }
+
+    #[cfg(feature = "spectators")]
+    pub fn spectate(self, addr: &str) -> std::io::Result<Self> {
+        Ok(Self {
+            spectators: Some(Spectators::serve(addr)?),
+            ..self
+        })
+    }
+
+    // the view is published every tick, so spectators only hear of it when it changes
+    pub fn publish(&self, view: BoardView) {
+        let mut latest = self.latest.lock().unwrap();
+        #[cfg(feature = "spectators")]
+        if let Some(spectators) = &self.spectators {
+            if latest.as_ref() != Some(&view) {
+                spectators.view(&view);
+            }
+        }
+        *latest = Some(view);
+    }
+
+    // a committed move, by either side
+    #[cfg_attr(
+        not(feature = "spectators"),
+        allow(clippy::unused_self, clippy::missing_const_for_fn, unused_variables)
+    )]
+    pub fn moved(&self, event: &Event) {
+        #[cfg(feature = "spectators")]
+        if let Some(spectators) = &self.spectators {
+            spectators.moved(event);
+        }
+    }
+
+    pub fn latest(&self) -> Option<BoardView> {
+        self.latest.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::TimeControl;
+    use shakmaty::{san::San, Square};
+    use std::time::Duration;
+
+    #[test]
+    fn view_shows_the_position_after_a_capture() {
+        let mut pos = Chess::default();
+        let mut graveyard = Graveyard::default();
+        let mut last = None;
+        for san in ["e4", "d5", "exd5"] {
+            let mv = san.parse::<San>().unwrap().to_move(&pos).unwrap();
+            graveyard.record(&mv, pos.turn());
+            pos.play_unchecked(&mv);
+            last = Some(mv);
+        }
+        let now = Instant::now();
+        let clocks = Clocks::new(TimeControl {
+            initial: Duration::from_mins(5),
+            increment: Duration::ZERO,
+        });
+        let state = State::FriendlyPU(Square::D8);
+        let view = BoardView::new(&pos, state, last.as_ref(), Some(&clocks), graveyard, now);
+        assert_eq!(view.fen, "rnbqkbnr/ppp1pppp/8/3P4/8/8/PPPP1PPP/RNBQKBNR b KQkq - 0 2");
+        assert_eq!(view.turn, "black");
+        assert_eq!(view.last_move.as_deref(), Some("e4d5"));
+        assert_eq!(view.captured, CapturedView { white: 0, black: 1 });
+        assert_eq!(view.clocks, Some(ClockView { white_ms: 300_000, black_ms: 300_000 }));
+        // the queen can go to d7, d6 or take on d5
+        assert_eq!(view.lifted_moves, Some(3));
+        assert!(!view.can_claim_draw);
+        // and anyone asking gets the latest one
+        let server = ViewServer::default();
+        server.publish(view.clone());
+        assert_eq!(server.latest(), Some(view));
+    }
+}